@@ -0,0 +1,297 @@
+//! Throughput of concurrent authenticated requests.
+//!
+//! The in-process part always runs. It compares the two ways the authentication middleware has
+//! wrapped its inner service. The old one kept it behind a Mutex, locked for the whole call. The
+//! current one shares it through an Rc. Both wrap the same handler, and both authenticate with
+//! the same simulated round trip to the database, so only the wrapping differs:
+//!
+//! cargo bench --bench auth_throughput
+//!
+//! Given a user, it also runs against a server already started with `cargo run --release`, logging
+//! in and sending every request with the session token:
+//!
+//! UTA_BENCH_USERNAME=... UTA_BENCH_PASSWORD=... cargo bench --bench auth_throughput
+//!
+//! Optional settings:
+//! - UTA_BENCH_URL: base URL of the server, https://127.0.0.1:8010 by default
+//! - UTA_BENCH_PATH: authenticated endpoint requested, /v1/api/internal/alive by default
+//! - UTA_BENCH_CONCURRENCY: requests in flight at the same time, 64 by default
+//! - UTA_BENCH_REQUESTS: total requests sent, 10000 by default
+//! - UTA_BENCH_LATENCY_MS: simulated latency of the authentication and of the handler in process,
+//!   1 by default
+
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use actix_web::{App, Error, HttpResponse, web};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::test::{init_service, TestRequest};
+use error_mapper::{map_to_new_error, SystemErrorCodes, TheError, TheResult};
+use futures_util::future::LocalBoxFuture;
+use futures_util::StreamExt;
+use tokio::sync::Mutex;
+
+#[tokio::main]
+async fn main() -> TheResult<()> {
+
+    let concurrency = env_or("UTA_BENCH_CONCURRENCY", "64").parse::<usize>().map_err(|e| map_to_new_error!(e))?;
+    let total_requests = env_or("UTA_BENCH_REQUESTS", "10000").parse::<usize>().map_err(|e| map_to_new_error!(e))?;
+    let latency = env_or("UTA_BENCH_LATENCY_MS", "1").parse::<u64>().map_err(|e| map_to_new_error!(e))?;
+    let latency = Duration::from_millis(latency);
+
+    //  Actix services aren't Send, like in a worker they are all driven from this thread
+    let local = tokio::task::LocalSet::new();
+    local.run_until(in_process::<LockedAuthentication>("Mutex middleware", concurrency, total_requests, latency)).await;
+    local.run_until(in_process::<SharedAuthentication>("Rc middleware", concurrency, total_requests, latency)).await;
+
+    if let Ok(username) = std::env::var("UTA_BENCH_USERNAME") {
+        against_server(username, concurrency, total_requests).await?;
+    }
+
+    Ok(())
+}
+
+/// Sends the requests through the middleware received, wrapping a handler that takes the latency
+/// to answer
+async fn in_process<M>(label: &str, concurrency: usize, total_requests: usize, latency: Duration)
+    where
+        M: Middleware
+{
+    let app = init_service(
+        App::new()
+            .route("/", web::get().to(move || async move {
+                tokio::time::sleep(latency).await;
+                HttpResponse::Ok().finish()
+            }))
+            .wrap(Authentication::<M>::new(latency))
+    ).await;
+    let app = &app;
+
+    let start = Instant::now();
+
+    let results = futures_util::stream::iter(0..total_requests)
+        .map(|_| async move {
+            let request_start = Instant::now();
+            match app.call(TestRequest::get().uri("/").to_request()).await {
+                Ok(response) if response.status().is_success() => Some(request_start.elapsed()),
+                _ => None
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+
+    let elapsed = start.elapsed();
+    let failed = results.iter().filter(|result| result.is_none()).count();
+    let latencies = results.into_iter().flatten().collect::<Vec<_>>();
+
+    report(label, concurrency, failed, elapsed, latencies);
+}
+
+/// How the middleware keeps the inner service and calls it
+trait Middleware: Sized + 'static {
+    type Service<S: 'static>;
+
+    fn wrap<S: 'static>(service: S) -> Self::Service<S>;
+
+    fn call<S>(service: &Self::Service<S>, req: ServiceRequest) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>>
+        where
+            S: Service<ServiceRequest, Response = ServiceResponse, Error = Error> + 'static;
+}
+
+/// The inner service behind a Mutex, locked for the whole call, as the middleware used to
+struct LockedAuthentication;
+
+impl Middleware for LockedAuthentication {
+    type Service<S: 'static> = Arc<Mutex<S>>;
+
+    fn wrap<S: 'static>(service: S) -> Self::Service<S> {
+        Arc::new(Mutex::new(service))
+    }
+
+    fn call<S>(service: &Self::Service<S>, req: ServiceRequest) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>>
+        where
+            S: Service<ServiceRequest, Response = ServiceResponse, Error = Error> + 'static
+    {
+        let service = Arc::clone(service);
+        Box::pin(async move {
+            let service = service.lock().await;
+            service.call(req).await
+        })
+    }
+}
+
+/// The inner service shared through an Rc, as the middleware does now
+struct SharedAuthentication;
+
+impl Middleware for SharedAuthentication {
+    type Service<S: 'static> = Rc<S>;
+
+    fn wrap<S: 'static>(service: S) -> Self::Service<S> {
+        Rc::new(service)
+    }
+
+    fn call<S>(service: &Self::Service<S>, req: ServiceRequest) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>>
+        where
+            S: Service<ServiceRequest, Response = ServiceResponse, Error = Error> + 'static
+    {
+        let service = Rc::clone(service);
+        Box::pin(async move { service.call(req).await })
+    }
+}
+
+/// Authenticates every request with a simulated round trip to the database, then calls the inner
+/// service the way of the middleware
+struct Authentication<M> {
+    latency: Duration,
+    middleware: std::marker::PhantomData<M>
+}
+
+impl<M> Authentication<M> {
+    fn new(latency: Duration) -> Self {
+        Self { latency, middleware: std::marker::PhantomData }
+    }
+}
+
+impl<S, M> Transform<S, ServiceRequest> for Authentication<M>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
+        M: Middleware
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Transform = AuthenticationMiddleware<S, M>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthenticationMiddleware { service: Rc::new(M::wrap(service)), latency: self.latency }))
+    }
+}
+
+struct AuthenticationMiddleware<S: 'static, M: Middleware> {
+    service: Rc<M::Service<S>>,
+    latency: Duration
+}
+
+impl<S, M> Service<ServiceRequest> for AuthenticationMiddleware<S, M>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
+        M: Middleware
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+
+        let service = Rc::clone(&self.service);
+        let latency = self.latency;
+
+        Box::pin(async move {
+            tokio::time::sleep(latency).await;
+            M::call(&service, req).await
+        })
+    }
+}
+
+/// Logs in with the user received and sends every request to the server with its session token
+async fn against_server(username: String, concurrency: usize, total_requests: usize) -> TheResult<()> {
+
+    let base_url = env_or("UTA_BENCH_URL", "https://127.0.0.1:8010");
+    let path = env_or("UTA_BENCH_PATH", "/v1/api/internal/alive");
+    let password = env_or("UTA_BENCH_PASSWORD", "");
+
+    //  The server runs with a self signed certificate
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .map_err(|e| map_to_new_error!(e))?;
+
+    let login = client.post(format!("{}/v1/users/login", base_url))
+        .json(&serde_json::json!({ "username": username, "password": password }))
+        .send()
+        .await
+        .map_err(|e| map_to_new_error!(e))?;
+    if !login.status().is_success() {
+        return Err(
+            TheError::new(
+                SystemErrorCodes::GenericError,
+                format!("Login failed with status {}", login.status())
+            )
+        )
+    }
+    //  The session token comes in the data of the login response
+    let login = login.json::<serde_json::Value>().await.map_err(|e| map_to_new_error!(e))?;
+    let Some(token) = login["data"]["session_token"].as_str().map(|token| token.to_string()) else {
+        return Err(TheError::new(SystemErrorCodes::GenericError, "Login response without a session token".to_string()))
+    };
+
+    let url = Arc::new(format!("{}{}", base_url, path));
+    let sent = Arc::new(AtomicUsize::new(0));
+    let failed = Arc::new(AtomicUsize::new(0));
+
+    let start = Instant::now();
+
+    //  Every task keeps one request in flight until the total is reached
+    let tasks = (0..concurrency).map(|_| {
+        let (client, url, username, token) = (client.clone(), Arc::clone(&url), username.clone(), token.clone());
+        let (sent, failed) = (Arc::clone(&sent), Arc::clone(&failed));
+        tokio::spawn(async move {
+            let mut latencies = Vec::new();
+            while sent.fetch_add(1, Ordering::Relaxed) < total_requests {
+                let request_start = Instant::now();
+                let response = client.get(url.as_str())
+                    .header("username", username.as_str())
+                    .header("token", token.as_str())
+                    .send()
+                    .await;
+                match response {
+                    Ok(response) if response.status().is_success() => latencies.push(request_start.elapsed()),
+                    _ => { failed.fetch_add(1, Ordering::Relaxed); }
+                }
+            }
+            latencies
+        })
+    }).collect::<Vec<_>>();
+
+    let mut latencies = Vec::with_capacity(total_requests);
+    for task in tasks {
+        latencies.extend(task.await.map_err(|e| map_to_new_error!(e))?);
+    }
+
+    report(url.as_str(), concurrency, failed.load(Ordering::Relaxed), start.elapsed(), latencies);
+
+    Ok(())
+}
+
+fn report(label: &str, concurrency: usize, failed: usize, elapsed: Duration, mut latencies: Vec<Duration>) {
+
+    latencies.sort();
+
+    println!("{} requests, {} in flight, {}", latencies.len() + failed, concurrency, label);
+    println!("Failed:      {}", failed);
+    println!("Elapsed:     {:?}", elapsed);
+    println!("Throughput:  {:.0} requests/s", latencies.len() as f64 / elapsed.as_secs_f64());
+    println!("Latency p50: {:?}", percentile(latencies.as_slice(), 50));
+    println!("Latency p99: {:?}", percentile(latencies.as_slice(), 99));
+    println!();
+}
+
+fn env_or(name: &str, default: &str) -> String {
+    std::env::var(name).unwrap_or(default.to_string())
+}
+
+fn percentile(sorted_latencies: &[Duration], percentile: usize) -> Duration {
+    if sorted_latencies.is_empty() {
+        return Duration::default()
+    }
+    sorted_latencies[(sorted_latencies.len() - 1) * percentile / 100]
+}
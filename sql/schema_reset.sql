@@ -8,10 +8,35 @@ CREATE TABLE users(
     username VARCHAR(20) UNIQUE KEY NOT NULL,
     hashed_pass VARCHAR(25) NOT NULL,
     email VARCHAR(50) NOT NULL,
+    display_name VARCHAR(50) DEFAULT NULL,
     level ENUM('View', 'Low', 'Medium', 'High', 'Super') NOT NULL DEFAULT 'View',
     created_at DATETIME NOT NULL DEFAULT CURTIME(),
     updated_at DATETIME NOT NULL DEFAULT CURTIME(),
-    deleted_at DATETIME DEFAULT NULL
+    deleted_at DATETIME DEFAULT NULL,
+    service_account BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+DROP TABLE if EXISTS users_username_history;
+CREATE TABLE users_username_history (
+    users_ID INT NOT NULL,
+    old_username VARCHAR(20) NOT NULL,
+    new_username VARCHAR(20) NOT NULL,
+    changed_at DATETIME NOT NULL DEFAULT CURTIME(),
+    KEY users_username_history_old_username (old_username),
+    FOREIGN KEY users_username_history_users_ID (users_ID) REFERENCES users (ID)
+);
+
+DROP TABLE if EXISTS users_profiles;
+CREATE TABLE users_profiles (
+    users_ID INT PRIMARY KEY,
+    first_name VARCHAR(50) DEFAULT NULL,
+    last_name VARCHAR(50) DEFAULT NULL,
+    locale VARCHAR(35) DEFAULT NULL,
+    timezone VARCHAR(64) DEFAULT NULL,
+    avatar_url VARCHAR(255) DEFAULT NULL,
+    metadata JSON DEFAULT NULL,
+    updated_at DATETIME NOT NULL DEFAULT CURTIME(),
+    FOREIGN KEY users_profiles_users_ID (users_ID) REFERENCES users (ID)
 );
 
 DROP TABLE if EXISTS users_sessions;
@@ -20,5 +45,149 @@ CREATE TABLE users_sessions (
 	token VARCHAR(45) NOT NULL,
 	creation DATETIME NOT NULL,
 	expiry DATETIME NOT NULL,
+	client_ip VARCHAR(45) DEFAULT NULL,
+	user_agent_family VARCHAR(30) DEFAULT NULL,
+	reauthenticated_at DATETIME DEFAULT NULL,
 	FOREIGN KEY users_sessions_users_ID (users_ID) REFERENCES users (ID)
 );
+
+DROP TABLE if EXISTS organizations;
+CREATE TABLE organizations (
+    ID INT PRIMARY KEY,
+    name VARCHAR(50) NOT NULL,
+    slug VARCHAR(30) UNIQUE KEY NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURTIME()
+);
+
+DROP TABLE if EXISTS organizations_users;
+CREATE TABLE organizations_users (
+    organizations_ID INT NOT NULL,
+    users_ID INT NOT NULL,
+    level ENUM('View', 'Low', 'Medium', 'High') NOT NULL DEFAULT 'View',
+    created_at DATETIME NOT NULL DEFAULT CURTIME(),
+    updated_at DATETIME NOT NULL DEFAULT CURTIME(),
+    deleted_at DATETIME DEFAULT NULL,
+    PRIMARY KEY (organizations_ID, users_ID),
+    FOREIGN KEY organizations_users_organizations_ID (organizations_ID) REFERENCES organizations (ID),
+    FOREIGN KEY organizations_users_users_ID (users_ID) REFERENCES users (ID)
+);
+
+DROP TABLE if EXISTS users_groups;
+CREATE TABLE users_groups (
+    ID INT PRIMARY KEY,
+    name VARCHAR(30) UNIQUE KEY NOT NULL,
+    level ENUM('View', 'Low', 'Medium', 'High') NOT NULL DEFAULT 'View',
+    created_at DATETIME NOT NULL DEFAULT CURTIME(),
+    updated_at DATETIME NOT NULL DEFAULT CURTIME()
+);
+
+DROP TABLE if EXISTS users_groups_members;
+CREATE TABLE users_groups_members (
+    users_groups_ID INT NOT NULL,
+    users_ID INT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURTIME(),
+    PRIMARY KEY (users_groups_ID, users_ID),
+    FOREIGN KEY users_groups_members_users_groups_ID (users_groups_ID) REFERENCES users_groups (ID),
+    FOREIGN KEY users_groups_members_users_ID (users_ID) REFERENCES users (ID)
+);
+
+DROP TABLE if EXISTS api_keys;
+CREATE TABLE api_keys (
+    ID INT PRIMARY KEY,
+    users_ID INT NOT NULL,
+    name VARCHAR(50) NOT NULL,
+    prefix VARCHAR(12) UNIQUE KEY NOT NULL,
+    hashed_key CHAR(64) NOT NULL,
+    scopes VARCHAR(100) NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURTIME(),
+    expires_at DATETIME DEFAULT NULL,
+    last_used_at DATETIME DEFAULT NULL,
+    revoked_at DATETIME DEFAULT NULL,
+    FOREIGN KEY api_keys_users_ID (users_ID) REFERENCES users (ID)
+);
+
+DROP TABLE if EXISTS personal_access_tokens;
+CREATE TABLE personal_access_tokens (
+    ID INT PRIMARY KEY,
+    users_ID INT NOT NULL,
+    name VARCHAR(50) NOT NULL,
+    prefix VARCHAR(12) UNIQUE KEY NOT NULL,
+    hashed_token CHAR(64) NOT NULL,
+    scopes VARCHAR(150) NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURTIME(),
+    expires_at DATETIME NOT NULL,
+    last_used_at DATETIME DEFAULT NULL,
+    revoked_at DATETIME DEFAULT NULL,
+    FOREIGN KEY personal_access_tokens_users_ID (users_ID) REFERENCES users (ID)
+);
+
+DROP TABLE if EXISTS oauth_clients;
+CREATE TABLE oauth_clients (
+    ID INT PRIMARY KEY,
+    client_id VARCHAR(24) UNIQUE KEY NOT NULL,
+    name VARCHAR(50) NOT NULL,
+    hashed_secret CHAR(64) DEFAULT NULL,
+    redirect_uris VARCHAR(1000) NOT NULL,
+    scopes VARCHAR(100) NOT NULL,
+    service_account_users_ID INT DEFAULT NULL,
+    revokes_sessions BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at DATETIME NOT NULL DEFAULT CURTIME(),
+    FOREIGN KEY oauth_clients_service_account_users_ID (service_account_users_ID) REFERENCES users (ID)
+);
+
+DROP TABLE if EXISTS oauth_consents;
+CREATE TABLE oauth_consents (
+    users_ID INT NOT NULL,
+    oauth_clients_ID INT NOT NULL,
+    scopes VARCHAR(100) NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURTIME(),
+    updated_at DATETIME NOT NULL DEFAULT CURTIME(),
+    PRIMARY KEY (users_ID, oauth_clients_ID),
+    FOREIGN KEY oauth_consents_users_ID (users_ID) REFERENCES users (ID),
+    FOREIGN KEY oauth_consents_oauth_clients_ID (oauth_clients_ID) REFERENCES oauth_clients (ID)
+);
+
+DROP TABLE if EXISTS oauth_authorization_codes;
+CREATE TABLE oauth_authorization_codes (
+    ID INT PRIMARY KEY,
+    oauth_clients_ID INT NOT NULL,
+    users_ID INT NOT NULL,
+    hashed_code CHAR(64) UNIQUE KEY NOT NULL,
+    redirect_uri VARCHAR(255) NOT NULL,
+    scopes VARCHAR(100) NOT NULL,
+    code_challenge VARCHAR(128) NOT NULL,
+    code_challenge_method ENUM('S256', 'plain') NOT NULL DEFAULT 'S256',
+    nonce VARCHAR(255) DEFAULT NULL,
+    expires_at DATETIME NOT NULL,
+    used_at DATETIME DEFAULT NULL,
+    FOREIGN KEY oauth_authorization_codes_oauth_clients_ID (oauth_clients_ID) REFERENCES oauth_clients (ID),
+    FOREIGN KEY oauth_authorization_codes_users_ID (users_ID) REFERENCES users (ID)
+);
+
+DROP TABLE if EXISTS oauth_tokens;
+CREATE TABLE oauth_tokens (
+    ID INT PRIMARY KEY,
+    oauth_clients_ID INT NOT NULL,
+    users_ID INT NOT NULL,
+    access_prefix VARCHAR(12) UNIQUE KEY NOT NULL,
+    hashed_access_token CHAR(64) NOT NULL,
+    refresh_prefix VARCHAR(12) UNIQUE KEY DEFAULT NULL,
+    hashed_refresh_token CHAR(64) DEFAULT NULL,
+    scopes VARCHAR(100) NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURTIME(),
+    expires_at DATETIME NOT NULL,
+    refresh_expires_at DATETIME DEFAULT NULL,
+    revoked_at DATETIME DEFAULT NULL,
+    FOREIGN KEY oauth_tokens_oauth_clients_ID (oauth_clients_ID) REFERENCES oauth_clients (ID),
+    FOREIGN KEY oauth_tokens_users_ID (users_ID) REFERENCES users (ID)
+);
+
+DROP TABLE if EXISTS session_events;
+CREATE TABLE session_events (
+    ID INT PRIMARY KEY AUTO_INCREMENT,
+    event ENUM('SessionTerminated', 'AccountDeleted', 'LevelChanged', 'UsernameChanged') NOT NULL,
+    users_ID INT NOT NULL,
+    origin VARCHAR(16) NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURTIME(),
+    INDEX session_events_created_at (created_at)
+);
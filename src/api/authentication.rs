@@ -1,14 +1,16 @@
 use std::fmt;
 use std::future::{ready, Ready};
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::rc::Rc;
 use std::task::{Context, Poll};
 
-use actix_web::{dev::{Service, ServiceRequest, ServiceResponse, Transform}, Error, HttpResponse, ResponseError};
-use actix_web::http::StatusCode;
+use actix_web::{dev::{Service, ServiceRequest, ServiceResponse, Transform}, Error, HttpMessage, HttpResponse, ResponseError};
+use actix_web::body::EitherBody;
 use futures_util::future::LocalBoxFuture;
-use crate::modules::users::user::{Level, User};
-use crate::modules::users::UsersSessions;
+use crate::api::authenticator::{AuthenticationError, Authenticator, Credential};
+use crate::general::problem::ErrorCode;
+use crate::modules::personal_tokens::personal_token::TokenScope;
+use crate::modules::users::session_binding::ClientFingerprint;
+use crate::modules::users::user::Level;
 
 // There are two steps in middleware processing.
 // 1. Middleware initialization, middleware factory gets called with
@@ -17,12 +19,20 @@ use crate::modules::users::UsersSessions;
 
 
 pub struct UserAuthentication {
-    level: Level
+    level: Level,
+    authenticator: Authenticator
 }
 
 impl UserAuthentication {
     pub fn new(level: Level) -> Self {
-        UserAuthentication { level }
+        UserAuthentication { level, authenticator: Authenticator::new() }
+    }
+
+    /// The required level is checked against the level the user holds in the organization the
+    /// request is addressed to, instead of the user's global level. The resolved organization is
+    /// made available to handlers as a Tenant in the request extensions
+    pub fn organization_scoped(level: Level) -> Self {
+        UserAuthentication { level, authenticator: Authenticator::organization_scoped() }
     }
 }
 
@@ -34,7 +44,7 @@ impl<S, B> Transform<S, ServiceRequest> for UserAuthentication
         S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
         S::Future: 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<EitherBody<B>>;
     type Error = Error;
     type Transform = UserAuthenticationMiddleware<S>;
     type InitError = ();
@@ -42,15 +52,19 @@ impl<S, B> Transform<S, ServiceRequest> for UserAuthentication
 
     fn new_transform(&self, service: S) -> Self::Future {
         ready(Ok(UserAuthenticationMiddleware {
-            service: Arc::new(Mutex::new(service)),
-            level: Arc::new(Mutex::new(self.level))
+            service: Rc::new(service),
+            level: self.level,
+            authenticator: self.authenticator
         }))
     }
 }
 
+//  Each worker builds its own middleware, so the inner service is shared with the requests in
+// flight through an Rc. Services take &self, there's no need to lock it to call it
 pub struct UserAuthenticationMiddleware<S> {
-    service: Arc<Mutex<S>>,
-    level: Arc<Mutex<Level>>
+    service: Rc<S>,
+    level: Level,
+    authenticator: Authenticator
 }
 
 impl<S, B> Service<ServiceRequest> for UserAuthenticationMiddleware<S>
@@ -58,154 +72,202 @@ impl<S, B> Service<ServiceRequest> for UserAuthenticationMiddleware<S>
         S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
         S::Future: 'static
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<EitherBody<B>>;
     type Error = Error;
     type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
-    fn poll_ready(&self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
+    fn poll_ready(&self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
     }
 
     fn call(&self, mut req: ServiceRequest) -> Self::Future {
 
-        let inner = Arc::clone(&self.service);
-        let level = Arc::clone(&self.level);
+        let service = Rc::clone(&self.service);
+        let level = self.level;
+        let authenticator = self.authenticator;
 
         Box::pin(async move {
-            if let Some(auth_error) = user_authentication_validation(&mut req, level).await {
-                return Err(actix_web::Error::from(auth_error));
+            //  Failed authentications are responded here instead of returned as errors, so the
+            // middlewares wrapping this one, like the versioning, see the response
+            if let Some(auth_error) = user_authentication_validation(&mut req, level, authenticator).await {
+                return Ok(req.error_response(auth_error).map_into_right_body());
             }
-            let service = inner.lock().await;
-            service.call(req).await
+            service.call(req).await.map(ServiceResponse::map_into_left_body)
         })
     }
 }
 
-async fn user_authentication_validation(req: &mut ServiceRequest, level: Arc<Mutex<Level>>) -> Option<TheHttpResponse> {
-
-    //  Attempt to fetch username from headers
-    let username = match req.headers().get("username") {
-        Some(username) => {
-            match username.to_str() {
-                Ok(username) => username,
-                Err(_) => {
-                    return Some(
-                        TheHttpResponse::status_code(StatusCode::FORBIDDEN)
-                            .with_body("Invalid username".to_string())
-                    )
-                }
-            }
-        },
-        None => {
-            return Some(
-                TheHttpResponse::status_code(StatusCode::FORBIDDEN)
-                    .with_body("No username provided or found".to_string())
-            )
-        }
-    };
+async fn user_authentication_validation(
+    req: &mut ServiceRequest,
+    level: Level,
+    authenticator: Authenticator
+) -> Option<AuthenticationError> {
 
-    //  Attempt to fetch token from headers
-    let token = match req.headers().get("token") {
-        Some(token) => {
-            match token.to_str() {
-                Ok(token) => token,
-                Err(_) => {
-                    return Some(
-                        TheHttpResponse::status_code(StatusCode::FORBIDDEN)
-                            .with_body("Invalid session token".to_string())
-                    )
-                }
-            }
-        },
-        None => {
-            return Some(
-                TheHttpResponse::status_code(StatusCode::FORBIDDEN)
-                    .with_body("No session token received".to_string())
-            )
-        }
-    };
+    let client = ClientFingerprint::from_request(req.peer_addr().map(|address| address.ip()), req.headers()).await;
 
-    //  Fetch user data from database
-    let user = match User::select_by_username(username).await {
-        Ok(Some(user)) => user,
-        Ok(None) => return Some(
-            TheHttpResponse::status_code(StatusCode::FORBIDDEN)
-                .with_body("User not found".to_string())
-        ),
-        Err(_) => {
-            return Some(
-                TheHttpResponse::status_code(StatusCode::INTERNAL_SERVER_ERROR)
-                    .with_body("Failed to fetch user data".to_string())
-            )
-        }
+    let authentication = match authenticator.authenticate(req.headers(), &client).await {
+        Ok(authentication) => authentication,
+        Err(auth_error) => return Some(auth_error)
     };
 
-    //  Check if user is logged in
-    if !UsersSessions::instance().is_user_logged_in(user.get_id()).await {
-        return Some(
-            TheHttpResponse::status_code(StatusCode::UNAUTHORIZED)
-                .with_body("User not logged in".to_string())
-        )
-    };
+    //  Validate user level, in the organization the request is addressed to if scoped
+    if let Err(auth_error) = authentication.require_level(level).await {
+        return Some(auth_error)
+    }
 
-    //  Validate user level
-    if *user.get_level() < *level.lock().await {
-        return Some(
-            TheHttpResponse::status_code(StatusCode::FORBIDDEN)
-                .with_body("User level below required privileges".to_string())
-        )
-    }
-
-    //  Validate session token
-    match super::modules::users::users_sessions::validate_session_token(&user, token).await {
-        Ok(true) => {},
-        Ok(false) => {
-            return Some(
-                TheHttpResponse::status_code(StatusCode::FORBIDDEN)
-                    .with_body("Invalid session token".to_string())
-            )
-        },
-        Err(_) => {
-            return Some(
-                TheHttpResponse::status_code(StatusCode::INTERNAL_SERVER_ERROR)
-                    .with_body("Failed to validate session token".to_string())
-            )
-        }
+    //  Handlers fetch the authenticated user from the request instead of validating it again, and
+    // the scope guards of the routes check the credential it was authenticated with
+    let (user, credential, tenant) = authentication.into_parts();
+    req.extensions_mut().insert(user);
+    req.extensions_mut().insert(credential);
+    if let Some(tenant) = tenant {
+        req.extensions_mut().insert(tenant);
     }
 
     //  If all validation was ok, return None to proceed
     None
 }
 
-#[derive(Debug)]
-struct TheHttpResponse {
-    status_code: StatusCode,
-    body: Option<String>,
+/// ## Description
+/// Guards a route, only letting through requests whose credential allows the scope. Requests
+/// authenticated with a token without it, or not authenticated at all, are refused. Must be used in
+/// routes of a scope wrapped by `UserAuthentication`:
+///
+/// ```text
+/// #[put("/change_password", wrap = "RequireScope::new(TokenScope::ManagePassword)")]
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct RequireScope {
+    scope: TokenScope
 }
 
-impl TheHttpResponse {
-    fn status_code(status_code: StatusCode) -> Self {
-        TheHttpResponse {
-            status_code,
-            body: None,
-        }
+impl RequireScope {
+    pub fn new(scope: TokenScope) -> Self {
+        RequireScope { scope }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireScope
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+        S::Future: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequireScopeMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireScopeMiddleware {
+            service: Rc::new(service),
+            scope: self.scope
+        }))
+    }
+}
+
+pub struct RequireScopeMiddleware<S> {
+    service: Rc<S>,
+    scope: TokenScope
+}
+
+impl<S, B> Service<ServiceRequest> for RequireScopeMiddleware<S>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+        S::Future: 'static
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
     }
 
-    fn with_body(mut self, body: String) -> Self {
-        self.body = Some(body);
-        self
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+
+        let service = Rc::clone(&self.service);
+
+        let scope_check = match req.extensions().get::<Credential>() {
+            Some(credential) => credential.require_scope(self.scope),
+            None => Err(AuthenticationError::new(ErrorCode::LoginRequired, "User not authenticated"))
+        };
+
+        Box::pin(async move {
+            if let Err(auth_error) = scope_check {
+                return Ok(req.error_response(auth_error).map_into_right_body());
+            }
+            service.call(req).await.map(ServiceResponse::map_into_left_body)
+        })
     }
 }
 
-impl fmt::Display for TheHttpResponse {
+impl fmt::Display for AuthenticationError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Display::fmt(&self.status_code, f)
+        fmt::Display::fmt(&self.get_status_code(), f)
     }
 }
 
-impl ResponseError for TheHttpResponse {
+impl ResponseError for AuthenticationError {
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code)
-            .body(self.body.clone().unwrap_or_default())
+        self.get_problem().clone().into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, HttpResponse, web};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use crate::modules::oauth::scope::{OAuthGrant, OAuthScope};
+    use super::*;
+
+    async fn status_with(credential: Option<Credential>) -> StatusCode {
+        let app = init_service(
+            App::new().route(
+                "/guarded",
+                web::get().to(|| async { HttpResponse::Ok().finish() }).wrap(RequireScope::new(TokenScope::ManageAccount))
+            )
+        ).await;
+
+        let request = TestRequest::get().uri("/guarded").to_request();
+        if let Some(credential) = credential {
+            request.extensions_mut().insert(credential);
+        }
+
+        call_service(&app, request).await.status()
+    }
+
+    #[actix_web::test]
+    async fn sessions_and_api_keys_pass_the_scope_guard() {
+        assert_eq!(status_with(Some(Credential::Session)).await, StatusCode::OK);
+        assert_eq!(status_with(Some(Credential::ApiKey)).await, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn scope_guard_fails_closed() {
+        assert_eq!(status_with(None).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn access_tokens_never_manage_the_account() {
+        for scope in [OAuthScope::Read, OAuthScope::Write, OAuthScope::Admin] {
+            let grant = OAuthGrant::new(1, &[scope, OAuthScope::OpenId, OAuthScope::Profile, OAuthScope::Email]);
+            assert_eq!(status_with(Some(Credential::AccessToken(grant))).await, StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[test]
+    fn access_tokens_map_their_scopes() {
+        let read = Credential::AccessToken(OAuthGrant::new(1, &[OAuthScope::Read]));
+        assert!(read.has_scope(TokenScope::ReadSelf));
+        assert!(!read.has_scope(TokenScope::AdminUsers));
+        assert!(read.is_token());
+
+        let admin = Credential::AccessToken(OAuthGrant::new(1, &[OAuthScope::Admin]));
+        assert!(admin.has_scope(TokenScope::AdminUsers));
+        for scope in [TokenScope::ManagePassword, TokenScope::ManageApiKeys, TokenScope::AdminService] {
+            assert!(!admin.has_scope(scope));
+        }
     }
 }
@@ -0,0 +1,407 @@
+use actix_web::http::header::HeaderMap;
+use actix_web::http::StatusCode;
+use crate::general::problem::{ErrorCode, Problem};
+use crate::modules::api_keys::functions::authenticate_api_key;
+use crate::modules::oauth::functions::authenticate_access_token;
+use crate::modules::oauth::scope::OAuthGrant;
+use crate::modules::oauth::token::ACCESS_TOKEN_HEADER;
+use crate::modules::organizations::functions::get_organization_slug_from_headers;
+use crate::modules::organizations::membership::Membership;
+use crate::modules::organizations::organization::Organization;
+use crate::modules::organizations::Tenant;
+use crate::modules::personal_tokens::functions::authenticate_personal_token;
+use crate::modules::personal_tokens::personal_token::{PersonalToken, TokenScope};
+use crate::modules::users::session_binding::ClientFingerprint;
+use crate::modules::users::user::{Level, User};
+use crate::modules::users::users_sessions::{self, SessionCheck};
+use crate::modules::users::UsersSessions;
+
+/// ## Description
+/// Headers the authenticator reads the credentials from. Implemented for the header maps of each
+/// framework the authentication is exposed in
+pub trait CredentialHeaders {
+    /// Value of the header. None if it's missing, and Some(None) if it's not valid text
+    fn get_header(&self, name: &str) -> Option<Option<&str>>;
+}
+
+impl CredentialHeaders for HeaderMap {
+    fn get_header(&self, name: &str) -> Option<Option<&str>> {
+        self.get(name).map(|value| value.to_str().ok())
+    }
+}
+
+/// ## Description
+/// Framework agnostic authentication of the requests. Validates the credentials received, session
+/// token, API key, personal access token or OAuth access token, and for organization scoped
+/// requests resolves the organization the request is addressed to. Both the actix middleware and
+/// the tower layer are built on top of it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Authenticator {
+    organization_scoped: bool
+}
+
+/// ## Description
+/// Result of a successful authentication. The credential tells how the request was authenticated,
+/// for the scope guards and the sensitive operations, and the tenant is only present for
+/// organization scoped requests
+#[derive(Debug, Clone)]
+pub struct Authentication {
+    user: User,
+    credential: Credential,
+    tenant: Option<Tenant>
+}
+
+/// ## Description
+/// Credential a request was authenticated with. Sessions and API keys act with the level of their
+/// user, while bearer tokens are also limited to the scopes they were granted, OAuth access tokens
+/// through the API scopes their OAuth scopes map to
+#[derive(Debug, Clone)]
+pub enum Credential {
+    Session,
+    ApiKey,
+    PersonalToken(PersonalToken),
+    AccessToken(OAuthGrant)
+}
+
+/// Why a request couldn't be authenticated, with the problem to respond with
+#[derive(Debug, Clone)]
+pub struct AuthenticationError {
+    problem: Problem
+}
+
+impl Authenticator {
+    pub fn new() -> Self {
+        Self { organization_scoped: false }
+    }
+
+    /// The organization the request is addressed to is resolved, and the user must be a member
+    /// of it. The level checked is then the one the user holds in the organization
+    pub fn organization_scoped() -> Self {
+        Self { organization_scoped: true }
+    }
+
+    /// Authenticates the request with the credentials in its headers. Sessions are also checked
+    /// against the client making the request
+    pub async fn authenticate(
+        &self,
+        headers: &(impl CredentialHeaders + ?Sized),
+        client: &ClientFingerprint
+    ) -> Result<Authentication, AuthenticationError> {
+
+        //  API keys, personal access tokens and OAuth access tokens are alternatives to username and
+        // session token
+        let (user, credential) = if headers.get_header("x-api-key").is_some() {
+            (api_key_validation(headers).await?, Credential::ApiKey)
+        } else if headers.get_header("authorization").is_some() {
+            bearer_validation(headers).await?
+        } else {
+            (session_validation(headers, client).await?, Credential::Session)
+        };
+
+        let tenant = match self.organization_scoped {
+            true => Some(resolve_tenant(headers, &user).await?),
+            false => None
+        };
+
+        Ok(Authentication { user, credential, tenant })
+    }
+}
+
+impl Authentication {
+    /// The level the request acts with: the one the user holds in the organization for organization
+    /// scoped requests, and the user's effective level otherwise
+    pub async fn get_level(&self) -> Level {
+        match &self.tenant {
+            Some(tenant) => *tenant.get_level(),
+            None => UsersSessions::instance().get_effective_level(&self.user).await
+        }
+    }
+
+    pub async fn require_level(&self, level: Level) -> Result<(), AuthenticationError> {
+        if self.get_level().await < level {
+            return Err(
+                AuthenticationError::new(ErrorCode::Forbidden, "User level below required privileges")
+            )
+        }
+        Ok(())
+    }
+
+    pub fn require_scope(&self, scope: TokenScope) -> Result<(), AuthenticationError> {
+        self.credential.require_scope(scope)
+    }
+
+    pub fn get_user(&self) -> &User {
+        &self.user
+    }
+
+    pub fn get_credential(&self) -> &Credential {
+        &self.credential
+    }
+
+    pub fn into_parts(self) -> (User, Credential, Option<Tenant>) {
+        (self.user, self.credential, self.tenant)
+    }
+}
+
+impl Credential {
+    /// Whether the request was authenticated with a bearer token. Tokens can't be used to create
+    /// other credentials, and have no session to re-authenticate
+    pub fn is_token(&self) -> bool {
+        matches!(self, Credential::PersonalToken(_) | Credential::AccessToken(_))
+    }
+
+    /// ## Description
+    /// Whether the credential allows acting with the scope. Bearer tokens must have been granted
+    /// it, anything unknown is refused
+    pub fn has_scope(&self, scope: TokenScope) -> bool {
+        match self {
+            Credential::Session | Credential::ApiKey => true,
+            Credential::PersonalToken(personal_token) => personal_token.has_scope(scope),
+            Credential::AccessToken(grant) => grant.has_scope(scope)
+        }
+    }
+
+    pub fn require_scope(&self, scope: TokenScope) -> Result<(), AuthenticationError> {
+        if !self.has_scope(scope) {
+            return Err(
+                AuthenticationError::new(ErrorCode::InsufficientScope, "Token lacks the required scope")
+            )
+        }
+        Ok(())
+    }
+}
+
+impl AuthenticationError {
+    pub fn new(code: ErrorCode, detail: &str) -> Self {
+        AuthenticationError {
+            problem: Problem::new(code).with_detail(detail)
+        }
+    }
+
+    pub fn get_status_code(&self) -> StatusCode {
+        self.problem.get_status_code()
+    }
+
+    pub fn get_problem(&self) -> &Problem {
+        &self.problem
+    }
+}
+
+async fn session_validation(
+    headers: &(impl CredentialHeaders + ?Sized),
+    client: &ClientFingerprint
+) -> Result<User, AuthenticationError> {
+
+    //  Attempt to fetch username from headers
+    let username = match headers.get_header("username") {
+        Some(username) => {
+            match username {
+                Some(username) => username,
+                None => {
+                    return Err(
+                        AuthenticationError::new(ErrorCode::MissingCredentials, "Invalid username")
+                    )
+                }
+            }
+        },
+        None => {
+            return Err(
+                AuthenticationError::new(ErrorCode::MissingCredentials, "No username provided or found")
+            )
+        }
+    };
+
+    //  Attempt to fetch token from headers
+    let token = match headers.get_header("token") {
+        Some(token) => {
+            match token {
+                Some(token) => token,
+                None => {
+                    return Err(
+                        AuthenticationError::new(ErrorCode::MissingCredentials, "Invalid session token")
+                    )
+                }
+            }
+        },
+        None => {
+            return Err(
+                AuthenticationError::new(ErrorCode::MissingCredentials, "No session token received")
+            )
+        }
+    };
+
+    //  Fetch user data from database
+    let user = match User::select_by_username(username).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Err(
+            AuthenticationError::new(ErrorCode::InvalidSession, "User not found")
+        ),
+        Err(_) => {
+            return Err(
+                AuthenticationError::new(ErrorCode::Internal, "Failed to fetch user data")
+            )
+        }
+    };
+
+    //  Check if user is logged in
+    if !UsersSessions::instance().is_user_logged_in(user.get_id()).await {
+        return Err(
+            AuthenticationError::new(ErrorCode::LoginRequired, "User not logged in")
+        )
+    };
+
+    //  Validate session token, and the client it's used from
+    match users_sessions::validate_session(&user, token, client).await {
+        Ok(SessionCheck::Valid) => Ok(user),
+        Ok(SessionCheck::InvalidToken) => {
+            Err(
+                AuthenticationError::new(ErrorCode::InvalidSession, "Invalid session token")
+            )
+        },
+        Ok(SessionCheck::StepUpRequired) => {
+            Err(
+                AuthenticationError::new(ErrorCode::ClientMismatch, "Session used from a different client, log in again to continue")
+            )
+        },
+        Ok(SessionCheck::Rejected) => {
+            Err(
+                AuthenticationError::new(ErrorCode::ClientMismatch, "Session used from a different client, it was closed")
+            )
+        },
+        Err(_) => {
+            Err(
+                AuthenticationError::new(ErrorCode::Internal, "Failed to validate session token")
+            )
+        }
+    }
+}
+
+async fn api_key_validation(headers: &(impl CredentialHeaders + ?Sized)) -> Result<User, AuthenticationError> {
+
+    //  Attempt to fetch API key from headers
+    let api_key = match headers.get_header("x-api-key") {
+        Some(Some(api_key)) => api_key,
+        _ => {
+            return Err(
+                AuthenticationError::new(ErrorCode::MissingCredentials, "Invalid API key")
+            )
+        }
+    };
+
+    match authenticate_api_key(api_key).await {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => {
+            Err(
+                AuthenticationError::new(ErrorCode::InvalidToken, "Invalid API key")
+            )
+        },
+        Err(_) => {
+            Err(
+                AuthenticationError::new(ErrorCode::Internal, "Failed to validate API key")
+            )
+        }
+    }
+}
+
+async fn bearer_validation(
+    headers: &(impl CredentialHeaders + ?Sized)
+) -> Result<(User, Credential), AuthenticationError> {
+
+    //  Attempt to fetch a bearer token from headers
+    let token = match headers.get_header("authorization") {
+        Some(Some(header)) => match header.strip_prefix("Bearer ") {
+            Some(token) => token.to_string(),
+            None => {
+                return Err(
+                    AuthenticationError::new(ErrorCode::MissingCredentials, "Invalid authorization scheme")
+                )
+            }
+        },
+        _ => {
+            return Err(
+                AuthenticationError::new(ErrorCode::MissingCredentials, "Invalid bearer token")
+            )
+        }
+    };
+
+    //  Both kinds of bearer tokens start with a header telling them apart
+    if token.starts_with(ACCESS_TOKEN_HEADER) {
+        access_token_validation(token.as_str()).await
+            .map(|(user, grant)| (user, Credential::AccessToken(grant)))
+    } else {
+        personal_token_validation(token.as_str()).await
+            .map(|(user, personal_token)| (user, Credential::PersonalToken(personal_token)))
+    }
+}
+
+async fn access_token_validation(token: &str) -> Result<(User, OAuthGrant), AuthenticationError> {
+
+    match authenticate_access_token(token).await {
+        Ok(Some((user, grant))) => Ok((user, grant)),
+        Ok(None) => {
+            Err(
+                AuthenticationError::new(ErrorCode::InvalidToken, "Invalid access token")
+            )
+        },
+        Err(_) => {
+            Err(
+                AuthenticationError::new(ErrorCode::Internal, "Failed to validate access token")
+            )
+        }
+    }
+}
+
+async fn personal_token_validation(token: &str) -> Result<(User, PersonalToken), AuthenticationError> {
+
+    match authenticate_personal_token(token).await {
+        Ok(Some((user, personal_token))) => Ok((user, personal_token)),
+        Ok(None) => {
+            Err(
+                AuthenticationError::new(ErrorCode::InvalidToken, "Invalid personal access token")
+            )
+        },
+        Err(_) => {
+            Err(
+                AuthenticationError::new(ErrorCode::Internal, "Failed to validate personal access token")
+            )
+        }
+    }
+}
+
+async fn resolve_tenant(headers: &(impl CredentialHeaders + ?Sized), user: &User) -> Result<Tenant, AuthenticationError> {
+
+    //  Attempt to fetch organization from headers or subdomain
+    let Some(slug) = get_organization_slug_from_headers(headers).await else {
+        return Err(
+            AuthenticationError::new(ErrorCode::OrganizationNotSpecified, "No organization provided or found")
+        )
+    };
+
+    let organization = match Organization::select_by_slug(slug.as_str()).await {
+        Ok(Some(organization)) => organization,
+        Ok(None) => return Err(
+            AuthenticationError::new(ErrorCode::OrganizationNotFound, "Organization not found")
+        ),
+        Err(_) => return Err(
+            AuthenticationError::new(ErrorCode::Internal, "Failed to fetch organization data")
+        )
+    };
+
+    //  Users can only act in organizations they're members of
+    match Membership::select(organization.get_id(), user.get_id()).await {
+        Ok(Some(membership)) => {
+            //  Scoped credentials cap the level in the organization as well
+            let level = match user.get_scope_level() {
+                Some(scope_level) => (*membership.get_level()).min(*scope_level),
+                None => *membership.get_level()
+            };
+            Ok(Tenant::new(organization, level))
+        },
+        Ok(None) => Err(
+            AuthenticationError::new(ErrorCode::Forbidden, "User is not a member of this organization")
+        ),
+        Err(_) => Err(
+            AuthenticationError::new(ErrorCode::Internal, "Failed to fetch organization membership")
+        )
+    }
+}
@@ -0,0 +1,278 @@
+//! Authentication for axum apps, equivalent to the actix `UserAuthentication` middleware. The
+//! `AuthenticationLayer` authenticates every request and inserts the `User`, the `Credential` and
+//! the `Tenant` when present, into the request extensions. The `RequireLevel` and `RequireScope`
+//! layers guard routes by level and token scope, and handlers get the user with the
+//! `AuthenticatedUser` extractor:
+//!
+//! ```text
+//! Router::new()
+//!     .route("/internal/create_user", post(create_user))
+//!     .route_layer(RequireScope::new(TokenScope::AdminUsers))
+//!     .route_layer(RequireLevel::new(Level::High))
+//!     .layer(AuthenticationLayer::new())
+//! ```
+
+use std::net::SocketAddr;
+use std::task::{Context, Poll};
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts, Request};
+use axum::http::{HeaderMap, StatusCode};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
+use futures_util::future::BoxFuture;
+use tower::{Layer, Service};
+use crate::api::authenticator::{Authentication, AuthenticationError, Authenticator, CredentialHeaders};
+use crate::general::problem::{ErrorCode, PROBLEM_CONTENT_TYPE};
+use crate::modules::personal_tokens::personal_token::TokenScope;
+use crate::modules::users::session_binding::ClientFingerprint;
+use crate::modules::users::user::{Level, User};
+
+impl CredentialHeaders for HeaderMap {
+    fn get_header(&self, name: &str) -> Option<Option<&str>> {
+        self.get(name).map(|value| value.to_str().ok())
+    }
+}
+
+impl IntoResponse for AuthenticationError {
+    fn into_response(self) -> Response {
+        let status_code = StatusCode::from_u16(self.get_status_code().as_u16())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        (
+            status_code,
+            [(CONTENT_TYPE, PROBLEM_CONTENT_TYPE)],
+            self.get_problem().to_json()
+        ).into_response()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AuthenticationLayer {
+    authenticator: Authenticator
+}
+
+impl AuthenticationLayer {
+    pub fn new() -> Self {
+        Self { authenticator: Authenticator::new() }
+    }
+
+    /// Resolves the organization the request is addressed to, and `RequireLevel` checks the level
+    /// the user holds in it
+    pub fn organization_scoped() -> Self {
+        Self { authenticator: Authenticator::organization_scoped() }
+    }
+}
+
+impl<S> Layer<S> for AuthenticationLayer {
+    type Service = AuthenticationService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AuthenticationService {
+            inner,
+            authenticator: self.authenticator
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthenticationService<S> {
+    inner: S,
+    authenticator: Authenticator
+}
+
+impl<S> Service<Request> for AuthenticationService<S>
+    where
+        S: Service<Request, Response = Response> + Clone + Send + 'static,
+        S::Future: Send + 'static
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(ctx)
+    }
+
+    fn call(&mut self, mut req: Request) -> Self::Future {
+
+        //  The service that was polled ready is the one that must be called, a fresh clone is left
+        // in its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let authenticator = self.authenticator;
+
+        Box::pin(async move {
+            //  The peer address is only known when the app is served with connect info
+            let peer_ip = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(address)| address.ip());
+            let client = ClientFingerprint::from_request(peer_ip, req.headers()).await;
+
+            let authentication = match authenticator.authenticate(req.headers(), &client).await {
+                Ok(authentication) => authentication,
+                Err(auth_error) => return Ok(auth_error.into_response())
+            };
+
+            //  Same extensions the actix middleware inserts, plus the authentication itself for
+            // the level and scope guards
+            let (user, credential, tenant) = authentication.clone().into_parts();
+            req.extensions_mut().insert(authentication);
+            req.extensions_mut().insert(user);
+            req.extensions_mut().insert(credential);
+            if let Some(tenant) = tenant {
+                req.extensions_mut().insert(tenant);
+            }
+
+            inner.call(req).await
+        })
+    }
+}
+
+/// ## Description
+/// Guards the routes it's applied to, only letting through requests acting with at least the
+/// level received. Must be applied after the `AuthenticationLayer`
+#[derive(Debug, Clone, Copy)]
+pub struct RequireLevel {
+    level: Level
+}
+
+impl RequireLevel {
+    pub fn new(level: Level) -> Self {
+        Self { level }
+    }
+}
+
+impl<S> Layer<S> for RequireLevel {
+    type Service = RequireLevelService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireLevelService {
+            inner,
+            level: self.level
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequireLevelService<S> {
+    inner: S,
+    level: Level
+}
+
+impl<S> Service<Request> for RequireLevelService<S>
+    where
+        S: Service<Request, Response = Response> + Clone + Send + 'static,
+        S::Future: Send + 'static
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(ctx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let level = self.level;
+
+        Box::pin(async move {
+            let Some(authentication) = req.extensions().get::<Authentication>().cloned() else {
+                return Ok(not_authenticated().into_response())
+            };
+
+            if let Err(auth_error) = authentication.require_level(level).await {
+                return Ok(auth_error.into_response())
+            }
+
+            inner.call(req).await
+        })
+    }
+}
+
+/// ## Description
+/// Guards the routes it's applied to, only letting through requests whose credential allows the
+/// scope. Must be applied after the `AuthenticationLayer`
+#[derive(Debug, Clone, Copy)]
+pub struct RequireScope {
+    scope: TokenScope
+}
+
+impl RequireScope {
+    pub fn new(scope: TokenScope) -> Self {
+        Self { scope }
+    }
+}
+
+impl<S> Layer<S> for RequireScope {
+    type Service = RequireScopeService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireScopeService {
+            inner,
+            scope: self.scope
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequireScopeService<S> {
+    inner: S,
+    scope: TokenScope
+}
+
+impl<S> Service<Request> for RequireScopeService<S>
+    where
+        S: Service<Request, Response = Response> + Clone + Send + 'static,
+        S::Future: Send + 'static
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(ctx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let scope_check = match req.extensions().get::<Authentication>() {
+            Some(authentication) => authentication.require_scope(self.scope),
+            None => Err(not_authenticated())
+        };
+
+        Box::pin(async move {
+            if let Err(auth_error) = scope_check {
+                return Ok(auth_error.into_response())
+            }
+
+            inner.call(req).await
+        })
+    }
+}
+
+/// Extractor for the user authenticated by the `AuthenticationLayer`
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser(pub User);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthenticatedUser
+    where
+        S: Send + Sync
+{
+    type Rejection = AuthenticationError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        match parts.extensions.get::<User>() {
+            Some(user) => Ok(AuthenticatedUser(user.clone())),
+            None => Err(not_authenticated())
+        }
+    }
+}
+
+fn not_authenticated() -> AuthenticationError {
+    AuthenticationError::new(ErrorCode::LoginRequired, "User not authenticated")
+}
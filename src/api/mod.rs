@@ -2,14 +2,20 @@
 use actix_web::{App, HttpServer, web};
 use error_mapper::{map_to_new_error, TheResult};
 use openssl::ssl::SslAcceptorBuilder;
-use tokio::sync::broadcast::{Receiver, Sender};
-use crate::{config, modules};
+use tokio::sync::broadcast::Sender;
+use crate::{config, web_local, AuthService};
 use crate::api::authentication::UserAuthentication;
+use crate::api::versioning::{ApiVersion, ApiVersioning};
 use crate::config::environment::EnvironmentConfig;
 use crate::modules::users::user::Level;
 
 pub mod services;
 pub mod authentication;
+pub mod authenticator;
+pub mod openapi;
+pub mod versioning;
+#[cfg(feature = "axum")]
+pub mod axum;
 
 #[derive(Debug, Clone)]
 pub enum StopMethod {
@@ -22,40 +28,90 @@ pub struct AppData {
     pub(crate) sender: Sender<StopMethod>
 }
 
-pub async fn start_api(
-    (builder, (sender, receiver)): (SslAcceptorBuilder, (Sender<StopMethod>, Receiver<StopMethod>))
-) -> TheResult<()> {
+/// ## Description
+/// Mounts every scope of the service, under each version of the API. The stop endpoints send
+/// their requests through the sender, and the local relying party is only mounted when it's
+/// configured. The OpenAPI document and its docs UI are served in the api scope
+pub fn configure(cfg: &mut web::ServiceConfig, sender: Sender<StopMethod>, local_relying_party: bool) {
+    //  A v2 would mount its own handlers next to these, falling back to the v1 ones for whatever
+    // didn't change
+    configure_version(cfg, ApiVersion::V1, sender.clone(), local_relying_party);
+    configure_version(cfg, ApiVersion::Unversioned, sender, local_relying_party);
 
-    let service_api_bind = format!(
-        "{}:{}",
-        EnvironmentConfig::instance().get_service_url().await,
-        EnvironmentConfig::instance().get_service_port().await
+    //  Well-known URIs are defined at the root of the host, they can't be versioned
+    cfg.service(
+        web::scope(".well-known")
+            .configure(services::oauth::well_known)
     );
+}
 
-    let server = HttpServer::new(move || {
-        let sender_api = sender.clone();
+/// Mounts the current surface with the prefix of the version, counting its requests and adding
+/// the deprecation headers if it's deprecated
+fn configure_version(cfg: &mut web::ServiceConfig, version: ApiVersion, sender: Sender<StopMethod>, local_relying_party: bool) {
+    let path = |scope: &str| format!("{}/{}", version.prefix(), scope);
 
-        App::new()
-            .service(
-                web::scope("api")
-                    .service(
+    cfg
+        .service(
+            web::scope(&path("api"))
+                .configure(|cfg| openapi::services(cfg, local_relying_party))
+                .service(
                     web::scope("public").configure(services::api::alive_service)
-                    ).service(
+                ).service(
                     web::scope("internal")
                         .configure(services::api::internal)
-                        .app_data(web::Data::new(AppData { sender: sender_api.clone() }))
+                        .app_data(web::Data::new(AppData { sender }))
                         .wrap(UserAuthentication::new(Level::High))
                 )
-            )
-            .service(
-                web::scope("users")
-                    .configure(services::users::services)
-            )
-            .service(
-                web::scope("internal")
-                    .configure(services::internal::services)
-                    .wrap(UserAuthentication::new(Level::High))
-            )
+                .wrap(ApiVersioning::new(version))
+        )
+        .service(
+            web::scope(&path("users"))
+                .configure(services::users::services)
+                .wrap(ApiVersioning::new(version))
+        )
+        .service(
+            web::scope(&path("internal"))
+                .configure(services::internal::services)
+                .wrap(UserAuthentication::new(Level::High))
+                .wrap(ApiVersioning::new(version))
+        )
+        .service(
+            web::scope(&path("oauth"))
+                .configure(services::oauth::services)
+                .wrap(ApiVersioning::new(version))
+        )
+        .service(
+            web::scope(&path("organizations"))
+                .service(
+                    web::scope("internal")
+                        .configure(services::organizations::internal)
+                        .wrap(UserAuthentication::organization_scoped(Level::High))
+                )
+                .wrap(ApiVersioning::new(version))
+        );
+
+    if local_relying_party {
+        cfg.service(
+            web::scope(&path("web_local"))
+                .configure(web_local::services::services)
+                .wrap(ApiVersioning::new(version))
+        );
+    }
+}
+
+pub(crate) async fn start_api(auth_service: AuthService, builder: SslAcceptorBuilder) -> TheResult<()> {
+
+    let service_api_bind = format!(
+        "{}:{}",
+        EnvironmentConfig::instance().get_service_url().await,
+        EnvironmentConfig::instance().get_service_port().await
+    );
+
+    let receiver = auth_service.stop_receiver();
+
+    let server = HttpServer::new(move || {
+        let auth_service = auth_service.clone();
+        App::new().configure(|cfg| auth_service.configure(cfg))
     })
         .workers(32)
         //  Kills main thread if it fails to open the Http server
@@ -63,7 +119,7 @@ pub async fn start_api(
         .map_err(|e| map_to_new_error!(e))?
         .run();
 
-    tokio::spawn(config::shutdown::stop_server(server.handle(), receiver.resubscribe()));
+    tokio::spawn(config::shutdown::stop_server(server.handle(), receiver));
 
     server.await.map_err(|e| map_to_new_error!(e))?; // Blocks main thread until server is stopped
 
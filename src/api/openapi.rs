@@ -0,0 +1,277 @@
+use std::collections::HashSet;
+use actix_web::{HttpResponse, web};
+use actix_web::http::header;
+use utoipa::{Modify, OpenApi};
+use utoipa::openapi::{Content, Ref, ResponseBuilder};
+use utoipa::openapi::path::Operation;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityRequirement, SecurityScheme};
+use utoipa_swagger_ui::{Config, SwaggerUi};
+use crate::general::problem::{PROBLEM_CONTENT_TYPE, Problem};
+use crate::modules;
+use crate::api::services;
+
+/// Scopes behind the user authentication middleware, which accept any of the credentials
+const AUTHENTICATED_SCOPES: [&str; 4] = [
+    "/v1/api/internal/",
+    "/v1/internal/",
+    "/v1/users/manage/",
+    "/v1/organizations/internal/"
+];
+
+#[derive(OpenApi)]
+#[openapi(paths(services::api::alive))]
+struct PublicApi;
+
+#[derive(OpenApi)]
+#[openapi(paths(
+    services::api::alive,
+    services::api::stop,
+    services::api::stop_now,
+    services::api::version_usage
+))]
+struct InternalServiceApi;
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        modules::users::services::user_login,
+        modules::users::services::user_logout,
+        modules::users::services::create_user
+    ),
+    nest((path = "/manage", api = ManageApi))
+)]
+struct UsersApi;
+
+#[derive(OpenApi)]
+#[openapi(paths(
+    modules::users::services::change_password,
+    modules::users::services::delete_user,
+    modules::users::services::check_password,
+    modules::users::services::change_username,
+    modules::users::services::get_me,
+    modules::users::services::update_me,
+    modules::api_keys::services::create_api_key,
+    modules::api_keys::services::revoke_api_key,
+    modules::api_keys::services::list_api_keys,
+    modules::personal_tokens::services::create_personal_token,
+    modules::personal_tokens::services::list_personal_tokens,
+    modules::personal_tokens::services::revoke_personal_token
+))]
+struct ManageApi;
+
+#[derive(OpenApi)]
+#[openapi(paths(
+    modules::users::services::create_user,
+    modules::users::services::delete_user_internal,
+    modules::users::services::undo_delete_user,
+    modules::users::services::change_user_level,
+    modules::users::services::explain_policy,
+    modules::users::services::list_users,
+    modules::organizations::services::create_organization,
+    modules::groups::services::create_group,
+    modules::groups::services::add_group_members,
+    modules::groups::services::remove_group_members,
+    modules::api_keys::services::create_service_account,
+    modules::oauth::services::create_oauth_client
+))]
+struct InternalApi;
+
+#[derive(OpenApi)]
+#[openapi(paths(
+    modules::oauth::services::authorize,
+    modules::oauth::services::authorize_decision,
+    modules::oauth::services::token,
+    modules::oauth::services::introspect,
+    modules::oauth::services::revoke,
+    modules::oauth::services::userinfo,
+    modules::oauth::services::jwks
+))]
+struct OAuthApi;
+
+#[derive(OpenApi)]
+#[openapi(paths(modules::oauth::services::openid_configuration))]
+struct WellKnownApi;
+
+#[derive(OpenApi)]
+#[openapi(paths(
+    modules::organizations::services::create_user,
+    modules::organizations::services::add_user,
+    modules::organizations::services::delete_user,
+    modules::organizations::services::undo_delete_user,
+    modules::organizations::services::change_user_level
+))]
+struct OrganizationsApi;
+
+#[derive(OpenApi)]
+#[openapi(paths(crate::web_local::services::login, crate::web_local::services::callback))]
+struct WebLocalApi;
+
+/// ## Description
+/// OpenAPI document of the service, generated from the handlers and their request structs. The
+/// nested APIs mirror the scopes `api::configure` mounts the handlers in, so both must be updated
+/// together, which the `openapi` test checks. Only the current version is documented, the
+/// deprecated unversioned paths are the same endpoints
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "user_token_authentication",
+        description = "Users, sessions, API keys, personal access tokens, organizations and an OAuth 2.0 and OpenID Connect provider"
+    ),
+    nest(
+        (path = "/v1/api/public", api = PublicApi),
+        (path = "/v1/api/internal", api = InternalServiceApi),
+        (path = "/v1/users", api = UsersApi),
+        (path = "/v1/internal", api = InternalApi),
+        (path = "/v1/oauth", api = OAuthApi),
+        (path = "/v1/organizations/internal", api = OrganizationsApi),
+        (path = "/v1/web_local", api = WebLocalApi),
+        (path = "/.well-known", api = WellKnownApi)
+    ),
+    components(schemas(Problem)),
+    modifiers(&Credentials, &ProblemResponses, &UniqueOperationIds)
+)]
+pub struct ApiDoc;
+
+/// Credentials the authentication middleware accepts: a session, an API key or a bearer token
+struct Credentials;
+
+impl Modify for Credentials {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "username",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description("username", "Username of the session")))
+        );
+        components.add_security_scheme(
+            "session_token",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description("token", "Session token returned by login")))
+        );
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description("x-api-key", "API key of a service account")))
+        );
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some("Personal access token or OAuth access token"))
+                    .build()
+            )
+        );
+
+        let requirements = vec![
+            SecurityRequirement::new("username", Vec::<String>::new()).add("session_token", Vec::<String>::new()),
+            SecurityRequirement::new("api_key", Vec::<String>::new()),
+            SecurityRequirement::new("bearer", Vec::<String>::new())
+        ];
+        for (path, item) in openapi.paths.paths.iter_mut() {
+            if AUTHENTICATED_SCOPES.iter().any(|scope| path.starts_with(scope)) {
+                for operation in operations(item) {
+                    operation.security = Some(requirements.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Every error responded as a problem, except the ones the operation documents itself, like the
+/// OAuth errors
+struct ProblemResponses;
+
+impl Modify for ProblemResponses {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        for item in openapi.paths.paths.values_mut() {
+            for operation in operations(item) {
+                let responses = &mut operation.responses.responses;
+                if responses.keys().any(|status| status.starts_with('4')) {
+                    continue;
+                }
+                for (status, description) in [("4XX", "Client error, as problem details"), ("5XX", "Server error, as problem details")] {
+                    let response = ResponseBuilder::new()
+                        .description(description)
+                        .content(PROBLEM_CONTENT_TYPE, Content::new(Some(Ref::from_schema_name("Problem"))))
+                        .build();
+                    responses.insert(status.to_string(), response.into());
+                }
+            }
+        }
+    }
+}
+
+/// Operation ids are the handler names, which repeat for handlers mounted in more than one scope
+/// and for the organization versions of the user endpoints. Repeated ones are prefixed with the
+/// scope they are mounted in
+struct UniqueOperationIds;
+
+impl Modify for UniqueOperationIds {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let mut seen = HashSet::new();
+        let mut repeated = HashSet::new();
+        for item in openapi.paths.paths.values_mut() {
+            for operation_id in operations(item).filter_map(|operation| operation.operation_id.clone()) {
+                if !seen.insert(operation_id.clone()) {
+                    repeated.insert(operation_id);
+                }
+            }
+        }
+
+        for (path, item) in openapi.paths.paths.iter_mut() {
+            let scope = path.trim_matches('/').rsplit_once('/').map(|(scope, _)| scope).unwrap_or_default();
+            let prefix = scope.replace(['/', '.', '-'], "_");
+            for operation in operations(item) {
+                if let Some(operation_id) = operation.operation_id.as_mut().filter(|id| repeated.contains(id.as_str())) {
+                    *operation_id = format!("{}_{}", prefix.trim_start_matches('_'), operation_id);
+                }
+            }
+        }
+    }
+}
+
+fn operations(item: &mut utoipa::openapi::PathItem) -> impl Iterator<Item = &mut Operation> {
+    [&mut item.get, &mut item.put, &mut item.post, &mut item.patch, &mut item.delete]
+        .into_iter()
+        .filter_map(|operation| operation.as_mut())
+}
+
+/// ## Description
+/// OpenAPI document of the mounted endpoints. The local relying party is left out unless it's
+/// mounted
+pub fn document(local_relying_party: bool) -> utoipa::openapi::OpenApi {
+    let mut openapi = ApiDoc::openapi();
+    //  The package has no license, which would be published as an empty one
+    openapi.info.license = None;
+    if !local_relying_party {
+        openapi.paths.paths.retain(|path, _| !path.starts_with("/v1/web_local/"));
+    }
+    openapi
+}
+
+struct ApiSpec {
+    json: String
+}
+
+/// Serves the document at `openapi.json` and the docs UI at `docs` in the scope it's mounted in.
+/// Swagger UI is embedded in the binary, the page doesn't load anything from other hosts
+pub(super) fn services(cfg: &mut web::ServiceConfig, local_relying_party: bool) {
+    //  Only strings and JSON values are serialized, this can't fail
+    let json = document(local_relying_party).to_json().unwrap_or_default();
+
+    //  The UI is served under docs/, the document is resolved relative to it
+    cfg.app_data(web::Data::new(ApiSpec { json }))
+        .service(web::resource("/openapi.json").route(web::get().to(openapi_json)))
+        .service(web::resource("/docs").route(web::get().to(docs)))
+        .service(SwaggerUi::new("/docs/{_:.*}").config(Config::new(["../openapi.json"])));
+}
+
+async fn openapi_json(spec: web::Data<ApiSpec>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(spec.json.clone())
+}
+
+async fn docs() -> HttpResponse {
+    HttpResponse::MovedPermanently()
+        .insert_header((header::LOCATION, "docs/"))
+        .finish()
+}
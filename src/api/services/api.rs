@@ -1,12 +1,17 @@
 use actix_web::{get, HttpRequest, HttpResponse, put, web};
 use actix_web::http::StatusCode;
 use chrono::{Local};
+use crate::api::authentication::RequireScope;
 use crate::{StopMethod};
 use crate::api::AppData;
+use crate::api::versioning::VersionUsage;
+use crate::auth::policy::{Action, Policy, PolicyRequest};
 use crate::config::shutdown::Shutdown;
-use crate::general::http_req_res::json_response;
-use crate::modules::users::functions;
-use crate::modules::users::user::Level;
+use crate::general::api_error::ApiError;
+use crate::general::http_req_res::data_response;
+use crate::general::problem::ErrorCode;
+use crate::modules::users::{functions, reauthentication};
+use crate::modules::personal_tokens::personal_token::TokenScope;
 
 pub fn alive_service(cfg: &mut web::ServiceConfig) {
     cfg.service(alive);
@@ -15,7 +20,8 @@ pub fn alive_service(cfg: &mut web::ServiceConfig) {
 pub fn internal(cfg: &mut web::ServiceConfig) {
     cfg.service(alive)
         .service(stop)
-        .service(stop_now);
+        .service(stop_now)
+        .service(version_usage);
 }
 
 /// ## Endpoint alive
@@ -29,7 +35,14 @@ pub fn internal(cfg: &mut web::ServiceConfig) {
 /// - Available in public and private modes for testing purposes
 /// - Private mode will require user to to be authenticated
 /// - Any user level will be able to request the alive service in private mode
-#[get("alive")]
+#[utoipa::path(
+    tag = "Service",
+    summary = "Checks the service is running",
+    responses(
+        (status = 200, description = "Service is alive")
+    )
+)]
+#[get("/alive")]
 async fn alive() -> HttpResponse {
 
     if Shutdown::instance().is_shutting_down().await {
@@ -53,31 +66,28 @@ async fn alive() -> HttpResponse {
 ///
 /// #### Information
 /// User needs to be authenticated to perform this action with level High or Super
-#[put("stop")]
-async fn stop(request: HttpRequest, data: web::Data<AppData>) -> HttpResponse {
+#[utoipa::path(
+    tag = "Service",
+    summary = "Stops the service gracefully",
+    responses(
+        (status = 200, description = "Service is stopping")
+    )
+)]
+#[put("/stop", wrap = "RequireScope::new(TokenScope::AdminService)")]
+async fn stop(request: HttpRequest, data: web::Data<AppData>) -> Result<HttpResponse, ApiError> {
 
-    //  Fetch username and token from headers
-    let username = functions::get_username_from_request(request.clone());
-    let session_token = functions::get_session_token_from_request(request.clone());
+    let user = functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
 
-    let user = match functions::get_user_from_headers(username, session_token).await {
-        Ok(Some(user)) => user,
-        Ok(None) => return json_response(StatusCode::UNAUTHORIZED, "Invalid username or session token".to_string()),
-        Err(_) => return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error restoring user".to_string())
-    };
-
-    if user.get_level() < &Level::High {
-        return json_response(
-            StatusCode::FORBIDDEN,
-            "User lacks the privileges to perform this operation".to_string()
-        )
+    if !Policy::instance().evaluate(&PolicyRequest::new(Action::Stop, &user).await).await.is_allowed() {
+        return Err(ApiError::new(ErrorCode::Forbidden, "User lacks the privileges to perform this operation"))
     }
 
     if let Err(e) = data.sender.send(StopMethod::Graceful) {
-        return HttpResponse::InternalServerError().json(format!("Failed to send stop signal: {}", e));
+        return Err(ApiError::new(ErrorCode::Internal, format!("Failed to send stop signal: {}", e).as_str()))
     };
 
-    HttpResponse::Ok().json("Service is stopping")
+    Ok(HttpResponse::Ok().json("Service is stopping"))
 }
 
 /// ## Endpoint stop now
@@ -89,29 +99,50 @@ async fn stop(request: HttpRequest, data: web::Data<AppData>) -> HttpResponse {
 ///
 /// #### Information
 /// User needs to be authenticated to perform this action with level Super
-#[put("stop_now")]
-async fn stop_now(request: HttpRequest, data: web::Data<AppData>) -> HttpResponse {
-
-    //  Fetch username and token from headers
-    let username = functions::get_username_from_request(request.clone());
-    let session_token = functions::get_session_token_from_request(request.clone());
+#[utoipa::path(
+    tag = "Service",
+    summary = "Stops the service immediately",
+    responses(
+        (status = 200, description = "Service is stopping")
+    )
+)]
+#[put("/stop_now", wrap = "RequireScope::new(TokenScope::AdminService)")]
+async fn stop_now(request: HttpRequest, data: web::Data<AppData>) -> Result<HttpResponse, ApiError> {
 
-    let user = match functions::get_user_from_headers(username, session_token).await {
-        Ok(Some(user)) => user,
-        Ok(None) => return json_response(StatusCode::UNAUTHORIZED, "Invalid username or session token".to_string()),
-        Err(_) => return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error restoring user".to_string())
-    };
+    let user = functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
 
-    if user.get_level() < &Level::Super {
-        return json_response(
-            StatusCode::FORBIDDEN,
-            "User lacks the privileges to perform this operation".to_string()
-        )
+    if !Policy::instance().evaluate(&PolicyRequest::new(Action::StopNow, &user).await).await.is_allowed() {
+        return Err(ApiError::new(ErrorCode::Forbidden, "User lacks the privileges to perform this operation"))
     }
 
+    //  Stopping immediately is sensitive, the user must have confirmed their password recently
+    reauthentication::require_recent_reauthentication(&request, &user).await?;
+
     if let Err(e) = data.sender.send(StopMethod::Immediate) {
-        return HttpResponse::InternalServerError().json(format!("Failed to send stop signal: {}", e));
+        return Err(ApiError::new(ErrorCode::Internal, format!("Failed to send stop signal: {}", e).as_str()))
     };
 
-    HttpResponse::Ok().json("Service is stopping al tiro")
+    Ok(HttpResponse::Ok().json("Service is stopping al tiro"))
+}
+
+/// ## Endpoint version usage
+/// GET {UTAUrl}:{UTAPort}/api/internal/version_usage (private)
+///
+/// ### Description
+/// Responds with the requests received by each version of the API since the service started, to
+/// know when the clients of a deprecated version are gone. Counters are kept by each replica
+///
+/// #### Information
+/// User needs to be authenticated with level High or Super
+#[utoipa::path(
+    tag = "Service",
+    summary = "Requests received by each version of the API",
+    responses(
+        (status = 200, description = "Requests by version, wrapped in a data envelope")
+    )
+)]
+#[get("/version_usage")]
+async fn version_usage() -> HttpResponse {
+    data_response(StatusCode::OK, &VersionUsage::instance().snapshot())
 }
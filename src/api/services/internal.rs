@@ -5,6 +5,14 @@ pub fn services(cfg: &mut web::ServiceConfig) {
     cfg.service(modules::users::services::create_user)
         .service(modules::users::services::delete_user_internal)
         .service(modules::users::services::undo_delete_user)
-        .service(modules::users::services::change_user_level);
+        .service(modules::users::services::change_user_level)
+        .service(modules::users::services::explain_policy)
+        .service(modules::users::services::list_users)
+        .service(modules::organizations::services::create_organization)
+        .service(modules::groups::services::create_group)
+        .service(modules::groups::services::add_group_members)
+        .service(modules::groups::services::remove_group_members)
+        .service(modules::api_keys::services::create_service_account)
+        .service(modules::oauth::services::create_oauth_client);
         
 }
\ No newline at end of file
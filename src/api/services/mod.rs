@@ -1,3 +1,5 @@
 pub(super) mod api;
 pub(super) mod internal;
+pub(super) mod oauth;
+pub(super) mod organizations;
 pub(super) mod users;
\ No newline at end of file
@@ -0,0 +1,16 @@
+use actix_web::web;
+use crate::modules;
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(modules::oauth::services::authorize)
+        .service(modules::oauth::services::authorize_decision)
+        .service(modules::oauth::services::token)
+        .service(modules::oauth::services::introspect)
+        .service(modules::oauth::services::revoke)
+        .service(modules::oauth::services::userinfo)
+        .service(modules::oauth::services::jwks);
+}
+
+pub fn well_known(cfg: &mut web::ServiceConfig) {
+    cfg.service(modules::oauth::services::openid_configuration);
+}
@@ -0,0 +1,10 @@
+use actix_web::web;
+use crate::modules;
+
+pub fn internal(cfg: &mut web::ServiceConfig) {
+    cfg.service(modules::organizations::services::create_user)
+        .service(modules::organizations::services::add_user)
+        .service(modules::organizations::services::delete_user)
+        .service(modules::organizations::services::undo_delete_user)
+        .service(modules::organizations::services::change_user_level);
+}
@@ -12,6 +12,15 @@ pub fn services(cfg: &mut web::ServiceConfig) {
                 .service(modules::users::services::change_password)
                 .service(modules::users::services::delete_user)
                 .service(modules::users::services::check_password)
+                .service(modules::users::services::change_username)
+                .service(modules::users::services::get_me)
+                .service(modules::users::services::update_me)
+                .service(modules::api_keys::services::create_api_key)
+                .service(modules::api_keys::services::revoke_api_key)
+                .service(modules::api_keys::services::list_api_keys)
+                .service(modules::personal_tokens::services::create_personal_token)
+                .service(modules::personal_tokens::services::list_personal_tokens)
+                .service(modules::personal_tokens::services::revoke_personal_token)
                 .wrap(crate::api::UserAuthentication::new(Level::Low))
         );
 }
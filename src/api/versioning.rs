@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+
+use actix_web::{dev::{Service, ServiceRequest, ServiceResponse, Transform}, Error};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use chrono::{NaiveTime, TimeZone, Utc};
+use futures_util::future::LocalBoxFuture;
+use lazy_static::lazy_static;
+use crate::config::environment::EnvironmentConfig;
+
+/// When the unversioned paths were deprecated, which is when v1 was introduced
+const UNVERSIONED_DEPRECATED_AT: i64 = 1_792_368_000;
+
+lazy_static!{
+    static ref VERSION_USAGE: VersionUsage = VersionUsage::new();
+}
+
+/// ## Description
+/// Versions the API is mounted with. The current surface is mounted under every version with its
+/// prefix, and the unversioned paths are kept, deprecated, for clients that haven't moved yet. A
+/// new version gets its own variant and prefix, and mounts its handlers next to the others
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApiVersion {
+    Unversioned,
+    V1
+}
+
+impl ApiVersion {
+    pub const ALL: [ApiVersion; 2] = [ApiVersion::Unversioned, ApiVersion::V1];
+
+    /// Version links and documents published by the service point to
+    pub const CURRENT: ApiVersion = ApiVersion::V1;
+
+    /// Prefix of every path of the version, empty for the unversioned paths
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            ApiVersion::Unversioned => "",
+            ApiVersion::V1 => "/v1"
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ApiVersion::Unversioned => "unversioned",
+            ApiVersion::V1 => "v1"
+        }
+    }
+
+    /// Version clients should move to, for deprecated versions only
+    pub fn successor(&self) -> Option<ApiVersion> {
+        match self {
+            ApiVersion::Unversioned => Some(ApiVersion::V1),
+            ApiVersion::V1 => None
+        }
+    }
+
+    /// Unix timestamp of the deprecation, published in the Deprecation header
+    fn deprecated_at(&self) -> Option<i64> {
+        match self {
+            ApiVersion::Unversioned => Some(UNVERSIONED_DEPRECATED_AT),
+            ApiVersion::V1 => None
+        }
+    }
+
+    /// Date after which the version might be removed, published in the Sunset header
+    async fn sunset(&self) -> Option<String> {
+        match self {
+            ApiVersion::Unversioned => EnvironmentConfig::instance().get_unversioned_routes_sunset().await
+                .map(|date| Utc.from_utc_datetime(&date.and_time(NaiveTime::MIN)))
+                .map(|sunset| sunset.format("%a, %d %b %Y %H:%M:%S GMT").to_string()),
+            ApiVersion::V1 => None
+        }
+    }
+}
+
+/// ## Description
+/// Requests received by each version since the service started, to tell when the clients of a
+/// deprecated version are gone. Every replica keeps its own counters
+pub struct VersionUsage {
+    requests: [AtomicU64; ApiVersion::ALL.len()]
+}
+
+impl VersionUsage {
+    fn new() -> Self {
+        Self {
+            requests: Default::default()
+        }
+    }
+
+    pub fn instance() -> &'static Self {
+        &VERSION_USAGE
+    }
+
+    pub fn record(&self, version: ApiVersion) {
+        self.requests[version as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get_requests(&self, version: ApiVersion) -> u64 {
+        self.requests[version as usize].load(Ordering::Relaxed)
+    }
+
+    /// Requests of every version, by label
+    pub fn snapshot(&self) -> BTreeMap<&'static str, u64> {
+        ApiVersion::ALL.iter()
+            .map(|version| (version.label(), self.get_requests(*version)))
+            .collect()
+    }
+}
+
+/// ## Description
+/// Middleware wrapping every scope of a version. Counts the requests of the version and, if it's
+/// deprecated, adds the Deprecation (RFC 9745) and Sunset (RFC 8594) headers to the responses,
+/// along with a link to the same path in the successor version
+#[derive(Clone, Copy)]
+pub struct ApiVersioning {
+    version: ApiVersion
+}
+
+impl ApiVersioning {
+    pub fn new(version: ApiVersion) -> Self {
+        ApiVersioning { version }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ApiVersioning
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+        S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = ApiVersioningMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ApiVersioningMiddleware {
+            service: Rc::new(service),
+            version: self.version
+        }))
+    }
+}
+
+pub struct ApiVersioningMiddleware<S> {
+    service: Rc<S>,
+    version: ApiVersion
+}
+
+impl<S, B> Service<ServiceRequest> for ApiVersioningMiddleware<S>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+        S::Future: 'static
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+
+        let service = Rc::clone(&self.service);
+        let version = self.version;
+
+        Box::pin(async move {
+            VersionUsage::instance().record(version);
+
+            let path = req.path().to_string();
+            let mut response = service.call(req).await?;
+
+            if let Some(successor) = version.successor() {
+                deprecation_headers(response.headers_mut(), version, successor, path.as_str()).await;
+            }
+
+            Ok(response)
+        })
+    }
+}
+
+async fn deprecation_headers(headers: &mut HeaderMap, version: ApiVersion, successor: ApiVersion, path: &str) {
+
+    let successor_path = format!("{}{}", successor.prefix(), path.strip_prefix(version.prefix()).unwrap_or(path));
+
+    let mut values = vec![
+        ("link", format!("<{}>; rel=\"successor-version\"", successor_path))
+    ];
+    if let Some(deprecated_at) = version.deprecated_at() {
+        values.push(("deprecation", format!("@{}", deprecated_at)));
+    }
+    if let Some(sunset) = version.sunset().await {
+        values.push(("sunset", sunset));
+    }
+
+    for (name, value) in values {
+        if let Ok(value) = HeaderValue::from_str(value.as_str()) {
+            headers.append(HeaderName::from_static(name), value);
+        }
+    }
+}
@@ -1,17 +1,21 @@
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use error_mapper::TheResult;
 use rand::{Rng, thread_rng};
 use rand::distributions::{Distribution};
+use sha2::{Digest, Sha256};
 
 struct TokenCharset;
 
 impl Distribution<u8> for TokenCharset {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> u8 {
-        const RANGE: u32 = 26 + 26 + 36;
         // Same situation as with the password hasher, I won't publish my personal CHARSET for
         // security reasons, even though the string is randomized
         const TOKEN_CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+        //  The range must follow the charset, or the index below goes out of bounds
+        const RANGE: u32 = TOKEN_CHARSET.len() as u32;
         loop {
             let var = rng.next_u32() >> (32 - 6);
             if var < RANGE {
@@ -21,16 +25,90 @@ impl Distribution<u8> for TokenCharset {
     }
 }
 
+const SESSION_TOKEN_LENGTH: usize = 40;
+
 pub fn generate_session_token() -> TheResult<String> {
     //generate random characters in a string
     Ok(
         thread_rng()
             .sample_iter(&TokenCharset)
-            .take(40)
+            .take(SESSION_TOKEN_LENGTH)
             .map(char::from).collect::<String>()
     )
 }
 
+/// Checks the token has the shape of the ones generated above, so it can be safely used to look
+/// the session up
+pub fn is_session_token(token: &str) -> bool {
+    token.len() == SESSION_TOKEN_LENGTH && token.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+}
+
+/// Length of the public part of prefixed keys, used to look the key up without its secret
+const KEY_PREFIX_LENGTH: usize = 8;
+
+/// ## Description
+/// Generates a key with the shape `<header>_<prefix>_<secret>`. The header tells apart the kind of
+/// credential, and the prefix identifies the key in storage, where only its hash is kept.
+/// Returns the prefix along with the full key
+pub fn generate_prefixed_key(header: &str) -> TheResult<(String, String)> {
+    let prefix = generate_session_token()?
+        .chars()
+        .take(KEY_PREFIX_LENGTH)
+        .collect::<String>();
+    let secret = generate_session_token()?;
+    let key = format!("{}_{}_{}", header, prefix, secret);
+    Ok((prefix, key))
+}
+
+/// Extracts the prefix from a key generated with generate_prefixed_key, None if the key doesn't
+/// start with the header or is not correctly formatted
+pub fn get_prefix_from_key<'a>(key: &'a str, header: &str) -> Option<&'a str> {
+    let key = key.strip_prefix(header)?.strip_prefix('_')?;
+    let (prefix, _) = key.split_once('_')?;
+    if !is_valid_key_prefix(prefix) {
+        return None
+    }
+    Some(prefix)
+}
+
+/// Whether the string can be the prefix of a key generated with generate_prefixed_key
+pub fn is_valid_key_prefix(prefix: &str) -> bool {
+    prefix.len() == KEY_PREFIX_LENGTH && prefix.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// PKCE challenge for a verifier with the S256 method: the unpadded base64url encoding of the
+/// verifier's SHA-256 digest
+pub fn pkce_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+/// SHA-256 digest of a session token, in hex. Sessions are kept in memory by their digest, never
+/// by the token itself
+pub fn session_token_digest(token: &str) -> String {
+    credential_digest(token)
+}
+
+/// ## Description
+/// SHA-256 digest of a generated credential, in hex: API keys, personal access tokens and the
+/// OAuth client secrets, codes and tokens. Only the digest is stored. Credentials are long random
+/// strings, so a fast digest is enough, and unlike `generate_hash` it's stable across toolchains
+pub fn credential_digest(credential: &str) -> String {
+    Sha256::digest(credential.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Checks the credential received against a stored digest, in constant time
+pub fn credential_matches(digest: &str, credential: &str) -> bool {
+    digests_match(digest, credential_digest(credential).as_str())
+}
+
+/// Compares two digests in constant time, so the comparison doesn't tell how much of them matched
+pub fn digests_match(lhs: &str, rhs: &str) -> bool {
+    lhs.len() == rhs.len() && openssl::memcmp::eq(lhs.as_bytes(), rhs.as_bytes())
+}
+
 pub fn generate_hash(string: &str) -> String {
 
     let mut hasher = DefaultHasher::new();
@@ -0,0 +1,187 @@
+use std::fs;
+use std::io::{Error, Write};
+use std::io::ErrorKind::{InvalidData, NotFound};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use error_mapper::{map_to_new_error, SystemErrorCodes, TheError, TheResult};
+use lazy_static::lazy_static;
+use openssl::bn::BigNum;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::sign::{Signer, Verifier};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+lazy_static!{
+    /// Key signing the ID tokens. It's read from the key file at startup, and generated there if
+    /// the file doesn't exist, so tokens stay valid across restarts. A key that can't be read is
+    /// kept as the error, for the service to fail when it's built
+    static ref SIGNING_KEY: Result<SigningKey, String> = SigningKey::load().map_err(|e| e.to_string());
+}
+
+const SIGNING_KEY_FILE_PATH: &str = "certs/oidc_key.pem";
+const SIGNING_KEY_BITS: u32 = 2048;
+
+pub struct SigningKey {
+    key: PKey<Private>,
+    kid: String
+}
+
+#[derive(Serialize, Deserialize)]
+struct JwtHeader {
+    alg: String,
+    typ: String,
+    kid: String
+}
+
+/// ## Description
+/// Public part of a signing key, in the JSON Web Key format published in the JWKS endpoint
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Jwk {
+    kty: String,
+    #[serde(rename = "use")]
+    key_use: String,
+    alg: String,
+    kid: String,
+    n: String,
+    e: String
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JwkSet {
+    keys: Vec<Jwk>
+}
+
+impl SigningKey {
+    fn load() -> std::io::Result<Self> {
+        //  Only a missing file means there's no key yet, any other error must not replace it
+        let rsa = match fs::read(SIGNING_KEY_FILE_PATH) {
+            Ok(pem) => Rsa::private_key_from_pem(pem.as_slice())
+                .map_err(|e| Error::new(InvalidData, format!("Invalid signing key {}: {}", SIGNING_KEY_FILE_PATH, e)))?,
+            Err(e) if e.kind() == NotFound => {
+                let rsa = Rsa::generate(SIGNING_KEY_BITS)
+                    .map_err(|e| Error::new(InvalidData, format!("{}", e)))?;
+                let pem = rsa.private_key_to_pem()
+                    .map_err(|e| Error::new(InvalidData, format!("{}", e)))?;
+                write_key_file(pem.as_slice())?;
+                rsa
+            },
+            Err(e) => return Err(e)
+        };
+
+        //  The key id is derived from the modulus, so it only changes when the key does
+        let kid = URL_SAFE_NO_PAD.encode(&Sha256::digest(rsa.n().to_vec())[..12]);
+
+        let key = PKey::from_rsa(rsa)
+            .map_err(|e| Error::new(InvalidData, format!("{}", e)))?;
+
+        Ok(Self { key, kid })
+    }
+
+    pub fn instance() -> TheResult<&'static Self> {
+        SIGNING_KEY.as_ref().map_err(|e| {
+            TheError::default()
+                .with_type(SystemErrorCodes::GenericError)
+                .with_content(format!("Failed to load the signing key: {}", e))
+        })
+    }
+
+    /// Forces the signing key to be read or generated at startup instead of on the first token.
+    /// Fails if the key file exists but can't be read
+    pub fn load_at_startup() -> TheResult<()> {
+        Self::instance().map(|_| ())
+    }
+
+    /// Encodes the claims into a JWT signed with RS256
+    pub fn sign<T: Serialize>(&self, claims: &T) -> TheResult<String> {
+
+        let header = JwtHeader {
+            alg: "RS256".to_string(),
+            typ: "JWT".to_string(),
+            kid: self.kid.clone()
+        };
+
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header).map_err(|e| map_to_new_error!(e))?),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).map_err(|e| map_to_new_error!(e))?)
+        );
+
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key).map_err(openssl_error)?;
+        signer.update(signing_input.as_bytes()).map_err(openssl_error)?;
+        let signature = signer.sign_to_vec().map_err(openssl_error)?;
+
+        Ok(format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature)))
+    }
+
+    pub fn jwks(&self) -> TheResult<JwkSet> {
+        let rsa = self.key.rsa().map_err(openssl_error)?;
+        Ok(JwkSet {
+            keys: vec![
+                Jwk {
+                    kty: "RSA".to_string(),
+                    key_use: "sig".to_string(),
+                    alg: "RS256".to_string(),
+                    kid: self.kid.clone(),
+                    n: URL_SAFE_NO_PAD.encode(rsa.n().to_vec()),
+                    e: URL_SAFE_NO_PAD.encode(rsa.e().to_vec())
+                }
+            ]
+        })
+    }
+}
+
+/// ## Description
+/// Verifies the signature of a JWT against the keys of a JWKS and returns its claims. Returns None
+/// if the token is malformed, signed with an unknown key or the signature doesn't match. Claims
+/// such as the issuer, audience or expiry are left to the caller
+pub fn verify<T: DeserializeOwned>(token: &str, jwks: &JwkSet) -> Option<T> {
+
+    let mut parts = token.split('.');
+    let (Some(header), Some(claims), Some(signature), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return None
+    };
+
+    let jwt_header = serde_json::from_slice::<JwtHeader>(URL_SAFE_NO_PAD.decode(header).ok()?.as_slice()).ok()?;
+    if jwt_header.alg != "RS256" {
+        return None
+    }
+    let jwk = jwks.keys.iter().find(|jwk| jwk.kid == jwt_header.kid)?;
+
+    let rsa = Rsa::from_public_components(
+        BigNum::from_slice(URL_SAFE_NO_PAD.decode(jwk.n.as_str()).ok()?.as_slice()).ok()?,
+        BigNum::from_slice(URL_SAFE_NO_PAD.decode(jwk.e.as_str()).ok()?.as_slice()).ok()?
+    ).ok()?;
+    let key = PKey::from_rsa(rsa).ok()?;
+
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &key).ok()?;
+    verifier.update(format!("{}.{}", header, claims).as_bytes()).ok()?;
+    if !verifier.verify(URL_SAFE_NO_PAD.decode(signature).ok()?.as_slice()).ok()? {
+        return None
+    }
+
+    serde_json::from_slice::<T>(URL_SAFE_NO_PAD.decode(claims).ok()?.as_slice()).ok()
+}
+
+/// Writes a new private key, readable by the owner only. An existing file is never overwritten
+fn write_key_file(pem: &[u8]) -> std::io::Result<()> {
+    if let Some(directory) = std::path::Path::new(SIGNING_KEY_FILE_PATH).parent() {
+        fs::create_dir_all(directory)?;
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(SIGNING_KEY_FILE_PATH)?.write_all(pem)
+}
+
+fn openssl_error(e: ErrorStack) -> TheError {
+    TheError::default()
+        .with_type(SystemErrorCodes::GenericError)
+        .with_content(e.to_string())
+}
@@ -1 +1,3 @@
-pub mod crypt;
\ No newline at end of file
+pub mod crypt;
+pub mod jwt;
+pub mod policy;
\ No newline at end of file
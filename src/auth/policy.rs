@@ -0,0 +1,559 @@
+use std::fs::File;
+use std::io::Error;
+use std::io::ErrorKind::InvalidData;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::modules::users::user::{Level, User};
+use crate::modules::users::UsersSessions;
+
+lazy_static!{
+    /// Authorization rules loaded from the policy file at startup. Every privilege check that
+    /// depends on the levels of the users involved is answered by this engine
+    static ref POLICY: Policy = Policy::new();
+}
+
+const POLICY_FILE_PATH: &str = "config/policy.json";
+
+pub struct Policy {
+    inner: PolicyInner
+}
+
+struct PolicyInner {
+    default_effect: Effect,
+    rules: Vec<Rule>
+}
+
+#[derive(Deserialize)]
+struct PolicyFile {
+    default_effect: Effect,
+    rules: Vec<RuleFile>
+}
+
+#[derive(Deserialize)]
+struct RuleFile {
+    name: String,
+    effect: Effect,
+    action: Action,
+    #[serde(default)]
+    conditions: Vec<String>
+}
+
+struct Rule {
+    name: String,
+    effect: Effect,
+    action: Action,
+    conditions: Vec<Condition>
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum Effect {
+    Allow,
+    Deny
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+pub enum Action {
+    #[serde(rename = "users.create")]
+    CreateUser,
+    #[serde(rename = "users.delete")]
+    DeleteUser,
+    #[serde(rename = "users.restore")]
+    RestoreUser,
+    #[serde(rename = "users.change_level")]
+    ChangeUserLevel,
+    #[serde(rename = "users.list")]
+    ListUsers,
+    #[serde(rename = "service.stop")]
+    Stop,
+    #[serde(rename = "service.stop_now")]
+    StopNow,
+    #[serde(rename = "organizations.create")]
+    CreateOrganization,
+    #[serde(rename = "groups.create")]
+    CreateGroup,
+    #[serde(rename = "groups.manage_members")]
+    ManageGroupMembers,
+    #[serde(rename = "api_keys.manage")]
+    ManageApiKeys,
+    #[serde(rename = "personal_tokens.create")]
+    CreatePersonalToken,
+    #[serde(rename = "oauth_clients.create")]
+    CreateOAuthClient,
+    #[serde(rename = "oauth_clients.revoke_sessions")]
+    CreateSessionRevokingClient
+}
+
+/// ## Description
+/// A single decision to be taken by the policy engine: who is acting (subject), what they're
+/// trying to do (action) and who or what they're doing it to (resource)
+///
+/// ### Attributes available to conditions
+/// - subject.level: level of the requesting user
+/// - resource.level: current level of the user affected by the action
+/// - resource.target_level: level requested for the affected user (create and change level)
+/// - resource.expiry_days: days until the requested credential expires (personal tokens)
+#[derive(Debug, Clone, Copy)]
+pub struct PolicyRequest {
+    action: Action,
+    subject_level: Level,
+    resource_level: Option<Level>,
+    resource_target_level: Option<Level>,
+    resource_expiry_days: Option<i16>
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PolicyDecision {
+    action: Action,
+    allowed: bool,
+    rule: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    explanation: Option<Vec<RuleEvaluation>>
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RuleEvaluation {
+    rule: String,
+    effect: Effect,
+    matched: bool,
+    conditions: Vec<ConditionEvaluation>
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ConditionEvaluation {
+    condition: String,
+    result: bool
+}
+
+#[derive(Debug, Clone)]
+struct Condition {
+    source: String,
+    lhs: Operand,
+    comparison: Comparison,
+    rhs: Operand
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    //  Attribute plus an offset, such as `subject.level - 1`
+    Attribute(Attribute, i16),
+    Literal(i16)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Attribute {
+    SubjectLevel,
+    ResourceLevel,
+    ResourceTargetLevel,
+    ResourceExpiryDays
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterOrEqual,
+    Lower,
+    LowerOrEqual
+}
+
+impl Policy {
+    fn new() -> Self {
+        Self {
+            inner: Self::load().unwrap()
+        }
+    }
+
+    fn load() -> std::io::Result<PolicyInner> {
+        let file = File::open(POLICY_FILE_PATH)
+            .map_err(|e| Error::new(InvalidData, format!("{}", e)))?;
+
+        let policy_file = serde_json::from_reader::<_, PolicyFile>(file)
+            .map_err(|e| Error::new(InvalidData, format!("{}", e)))?;
+
+        Self::parse(policy_file)
+    }
+
+    fn parse(policy_file: PolicyFile) -> std::io::Result<PolicyInner> {
+
+        let mut rules = vec![];
+        for rule in policy_file.rules {
+            let mut conditions = vec![];
+            for condition in rule.conditions {
+                conditions.push(
+                    Condition::parse(condition.as_str())
+                        .map_err(|e| Error::new(InvalidData, format!("Rule {}: {}", rule.name, e)))?
+                );
+            }
+            rules.push(Rule {
+                name: rule.name,
+                effect: rule.effect,
+                action: rule.action,
+                conditions
+            });
+        }
+
+        Ok(PolicyInner {
+            default_effect: policy_file.default_effect,
+            rules
+        })
+    }
+
+    pub fn instance() -> &'static Self {
+        &POLICY
+    }
+
+    /// Forces the policy file to be read and parsed, so a broken policy stops the app at startup
+    /// instead of on the first request that needs it
+    pub fn load_at_startup() {
+        lazy_static::initialize(&POLICY);
+    }
+
+    /// Evaluates the request against the rules and returns the decision along with the rule
+    /// that took it
+    pub async fn evaluate(&self, request: &PolicyRequest) -> PolicyDecision {
+        self.decide(request, false).await
+    }
+
+    /// Same as evaluate, but the decision also carries the result of every rule and condition
+    /// checked to reach it
+    pub async fn explain(&self, request: &PolicyRequest) -> PolicyDecision {
+        self.decide(request, true).await
+    }
+
+    async fn decide(&self, request: &PolicyRequest, explain: bool) -> PolicyDecision {
+
+        let inner = &self.inner;
+        let mut explanation = vec![];
+
+        //  Rules are checked in order, the first one whose conditions all hold decides
+        for rule in inner.rules.iter().filter(|rule| rule.action == request.action) {
+            let conditions = rule.conditions.iter()
+                .map(|condition| ConditionEvaluation {
+                    condition: condition.source.clone(),
+                    result: condition.evaluate(request)
+                })
+                .collect::<Vec<_>>();
+            let matched = conditions.iter().all(|condition| condition.result);
+
+            if explain {
+                explanation.push(RuleEvaluation {
+                    rule: rule.name.clone(),
+                    effect: rule.effect,
+                    matched,
+                    conditions
+                });
+            }
+
+            if matched {
+                return PolicyDecision {
+                    action: request.action,
+                    allowed: rule.effect == Effect::Allow,
+                    rule: Some(rule.name.clone()),
+                    explanation: explain.then_some(explanation)
+                }
+            }
+        }
+
+        //  No rule matched, the default effect applies
+        PolicyDecision {
+            action: request.action,
+            allowed: inner.default_effect == Effect::Allow,
+            rule: None,
+            explanation: explain.then_some(explanation)
+        }
+    }
+}
+
+impl PolicyRequest {
+    /// The subject acts with their effective level, which includes the levels granted by groups
+    pub async fn new(action: Action, subject: &User) -> Self {
+        Self::with_subject_level(action, UsersSessions::instance().get_effective_level(subject).await)
+    }
+
+    /// Used when the subject acts with a level other than their global one, such as the level
+    /// they hold in an organization
+    pub fn with_subject_level(action: Action, subject_level: Level) -> Self {
+        Self {
+            action,
+            subject_level,
+            resource_level: None,
+            resource_target_level: None,
+            resource_expiry_days: None
+        }
+    }
+
+    pub fn with_resource_level(mut self, level: Level) -> Self {
+        self.resource_level = Some(level);
+        self
+    }
+
+    pub fn with_resource_target_level(mut self, level: Level) -> Self {
+        self.resource_target_level = Some(level);
+        self
+    }
+
+    pub fn with_resource_expiry_days(mut self, days: i16) -> Self {
+        self.resource_expiry_days = Some(days);
+        self
+    }
+
+    fn attribute(&self, attribute: Attribute) -> Option<i16> {
+        match attribute {
+            Attribute::SubjectLevel => Some(self.subject_level as i16),
+            Attribute::ResourceLevel => self.resource_level.map(|level| level as i16),
+            Attribute::ResourceTargetLevel => self.resource_target_level.map(|level| level as i16),
+            Attribute::ResourceExpiryDays => self.resource_expiry_days
+        }
+    }
+}
+
+impl PolicyDecision {
+    pub fn is_allowed(&self) -> bool {
+        self.allowed
+    }
+}
+
+impl Condition {
+    /// Parses conditions with the shape `<operand> <comparison> <operand>`, where an operand is an
+    /// attribute (optionally followed by `+ n` or `- n`), a level name or a number
+    fn parse(source: &str) -> Result<Self, String> {
+
+        let tokens = source.split_whitespace().collect::<Vec<_>>();
+
+        let Some(position) = tokens.iter().position(|token| Comparison::parse(token).is_some()) else {
+            return Err(format!("No comparison found in condition \"{}\"", source))
+        };
+
+        Ok(Self {
+            source: source.to_string(),
+            lhs: Operand::parse(&tokens[..position])?,
+            comparison: Comparison::parse(tokens[position]).unwrap_or(Comparison::Equal),
+            rhs: Operand::parse(&tokens[position + 1..])?
+        })
+    }
+
+    fn evaluate(&self, request: &PolicyRequest) -> bool {
+        //  A condition on an attribute the request doesn't carry never holds
+        let (Some(lhs), Some(rhs)) = (self.lhs.value(request), self.rhs.value(request)) else {
+            return false
+        };
+
+        match self.comparison {
+            Comparison::Equal => lhs == rhs,
+            Comparison::NotEqual => lhs != rhs,
+            Comparison::Greater => lhs > rhs,
+            Comparison::GreaterOrEqual => lhs >= rhs,
+            Comparison::Lower => lhs < rhs,
+            Comparison::LowerOrEqual => lhs <= rhs
+        }
+    }
+}
+
+impl Operand {
+    fn parse(tokens: &[&str]) -> Result<Self, String> {
+        match tokens {
+            [term] => Self::parse_term(term, 0),
+            [term, sign, offset] => {
+                let offset = offset.parse::<i16>()
+                    .map_err(|_| format!("Invalid offset \"{}\"", offset))?;
+                match *sign {
+                    "+" => Self::parse_term(term, offset),
+                    "-" => Self::parse_term(term, -offset),
+                    _ => Err(format!("Invalid operator \"{}\"", sign))
+                }
+            },
+            _ => Err(format!("Invalid operand \"{}\"", tokens.join(" ")))
+        }
+    }
+
+    fn parse_term(term: &str, offset: i16) -> Result<Self, String> {
+        match term {
+            "subject.level" => Ok(Operand::Attribute(Attribute::SubjectLevel, offset)),
+            "resource.level" => Ok(Operand::Attribute(Attribute::ResourceLevel, offset)),
+            "resource.target_level" => Ok(Operand::Attribute(Attribute::ResourceTargetLevel, offset)),
+            "resource.expiry_days" => Ok(Operand::Attribute(Attribute::ResourceExpiryDays, offset)),
+            "View" | "Low" | "Medium" | "High" | "Super" => {
+                Ok(Operand::Literal(Level::from(term.to_string()) as i16 + offset))
+            },
+            _ => match term.parse::<i16>() {
+                Ok(value) => Ok(Operand::Literal(value + offset)),
+                Err(_) => Err(format!("Unknown attribute \"{}\"", term))
+            }
+        }
+    }
+
+    fn value(&self, request: &PolicyRequest) -> Option<i16> {
+        match self {
+            //  Same as Level::one_level_below, levels never go below View
+            Operand::Attribute(attribute, offset) => {
+                request.attribute(*attribute).map(|value| (value + offset).max(0))
+            },
+            Operand::Literal(value) => Some((*value).max(0))
+        }
+    }
+}
+
+impl Comparison {
+    fn parse(token: &str) -> Option<Self> {
+        match token {
+            "==" => Some(Comparison::Equal),
+            "!=" => Some(Comparison::NotEqual),
+            ">" => Some(Comparison::Greater),
+            ">=" => Some(Comparison::GreaterOrEqual),
+            "<" => Some(Comparison::Lower),
+            "<=" => Some(Comparison::LowerOrEqual),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(json: &str) -> std::io::Result<Policy> {
+        let policy_file = serde_json::from_str::<PolicyFile>(json)
+            .map_err(|e| Error::new(InvalidData, format!("{}", e)))?;
+        Ok(Policy { inner: Policy::parse(policy_file)? })
+    }
+
+    fn repo_policy() -> Policy {
+        Policy { inner: Policy::load().unwrap() }
+    }
+
+    fn request(action: Action, subject: Level) -> PolicyRequest {
+        PolicyRequest::with_subject_level(action, subject)
+    }
+
+    #[test]
+    fn parses_conditions_with_offsets_levels_and_numbers() {
+        let condition = Condition::parse("resource.target_level <= subject.level - 1").unwrap();
+        let create = request(Action::CreateUser, Level::High);
+        assert!(condition.evaluate(&create.with_resource_target_level(Level::Medium)));
+        assert!(!condition.evaluate(&create.with_resource_target_level(Level::High)));
+
+        let condition = Condition::parse("subject.level == Super").unwrap();
+        assert!(condition.evaluate(&request(Action::Stop, Level::Super)));
+        assert!(!condition.evaluate(&request(Action::Stop, Level::High)));
+
+        let condition = Condition::parse("resource.expiry_days <= 90").unwrap();
+        assert!(condition.evaluate(&request(Action::CreatePersonalToken, Level::Low).with_resource_expiry_days(90)));
+        assert!(!condition.evaluate(&request(Action::CreatePersonalToken, Level::Low).with_resource_expiry_days(91)));
+
+        let condition = Condition::parse("subject.level + 1 > High").unwrap();
+        assert!(condition.evaluate(&request(Action::Stop, Level::High)));
+        assert!(!condition.evaluate(&request(Action::Stop, Level::Medium)));
+    }
+
+    #[test]
+    fn offsets_never_go_below_view() {
+        let condition = Condition::parse("subject.level - 3 == View").unwrap();
+        assert!(condition.evaluate(&request(Action::Stop, Level::Low)));
+    }
+
+    #[test]
+    fn conditions_on_missing_attributes_never_hold() {
+        let condition = Condition::parse("resource.level < subject.level").unwrap();
+        assert!(!condition.evaluate(&request(Action::DeleteUser, Level::Super)));
+
+        let condition = Condition::parse("resource.level != subject.level").unwrap();
+        assert!(!condition.evaluate(&request(Action::DeleteUser, Level::Super)));
+    }
+
+    #[test]
+    fn rejects_malformed_conditions() {
+        for source in [
+            "",
+            "subject.level",
+            "subject.level High",
+            "subject.level => High",
+            "subject.role == High",
+            "subject.level == Admin",
+            "subject.level * 2 == High",
+            "subject.level - one == High",
+            "subject.level - 1 - 1 == High",
+            "== High"
+        ] {
+            assert!(Condition::parse(source).is_err(), "\"{}\" must not parse", source);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_policies() {
+        let unknown_action = r#"{"default_effect": "Deny", "rules": [
+            {"name": "r", "effect": "Allow", "action": "users.fly", "conditions": []}
+        ]}"#;
+        let unknown_effect = r#"{"default_effect": "Maybe", "rules": []}"#;
+        let broken_condition = r#"{"default_effect": "Deny", "rules": [
+            {"name": "broken", "effect": "Allow", "action": "service.stop", "conditions": ["subject.level >>= High"]}
+        ]}"#;
+
+        assert!(policy(unknown_action).is_err());
+        assert!(policy(unknown_effect).is_err());
+        let error = policy(broken_condition).err().unwrap();
+        assert!(error.to_string().contains("Rule broken"));
+    }
+
+    #[actix_web::test]
+    async fn first_matching_rule_decides_and_default_applies_otherwise() {
+        let policy = policy(r#"{"default_effect": "Deny", "rules": [
+            {"name": "deny_low", "effect": "Deny", "action": "service.stop", "conditions": ["subject.level <= Low"]},
+            {"name": "allow_all", "effect": "Allow", "action": "service.stop"}
+        ]}"#).unwrap();
+
+        let decision = policy.evaluate(&request(Action::Stop, Level::Low)).await;
+        assert!(!decision.is_allowed());
+        assert_eq!(decision.rule.as_deref(), Some("deny_low"));
+
+        let decision = policy.evaluate(&request(Action::Stop, Level::Medium)).await;
+        assert!(decision.is_allowed());
+        assert_eq!(decision.rule.as_deref(), Some("allow_all"));
+
+        let decision = policy.evaluate(&request(Action::StopNow, Level::Super)).await;
+        assert!(!decision.is_allowed());
+        assert_eq!(decision.rule, None);
+    }
+
+    #[actix_web::test]
+    async fn explain_lists_every_rule_checked() {
+        let policy = policy(r#"{"default_effect": "Deny", "rules": [
+            {"name": "first", "effect": "Allow", "action": "service.stop", "conditions": ["subject.level == Super"]},
+            {"name": "second", "effect": "Allow", "action": "service.stop", "conditions": ["subject.level >= High"]}
+        ]}"#).unwrap();
+
+        let decision = policy.explain(&request(Action::Stop, Level::High)).await;
+        let explanation = decision.explanation.unwrap();
+        assert_eq!(explanation.len(), 2);
+        assert!(!explanation[0].matched);
+        assert!(!explanation[0].conditions[0].result);
+        assert!(explanation[1].matched);
+        assert!(policy.evaluate(&request(Action::Stop, Level::High)).await.explanation.is_none());
+    }
+
+    #[actix_web::test]
+    async fn repo_policy_keeps_the_documented_privileges() {
+        let policy = repo_policy();
+
+        let create = |subject, target| request(Action::CreateUser, subject).with_resource_target_level(target);
+        assert!(policy.evaluate(&create(Level::High, Level::Medium)).await.is_allowed());
+        assert!(!policy.evaluate(&create(Level::High, Level::High)).await.is_allowed());
+        assert!(!policy.evaluate(&create(Level::Super, Level::Super)).await.is_allowed());
+
+        let delete = |subject, target| request(Action::DeleteUser, subject).with_resource_level(target);
+        assert!(policy.evaluate(&delete(Level::Super, Level::Medium)).await.is_allowed());
+        assert!(!policy.evaluate(&delete(Level::Super, Level::High)).await.is_allowed());
+
+        assert!(policy.evaluate(&request(Action::StopNow, Level::Super)).await.is_allowed());
+        assert!(!policy.evaluate(&request(Action::StopNow, Level::High)).await.is_allowed());
+
+        let list = |subject, target| request(Action::ListUsers, subject).with_resource_level(target);
+        assert!(policy.evaluate(&list(Level::High, Level::Medium)).await.is_allowed());
+        assert!(!policy.evaluate(&list(Level::High, Level::High)).await.is_allowed());
+        assert!(!policy.evaluate(&list(Level::Medium, Level::View)).await.is_allowed());
+
+        assert!(policy.evaluate(&request(Action::CreateSessionRevokingClient, Level::Super)).await.is_allowed());
+        assert!(!policy.evaluate(&request(Action::CreateSessionRevokingClient, Level::High)).await.is_allowed());
+    }
+}
@@ -1,36 +1,117 @@
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::Error;
-use std::io::ErrorKind::InvalidData;
+use chrono::NaiveDate;
+use error_mapper::{map_to_new_error, TheResult};
 use serde::Deserialize;
 use tokio::sync::RwLock;
 use crate::config::ENVIRONMENT_CONFIG;
+use crate::modules::users::session_binding::BindingAction;
+use crate::modules::users::user::Level;
+
+/// Path of the config file read when no settings are given to the service
+pub const CONFIG_FILE_PATH: &str = "config/env.json";
 
 pub struct EnvironmentConfig {
-    config: RwLock<EnvironmentConfigInner>
+    config: RwLock<EnvironmentSettings>
 }
 
-#[derive(Deserialize)]
-struct EnvironmentConfigInner {
+/// ## Description
+/// Settings of the service, read from the config file or built in code by apps embedding it
+#[derive(Deserialize, Default, Clone)]
+pub struct EnvironmentSettings {
     service_url: String,
     service_port: String,
     db_url: String,
-    reset_db: bool
+    reset_db: bool,
+    #[serde(default)]
+    tenant_domain: Option<String>,
+    #[serde(default)]
+    issuer: Option<String>,
+    #[serde(default)]
+    local_relying_party: Option<LocalRelyingPartyConfig>,
+    #[serde(default)]
+    session_store: SessionStoreConfig,
+    #[serde(default)]
+    session_binding: Option<SessionBindingConfig>,
+    #[serde(default)]
+    reauthentication_window_seconds: Option<i64>,
+    #[serde(default)]
+    username_quarantine_days: Option<i64>,
+    #[serde(default)]
+    unversioned_routes_sunset: Option<NaiveDate>
 }
 
-impl EnvironmentConfig {
-    pub(super) fn new() -> Self {
-        Self {
-            config: RwLock::new(Self::load().unwrap())
-        }
+/// How long a password confirmation allows sensitive operations, unless configured
+const DEFAULT_REAUTHENTICATION_WINDOW_SECONDS: i64 = 300;
+
+/// How long a username given up in a rename stays reserved for its previous owner, unless configured
+const DEFAULT_USERNAME_QUARANTINE_DAYS: i64 = 90;
+
+/// ## Description
+/// OAuth client registration used by the local relying party, which is only mounted when set
+#[derive(Deserialize, Default, Clone)]
+pub struct LocalRelyingPartyConfig {
+    client_id: String,
+    #[serde(default)]
+    client_secret: Option<String>,
+    redirect_uri: String
+}
+
+/// ## Description
+/// Where the sessions are answered from. Sessions are kept in memory by default, replicas behind
+/// a load balancer should share them through the database or Redis
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum SessionStoreConfig {
+    #[default]
+    Memory,
+    Mysql,
+    Redis {
+        url: String,
+        #[serde(default = "default_redis_key_prefix")]
+        key_prefix: String
     }
+}
 
-    fn load() -> std::io::Result<EnvironmentConfigInner> {
-        let file = File::open("config/env.json")
-            .map_err(|e| Error::new(InvalidData, format!("{}", e)))?;
+fn default_redis_key_prefix() -> String {
+    "uta".to_string()
+}
+
+/// ## Description
+/// Tolerance of the sessions to changes of the client using them. Sessions are bound to the IP
+/// and user agent family they were opened from, and requests from IPs outside the network given by
+/// the prefixes, or from other user agent families, get the action configured for the user's level
+#[derive(Deserialize, Clone, Debug)]
+pub struct SessionBindingConfig {
+    #[serde(default = "default_ipv4_prefix")]
+    ipv4_prefix: u8,
+    #[serde(default = "default_ipv6_prefix")]
+    ipv6_prefix: u8,
+    #[serde(default)]
+    trust_forwarded_headers: bool,
+    #[serde(default = "default_trusted_proxies")]
+    trusted_proxies: u8,
+    #[serde(default)]
+    levels: HashMap<Level, BindingAction>
+}
 
-        match serde_json::from_reader::<_, EnvironmentConfigInner>(file) {
-            Ok(config) => {Ok(config)},
-            Err(e) => Err(Error::new(InvalidData, format!("{}", e)))?
+fn default_ipv4_prefix() -> u8 {
+    24
+}
+
+fn default_ipv6_prefix() -> u8 {
+    64
+}
+
+fn default_trusted_proxies() -> u8 {
+    1
+}
+
+impl EnvironmentConfig {
+    pub(super) fn new() -> Self {
+        //  The service sets the actual settings when it's built, from the config file by default
+        Self {
+            config: RwLock::new(EnvironmentSettings::from_file(CONFIG_FILE_PATH).unwrap_or_default())
         }
     }
 
@@ -38,6 +119,10 @@ impl EnvironmentConfig {
         &ENVIRONMENT_CONFIG
     }
 
+    pub async fn set_settings(&self, settings: EnvironmentSettings) {
+        *self.config.write().await = settings;
+    }
+
     pub async fn get_service_url(&self) -> String {
         self.config.read().await.service_url.clone()
     }
@@ -53,4 +138,181 @@ impl EnvironmentConfig {
     pub async fn reset_db(&self) -> bool {
         self.config.read().await.reset_db
     }
+
+    pub async fn get_tenant_domain(&self) -> Option<String> {
+        self.config.read().await.tenant_domain.clone()
+    }
+
+    /// Public URL of the service, used as the issuer of the ID tokens and as the base of the
+    /// endpoints published in the OpenID discovery document. Defaults to the service url and port
+    pub async fn get_issuer(&self) -> String {
+        let config = self.config.read().await;
+        match &config.issuer {
+            Some(issuer) => issuer.trim_end_matches('/').to_string(),
+            None => format!("https://{}:{}", config.service_url, config.service_port)
+        }
+    }
+
+    pub async fn get_local_relying_party(&self) -> Option<LocalRelyingPartyConfig> {
+        self.config.read().await.local_relying_party.clone()
+    }
+
+    pub async fn get_session_store(&self) -> SessionStoreConfig {
+        self.config.read().await.session_store.clone()
+    }
+
+    pub async fn get_session_binding(&self) -> Option<SessionBindingConfig> {
+        self.config.read().await.session_binding.clone()
+    }
+
+    pub async fn get_reauthentication_window_seconds(&self) -> i64 {
+        self.config.read().await.reauthentication_window_seconds.unwrap_or(DEFAULT_REAUTHENTICATION_WINDOW_SECONDS)
+    }
+
+    pub async fn get_username_quarantine_days(&self) -> i64 {
+        self.config.read().await.username_quarantine_days.unwrap_or(DEFAULT_USERNAME_QUARANTINE_DAYS)
+    }
+
+    pub async fn get_unversioned_routes_sunset(&self) -> Option<NaiveDate> {
+        self.config.read().await.unversioned_routes_sunset
+    }
+}
+
+impl EnvironmentSettings {
+    pub fn new(service_url: &str, service_port: &str, db_url: &str) -> Self {
+        Self {
+            service_url: service_url.to_string(),
+            service_port: service_port.to_string(),
+            db_url: db_url.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn from_file(path: &str) -> TheResult<Self> {
+        let file = File::open(path).map_err(|e| map_to_new_error!(e))?;
+        serde_json::from_reader::<_, Self>(file).map_err(|e| map_to_new_error!(e))
+    }
+
+    /// Drops and creates the database again when the service is built
+    pub fn with_reset_db(mut self, reset_db: bool) -> Self {
+        self.reset_db = reset_db;
+        self
+    }
+
+    pub fn with_tenant_domain(mut self, tenant_domain: &str) -> Self {
+        self.tenant_domain = Some(tenant_domain.to_string());
+        self
+    }
+
+    pub fn with_issuer(mut self, issuer: &str) -> Self {
+        self.issuer = Some(issuer.to_string());
+        self
+    }
+
+    pub fn with_local_relying_party(mut self, local_relying_party: LocalRelyingPartyConfig) -> Self {
+        self.local_relying_party = Some(local_relying_party);
+        self
+    }
+
+    pub fn with_session_store(mut self, session_store: SessionStoreConfig) -> Self {
+        self.session_store = session_store;
+        self
+    }
+
+    pub fn with_session_binding(mut self, session_binding: SessionBindingConfig) -> Self {
+        self.session_binding = Some(session_binding);
+        self
+    }
+
+    /// How long after confirming their password users can perform sensitive operations
+    pub fn with_reauthentication_window_seconds(mut self, seconds: i64) -> Self {
+        self.reauthentication_window_seconds = Some(seconds);
+        self
+    }
+
+    /// How long usernames given up in a rename can't be claimed by other users
+    pub fn with_username_quarantine_days(mut self, days: i64) -> Self {
+        self.username_quarantine_days = Some(days);
+        self
+    }
+
+    /// Date after which the deprecated unversioned paths might be removed, announced to clients
+    pub fn with_unversioned_routes_sunset(mut self, sunset: NaiveDate) -> Self {
+        self.unversioned_routes_sunset = Some(sunset);
+        self
+    }
+}
+
+impl SessionBindingConfig {
+    pub fn new(ipv4_prefix: u8, ipv6_prefix: u8) -> Self {
+        Self {
+            ipv4_prefix,
+            ipv6_prefix,
+            trust_forwarded_headers: false,
+            trusted_proxies: default_trusted_proxies(),
+            levels: HashMap::new()
+        }
+    }
+
+    /// Takes the client IP from the X-Forwarded-For header, only for services behind a proxy
+    /// that sets it
+    pub fn with_trust_forwarded_headers(mut self, trust_forwarded_headers: bool) -> Self {
+        self.trust_forwarded_headers = trust_forwarded_headers;
+        self
+    }
+
+    /// How many proxies in front of the service append to the X-Forwarded-For header, one by
+    /// default. The client IP is the one appended by the farthest of them
+    pub fn with_trusted_proxies(mut self, trusted_proxies: u8) -> Self {
+        self.trusted_proxies = trusted_proxies;
+        self
+    }
+
+    pub fn with_action(mut self, level: Level, action: BindingAction) -> Self {
+        self.levels.insert(level, action);
+        self
+    }
+
+    pub fn get_ipv4_prefix(&self) -> u8 {
+        self.ipv4_prefix
+    }
+
+    pub fn get_ipv6_prefix(&self) -> u8 {
+        self.ipv6_prefix
+    }
+
+    pub fn trust_forwarded_headers(&self) -> bool {
+        self.trust_forwarded_headers
+    }
+
+    pub fn get_trusted_proxies(&self) -> u8 {
+        self.trusted_proxies
+    }
+
+    /// Action for mismatches of users with the level received. Levels not configured are allowed
+    pub fn get_action(&self, level: &Level) -> BindingAction {
+        self.levels.get(level).copied().unwrap_or_default()
+    }
+}
+
+impl LocalRelyingPartyConfig {
+    pub fn new(client_id: &str, client_secret: Option<&str>, redirect_uri: &str) -> Self {
+        Self {
+            client_id: client_id.to_string(),
+            client_secret: client_secret.map(str::to_string),
+            redirect_uri: redirect_uri.to_string()
+        }
+    }
+
+    pub fn get_client_id(&self) -> &str {
+        self.client_id.as_str()
+    }
+
+    pub fn get_client_secret(&self) -> Option<&str> {
+        self.client_secret.as_deref()
+    }
+
+    pub fn get_redirect_uri(&self) -> &str {
+        self.redirect_uri.as_str()
+    }
 }
\ No newline at end of file
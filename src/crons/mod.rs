@@ -11,8 +11,10 @@ lazy_static!{
 }
 
 pub mod sessions;
+pub mod session_events;
 
 pub async fn run_crons(stopper: Receiver<StopMethod>) {
     tokio::spawn(sessions::close_expired_sessions(stopper.resubscribe()));
+    tokio::spawn(session_events::apply_session_events(stopper.resubscribe()));
 }
 
@@ -0,0 +1,27 @@
+use std::time::Duration;
+use tokio::sync::broadcast::Receiver;
+use crate::api::StopMethod;
+use crate::modules::users::session_events::SessionEvents;
+
+/// Often enough for the changes made in other replicas to be applied here within a second
+const POLL_INTERVAL_MILLIS: u64 = 500;
+
+//  Cron to apply the session events published by other replicas
+pub(super) async fn apply_session_events(mut stopper: Receiver<StopMethod>) {
+
+    let cron_loop = async {
+        loop {
+            if let Err(e) = SessionEvents::instance().apply_received().await {
+                eprintln!("Error applying session events: {}", e);
+            }
+
+            tokio::time::sleep(Duration::from_millis(POLL_INTERVAL_MILLIS)).await;
+        }
+    };
+
+    //  Events are only applied to the runtime sessions, nothing is left half written if stopped
+    tokio::select!{
+        _ = cron_loop => {},
+        _ = stopper.recv() => {}
+    }
+}
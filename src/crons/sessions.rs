@@ -1,14 +1,9 @@
 use std::time::Duration;
-use error_mapper::{map_to_new_error, TheResult};
+use error_mapper::TheResult;
 use lazy_static::lazy_static;
-use mysql_async::prelude::Queryable;
 use tokio::sync::broadcast::Receiver;
 use crate::api::StopMethod;
-use crate::database::db_conn::get_conn;
 use crate::{modules};
-use crate::modules::users::user::User;
-use crate::modules::users::users_sessions::{SessionData, SessionStatus};
-use crate::modules::users::UsersSessions;
 
 //  Cron to close expired sessions
 pub(super) async fn close_expired_sessions(mut stopper: Receiver<StopMethod>) {
@@ -59,35 +54,11 @@ pub(super) async fn close_expired_sessions(mut stopper: Receiver<StopMethod>) {
 
 async fn validate_db_sessions_status() -> TheResult<()> {
 
-    let conn = &mut get_conn().await?;
+    //  Closes the expired sessions and makes sure the runtime sessions match the database
+    modules::users::users_sessions::reconcile_runtime_sessions().await?;
 
-    let sessions = conn.query::<SessionData, _>(
-        "SELECT users_ID, creation, expiry FROM users_sessions"
-    ).await.map_err(|e| map_to_new_error!(e))?;
-
-    for session in sessions {
-        let user;
-        if let Some(user_fetched) = User::select_by_id(session.get_user_id()).await? {
-            user = user_fetched
-        } else {
-            //  If no user was found, need to make sure he's logged out and no active sessions are prsent in db 
-            modules::users::users_sessions::delete_logins_session(session.get_user_id()).await?;
-            UsersSessions::instance().delete_user_entry(session.get_user_id()).await;
-            continue;
-        };
-        match session.get_session_status() {
-            SessionStatus::Expired | SessionStatus::SessionError => {
-                //  If session is expired or has any error, delete it from DB and logout from runtime
-                modules::users::users_sessions::delete_logins_session(session.get_user_id()).await?;
-                UsersSessions::instance().logout_user(&user).await;
-            },
-            SessionStatus::Active => {
-                //  If active, do nothing, session is ok
-                //  Ensure session is active in runtime
-                UsersSessions::instance().login_user(&user).await;
-            }
-        }
-    }
+    //  Events older than the last reconciliation are no longer needed by any replica
+    modules::users::session_events::SessionEvents::instance().prune().await?;
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,12 +1,48 @@
-
 use error_mapper::{map_to_new_error, TheResult};
-use mysql_async::{Conn};
+use lazy_static::lazy_static;
+use mysql_async::{Conn, Pool};
+use tokio::sync::RwLock;
 use crate::config::environment::EnvironmentConfig;
 
+lazy_static!{
+    static ref STORE: RwLock<Option<Store>> = RwLock::new(None);
+}
+
+/// ## Description
+/// MySQL database holding the users, their sessions and the rest of the tables. Apps embedding
+/// the service can hand over a pool of their own, otherwise one is created from the configured
+/// database URL the first time a connection is needed
+#[derive(Clone)]
+pub struct Store {
+    pool: Pool
+}
+
+impl Store {
+    pub fn new(db_url: &str) -> Self {
+        Self {
+            pool: Pool::new(db_url)
+        }
+    }
+
+    pub fn from_pool(pool: Pool) -> Self {
+        Self { pool }
+    }
+}
+
+pub(crate) async fn set_store(store: Store) {
+    *STORE.write().await = Some(store);
+}
+
 pub async fn get_conn() -> TheResult<Conn> {
-    let pool = mysql_async::Pool::new(
-        EnvironmentConfig::instance().get_db_url().await.as_str()
-    );
+    let pool = STORE.read().await.as_ref().map(|store| store.pool.clone());
+    let pool = match pool {
+        Some(pool) => pool,
+        None => {
+            let store = Store::new(EnvironmentConfig::instance().get_db_url().await.as_str());
+            set_store(store.clone()).await;
+            store.pool
+        }
+    };
 
     pool.get_conn().await.map_err(|e| map_to_new_error!(e))
 }
@@ -1,12 +1,30 @@
 
 use error_mapper::{map_to_new_error, SystemErrorCodes, TheError, TheResult};
 use mysql_async::prelude::Queryable;
+use mysql_async::Value;
 use tokio::io::AsyncReadExt;
 
 pub mod db_conn;
 
 pub const DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
 
+/// ## Description
+/// Reads a DATETIME column from either protocol. Plain queries return it as text in
+/// DATETIME_FORMAT, statements executed with parameters return it as a date value
+pub fn value_to_naive_datetime(value: &Value) -> Option<chrono::NaiveDateTime> {
+    match value {
+        Value::Bytes(bytes) => chrono::NaiveDateTime::parse_from_str(
+            std::str::from_utf8(bytes).ok()?,
+            DATETIME_FORMAT
+        ).ok(),
+        Value::Date(year, month, day, hour, minute, second, micros) => {
+            chrono::NaiveDate::from_ymd_opt(*year as i32, *month as u32, *day as u32)?
+                .and_hms_micro_opt(*hour as u32, *minute as u32, *second as u32, *micros)
+        },
+        _ => None
+    }
+}
+
 pub async fn load_schema_reset() -> TheResult<String> {
 
     let mut file = tokio::fs::File::options()
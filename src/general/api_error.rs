@@ -0,0 +1,107 @@
+use std::fmt::{Display, Formatter};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use error_mapper::{SystemErrorCodes, TheError};
+use crate::general::problem::{ErrorCode, Problem};
+
+/// Header with the correlation id of internal errors, also sent in the problem
+pub const CORRELATION_ID_HEADER: &str = "x-correlation-id";
+
+/// ## Description
+/// Error returned by the handlers, which can use `?` on any `TheResult`. Problems the handler
+/// knows about are responded as they are. Errors from the internals are mapped to a problem by
+/// their type, and logged along with a correlation id sent to the client, so the details only
+/// reach the logs
+#[derive(Debug)]
+pub enum ApiError {
+    Problem(Problem),
+    Internal(TheError)
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, detail: &str) -> Self {
+        ApiError::Problem(Problem::new(code).with_detail(detail))
+    }
+}
+
+impl From<Problem> for ApiError {
+    fn from(problem: Problem) -> Self {
+        ApiError::Problem(problem)
+    }
+}
+
+impl From<TheError> for ApiError {
+    fn from(error: TheError) -> Self {
+        ApiError::Internal(error)
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::Problem(problem) => write!(f, "{}: {}", problem.get_code(), problem.get_detail().unwrap_or_default()),
+            ApiError::Internal(error) => write!(f, "{}", error)
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::Problem(problem) => problem.get_status_code(),
+            ApiError::Internal(error) => internal_error_code(error.get_type()).status()
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            ApiError::Problem(problem) => problem.clone().into_response(),
+            ApiError::Internal(error) => {
+                let correlation_id = format!("{:016x}", rand::random::<u64>());
+
+                //  TODO remove when logger is implemented
+                eprintln!("Internal error {}: {}", correlation_id, error);
+
+                let code = internal_error_code(error.get_type());
+                let mut response = Problem::new(code)
+                    .with_detail(internal_error_detail(code))
+                    .with_extension("correlation_id", correlation_id.as_str())
+                    .into_response();
+                if let Ok(value) = HeaderValue::from_str(correlation_id.as_str()) {
+                    response.headers_mut().insert(HeaderName::from_static(CORRELATION_ID_HEADER), value);
+                }
+                response
+            }
+        }
+    }
+}
+
+/// Problem responded for the type of an internal error. Anything not listed is an internal error
+fn internal_error_code(error_type: &SystemErrorCodes) -> ErrorCode {
+    match error_type {
+        SystemErrorCodes::NotFound => ErrorCode::ResourceNotFound,
+        //  The database or another dependency is unreachable or overloaded, retrying might work
+        SystemErrorCodes::DbConnectionError
+        | SystemErrorCodes::DbConnectionTimedOut
+        | SystemErrorCodes::ConnectionClosed
+        | SystemErrorCodes::ConnectionError
+        | SystemErrorCodes::ConnectionRefused
+        | SystemErrorCodes::HostUnreachable
+        | SystemErrorCodes::NotConnected
+        | SystemErrorCodes::PoolDisconnected
+        | SystemErrorCodes::Deadlock
+        | SystemErrorCodes::ResourceBusy
+        | SystemErrorCodes::SlowConnection
+        | SystemErrorCodes::TimedOut => ErrorCode::ServiceUnavailable,
+        _ => ErrorCode::Internal
+    }
+}
+
+fn internal_error_detail(code: ErrorCode) -> &'static str {
+    match code {
+        ErrorCode::ResourceNotFound => "The resource requested was not found",
+        ErrorCode::ServiceUnavailable => "The service is temporarily unavailable, try again later",
+        _ => "The request could not be completed"
+    }
+}
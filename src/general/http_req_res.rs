@@ -1,14 +1,36 @@
 use actix_web::http::StatusCode;
-use actix_web::HttpResponse;
+use actix_web::{HttpRequest, HttpResponse};
 use error_mapper::{map_to_new_error, TheResult};
 use serde::Serialize;
+use crate::general::problem::{ErrorCode, Problem};
+
+/// Envelope of the successful responses, keeping the data apart from any metadata added later
+#[derive(Serialize)]
+struct Envelope<'a, T: Serialize> {
+    data: &'a T
+}
 
 pub fn json_response(status_code: StatusCode, msg: String) -> HttpResponse {
     HttpResponse::build(status_code).content_type("application/json").body(msg.clone())
 }
 
-pub fn plain_text_response(status_code: StatusCode, text: String) -> HttpResponse {
-    HttpResponse::build(status_code).body(text)
+/// Error response with the problem details of the code received, see `Problem`
+pub fn problem_response(code: ErrorCode, detail: &str) -> HttpResponse {
+    Problem::new(code).with_detail(detail).into_response()
+}
+
+/// Successful response with the data received in the `data` member of a JSON object
+pub fn data_response<T: Serialize>(status_code: StatusCode, data: &T) -> HttpResponse {
+    match serialize_into_json(&Envelope { data }) {
+        Ok(body) => json_response(status_code, body),
+        Err(_) => problem_response(ErrorCode::Internal, "Error serializing response")
+    }
+}
+
+/// Path of the scope the request was routed to, like `/v1/oauth`. Cookies are scoped to it so
+/// they are only sent back to the same version of the endpoints
+pub fn scope_path(request: &HttpRequest) -> String {
+    request.path().rsplit_once('/').map(|(scope, _)| scope.to_string()).unwrap_or_default()
 }
 
 pub fn serialize_into_json<T: Serialize>(struct_to_serialize: &T) -> TheResult<String> {
@@ -31,8 +31,8 @@ macro_rules! row_to_data {
 #[macro_export]
 macro_rules! row_to_naive_datetime {
     ($row:ident, $field:expr, $table:expr) => {
-        if let Some(string) = $row.get::<String, _>($field) {
-            if let Ok(date) = chrono::NaiveDateTime::parse_from_str(string.as_str(), $crate::database::DATETIME_FORMAT) {
+        if let Some(value) = $row.get::<mysql_async::Value, _>($field) {
+            if let Some(date) = $crate::database::value_to_naive_datetime(&value) {
                 date
             } else {
                 panic!("Datetime incorrectly formatted in database for table {} and column {}", $table, $field)
@@ -43,6 +43,32 @@ macro_rules! row_to_naive_datetime {
     };
 }
 
+/// ## Description
+/// Macro that extracts a nullable DateTime value in Option<NaiveDateTime> format from a Row element
+///
+/// ### Parameters
+/// - row: row element in FromRow implementation
+/// - field: column name in database table
+/// - table: table name in database
+#[macro_export]
+macro_rules! row_to_optional_naive_datetime {
+    ($row:ident, $field:expr, $table:expr) => {
+        match $row.get::<mysql_async::Value, _>($field) {
+            Some(mysql_async::Value::NULL) => None,
+            Some(value) => {
+                if let Some(date) = $crate::database::value_to_naive_datetime(&value) {
+                    Some(date)
+                } else {
+                    panic!("Datetime incorrectly formatted in database for table {} and column {}", $table, $field)
+                }
+            },
+            None => {
+                panic!("Unknown column {} in table {}", $field.to_string(), $table);
+            }
+        }
+    };
+}
+
 /// ## Description
 /// Macro that extracts an Enum value from a Row element
 ///
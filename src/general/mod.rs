@@ -3,3 +3,5 @@
 pub mod types;
 pub mod macros;
 pub mod http_req_res;
+pub mod problem;
+pub mod api_error;
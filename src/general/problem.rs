@@ -0,0 +1,257 @@
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use utoipa::ToSchema;
+
+/// Content type of the error responses, as RFC 7807 defines it
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// ## Description
+/// Machine-readable code of every error the service responds with. Each code has a fixed status
+/// and title, and clients should rely on the code instead of the detail, which is meant for people
+/// and might change
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    //  Request
+    InvalidRequest,
+    ValidationFailed,
+    //  Authentication and authorization
+    MissingCredentials,
+    InvalidCredentials,
+    InvalidSession,
+    InvalidToken,
+    SessionExpired,
+    LoginRequired,
+    ClientMismatch,
+    ReauthenticationRequired,
+    SessionRequired,
+    InsufficientScope,
+    Forbidden,
+    LevelNotAllowed,
+    //  Users
+    UserNotFound,
+    UsernameTaken,
+    IncorrectPassword,
+    //  Groups
+    GroupNotFound,
+    GroupNameTaken,
+    //  Organizations
+    OrganizationNotSpecified,
+    OrganizationNotFound,
+    SlugTaken,
+    AlreadyMember,
+    NotMember,
+    //  Service accounts, API keys and personal access tokens
+    InvalidServiceAccount,
+    ApiKeyNotFound,
+    PersonalTokenNotFound,
+    ExpirationTooLong,
+    //  OAuth clients
+    InvalidClient,
+    InvalidRedirectUri,
+    //  Local relying party
+    RelyingPartyNotConfigured,
+    InvalidCallback,
+    InvalidIdToken,
+    ProviderError,
+    //  Anything failing on the service side
+    ResourceNotFound,
+    ServiceUnavailable,
+    Internal
+}
+
+impl ErrorCode {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidRequest => "request.invalid",
+            ErrorCode::ValidationFailed => "request.validation_failed",
+            ErrorCode::MissingCredentials => "auth.missing_credentials",
+            ErrorCode::InvalidCredentials => "auth.invalid_credentials",
+            ErrorCode::InvalidSession => "auth.invalid_session",
+            ErrorCode::InvalidToken => "auth.invalid_token",
+            ErrorCode::SessionExpired => "auth.session_expired",
+            ErrorCode::LoginRequired => "auth.login_required",
+            ErrorCode::ClientMismatch => "auth.client_mismatch",
+            ErrorCode::ReauthenticationRequired => "auth.reauthentication_required",
+            ErrorCode::SessionRequired => "auth.session_required",
+            ErrorCode::InsufficientScope => "auth.insufficient_scope",
+            ErrorCode::Forbidden => "auth.forbidden",
+            ErrorCode::LevelNotAllowed => "auth.level_not_allowed",
+            ErrorCode::UserNotFound => "users.not_found",
+            ErrorCode::UsernameTaken => "users.username_taken",
+            ErrorCode::IncorrectPassword => "users.incorrect_password",
+            ErrorCode::GroupNotFound => "groups.not_found",
+            ErrorCode::GroupNameTaken => "groups.name_taken",
+            ErrorCode::OrganizationNotSpecified => "organizations.not_specified",
+            ErrorCode::OrganizationNotFound => "organizations.not_found",
+            ErrorCode::SlugTaken => "organizations.slug_taken",
+            ErrorCode::AlreadyMember => "organizations.already_member",
+            ErrorCode::NotMember => "organizations.not_member",
+            ErrorCode::InvalidServiceAccount => "service_accounts.invalid",
+            ErrorCode::ApiKeyNotFound => "api_keys.not_found",
+            ErrorCode::PersonalTokenNotFound => "personal_tokens.not_found",
+            ErrorCode::ExpirationTooLong => "personal_tokens.expiration_too_long",
+            ErrorCode::InvalidClient => "oauth.invalid_client",
+            ErrorCode::InvalidRedirectUri => "oauth.invalid_redirect_uri",
+            ErrorCode::RelyingPartyNotConfigured => "relying_party.not_configured",
+            ErrorCode::InvalidCallback => "relying_party.invalid_callback",
+            ErrorCode::InvalidIdToken => "relying_party.invalid_id_token",
+            ErrorCode::ProviderError => "relying_party.provider_error",
+            ErrorCode::ResourceNotFound => "server.not_found",
+            ErrorCode::ServiceUnavailable => "server.unavailable",
+            ErrorCode::Internal => "server.internal_error"
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidRequest
+            | ErrorCode::ValidationFailed
+            | ErrorCode::UsernameTaken
+            | ErrorCode::IncorrectPassword
+            | ErrorCode::GroupNameTaken
+            | ErrorCode::OrganizationNotSpecified
+            | ErrorCode::SlugTaken
+            | ErrorCode::AlreadyMember
+            | ErrorCode::NotMember
+            | ErrorCode::InvalidServiceAccount
+            | ErrorCode::ExpirationTooLong
+            | ErrorCode::InvalidClient
+            | ErrorCode::InvalidRedirectUri
+            | ErrorCode::InvalidCallback => StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidCredentials
+            | ErrorCode::InvalidSession
+            | ErrorCode::InvalidToken
+            | ErrorCode::SessionExpired
+            | ErrorCode::LoginRequired
+            | ErrorCode::ClientMismatch
+            | ErrorCode::ReauthenticationRequired
+            | ErrorCode::InvalidIdToken => StatusCode::UNAUTHORIZED,
+            ErrorCode::MissingCredentials
+            | ErrorCode::SessionRequired
+            | ErrorCode::InsufficientScope
+            | ErrorCode::Forbidden
+            | ErrorCode::LevelNotAllowed => StatusCode::FORBIDDEN,
+            ErrorCode::UserNotFound
+            | ErrorCode::GroupNotFound
+            | ErrorCode::OrganizationNotFound
+            | ErrorCode::ApiKeyNotFound
+            | ErrorCode::PersonalTokenNotFound
+            | ErrorCode::RelyingPartyNotConfigured
+            | ErrorCode::ResourceNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::ProviderError => StatusCode::BAD_GATEWAY,
+            ErrorCode::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidRequest => "Invalid request",
+            ErrorCode::ValidationFailed => "Validation failed",
+            ErrorCode::MissingCredentials => "Missing credentials",
+            ErrorCode::InvalidCredentials => "Invalid credentials",
+            ErrorCode::InvalidSession => "Invalid session",
+            ErrorCode::InvalidToken => "Invalid token",
+            ErrorCode::SessionExpired => "Session expired",
+            ErrorCode::LoginRequired => "Login required",
+            ErrorCode::ClientMismatch => "Session used from a different client",
+            ErrorCode::ReauthenticationRequired => "Re-authentication required",
+            ErrorCode::SessionRequired => "Session required",
+            ErrorCode::InsufficientScope => "Insufficient scope",
+            ErrorCode::Forbidden => "Forbidden",
+            ErrorCode::LevelNotAllowed => "Level not allowed",
+            ErrorCode::UserNotFound => "User not found",
+            ErrorCode::UsernameTaken => "Username taken",
+            ErrorCode::IncorrectPassword => "Incorrect password",
+            ErrorCode::GroupNotFound => "Group not found",
+            ErrorCode::GroupNameTaken => "Group name taken",
+            ErrorCode::OrganizationNotSpecified => "Organization not specified",
+            ErrorCode::OrganizationNotFound => "Organization not found",
+            ErrorCode::SlugTaken => "Organization slug taken",
+            ErrorCode::AlreadyMember => "Already a member",
+            ErrorCode::NotMember => "Not a member",
+            ErrorCode::InvalidServiceAccount => "Invalid service account",
+            ErrorCode::ApiKeyNotFound => "API key not found",
+            ErrorCode::PersonalTokenNotFound => "Personal access token not found",
+            ErrorCode::ExpirationTooLong => "Expiration too long",
+            ErrorCode::InvalidClient => "Invalid OAuth client",
+            ErrorCode::InvalidRedirectUri => "Invalid redirect URI",
+            ErrorCode::RelyingPartyNotConfigured => "Relying party not configured",
+            ErrorCode::InvalidCallback => "Invalid callback",
+            ErrorCode::InvalidIdToken => "Invalid ID token",
+            ErrorCode::ProviderError => "Provider error",
+            ErrorCode::ResourceNotFound => "Not found",
+            ErrorCode::ServiceUnavailable => "Service unavailable",
+            ErrorCode::Internal => "Internal error"
+        }
+    }
+}
+
+/// ## Description
+/// Error response following RFC 7807, problem details for HTTP APIs. The type is a URN built from
+/// the code, which is repeated as an extension member for clients that don't parse URNs. Other
+/// extension members carry whatever the client needs to handle the error
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    problem_type: String,
+    title: &'static str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    code: &'static str,
+    #[serde(flatten)]
+    #[schema(ignore)]
+    extensions: Map<String, Value>
+}
+
+impl Problem {
+    pub fn new(code: ErrorCode) -> Self {
+        Self {
+            problem_type: format!("urn:uta:problem:{}", code.code()),
+            title: code.title(),
+            status: code.status().as_u16(),
+            detail: None,
+            code: code.code(),
+            extensions: Map::new()
+        }
+    }
+
+    pub fn with_detail(mut self, detail: &str) -> Self {
+        self.detail = Some(detail.to_string());
+        self
+    }
+
+    /// Adds an extension member. Names of the standard members are ignored
+    pub fn with_extension(mut self, name: &str, value: impl Into<Value>) -> Self {
+        if !["type", "title", "status", "detail", "code"].contains(&name) {
+            self.extensions.insert(name.to_string(), value.into());
+        }
+        self
+    }
+
+    pub fn get_status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    pub fn get_code(&self) -> &'static str {
+        self.code
+    }
+
+    pub fn get_detail(&self) -> Option<&str> {
+        self.detail.as_deref()
+    }
+
+    pub fn to_json(&self) -> String {
+        //  Only strings and JSON values are serialized, this can't fail
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    pub fn into_response(self) -> HttpResponse {
+        HttpResponse::build(self.get_status_code())
+            .content_type(PROBLEM_CONTENT_TYPE)
+            .body(self.to_json())
+    }
+}
@@ -1,3 +1,9 @@
-
 pub type UsersIdType = u32;
-pub type UserLevelType = u8;
\ No newline at end of file
+pub type UserLevelType = u8;
+pub type OrganizationsIdType = u32;
+pub type GroupsIdType = u32;
+pub type ApiKeysIdType = u32;
+pub type PersonalTokensIdType = u32;
+pub type OAuthClientsIdType = u32;
+pub type OAuthCodesIdType = u32;
+pub type OAuthTokensIdType = u32;
\ No newline at end of file
@@ -0,0 +1,18 @@
+use crate::config::shutdown::Shutdown;
+
+pub use api::StopMethod;
+pub use service::{AuthService, AuthServiceBuilder};
+
+pub mod database;
+pub mod config;
+mod web_local;
+pub mod modules;
+pub mod api;
+pub mod general;
+pub mod auth;
+mod crons;
+mod service;
+
+pub async fn is_shutting_down() -> bool {
+    Shutdown::instance().is_shutting_down().await
+}
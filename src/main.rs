@@ -1,21 +1,5 @@
-
-use error_mapper::{TheResult};
-use openssl::ssl::SslAcceptorBuilder;
-use tokio::sync::broadcast::{Receiver, Sender};
-use crate::api::StopMethod;
-use crate::config::environment::EnvironmentConfig;
-use crate::config::shutdown::Shutdown;
-use crate::modules::users::user::User;
-use crate::modules::users::UsersSessions;
-
-mod database;
-mod config;
-mod web_local;
-mod modules;
-mod api;
-mod general;
-mod auth;
-mod crons;
+use error_mapper::TheResult;
+use token_authentication_public::AuthService;
 
 #[tokio::main]
 async fn main() -> TheResult<()> {
@@ -32,50 +16,11 @@ async fn main() -> TheResult<()> {
     //   -Hot reload for the config.json file. Easy implementation, just need to do it lol
 
 
-    let (builder, (sender, receiver)) = setup_service().await?;
-
-    setup_initial_env(receiver.resubscribe()).await?;
+    let auth_service = AuthService::builder().build().await?;
 
-    if let Err(e) = api::start_api(
-        (builder, (sender, receiver))
-    ).await {
+    if let Err(e) = auth_service.run().await {
         panic!("Failed to start api services: {}", e);
     }
 
     Ok(())
 }
-
-pub async fn is_shutting_down() -> bool {
-    Shutdown::instance().is_shutting_down().await
-}
-
-async fn setup_service() -> TheResult<(SslAcceptorBuilder, (Sender<StopMethod>, Receiver<StopMethod>))> {
-
-    let builder = config::openssl::create_openssl_builder()?;
-
-    let (sender, receiver) = tokio::sync::broadcast::channel::<StopMethod>(4);
-
-    Shutdown::instance().set_shutdown_state_to_false().await;
-
-    Ok((builder, (sender, receiver)))
-}
-
-async fn setup_initial_env(stopper: Receiver<StopMethod>) -> TheResult<()> {
-
-    if EnvironmentConfig::instance().reset_db().await {
-        if let Err(e) = database::reset_db().await {
-            println!("There was an error resetting database: {}", e);
-        };
-    }
-
-    modules::users::functions::create_default_super_user().await?;
-
-    let users = User::select_all().await?;
-
-    UsersSessions::instance().register_users_in_runtime(users.as_slice()).await?;
-
-    //  Start session cron(s)
-    tokio::spawn(crons::run_crons(stopper));
-
-    Ok(())
-}
\ No newline at end of file
@@ -0,0 +1,282 @@
+use std::ops::Add;
+use chrono::NaiveDateTime;
+use error_mapper::{map_to_new_error, TheResult};
+use mysql_async::prelude::{FromRow, Queryable};
+use mysql_async::{FromRowError, Row};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::{auth, database, row_to_data, row_to_naive_datetime, row_to_optional_naive_datetime};
+use crate::database::db_conn::get_conn;
+use crate::general::types::{ApiKeysIdType, UsersIdType};
+use crate::modules::users::user::Level;
+
+/// Every API key starts with this, so they're easy to tell apart from session tokens
+const API_KEY_HEADER: &str = "uta";
+
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct ApiKey {
+    id: ApiKeysIdType,
+    #[serde(skip_serializing)]
+    users_id: UsersIdType,
+    name: String,
+    prefix: String,
+    #[serde(skip_serializing)]
+    hashed_key: String,
+    scopes: Vec<ApiKeyScope>,
+    created_at: NaiveDateTime,
+    expires_at: Option<NaiveDateTime>,
+    last_used_at: Option<NaiveDateTime>,
+    revoked_at: Option<NaiveDateTime>
+}
+
+/// ## Description
+/// Scopes granted to an API key. A key can never act with a level above the highest of its
+/// scopes, regardless of the level of the service account that owns it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+pub enum ApiKeyScope {
+    #[serde(rename = "read")]
+    Read,
+    #[serde(rename = "write")]
+    Write,
+    #[serde(rename = "admin")]
+    Admin
+}
+
+impl ApiKey {
+
+    /// ## Description
+    /// Creates an API key for the user and returns it along with the full key. The full key is
+    /// only available here, only its hash is stored
+    pub async fn create_api_key(
+        user_id: &UsersIdType,
+        name: &str,
+        scopes: &[ApiKeyScope],
+        expires_in_days: Option<u32>
+    ) -> TheResult<(Self, String)> {
+
+        let now = chrono::Utc::now().naive_utc();
+
+        let (prefix, key) = auth::crypt::generate_prefixed_key(API_KEY_HEADER)?;
+
+        let api_key = Self {
+            id: Self::select_last_id().await? + 1,
+            users_id: *user_id,
+            name: name.to_string(),
+            prefix,
+            hashed_key: auth::crypt::credential_digest(key.as_str()),
+            scopes: scopes.to_vec(),
+            created_at: now,
+            expires_at: expires_in_days.map(|days| now.add(chrono::Duration::days(days as i64))),
+            last_used_at: None,
+            revoked_at: None
+        };
+
+        api_key.insert().await?;
+
+        Ok((api_key, key))
+    }
+
+    pub async fn select_by_id(api_key_id: &ApiKeysIdType) -> TheResult<Option<Self>> {
+
+        let conn = &mut get_conn().await?;
+
+        let api_key = conn.query_first::<Self, _>(
+            format!(
+                "SELECT * FROM api_keys WHERE ID = {}",
+                api_key_id
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(api_key)
+    }
+
+    pub async fn select_by_prefix(prefix: &str) -> TheResult<Option<Self>> {
+
+        let conn = &mut get_conn().await?;
+
+        //  Prefixes come from request bodies, they're sent as parameters and never formatted in
+        let api_key = conn.exec_first::<Self, _, _>(
+            "SELECT * FROM api_keys WHERE prefix = ?",
+            (prefix,)
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(api_key)
+    }
+
+    pub async fn select_by_user(user_id: &UsersIdType) -> TheResult<Vec<Self>> {
+
+        let conn = &mut get_conn().await?;
+
+        let api_keys = conn.query::<Self, _>(
+            format!(
+                "SELECT * FROM api_keys WHERE users_ID = {}",
+                user_id
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(api_keys)
+    }
+
+    async fn select_last_id() -> TheResult<ApiKeysIdType> {
+
+        let conn = &mut get_conn().await?;
+
+        let id = conn.query_first::<Option<ApiKeysIdType>, _>(
+            "SELECT MAX(ID) FROM api_keys LIMIT 1"
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        if let Some(Some(api_key_id)) = id {
+            return Ok(api_key_id)
+        }
+
+        Ok(ApiKeysIdType::default())
+    }
+
+    async fn insert(&self) -> TheResult<()> {
+
+        let conn = &mut get_conn().await?;
+
+        let expires_at = match self.expires_at {
+            Some(expires_at) => format!("'{}'", expires_at.format(database::DATETIME_FORMAT)),
+            None => "NULL".to_string()
+        };
+
+        conn.query_drop(
+            format!(
+                "INSERT INTO api_keys (ID, users_ID, name, prefix, hashed_key, scopes, created_at, expires_at) \
+                VALUES ({}, {}, '{}', '{}', '{}', '{}', '{}', {})",
+                self.id,
+                self.users_id,
+                self.name.as_str(),
+                self.prefix.as_str(),
+                self.hashed_key.as_str(),
+                scopes_to_string(self.scopes.as_slice()),
+                self.created_at.format(database::DATETIME_FORMAT),
+                expires_at
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+
+    pub async fn revoke(&self) -> TheResult<()> {
+
+        let conn = &mut get_conn().await?;
+
+        conn.query_drop(
+            format!(
+                "UPDATE api_keys SET revoked_at = '{}' WHERE ID = {}",
+                chrono::Utc::now().format(database::DATETIME_FORMAT),
+                self.id
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+
+    pub async fn update_last_used(&self) -> TheResult<()> {
+
+        let conn = &mut get_conn().await?;
+
+        conn.query_drop(
+            format!(
+                "UPDATE api_keys SET last_used_at = '{}' WHERE ID = {}",
+                chrono::Utc::now().format(database::DATETIME_FORMAT),
+                self.id
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+
+    /// Checks the full key received against the stored hash, and that the key can still be used
+    pub fn validate_key(&self, key: &str) -> bool {
+        if self.revoked_at.is_some() {
+            return false
+        }
+        if let Some(expires_at) = self.expires_at {
+            if expires_at < chrono::Utc::now().naive_utc() {
+                return false
+            }
+        }
+        auth::crypt::credential_matches(&self.hashed_key, key)
+    }
+
+    /// Highest level the key can act with, given by the highest of its scopes
+    pub fn get_scope_level(&self) -> Level {
+        self.scopes.iter()
+            .map(|scope| scope.max_level())
+            .max()
+            .unwrap_or_default()
+    }
+
+    pub fn get_id(&self) -> &ApiKeysIdType {
+        &self.id
+    }
+
+    pub fn get_user_id(&self) -> &UsersIdType {
+        &self.users_id
+    }
+
+    pub fn get_prefix(&self) -> &str {
+        self.prefix.as_str()
+    }
+}
+
+impl ApiKeyScope {
+    pub fn max_level(&self) -> Level {
+        match self {
+            ApiKeyScope::Read => Level::View,
+            ApiKeyScope::Write => Level::Medium,
+            ApiKeyScope::Admin => Level::High
+        }
+    }
+}
+
+/// Extracts the prefix from a full API key, None if the key is not correctly formatted
+pub fn get_prefix_from_key(key: &str) -> Option<&str> {
+    auth::crypt::get_prefix_from_key(key, API_KEY_HEADER)
+}
+
+fn scopes_to_string(scopes: &[ApiKeyScope]) -> String {
+    scopes.iter()
+        .map(|scope| match scope {
+            ApiKeyScope::Read => "read",
+            ApiKeyScope::Write => "write",
+            ApiKeyScope::Admin => "admin"
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn scopes_from_string(scopes: &str) -> Vec<ApiKeyScope> {
+    scopes.split(',')
+        .filter_map(|scope| match scope {
+            "read" => Some(ApiKeyScope::Read),
+            "write" => Some(ApiKeyScope::Write),
+            "admin" => Some(ApiKeyScope::Admin),
+            _ => None
+        })
+        .collect()
+}
+
+impl FromRow for ApiKey {
+    fn from_row(row: Row) -> Self where Self: Sized {
+        Self {
+            id: row_to_data!(row, "ID", "api_keys", ApiKeysIdType),
+            users_id: row_to_data!(row, "users_ID", "api_keys", UsersIdType),
+            name: row_to_data!(row, "name", "api_keys", String),
+            prefix: row_to_data!(row, "prefix", "api_keys", String),
+            hashed_key: row_to_data!(row, "hashed_key", "api_keys", String),
+            scopes: scopes_from_string(row_to_data!(row, "scopes", "api_keys", String).as_str()),
+            created_at: row_to_naive_datetime!(row, "created_at", "api_keys"),
+            expires_at: row_to_optional_naive_datetime!(row, "expires_at", "api_keys"),
+            last_used_at: row_to_optional_naive_datetime!(row, "last_used_at", "api_keys"),
+            revoked_at: row_to_optional_naive_datetime!(row, "revoked_at", "api_keys")
+        }
+    }
+
+    fn from_row_opt(_: Row) -> Result<Self, FromRowError> where Self: Sized {
+        unimplemented!()
+    }
+}
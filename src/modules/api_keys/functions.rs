@@ -0,0 +1,34 @@
+use error_mapper::TheResult;
+use crate::modules::api_keys::api_key;
+use crate::modules::api_keys::api_key::ApiKey;
+use crate::modules::users::user::User;
+
+/// ## Description
+/// Authenticates a request with an API key. Returns the service account that owns the key, with
+/// its level capped by the key's scopes, or None if the key is invalid, revoked or expired
+pub async fn authenticate_api_key(key: &str) -> TheResult<Option<User>> {
+
+    let Some(prefix) = api_key::get_prefix_from_key(key) else {
+        return Ok(None)
+    };
+
+    let Some(api_key) = ApiKey::select_by_prefix(prefix).await? else {
+        return Ok(None)
+    };
+
+    if !api_key.validate_key(key) {
+        return Ok(None)
+    }
+
+    //  Only service accounts that were not deleted can use their keys
+    let mut user = match User::select_by_id(api_key.get_user_id()).await? {
+        Some(user) if user.is_service_account() => user,
+        _ => return Ok(None)
+    };
+
+    user.set_scope_level(api_key.get_scope_level());
+
+    api_key.update_last_used().await?;
+
+    Ok(Some(user))
+}
@@ -0,0 +1,276 @@
+
+use actix_web::{get, HttpMessage, HttpRequest, HttpResponse, post, put, web};
+use actix_web::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use crate::api::authentication::RequireScope;
+use crate::api::authenticator::Credential;
+use crate::{auth, general};
+use crate::auth::policy::{Action, Policy, PolicyRequest};
+use crate::general::api_error::ApiError;
+use crate::general::http_req_res::json_response;
+use crate::general::problem::ErrorCode;
+use crate::general::types::{ApiKeysIdType, UsersIdType};
+use crate::modules::api_keys::api_key::{ApiKey, ApiKeyScope};
+use crate::modules::users;
+use crate::modules::users::user::{Level, User};
+use crate::modules::personal_tokens::personal_token::TokenScope;
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct PostServiceAccount {
+    username: String,
+    email: String,
+    level: u8
+}
+
+#[derive(Serialize)]
+struct ServiceAccountCreated {
+    user_id: UsersIdType
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct PostApiKey {
+    #[schema(value_type = Option<u32>)]
+    service_account_id: Option<UsersIdType>,
+    service_account_username: Option<String>,
+    name: String,
+    scopes: Vec<ApiKeyScope>,
+    expires_in_days: Option<u32>
+}
+
+#[derive(Serialize)]
+struct ApiKeyCreated {
+    api_key_id: ApiKeysIdType,
+    prefix: String,
+    api_key: String
+}
+
+#[derive(Deserialize, Debug, Clone, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ServiceAccount {
+    #[param(value_type = Option<u32>)]
+    service_account_id: Option<UsersIdType>,
+    service_account_username: Option<String>
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct RevokeApiKey {
+    #[schema(value_type = Option<u32>)]
+    api_key_id: Option<ApiKeysIdType>,
+    prefix: Option<String>
+}
+
+/// ##  Endpoint create service account
+/// POST {UTAUrl}:{UTAPort}/internal/create_service_account (private)
+///
+/// #### Required Body
+/// - username: ans-20 max string
+/// - email: ans-50 max string
+/// - level: from 0 to 3 u8
+///
+/// ### Description
+/// Creates a service account, a user that can't log in with a password and authenticates with
+/// API keys instead. The level can be at most one level below the requesting user's
+#[utoipa::path(
+    tag = "API keys",
+    summary = "Creates a service account",
+    responses(
+        (status = 201, description = "Service account created")
+    )
+)]
+#[post("/create_service_account", wrap = "RequireScope::new(TokenScope::AdminUsers)")]
+async fn create_service_account(request: HttpRequest, body: web::Json<PostServiceAccount>) -> Result<HttpResponse, ApiError> {
+
+    let user = users::functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
+
+    let level: Level = body.level.into();
+    let request = PolicyRequest::new(Action::CreateUser, &user).await
+        .with_resource_target_level(level);
+    if !Policy::instance().evaluate(&request).await.is_allowed() {
+        return Err(
+            ApiError::new(
+                ErrorCode::LevelNotAllowed,
+                "User level must be at least one level below the requesting account's"
+            )
+        )
+    }
+
+    if !users::user::username_available(body.username.as_str(), None).await? {
+        return Err(ApiError::new(ErrorCode::UsernameTaken, "Username not available"))
+    }
+
+    let user_id = User::create_service_account(&body.username, &body.email, &level).await?;
+
+    Ok(json_response(StatusCode::CREATED, general::http_req_res::serialize_into_json(&ServiceAccountCreated { user_id })?))
+}
+
+/// ##  Endpoint create API key
+/// POST {UTAUrl}:{UTAPort}/users/manage/create_api_key (private)
+///
+/// #### Required Body
+/// One of the optional service account parameters must be present in the request body
+/// - service_account_id (optional): optional u32
+/// - service_account_username (optional): optional ans-20 max string
+/// - name: ans-50 max string, to tell keys apart
+/// - scopes: list of "read", "write" or "admin"
+/// - expires_in_days (optional): optional u32, the key never expires if not present
+///
+/// ### Description
+/// Creates an API key for a service account whose level is below the requesting user's. The
+/// key is included in the response and can't be retrieved again, only its hash is stored
+#[utoipa::path(
+    tag = "API keys",
+    summary = "Creates an API key for a service account",
+    responses(
+        (status = 201, description = "API key created, the key is only included here")
+    )
+)]
+#[post("/create_api_key", wrap = "RequireScope::new(TokenScope::ManageApiKeys)")]
+async fn create_api_key(request: HttpRequest, body: web::Json<PostApiKey>) -> Result<HttpResponse, ApiError> {
+
+    let api_key_data = body.into_inner();
+
+    let user = users::functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
+
+    //  Clients acting for the user can't mint credentials that would outlive their grant
+    if matches!(request.extensions().get::<Credential>(), Some(Credential::AccessToken(_))) {
+        return Err(ApiError::new(ErrorCode::SessionRequired, "API keys can't be created with an OAuth access token"))
+    }
+
+    let service_account = match users::functions::get_user_by_id_or_username(
+        api_key_data.service_account_id,
+        api_key_data.service_account_username
+    ).await? {
+        Some(service_account) if service_account.is_service_account() => service_account,
+        _ => return Err(ApiError::new(ErrorCode::InvalidServiceAccount, "Invalid service account"))
+    };
+
+    let request = PolicyRequest::new(Action::ManageApiKeys, &user).await
+        .with_resource_level(*service_account.get_level());
+    if !Policy::instance().evaluate(&request).await.is_allowed() {
+        return Err(ApiError::new(ErrorCode::Forbidden, "User lacks the privileges to manage this service account"))
+    }
+
+    if api_key_data.scopes.is_empty() {
+        return Err(ApiError::new(ErrorCode::InvalidRequest, "At least one scope is required"))
+    }
+
+    let (api_key, key) = ApiKey::create_api_key(
+        service_account.get_id(),
+        api_key_data.name.as_str(),
+        api_key_data.scopes.as_slice(),
+        api_key_data.expires_in_days
+    ).await?;
+
+    let api_key_created = ApiKeyCreated {
+        api_key_id: *api_key.get_id(),
+        prefix: api_key.get_prefix().to_string(),
+        api_key: key
+    };
+
+    Ok(json_response(StatusCode::CREATED, general::http_req_res::serialize_into_json(&api_key_created)?))
+}
+
+/// ##  Endpoint list API keys
+/// GET {UTAUrl}:{UTAPort}/users/manage/list_api_keys (private)
+///
+/// #### Required Query Parameters
+/// One of the optional parameters must be present in the query
+/// - service_account_id (optional): optional u32
+/// - service_account_username (optional): optional ans-20 max string
+///
+/// ### Description
+/// Lists the API keys of a service account whose level is below the requesting user's, including
+/// revoked and expired ones, with their prefix, scopes, expiry and last time they were used. The
+/// keys themselves are never included
+#[utoipa::path(
+    tag = "API keys",
+    summary = "Lists the API keys of a service account",
+    params(ServiceAccount),
+    responses(
+        (status = 200, description = "API keys, without the keys themselves")
+    )
+)]
+#[get("/list_api_keys", wrap = "RequireScope::new(TokenScope::ManageApiKeys)")]
+async fn list_api_keys(request: HttpRequest, query: web::Query<ServiceAccount>) -> Result<HttpResponse, ApiError> {
+
+    let service_account_data = query.into_inner();
+
+    let user = users::functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
+
+    let service_account = match users::functions::get_user_by_id_or_username(
+        service_account_data.service_account_id,
+        service_account_data.service_account_username
+    ).await? {
+        Some(service_account) if service_account.is_service_account() => service_account,
+        _ => return Err(ApiError::new(ErrorCode::InvalidServiceAccount, "Invalid service account"))
+    };
+
+    let request = PolicyRequest::new(Action::ManageApiKeys, &user).await
+        .with_resource_level(*service_account.get_level());
+    if !Policy::instance().evaluate(&request).await.is_allowed() {
+        return Err(ApiError::new(ErrorCode::Forbidden, "User lacks the privileges to manage this service account"))
+    }
+
+    let api_keys = ApiKey::select_by_user(service_account.get_id()).await?;
+
+    Ok(json_response(StatusCode::OK, general::http_req_res::serialize_into_json(&api_keys)?))
+}
+
+/// ##  Endpoint revoke API key
+/// PUT {UTAUrl}:{UTAPort}/users/manage/revoke_api_key (private)
+///
+/// #### Required Body
+/// One of the optional parameters must be present in the request body
+/// - api_key_id (optional): optional u32
+/// - prefix (optional): optional ans-8 string, as returned when the key was created
+///
+/// ### Description
+/// Revokes an API key of a service account whose level is below the requesting user's. Revoked
+/// keys are rejected from then on
+#[utoipa::path(
+    tag = "API keys",
+    summary = "Revokes an API key",
+    responses(
+        (status = 204, description = "API key revoked")
+    )
+)]
+#[put("/revoke_api_key", wrap = "RequireScope::new(TokenScope::ManageApiKeys)")]
+async fn revoke_api_key(request: HttpRequest, body: web::Json<RevokeApiKey>) -> Result<HttpResponse, ApiError> {
+
+    let revoke_data = body.into_inner();
+
+    let user = users::functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
+
+    let api_key = if let Some(api_key_id) = revoke_data.api_key_id {
+        ApiKey::select_by_id(&api_key_id).await?
+    } else if let Some(prefix) = revoke_data.prefix {
+        if !auth::crypt::is_valid_key_prefix(prefix.as_str()) {
+            return Err(ApiError::new(ErrorCode::InvalidRequest, "Invalid API key prefix"))
+        }
+        ApiKey::select_by_prefix(prefix.as_str()).await?
+    } else {
+        return Err(ApiError::new(ErrorCode::InvalidRequest, "Invalid API key id and prefix"))
+    };
+    let api_key = api_key.ok_or_else(|| ApiError::new(ErrorCode::ApiKeyNotFound, "API key not found"))?;
+
+    //  Keys of deleted owners are treated as owned by the lowest level, so they can still be revoked
+    let owner_level = match User::select_by_id(api_key.get_user_id()).await? {
+        Some(owner) => *owner.get_level(),
+        None => Level::View
+    };
+
+    let request = PolicyRequest::new(Action::ManageApiKeys, &user).await
+        .with_resource_level(owner_level);
+    if !Policy::instance().evaluate(&request).await.is_allowed() {
+        return Err(ApiError::new(ErrorCode::Forbidden, "User lacks the privileges to manage this service account"))
+    }
+
+    api_key.revoke().await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
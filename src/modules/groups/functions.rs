@@ -0,0 +1,66 @@
+use error_mapper::TheResult;
+use crate::general::types::UsersIdType;
+use crate::modules::groups::group;
+use crate::modules::users::user::Level;
+use crate::modules::users::UsersSessions;
+
+/// ## Description
+/// Computes the user's effective level, the highest between their own level and the levels
+/// granted by their groups, and caches it in the runtime sessions map
+///
+/// ### Parameters
+/// - user_id: user whose effective level is refreshed
+/// - level: the user's own level, as stored in the users table
+pub async fn refresh_effective_level(user_id: &UsersIdType, level: &Level) -> TheResult<Level> {
+
+    let group_levels = group::select_group_levels_by_user(user_id).await?;
+
+    Ok(cache_effective_level(user_id, level, group_levels.as_slice()).await)
+}
+
+/// Caches the highest between the user's own level and the levels granted by the groups
+/// received, replacing the level cached before
+async fn cache_effective_level(user_id: &UsersIdType, level: &Level, group_levels: &[Level]) -> Level {
+
+    let effective_level = group_levels.iter().copied().fold(*level, Level::max);
+
+    UsersSessions::instance().set_effective_level(user_id, effective_level).await;
+
+    effective_level
+}
+
+/// ## Description
+/// Adds the levels granted by groups to the effective levels cached in the runtime sessions map.
+/// Meant to run once at startup, after users are registered in runtime with their own levels
+pub async fn register_group_levels_in_runtime() -> TheResult<()> {
+
+    for (user_id, level) in group::select_all_group_levels().await? {
+        UsersSessions::instance().grant_effective_level(&user_id, level).await;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::users::user::User;
+
+    #[actix_web::test]
+    async fn removing_a_membership_lowers_the_cached_level() {
+
+        let member: User = serde_json::from_value(serde_json::json!({
+            "id": 4_000_000_000u32,
+            "username": "member",
+            "hashed_pass": "",
+            "email": ""
+        })).unwrap();
+
+        cache_effective_level(member.get_id(), member.get_level(), &[Level::High]).await;
+        assert_eq!(UsersSessions::instance().get_effective_level(&member).await, Level::High);
+
+        //  The only group granting a level is gone, the user is left with their own
+        cache_effective_level(member.get_id(), member.get_level(), &[]).await;
+        assert_eq!(UsersSessions::instance().get_effective_level(&member).await, Level::View);
+    }
+}
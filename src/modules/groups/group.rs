@@ -0,0 +1,215 @@
+use chrono::NaiveDateTime;
+use error_mapper::{map_to_new_error, TheResult};
+use mysql_async::prelude::{FromRow, Queryable};
+use mysql_async::{FromRowError, Row};
+use serde::Serialize;
+use crate::{database, row_to_data, row_to_enum, row_to_naive_datetime};
+use crate::database::db_conn::get_conn;
+use crate::general::types::{GroupsIdType, UsersIdType};
+use crate::modules::users::user::Level;
+
+/// ## Description
+/// Group of users. Every member of the group is granted the group's level, on top of their own
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct Group {
+    id: GroupsIdType,
+    name: String,
+    level: Level,
+    #[serde(skip_serializing)]
+    created_at: NaiveDateTime
+}
+
+impl Group {
+
+    pub async fn create_group(name: &str, level: &Level) -> TheResult<Self> {
+
+        let group = Self {
+            id: Self::select_last_id().await? + 1,
+            name: name.to_string(),
+            level: *level,
+            created_at: chrono::Utc::now().naive_utc()
+        };
+
+        group.insert().await?;
+
+        Ok(group)
+    }
+
+    pub async fn select_by_id(group_id: &GroupsIdType) -> TheResult<Option<Self>> {
+
+        let conn = &mut get_conn().await?;
+
+        let group = conn.query_first::<Self, _>(
+            format!(
+                "SELECT * FROM users_groups WHERE ID = {}",
+                group_id
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(group)
+    }
+
+    pub async fn select_by_name(name: &str) -> TheResult<Option<Self>> {
+
+        let conn = &mut get_conn().await?;
+
+        //  Names come from request bodies, they're sent as parameters and never formatted in
+        let group = conn.exec_first::<Self, _, _>(
+            "SELECT * FROM users_groups WHERE name = ?",
+            (name,)
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(group)
+    }
+
+    pub(super) fn validate_name(name: &str) -> Vec<String> {
+
+        let mut errors = vec![];
+
+        if name.is_empty() || name.len() > 30 {
+            errors.push("Group name must be between 1 and 30 characters long".to_string());
+        }
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.') {
+            errors.push("Group name must contain only letters, numbers, underscores, hyphens and dots".to_string());
+        }
+
+        errors
+    }
+
+    async fn select_last_id() -> TheResult<GroupsIdType> {
+
+        let conn = &mut get_conn().await?;
+
+        let id = conn.query_first::<Option<GroupsIdType>, _>(
+            "SELECT MAX(ID) FROM users_groups LIMIT 1"
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        if let Some(Some(group_id)) = id {
+            return Ok(group_id)
+        }
+
+        Ok(GroupsIdType::default())
+    }
+
+    async fn insert(&self) -> TheResult<()> {
+
+        let conn = &mut get_conn().await?;
+
+        let created_at = self.created_at.format(database::DATETIME_FORMAT).to_string();
+
+        conn.exec_drop(
+            "INSERT INTO users_groups (ID, name, level, created_at, updated_at) VALUES (?, ?, ?, ?, ?)",
+            (self.id, self.name.as_str(), self.level.to_string(), created_at.as_str(), created_at.as_str())
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+
+    /// Adds the users to the group in a single statement. Users that already were members are
+    /// left as they were
+    pub async fn add_members(&self, users_ids: &[UsersIdType]) -> TheResult<()> {
+
+        if users_ids.is_empty() {
+            return Ok(())
+        }
+
+        let conn = &mut get_conn().await?;
+
+        let created_at = chrono::Utc::now().format(database::DATETIME_FORMAT).to_string();
+        let values = users_ids.iter()
+            .map(|user_id| format!("({}, {}, '{}')", self.id, user_id, created_at))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        conn.query_drop(
+            format!(
+                "INSERT IGNORE INTO users_groups_members (users_groups_ID, users_ID, created_at) VALUES {}",
+                values
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+
+    /// Removes the users from the group in a single statement
+    pub async fn remove_members(&self, users_ids: &[UsersIdType]) -> TheResult<()> {
+
+        if users_ids.is_empty() {
+            return Ok(())
+        }
+
+        let conn = &mut get_conn().await?;
+
+        let ids = users_ids.iter()
+            .map(|user_id| user_id.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        conn.query_drop(
+            format!(
+                "DELETE FROM users_groups_members WHERE users_groups_ID = {} AND users_ID IN ({})",
+                self.id,
+                ids
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+
+    pub fn get_id(&self) -> &GroupsIdType {
+        &self.id
+    }
+
+    pub fn get_level(&self) -> &Level {
+        &self.level
+    }
+}
+
+pub(super) async fn name_available(name: &str) -> TheResult<bool> {
+    Ok(Group::select_by_name(name).await?.is_none())
+}
+
+/// Levels granted to the user by every group they're a member of
+pub(super) async fn select_group_levels_by_user(user_id: &UsersIdType) -> TheResult<Vec<Level>> {
+
+    let conn = &mut get_conn().await?;
+
+    let levels = conn.query::<String, _>(
+        format!(
+            "SELECT ug.level FROM users_groups ug \
+            INNER JOIN users_groups_members ugm ON ugm.users_groups_ID = ug.ID \
+            WHERE ugm.users_ID = {}",
+            user_id
+        )
+    ).await.map_err(|e| map_to_new_error!(e))?;
+
+    Ok(levels.into_iter().map(Level::from).collect())
+}
+
+/// Levels granted by groups for every user that's a member of at least one group
+pub(super) async fn select_all_group_levels() -> TheResult<Vec<(UsersIdType, Level)>> {
+
+    let conn = &mut get_conn().await?;
+
+    let levels = conn.query::<(UsersIdType, String), _>(
+        "SELECT ugm.users_ID, ug.level FROM users_groups ug \
+        INNER JOIN users_groups_members ugm ON ugm.users_groups_ID = ug.ID"
+    ).await.map_err(|e| map_to_new_error!(e))?;
+
+    Ok(levels.into_iter().map(|(user_id, level)| (user_id, Level::from(level))).collect())
+}
+
+impl FromRow for Group {
+    fn from_row(row: Row) -> Self where Self: Sized {
+        Self {
+            id: row_to_data!(row, "ID", "users_groups", GroupsIdType),
+            name: row_to_data!(row, "name", "users_groups", String),
+            level: row_to_enum!(row, "level", "users_groups", Level),
+            created_at: row_to_naive_datetime!(row, "created_at", "users_groups")
+        }
+    }
+
+    fn from_row_opt(_: Row) -> Result<Self, FromRowError> where Self: Sized {
+        unimplemented!()
+    }
+}
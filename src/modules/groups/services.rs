@@ -0,0 +1,214 @@
+
+use actix_web::{HttpRequest, HttpResponse, post, put, web};
+use actix_web::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::api::authentication::RequireScope;
+use crate::general;
+use crate::auth::policy::{Action, Policy, PolicyRequest};
+use crate::general::api_error::ApiError;
+use crate::general::http_req_res::json_response;
+use crate::general::problem::{ErrorCode, Problem};
+use crate::general::types::{GroupsIdType, UsersIdType};
+use crate::modules::groups::{functions, group};
+use crate::modules::groups::group::Group;
+use crate::modules::users;
+use crate::modules::users::session_events::{SessionEventKind, SessionEvents};
+use crate::modules::users::user::{Level, User};
+use crate::modules::personal_tokens::personal_token::TokenScope;
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct PostGroup {
+    name: String,
+    level: u8
+}
+
+#[derive(Serialize)]
+struct GroupCreated {
+    group_id: GroupsIdType
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct GroupMembers {
+    #[schema(value_type = Option<u32>)]
+    group_id: Option<GroupsIdType>,
+    group_name: Option<String>,
+    #[serde(default)]
+    #[schema(value_type = Vec<u32>)]
+    user_ids: Vec<UsersIdType>,
+    #[serde(default)]
+    usernames: Vec<String>
+}
+
+#[derive(Serialize)]
+struct GroupMembersUpdated {
+    updated: Vec<UsersIdType>,
+    not_found: Vec<String>
+}
+
+/// ##  Endpoint create group
+/// POST {UTAUrl}:{UTAPort}/internal/create_group (private)
+///
+/// #### Required Body
+/// - name: ans-30 max string
+/// - level: from 0 to 3 u8, level granted to every member of the group
+///
+/// ### Description
+/// Creates a group of users. The level granted by the group can be at most one level below the
+/// requesting user's
+#[utoipa::path(
+    tag = "Groups",
+    summary = "Creates a group",
+    responses(
+        (status = 201, description = "Group created")
+    )
+)]
+#[post("/create_group", wrap = "RequireScope::new(TokenScope::AdminUsers)")]
+async fn create_group(request: HttpRequest, body: web::Json<PostGroup>) -> Result<HttpResponse, ApiError> {
+
+    let user = users::functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
+
+    let group_level: Level = body.level.into();
+    let request = PolicyRequest::new(Action::CreateGroup, &user).await
+        .with_resource_target_level(group_level);
+    if !Policy::instance().evaluate(&request).await.is_allowed() {
+        return Err(
+            ApiError::new(
+                ErrorCode::LevelNotAllowed,
+                "Group level must be at least one level below the requesting account's"
+            )
+        )
+    }
+
+    let errors = Group::validate_name(body.name.as_str());
+    if !errors.is_empty() {
+        return Err(
+            Problem::new(ErrorCode::ValidationFailed)
+                .with_detail("Invalid group name")
+                .with_extension("errors", errors)
+                .into()
+        )
+    }
+
+    if !group::name_available(body.name.as_str()).await? {
+        return Err(ApiError::new(ErrorCode::GroupNameTaken, "Group name not available"))
+    }
+
+    let group = Group::create_group(body.name.as_str(), &group_level).await?;
+
+    let group_created = GroupCreated {
+        group_id: *group.get_id()
+    };
+
+    Ok(json_response(StatusCode::CREATED, general::http_req_res::serialize_into_json(&group_created)?))
+}
+
+/// ##  Endpoint add group members
+/// PUT {UTAUrl}:{UTAPort}/internal/add_group_members (private)
+///
+/// #### Required Body
+/// One of the optional group parameters must be present in the request body
+/// - group_id (optional): optional u32
+/// - group_name (optional): optional ans-30 max string
+/// - user_ids (optional): list of u32
+/// - usernames (optional): list of ans-20 max strings
+///
+/// ### Description
+/// Adds every user sent in the body to the group. Only groups granting a level below the
+/// requesting user's can be managed. Responds with the users added and the ones not found
+#[utoipa::path(
+    tag = "Groups",
+    summary = "Adds users to a group",
+    responses(
+        (status = 200, description = "Users added and the ones not found")
+    )
+)]
+#[put("/add_group_members", wrap = "RequireScope::new(TokenScope::AdminUsers)")]
+async fn add_group_members(request: HttpRequest, body: web::Json<GroupMembers>) -> Result<HttpResponse, ApiError> {
+    update_group_members(request, body.into_inner(), true).await
+}
+
+/// ##  Endpoint remove group members
+/// PUT {UTAUrl}:{UTAPort}/internal/remove_group_members (private)
+///
+/// #### Required Body
+/// Same as add_group_members
+///
+/// ### Description
+/// Removes every user sent in the body from the group. Responds with the users removed and the
+/// ones not found
+#[utoipa::path(
+    tag = "Groups",
+    summary = "Removes users from a group",
+    responses(
+        (status = 200, description = "Users removed and the ones not found")
+    )
+)]
+#[put("/remove_group_members", wrap = "RequireScope::new(TokenScope::AdminUsers)")]
+async fn remove_group_members(request: HttpRequest, body: web::Json<GroupMembers>) -> Result<HttpResponse, ApiError> {
+    update_group_members(request, body.into_inner(), false).await
+}
+
+async fn update_group_members(request: HttpRequest, members: GroupMembers, add: bool) -> Result<HttpResponse, ApiError> {
+
+    let user = users::functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
+
+    let group = if let Some(group_id) = members.group_id {
+        Group::select_by_id(&group_id).await?
+    } else if let Some(group_name) = members.group_name {
+        Group::select_by_name(group_name.as_str()).await?
+    } else {
+        return Err(ApiError::new(ErrorCode::InvalidRequest, "Invalid group id and group name"))
+    };
+    let group = group.ok_or_else(|| ApiError::new(ErrorCode::GroupNotFound, "Group not found"))?;
+
+    let request = PolicyRequest::new(Action::ManageGroupMembers, &user).await
+        .with_resource_level(*group.get_level());
+    if !Policy::instance().evaluate(&request).await.is_allowed() {
+        return Err(ApiError::new(ErrorCode::Forbidden, "User lacks the privileges to manage this group"))
+    }
+
+    //  Resolve every user received, keeping track of the ones that don't exist
+    let mut targets: Vec<User> = vec![];
+    let mut not_found = vec![];
+    for user_id in members.user_ids {
+        match User::select_by_id(&user_id).await? {
+            Some(target) => targets.push(target),
+            None => not_found.push(user_id.to_string())
+        }
+    }
+    for username in members.usernames {
+        match User::select_by_username(username.as_str()).await? {
+            Some(target) => targets.push(target),
+            None => not_found.push(username)
+        }
+    }
+
+    let mut users_ids = targets.iter().map(|target| *target.get_id()).collect::<Vec<_>>();
+    users_ids.sort();
+    users_ids.dedup();
+
+    if add {
+        group.add_members(users_ids.as_slice()).await?;
+    } else {
+        group.remove_members(users_ids.as_slice()).await?;
+    }
+
+    //  Keep the cached effective levels in line with the new memberships. This replica refreshes
+    //  them here, the others on the events
+    for target in targets.iter() {
+        functions::refresh_effective_level(target.get_id(), target.get_level()).await?;
+    }
+    for user_id in users_ids.iter() {
+        SessionEvents::instance().publish(SessionEventKind::LevelChanged, user_id).await;
+    }
+
+    let members_updated = GroupMembersUpdated {
+        updated: users_ids,
+        not_found
+    };
+
+    Ok(json_response(StatusCode::OK, general::http_req_res::serialize_into_json(&members_updated)?))
+}
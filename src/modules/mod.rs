@@ -1 +1,6 @@
-pub mod users;
\ No newline at end of file
+pub mod users;
+pub mod organizations;
+pub mod groups;
+pub mod api_keys;
+pub mod personal_tokens;
+pub mod oauth;
\ No newline at end of file
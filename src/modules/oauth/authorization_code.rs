@@ -0,0 +1,213 @@
+use std::ops::Add;
+use chrono::NaiveDateTime;
+use error_mapper::{map_to_new_error, TheResult};
+use mysql_async::prelude::{FromRow, Queryable};
+use mysql_async::{FromRowError, Row};
+use serde::Deserialize;
+use utoipa::ToSchema;
+use crate::{auth, database, row_to_data, row_to_naive_datetime, row_to_optional_naive_datetime};
+use crate::database::db_conn::get_conn;
+use crate::general::types::{OAuthClientsIdType, OAuthCodesIdType, UsersIdType};
+use crate::modules::oauth::scope;
+use crate::modules::oauth::scope::OAuthScope;
+
+/// Authorization codes are exchanged right after the redirect, so they're short lived
+const AUTHORIZATION_CODE_LIFETIME_MINUTES: i64 = 10;
+
+/// ## Description
+/// Code issued to a client once the user authorized it, to be exchanged for tokens in the token
+/// endpoint. Codes can be used only once, and only by presenting the PKCE verifier matching the
+/// challenge sent when the code was requested
+#[derive(Debug, Default, Clone)]
+pub struct AuthorizationCode {
+    id: OAuthCodesIdType,
+    oauth_clients_id: OAuthClientsIdType,
+    users_id: UsersIdType,
+    hashed_code: String,
+    redirect_uri: String,
+    scopes: Vec<OAuthScope>,
+    code_challenge: String,
+    code_challenge_method: PkceMethod,
+    nonce: Option<String>,
+    expires_at: NaiveDateTime,
+    used_at: Option<NaiveDateTime>
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, ToSchema)]
+pub enum PkceMethod {
+    #[default]
+    S256,
+    #[serde(rename = "plain")]
+    Plain
+}
+
+impl AuthorizationCode {
+
+    /// Issues a code for the user and client and returns it along with the full code, only its
+    /// hash is stored
+    pub async fn create_authorization_code(
+        oauth_clients_id: &OAuthClientsIdType,
+        user_id: &UsersIdType,
+        redirect_uri: &str,
+        scopes: &[OAuthScope],
+        code_challenge: &str,
+        code_challenge_method: PkceMethod,
+        nonce: Option<&str>
+    ) -> TheResult<(Self, String)> {
+
+        let code = auth::crypt::generate_session_token()?;
+
+        let authorization_code = Self {
+            id: Self::select_last_id().await? + 1,
+            oauth_clients_id: *oauth_clients_id,
+            users_id: *user_id,
+            hashed_code: auth::crypt::credential_digest(code.as_str()),
+            redirect_uri: redirect_uri.to_string(),
+            scopes: scopes.to_vec(),
+            code_challenge: code_challenge.to_string(),
+            code_challenge_method,
+            nonce: nonce.map(|nonce| nonce.to_string()),
+            expires_at: chrono::Utc::now().naive_utc()
+                .add(chrono::Duration::minutes(AUTHORIZATION_CODE_LIFETIME_MINUTES)),
+            used_at: None
+        };
+
+        authorization_code.insert().await?;
+
+        Ok((authorization_code, code))
+    }
+
+    pub async fn select_by_code(code: &str) -> TheResult<Option<Self>> {
+
+        let conn = &mut get_conn().await?;
+
+        let authorization_code = conn.exec_first::<Self, _, _>(
+            "SELECT * FROM oauth_authorization_codes WHERE hashed_code = ?",
+            (auth::crypt::credential_digest(code),)
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(authorization_code)
+    }
+
+    async fn select_last_id() -> TheResult<OAuthCodesIdType> {
+
+        let conn = &mut get_conn().await?;
+
+        let id = conn.query_first::<Option<OAuthCodesIdType>, _>(
+            "SELECT MAX(ID) FROM oauth_authorization_codes LIMIT 1"
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        if let Some(Some(code_id)) = id {
+            return Ok(code_id)
+        }
+
+        Ok(OAuthCodesIdType::default())
+    }
+
+    async fn insert(&self) -> TheResult<()> {
+
+        let conn = &mut get_conn().await?;
+
+        //  The redirect URI, challenge and nonce come from the authorize query, they're sent as
+        //  parameters and never formatted in
+        conn.exec_drop(
+            "INSERT INTO oauth_authorization_codes (ID, oauth_clients_ID, users_ID, hashed_code, redirect_uri, scopes, \
+            code_challenge, code_challenge_method, nonce, expires_at) \
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            (
+                self.id,
+                self.oauth_clients_id,
+                self.users_id,
+                self.hashed_code.as_str(),
+                self.redirect_uri.as_str(),
+                scope::scopes_to_string(self.scopes.as_slice()),
+                self.code_challenge.as_str(),
+                self.code_challenge_method.as_str(),
+                self.nonce.as_deref(),
+                self.expires_at.format(database::DATETIME_FORMAT).to_string()
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+
+    /// ## Description
+    /// Marks the code as used. Returns false if it was already used, so two requests racing
+    /// with the same code can't both get tokens
+    pub async fn consume(&self) -> TheResult<bool> {
+
+        let conn = &mut get_conn().await?;
+
+        conn.exec_drop(
+            "UPDATE oauth_authorization_codes SET used_at = ? WHERE ID = ? AND used_at IS NULL",
+            (chrono::Utc::now().format(database::DATETIME_FORMAT).to_string(), self.id)
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(conn.affected_rows() > 0)
+    }
+
+    /// Checks that the code can still be exchanged by the client, with the same redirect URI it
+    /// was issued for
+    pub fn is_valid_for(&self, oauth_clients_id: &OAuthClientsIdType, redirect_uri: &str) -> bool {
+        self.used_at.is_none()
+            && self.expires_at > chrono::Utc::now().naive_utc()
+            && self.oauth_clients_id == *oauth_clients_id
+            && self.redirect_uri == redirect_uri
+    }
+
+    /// ## Description
+    /// Checks the PKCE verifier against the challenge received when the code was requested
+    pub fn verify_code_challenge(&self, code_verifier: &str) -> bool {
+        match self.code_challenge_method {
+            PkceMethod::S256 => auth::crypt::pkce_challenge(code_verifier) == self.code_challenge,
+            PkceMethod::Plain => code_verifier == self.code_challenge
+        }
+    }
+
+    pub fn get_user_id(&self) -> &UsersIdType {
+        &self.users_id
+    }
+
+    pub fn get_scopes(&self) -> &[OAuthScope] {
+        self.scopes.as_slice()
+    }
+
+    pub fn get_nonce(&self) -> Option<&str> {
+        self.nonce.as_deref()
+    }
+}
+
+impl PkceMethod {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PkceMethod::S256 => "S256",
+            PkceMethod::Plain => "plain"
+        }
+    }
+}
+
+impl FromRow for AuthorizationCode {
+    fn from_row(row: Row) -> Self where Self: Sized {
+        Self {
+            id: row_to_data!(row, "ID", "oauth_authorization_codes", OAuthCodesIdType),
+            oauth_clients_id: row_to_data!(row, "oauth_clients_ID", "oauth_authorization_codes", OAuthClientsIdType),
+            users_id: row_to_data!(row, "users_ID", "oauth_authorization_codes", UsersIdType),
+            hashed_code: row_to_data!(row, "hashed_code", "oauth_authorization_codes", String),
+            redirect_uri: row_to_data!(row, "redirect_uri", "oauth_authorization_codes", String),
+            scopes: scope::scopes_from_string(row_to_data!(row, "scopes", "oauth_authorization_codes", String).as_str())
+                .unwrap_or_default(),
+            code_challenge: row_to_data!(row, "code_challenge", "oauth_authorization_codes", String),
+            code_challenge_method: match row_to_data!(row, "code_challenge_method", "oauth_authorization_codes", String).as_str() {
+                "plain" => PkceMethod::Plain,
+                _ => PkceMethod::S256
+            },
+            nonce: row_to_data!(row, "nonce", "oauth_authorization_codes", Option<String>),
+            expires_at: row_to_naive_datetime!(row, "expires_at", "oauth_authorization_codes"),
+            used_at: row_to_optional_naive_datetime!(row, "used_at", "oauth_authorization_codes")
+        }
+    }
+
+    fn from_row_opt(_: Row) -> Result<Self, FromRowError> where Self: Sized {
+        unimplemented!()
+    }
+}
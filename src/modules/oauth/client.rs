@@ -0,0 +1,250 @@
+use chrono::NaiveDateTime;
+use error_mapper::{map_to_new_error, TheResult};
+use mysql_async::prelude::{FromRow, Queryable};
+use mysql_async::{FromRowError, Row};
+use url::{Host, Url};
+use crate::{auth, database, row_to_data, row_to_naive_datetime};
+use crate::database::db_conn::get_conn;
+use crate::general::types::{OAuthClientsIdType, UsersIdType};
+use crate::modules::oauth::scope;
+use crate::modules::oauth::scope::OAuthScope;
+
+/// Length of the public client identifier
+const CLIENT_ID_LENGTH: usize = 24;
+
+/// ## Description
+/// Whether the redirect URI can be registered. It must be absolute, without a fragment and use
+/// https. Plain http is only allowed for loopback IP literals, where native apps listen for the
+/// response (RFC 8252)
+pub fn is_valid_redirect_uri(redirect_uri: &str) -> bool {
+
+    //  Redirect URIs are stored separated by spaces
+    if redirect_uri.contains(char::is_whitespace) {
+        return false
+    }
+
+    let Ok(url) = Url::parse(redirect_uri) else {
+        return false
+    };
+
+    if url.fragment().is_some() {
+        return false
+    }
+
+    match (url.scheme(), url.host()) {
+        ("https", Some(_)) => true,
+        ("http", Some(Host::Ipv4(address))) => address.is_loopback(),
+        ("http", Some(Host::Ipv6(address))) => address.is_loopback(),
+        _ => false
+    }
+}
+
+/// ## Description
+/// Application registered to authenticate its users against this service. Confidential clients
+/// hold a secret, only its hash is stored. Public clients (single page and mobile apps) can't keep
+/// a secret and rely on PKCE alone. Clients linked to a service account can also use the
+/// client_credentials grant, acting as that service account. Only clients registered to revoke
+/// sessions can log users out through the revocation endpoint
+#[derive(Debug, Default, Clone)]
+pub struct OAuthClient {
+    id: OAuthClientsIdType,
+    client_id: String,
+    name: String,
+    hashed_secret: Option<String>,
+    redirect_uris: Vec<String>,
+    scopes: Vec<OAuthScope>,
+    service_account_id: Option<UsersIdType>,
+    revokes_sessions: bool,
+    created_at: NaiveDateTime
+}
+
+impl OAuthClient {
+
+    /// ## Description
+    /// Registers a client and returns it along with its secret, if the client is confidential.
+    /// The secret is only available here
+    pub async fn create_oauth_client(
+        name: &str,
+        redirect_uris: &[String],
+        scopes: &[OAuthScope],
+        confidential: bool,
+        service_account_id: Option<UsersIdType>,
+        revokes_sessions: bool
+    ) -> TheResult<(Self, Option<String>)> {
+
+        let client_id = auth::crypt::generate_session_token()?
+            .chars()
+            .take(CLIENT_ID_LENGTH)
+            .collect::<String>();
+
+        let secret = match confidential {
+            true => Some(auth::crypt::generate_session_token()?),
+            false => None
+        };
+
+        let client = Self {
+            id: Self::select_last_id().await? + 1,
+            client_id,
+            name: name.to_string(),
+            hashed_secret: secret.as_deref().map(auth::crypt::credential_digest),
+            redirect_uris: redirect_uris.to_vec(),
+            scopes: scopes.to_vec(),
+            service_account_id,
+            revokes_sessions,
+            created_at: chrono::Utc::now().naive_utc()
+        };
+
+        client.insert().await?;
+
+        Ok((client, secret))
+    }
+
+    pub async fn select_by_client_id(client_id: &str) -> TheResult<Option<Self>> {
+
+        let conn = &mut get_conn().await?;
+
+        //  Client ids come from requests, they're sent as parameters and never formatted in
+        let client = conn.exec_first::<Self, _, _>(
+            "SELECT * FROM oauth_clients WHERE client_id = ?",
+            (client_id,)
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(client)
+    }
+
+    async fn select_last_id() -> TheResult<OAuthClientsIdType> {
+
+        let conn = &mut get_conn().await?;
+
+        let id = conn.query_first::<Option<OAuthClientsIdType>, _>(
+            "SELECT MAX(ID) FROM oauth_clients LIMIT 1"
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        if let Some(Some(client_id)) = id {
+            return Ok(client_id)
+        }
+
+        Ok(OAuthClientsIdType::default())
+    }
+
+    async fn insert(&self) -> TheResult<()> {
+
+        let conn = &mut get_conn().await?;
+
+        conn.exec_drop(
+            "INSERT INTO oauth_clients \
+            (ID, client_id, name, hashed_secret, redirect_uris, scopes, service_account_users_ID, revokes_sessions, created_at) \
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            (
+                self.id,
+                self.client_id.as_str(),
+                self.name.as_str(),
+                self.hashed_secret.as_deref(),
+                self.redirect_uris.join(" "),
+                scope::scopes_to_string(self.scopes.as_slice()),
+                self.service_account_id,
+                self.revokes_sessions,
+                self.created_at.format(database::DATETIME_FORMAT).to_string()
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+
+    /// Checks the secret received against the stored hash. Public clients have no secret, so
+    /// they never authenticate with one
+    pub fn validate_secret(&self, secret: &str) -> bool {
+        match &self.hashed_secret {
+            Some(hashed_secret) => auth::crypt::credential_matches(hashed_secret, secret),
+            None => false
+        }
+    }
+
+    /// Redirect URIs must match one of the registered ones exactly
+    pub fn allows_redirect_uri(&self, redirect_uri: &str) -> bool {
+        self.redirect_uris.iter().any(|allowed| allowed == redirect_uri)
+    }
+
+    pub fn is_confidential(&self) -> bool {
+        self.hashed_secret.is_some()
+    }
+
+    /// Whether the client can revoke session tokens, logging the users out
+    pub fn revokes_sessions(&self) -> bool {
+        self.revokes_sessions
+    }
+
+    pub fn get_id(&self) -> &OAuthClientsIdType {
+        &self.id
+    }
+
+    pub fn get_client_id(&self) -> &str {
+        self.client_id.as_str()
+    }
+
+    pub fn get_name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn get_scopes(&self) -> &[OAuthScope] {
+        self.scopes.as_slice()
+    }
+
+    pub fn get_service_account_id(&self) -> Option<&UsersIdType> {
+        self.service_account_id.as_ref()
+    }
+}
+
+impl FromRow for OAuthClient {
+    fn from_row(row: Row) -> Self where Self: Sized {
+        Self {
+            id: row_to_data!(row, "ID", "oauth_clients", OAuthClientsIdType),
+            client_id: row_to_data!(row, "client_id", "oauth_clients", String),
+            name: row_to_data!(row, "name", "oauth_clients", String),
+            hashed_secret: row_to_data!(row, "hashed_secret", "oauth_clients", Option<String>),
+            redirect_uris: row_to_data!(row, "redirect_uris", "oauth_clients", String)
+                .split_whitespace()
+                .map(|redirect_uri| redirect_uri.to_string())
+                .collect(),
+            scopes: scope::scopes_from_string(row_to_data!(row, "scopes", "oauth_clients", String).as_str())
+                .unwrap_or_default(),
+            service_account_id: row_to_data!(row, "service_account_users_ID", "oauth_clients", Option<UsersIdType>),
+            revokes_sessions: row_to_data!(row, "revokes_sessions", "oauth_clients", bool),
+            created_at: row_to_naive_datetime!(row, "created_at", "oauth_clients")
+        }
+    }
+
+    fn from_row_opt(_: Row) -> Result<Self, FromRowError> where Self: Sized {
+        unimplemented!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_valid_redirect_uri;
+
+    #[test]
+    fn redirect_uris_must_use_https() {
+        assert!(is_valid_redirect_uri("https://app.example.com/callback"));
+        assert!(is_valid_redirect_uri("https://app.example.com:8443/callback?from=login"));
+        assert!(!is_valid_redirect_uri("http://app.example.com/callback"));
+        assert!(!is_valid_redirect_uri("javascript://app.example.com/%0aalert(1)"));
+        assert!(!is_valid_redirect_uri("com.example.app://callback"));
+    }
+
+    #[test]
+    fn plain_http_only_for_loopback_addresses() {
+        assert!(is_valid_redirect_uri("http://127.0.0.1:51004/callback"));
+        assert!(is_valid_redirect_uri("http://[::1]:51004/callback"));
+        assert!(!is_valid_redirect_uri("http://localhost:51004/callback"));
+        assert!(!is_valid_redirect_uri("http://10.0.0.1/callback"));
+    }
+
+    #[test]
+    fn malformed_redirect_uris_are_rejected() {
+        assert!(!is_valid_redirect_uri("https://app.example.com/callback#token"));
+        assert!(!is_valid_redirect_uri("https://app.example.com/call back"));
+        assert!(!is_valid_redirect_uri("/callback"));
+        assert!(!is_valid_redirect_uri("https://"));
+    }
+}
@@ -0,0 +1,69 @@
+use error_mapper::{map_to_new_error, TheResult};
+use mysql_async::prelude::Queryable;
+use crate::database;
+use crate::database::db_conn::get_conn;
+use crate::general::types::{OAuthClientsIdType, UsersIdType};
+use crate::modules::oauth::scope;
+use crate::modules::oauth::scope::OAuthScope;
+
+/// ## Description
+/// Scopes the user already agreed to grant to a client. While a consent covers the scopes
+/// requested, the user is not asked again
+#[derive(Debug, Default, Clone)]
+pub struct Consent {
+    scopes: Vec<OAuthScope>
+}
+
+impl Consent {
+
+    pub async fn select(user_id: &UsersIdType, oauth_clients_id: &OAuthClientsIdType) -> TheResult<Option<Self>> {
+
+        let conn = &mut get_conn().await?;
+
+        let scopes = conn.exec_first::<String, _, _>(
+            "SELECT scopes FROM oauth_consents WHERE users_ID = ? AND oauth_clients_ID = ?",
+            (user_id, oauth_clients_id)
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(scopes.map(|scopes| Self {
+            scopes: scope::scopes_from_string(scopes.as_str()).unwrap_or_default()
+        }))
+    }
+
+    /// ## Description
+    /// Records the user's consent to the scopes, on top of the ones already granted to the client
+    pub async fn grant(
+        user_id: &UsersIdType,
+        oauth_clients_id: &OAuthClientsIdType,
+        scopes: &[OAuthScope]
+    ) -> TheResult<()> {
+
+        let mut granted = match Self::select(user_id, oauth_clients_id).await? {
+            Some(consent) => consent.scopes,
+            None => vec![]
+        };
+        for scope in scopes {
+            if !granted.contains(scope) {
+                granted.push(*scope);
+            }
+        }
+
+        let conn = &mut get_conn().await?;
+
+        let now = chrono::Utc::now().format(database::DATETIME_FORMAT).to_string();
+        let scopes = scope::scopes_to_string(granted.as_slice());
+
+        conn.exec_drop(
+            "INSERT INTO oauth_consents (users_ID, oauth_clients_ID, scopes, created_at, updated_at) \
+            VALUES (?, ?, ?, ?, ?) \
+            ON DUPLICATE KEY UPDATE scopes = VALUES(scopes), updated_at = VALUES(updated_at)",
+            (user_id, oauth_clients_id, scopes, now.as_str(), now.as_str())
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+
+    pub fn covers(&self, scopes: &[OAuthScope]) -> bool {
+        scope::scopes_cover(self.scopes.as_slice(), scopes)
+    }
+}
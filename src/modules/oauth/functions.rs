@@ -0,0 +1,113 @@
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::HttpRequest;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use error_mapper::TheResult;
+use crate::modules::oauth::client::OAuthClient;
+use crate::modules::oauth::scope;
+use crate::modules::oauth::scope::OAuthGrant;
+use crate::modules::oauth::token::OAuthToken;
+use crate::modules::users;
+use crate::modules::users::user::User;
+
+/// Cookies holding the user's browser session while going through the authorization endpoint
+const SESSION_USERNAME_COOKIE: &str = "uta_username";
+const SESSION_TOKEN_COOKIE: &str = "uta_session";
+
+/// ## Description
+/// Authenticates a request with an OAuth access token. Returns the user the token acts for, with
+/// its level capped by the token's scopes, along with what the token was granted. None if the
+/// token is invalid, revoked or expired
+pub async fn authenticate_access_token(access_token: &str) -> TheResult<Option<(User, OAuthGrant)>> {
+
+    let Some(oauth_token) = OAuthToken::select_by_access_token(access_token).await? else {
+        return Ok(None)
+    };
+
+    if !oauth_token.validate_access_token(access_token) {
+        return Ok(None)
+    }
+
+    //  Tokens of deleted users can't be used anymore
+    let Some(mut user) = User::select_by_id(oauth_token.get_user_id()).await? else {
+        return Ok(None)
+    };
+
+    user.set_scope_level(scope::scopes_level(oauth_token.get_scopes()));
+
+    let grant = OAuthGrant::new(*oauth_token.get_oauth_clients_id(), oauth_token.get_scopes());
+
+    Ok(Some((user, grant)))
+}
+
+/// ## Description
+/// Authenticates the client calling the token endpoint, with HTTP Basic credentials or with
+/// client_id and client_secret in the request body. Public clients only send their client_id
+pub async fn authenticate_client(
+    request: &HttpRequest,
+    client_id: Option<&str>,
+    client_secret: Option<&str>
+) -> TheResult<Option<OAuthClient>> {
+
+    let basic_credentials = get_basic_credentials(request);
+    let (client_id, client_secret) = match &basic_credentials {
+        Some((client_id, client_secret)) => (Some(client_id.as_str()), Some(client_secret.as_str())),
+        None => (client_id, client_secret)
+    };
+
+    let Some(client_id) = client_id else {
+        return Ok(None)
+    };
+
+    let Some(client) = OAuthClient::select_by_client_id(client_id).await? else {
+        return Ok(None)
+    };
+
+    //  Confidential clients must always prove they hold the secret
+    match (client.is_confidential(), client_secret) {
+        (true, Some(client_secret)) if client.validate_secret(client_secret) => Ok(Some(client)),
+        (false, None) => Ok(Some(client)),
+        _ => Ok(None)
+    }
+}
+
+fn get_basic_credentials(request: &HttpRequest) -> Option<(String, String)> {
+    let header = request.headers().get("authorization")?.to_str().ok()?;
+    let encoded = header.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded).ok()?).ok()?;
+    let (client_id, client_secret) = decoded.split_once(':')?;
+    Some((client_id.to_string(), client_secret.to_string()))
+}
+
+/// ## Description
+/// Restores the user from the browser session cookies set by the authorization endpoint. The
+/// cookies hold the same username and session token as the headers used in the rest of the API
+pub async fn get_user_from_browser_session(request: &HttpRequest) -> TheResult<Option<User>> {
+    let username = request.cookie(SESSION_USERNAME_COOKIE).map(|cookie| cookie.value().to_string());
+    let session_token = request.cookie(SESSION_TOKEN_COOKIE).map(|cookie| cookie.value().to_string());
+    let client = users::functions::get_client_from_request(request).await;
+    users::functions::get_user_from_headers(username, session_token, &client).await
+}
+
+/// Cookies to keep the user's browser session after logging in through the authorization endpoint,
+/// sent back only to the scope path of the OAuth endpoints
+pub fn browser_session_cookies(username: &str, session_token: &str, path: &str) -> Vec<Cookie<'static>> {
+    [(SESSION_USERNAME_COOKIE, username), (SESSION_TOKEN_COOKIE, session_token)]
+        .into_iter()
+        .map(|(name, value)| {
+            Cookie::build(name, value.to_string())
+                .path(path.to_string())
+                .http_only(true)
+                .secure(true)
+                .same_site(SameSite::Lax)
+                .finish()
+        })
+        .collect()
+}
+
+/// Appends the parameters to the query of the client's redirect URI
+pub fn redirect_uri_with(redirect_uri: &str, params: &[(&str, &str)]) -> String {
+    let separator = if redirect_uri.contains('?') { '&' } else { '?' };
+    let query = serde_urlencoded::to_string(params).unwrap_or_default();
+    format!("{}{}{}", redirect_uri, separator, query)
+}
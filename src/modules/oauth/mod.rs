@@ -0,0 +1,8 @@
+pub mod services;
+pub mod functions;
+pub mod scope;
+pub mod client;
+pub mod authorization_code;
+pub mod consent;
+pub mod token;
+pub mod oidc;
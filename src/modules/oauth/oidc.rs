@@ -0,0 +1,201 @@
+use error_mapper::TheResult;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use crate::api::versioning::ApiVersion;
+use crate::auth::jwt::SigningKey;
+use crate::config::environment::EnvironmentConfig;
+use crate::modules::oauth::scope::OAuthScope;
+use crate::modules::oauth::token::ACCESS_TOKEN_LIFETIME_SECONDS;
+use crate::modules::users::profile::UserProfile;
+use crate::modules::users::profile_schema::ProfileSchema;
+use crate::modules::users::user::{Level, User};
+
+/// Claims set by the ID tokens and the userinfo endpoint, or registered by the JWT and OpenID
+/// Connect specs, which profile attributes can't be published as
+pub const RESERVED_CLAIMS: [&str; 24] = [
+    "iss", "sub", "aud", "exp", "iat", "nbf", "jti", "nonce", "auth_time", "azp", "acr", "amr", "at_hash", "c_hash",
+    "sid", "preferred_username", "name", "given_name", "family_name", "locale", "zoneinfo", "picture", "email",
+    "level"
+];
+
+/// ## Description
+/// Claims of the ID tokens issued along with the access tokens when the openid scope was granted.
+/// The profile claims are only included with the profile scope, and the email with the email scope.
+/// The subject is the one of the user info, so it's serialized once
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub aud: String,
+    pub exp: i64,
+    pub iat: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(flatten)]
+    pub user_info: UserInfo
+}
+
+/// ## Description
+/// Claims about the user, returned by the userinfo endpoint and included in the ID tokens. The
+/// profile attributes registered as claims in the profile schema are added with their own names
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserInfo {
+    pub sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub given_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub family_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zoneinfo: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub picture: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<Level>,
+    #[serde(flatten, default, skip_serializing_if = "Map::is_empty")]
+    pub attributes: Map<String, Value>
+}
+
+#[derive(Serialize)]
+pub struct DiscoveryDocument {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+    jwks_uri: String,
+    introspection_endpoint: String,
+    revocation_endpoint: String,
+    response_types_supported: Vec<&'static str>,
+    grant_types_supported: Vec<&'static str>,
+    subject_types_supported: Vec<&'static str>,
+    id_token_signing_alg_values_supported: Vec<&'static str>,
+    scopes_supported: Vec<&'static str>,
+    token_endpoint_auth_methods_supported: Vec<&'static str>,
+    code_challenge_methods_supported: Vec<&'static str>,
+    claims_supported: Vec<String>
+}
+
+impl UserInfo {
+    /// Claims of the user for the scopes granted. The profile attributes are only read with the
+    /// profile scope
+    pub async fn new(user: &User, scopes: &[OAuthScope]) -> TheResult<Self> {
+
+        let profile = match scopes.contains(&OAuthScope::Profile) {
+            true => Some(UserProfile::select_by_user_id(user.get_id()).await?),
+            false => None
+        };
+        let profile_claim = |claim: fn(&UserProfile) -> Option<&str>| {
+            profile.as_ref().and_then(claim).map(str::to_string)
+        };
+
+        Ok(Self {
+            sub: user.get_id().to_string(),
+            preferred_username: profile.as_ref().map(|_| user.get_username().to_string()),
+            name: profile.as_ref().and_then(|_| user.get_display_name()).map(str::to_string),
+            given_name: profile_claim(UserProfile::get_first_name),
+            family_name: profile_claim(UserProfile::get_last_name),
+            locale: profile_claim(UserProfile::get_locale),
+            zoneinfo: profile_claim(UserProfile::get_timezone),
+            picture: profile_claim(UserProfile::get_avatar_url),
+            email: scopes.contains(&OAuthScope::Email).then(|| user.get_email().to_string()),
+            level: profile.as_ref().map(|_| *user.get_level()),
+            attributes: profile.as_ref()
+                .map(|profile| ProfileSchema::instance().claims(profile.get_metadata()))
+                .unwrap_or_default()
+        })
+    }
+}
+
+impl DiscoveryDocument {
+    pub async fn new() -> Self {
+        let issuer = EnvironmentConfig::instance().get_issuer().await;
+        //  The issuer stays the same across versions, only the endpoints are versioned
+        let oauth = format!("{}{}/oauth", issuer, ApiVersion::CURRENT.prefix());
+        Self {
+            authorization_endpoint: format!("{}/authorize", oauth),
+            token_endpoint: format!("{}/token", oauth),
+            userinfo_endpoint: format!("{}/userinfo", oauth),
+            jwks_uri: format!("{}/jwks", oauth),
+            introspection_endpoint: format!("{}/introspect", oauth),
+            revocation_endpoint: format!("{}/revoke", oauth),
+            issuer,
+            response_types_supported: vec!["code"],
+            grant_types_supported: vec!["authorization_code", "refresh_token", "client_credentials"],
+            subject_types_supported: vec!["public"],
+            id_token_signing_alg_values_supported: vec!["RS256"],
+            scopes_supported: vec!["openid", "profile", "email", "read", "write", "admin"],
+            token_endpoint_auth_methods_supported: vec!["client_secret_basic", "client_secret_post", "none"],
+            code_challenge_methods_supported: vec!["S256", "plain"],
+            claims_supported: ["iss", "sub", "aud", "exp", "iat", "nonce", "preferred_username", "name", "given_name",
+                "family_name", "locale", "zoneinfo", "picture", "email", "level"].into_iter()
+                .chain(ProfileSchema::instance().claim_names())
+                .map(str::to_string)
+                .collect()
+        }
+    }
+}
+
+/// Signs an ID token for the user, addressed to the client. Expires along with the access token
+pub async fn create_id_token(
+    user: &User,
+    client_id: &str,
+    scopes: &[OAuthScope],
+    nonce: Option<String>
+) -> TheResult<String> {
+
+    let now = chrono::Utc::now().timestamp();
+
+    let claims = IdTokenClaims {
+        iss: EnvironmentConfig::instance().get_issuer().await,
+        aud: client_id.to_string(),
+        exp: now + ACCESS_TOKEN_LIFETIME_SECONDS,
+        iat: now,
+        nonce,
+        user_info: UserInfo::new(user, scopes).await?
+    };
+
+    SigningKey::instance()?.sign(&claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_claim_issued_is_reserved() {
+
+        let claims = IdTokenClaims {
+            iss: String::new(),
+            aud: String::new(),
+            exp: 0,
+            iat: 0,
+            nonce: Some(String::new()),
+            user_info: UserInfo {
+                sub: String::new(),
+                preferred_username: Some(String::new()),
+                name: Some(String::new()),
+                given_name: Some(String::new()),
+                family_name: Some(String::new()),
+                locale: Some(String::new()),
+                zoneinfo: Some(String::new()),
+                picture: Some(String::new()),
+                email: Some(String::new()),
+                level: Some(Level::View),
+                attributes: Map::new()
+            }
+        };
+
+        let Value::Object(claims) = serde_json::to_value(claims).unwrap() else {
+            panic!("ID token claims must serialize as an object")
+        };
+        for claim in claims.keys() {
+            assert!(RESERVED_CLAIMS.contains(&claim.as_str()), "{} is issued but not reserved", claim);
+        }
+    }
+}
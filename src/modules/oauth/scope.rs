@@ -0,0 +1,133 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::general::types::OAuthClientsIdType;
+use crate::modules::personal_tokens::personal_token::TokenScope;
+use crate::modules::users::user::Level;
+
+/// ## Description
+/// Scopes a client can request on behalf of a user. Tokens can never act with a level above the
+/// highest of their scopes, regardless of the level of the user that authorized them. The OpenID
+/// Connect scopes only give access to the user's identity, so they act with the lowest level
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+pub enum OAuthScope {
+    #[serde(rename = "read")]
+    Read,
+    #[serde(rename = "write")]
+    Write,
+    #[serde(rename = "admin")]
+    Admin,
+    #[serde(rename = "openid")]
+    OpenId,
+    #[serde(rename = "profile")]
+    Profile,
+    #[serde(rename = "email")]
+    Email
+}
+
+impl OAuthScope {
+    pub fn max_level(&self) -> Level {
+        match self {
+            OAuthScope::Read => Level::View,
+            OAuthScope::Write => Level::Medium,
+            OAuthScope::Admin => Level::High,
+            OAuthScope::OpenId | OAuthScope::Profile | OAuthScope::Email => Level::View
+        }
+    }
+
+    /// ## Description
+    /// Scopes of the API the scope gives access to, the same ones personal access tokens are
+    /// checked against. Clients act on what the user can reach, but never manage the user's
+    /// password, account or credentials
+    pub fn token_scopes(&self) -> &'static [TokenScope] {
+        match self {
+            OAuthScope::Admin => &[TokenScope::ReadSelf, TokenScope::AdminUsers],
+            OAuthScope::Read | OAuthScope::Write
+                | OAuthScope::OpenId | OAuthScope::Profile | OAuthScope::Email => &[TokenScope::ReadSelf]
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            OAuthScope::Read => "read",
+            OAuthScope::Write => "write",
+            OAuthScope::Admin => "admin",
+            OAuthScope::OpenId => "openid",
+            OAuthScope::Profile => "profile",
+            OAuthScope::Email => "email"
+        }
+    }
+
+    fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "read" => Some(OAuthScope::Read),
+            "write" => Some(OAuthScope::Write),
+            "admin" => Some(OAuthScope::Admin),
+            "openid" => Some(OAuthScope::OpenId),
+            "profile" => Some(OAuthScope::Profile),
+            "email" => Some(OAuthScope::Email),
+            _ => None
+        }
+    }
+}
+
+/// ## Description
+/// What an OAuth access token was granted, recorded by the authentication so the scope guards
+/// check the requests made with it
+#[derive(Debug, Clone, PartialEq)]
+pub struct OAuthGrant {
+    oauth_clients_id: OAuthClientsIdType,
+    scopes: Vec<OAuthScope>
+}
+
+impl OAuthGrant {
+    pub fn new(oauth_clients_id: OAuthClientsIdType, scopes: &[OAuthScope]) -> Self {
+        Self { oauth_clients_id, scopes: scopes.to_vec() }
+    }
+
+    /// Whether any of the scopes granted gives access to the scope of the API
+    pub fn has_scope(&self, scope: TokenScope) -> bool {
+        self.scopes.iter().any(|granted| granted.token_scopes().contains(&scope))
+    }
+
+    pub fn get_oauth_clients_id(&self) -> &OAuthClientsIdType {
+        &self.oauth_clients_id
+    }
+
+    pub fn get_scopes(&self) -> &[OAuthScope] {
+        &self.scopes
+    }
+}
+
+/// Highest level that can be acted with given the scopes
+pub fn scopes_level(scopes: &[OAuthScope]) -> Level {
+    scopes.iter()
+        .map(|scope| scope.max_level())
+        .max()
+        .unwrap_or_default()
+}
+
+/// Formats the scopes the way OAuth expects them, separated by spaces
+pub fn scopes_to_string(scopes: &[OAuthScope]) -> String {
+    scopes.iter()
+        .map(|scope| scope.as_str())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Parses a space separated list of scopes. Returns None if any of them is unknown, since a
+/// client must not be granted less than it asked for without noticing
+pub fn scopes_from_string(scopes: &str) -> Option<Vec<OAuthScope>> {
+    let mut parsed: Vec<OAuthScope> = vec![];
+    for scope in scopes.split_whitespace() {
+        let scope = OAuthScope::parse(scope)?;
+        if !parsed.contains(&scope) {
+            parsed.push(scope);
+        }
+    }
+    Some(parsed)
+}
+
+/// Whether every scope requested is included in the scopes granted
+pub fn scopes_cover(granted: &[OAuthScope], requested: &[OAuthScope]) -> bool {
+    requested.iter().all(|scope| granted.contains(scope))
+}
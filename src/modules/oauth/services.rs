@@ -0,0 +1,904 @@
+
+use actix_web::{get, HttpRequest, HttpResponse, post, ResponseError, web};
+use actix_web::cookie::Cookie;
+use actix_web::http::header::{CACHE_CONTROL, LOCATION, WWW_AUTHENTICATE};
+use actix_web::http::StatusCode;
+use chrono::NaiveDateTime;
+use error_mapper::TheResult;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use crate::api::authentication::RequireScope;
+use crate::general;
+use crate::auth::jwt::SigningKey;
+use crate::auth::policy::{Action, Policy, PolicyRequest};
+use crate::general::api_error::ApiError;
+use crate::general::http_req_res::json_response;
+use crate::general::problem::ErrorCode;
+use crate::general::types::UsersIdType;
+use crate::modules::oauth::{client, functions, oidc, scope};
+use crate::modules::oauth::authorization_code::{AuthorizationCode, PkceMethod};
+use crate::modules::oauth::client::OAuthClient;
+use crate::modules::oauth::consent::Consent;
+use crate::modules::oauth::oidc::{DiscoveryDocument, UserInfo};
+use crate::modules::oauth::scope::OAuthScope;
+use crate::modules::oauth::token::{ACCESS_TOKEN_LIFETIME_SECONDS, OAuthToken};
+use crate::modules::users;
+use crate::modules::users::user::{Level, User};
+use crate::modules::users::users_sessions;
+use crate::modules::users::UsersSessions;
+use crate::modules::personal_tokens::personal_token::TokenScope;
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct PostOAuthClient {
+    name: String,
+    redirect_uris: Vec<String>,
+    scopes: Vec<OAuthScope>,
+    confidential: bool,
+    #[schema(value_type = Option<u32>)]
+    service_account_id: Option<UsersIdType>,
+    service_account_username: Option<String>,
+    #[serde(default)]
+    revokes_sessions: bool
+}
+
+#[derive(Serialize)]
+struct OAuthClientCreated {
+    client_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret: Option<String>
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AuthorizationRequest {
+    response_type: String,
+    client_id: String,
+    redirect_uri: String,
+    scope: Option<String>,
+    state: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<PkceMethod>,
+    nonce: Option<String>
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct AuthorizationDecision {
+    #[serde(flatten)]
+    authorization: AuthorizationRequest,
+    username: Option<String>,
+    password: Option<String>,
+    #[serde(default)]
+    consent: bool
+}
+
+#[derive(Serialize)]
+struct AuthorizationPrompt {
+    client_name: String,
+    scope: String,
+    login_required: bool,
+    consent_required: bool
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct TokenRequest {
+    grant_type: String,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    code_verifier: Option<String>,
+    refresh_token: Option<String>,
+    scope: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id_token: Option<String>,
+    scope: String
+}
+
+/// Body of both the introspection and the revocation requests
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct TokenHintRequest {
+    token: String,
+    #[allow(dead_code)]
+    token_type_hint: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>
+}
+
+/// Inactive tokens are only reported as such, without any other information about them
+#[derive(Serialize, Default)]
+struct IntrospectionResponse {
+    active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    level: Option<Level>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iat: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_type: Option<&'static str>
+}
+
+#[derive(Serialize)]
+struct OAuthError {
+    error: &'static str,
+    error_description: String
+}
+
+/// Authorization request already checked against the client registration
+struct ValidAuthorization {
+    client: OAuthClient,
+    scopes: Vec<OAuthScope>,
+    code_challenge: String,
+    code_challenge_method: PkceMethod
+}
+
+/// ##  Endpoint create OAuth client
+/// POST {UTAUrl}:{UTAPort}/internal/create_oauth_client (private)
+///
+/// #### Required Body
+/// - name: ans-50 max string, shown to users when asked for their consent
+/// - redirect_uris: list of URIs the client can receive authorization codes in
+/// - scopes: list of "read", "write" or "admin", the scopes the client can request
+/// - confidential: bool, whether the client can keep a secret
+/// - service_account_id (optional): optional u32, service account the client acts as in the
+/// client_credentials grant
+/// - service_account_username (optional): optional ans-20 max string, same as above
+/// - revokes_sessions (optional): bool, whether the client can revoke session tokens. False if
+/// not present
+///
+/// ### Description
+/// Registers an OAuth client. The client_id and, for confidential clients, the client_secret are
+/// included in the response. The secret can't be retrieved again, only its hash is stored. Linking
+/// a service account requires the same privileges as managing its API keys, and letting a
+/// confidential client revoke sessions is limited to Super users by the default policy
+#[utoipa::path(
+    tag = "OAuth",
+    summary = "Registers an OAuth client",
+    responses(
+        (status = 201, description = "Client registered, the secret is only included here")
+    )
+)]
+#[post("/create_oauth_client", wrap = "RequireScope::new(TokenScope::AdminUsers)")]
+async fn create_oauth_client(request: HttpRequest, body: web::Json<PostOAuthClient>) -> Result<HttpResponse, ApiError> {
+
+    let client_data = body.into_inner();
+
+    let user = users::functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
+
+    if !Policy::instance().evaluate(&PolicyRequest::new(Action::CreateOAuthClient, &user).await).await.is_allowed() {
+        return Err(ApiError::new(ErrorCode::Forbidden, "User lacks the privileges to register OAuth clients"))
+    }
+
+    if client_data.redirect_uris.is_empty() || client_data.scopes.is_empty() {
+        return Err(ApiError::new(ErrorCode::InvalidRequest, "At least one redirect URI and one scope are required"))
+    }
+    if !client_data.redirect_uris.iter().all(|redirect_uri| client::is_valid_redirect_uri(redirect_uri)) {
+        return Err(ApiError::new(ErrorCode::InvalidRedirectUri, "Invalid redirect URI"))
+    }
+
+    let service_account_id = if client_data.service_account_id.is_some() || client_data.service_account_username.is_some() {
+        let service_account = match users::functions::get_user_by_id_or_username(
+            client_data.service_account_id,
+            client_data.service_account_username
+        ).await? {
+            Some(service_account) if service_account.is_service_account() => service_account,
+            _ => return Err(ApiError::new(ErrorCode::InvalidServiceAccount, "Invalid service account"))
+        };
+
+        let request = PolicyRequest::new(Action::ManageApiKeys, &user).await
+            .with_resource_level(*service_account.get_level());
+        if !Policy::instance().evaluate(&request).await.is_allowed() {
+            return Err(ApiError::new(ErrorCode::Forbidden, "User lacks the privileges to manage this service account"))
+        }
+
+        Some(*service_account.get_id())
+    } else {
+        None
+    };
+
+    if client_data.revokes_sessions {
+        if !client_data.confidential {
+            return Err(ApiError::new(ErrorCode::InvalidRequest, "Only confidential clients can revoke sessions"))
+        }
+        if !Policy::instance().evaluate(&PolicyRequest::new(Action::CreateSessionRevokingClient, &user).await).await.is_allowed() {
+            return Err(ApiError::new(ErrorCode::Forbidden, "User lacks the privileges to let clients revoke sessions"))
+        }
+    }
+
+    let (client, client_secret) = OAuthClient::create_oauth_client(
+        client_data.name.as_str(),
+        client_data.redirect_uris.as_slice(),
+        client_data.scopes.as_slice(),
+        client_data.confidential,
+        service_account_id,
+        client_data.revokes_sessions
+    ).await?;
+
+    let client_created = OAuthClientCreated {
+        client_id: client.get_client_id().to_string(),
+        client_secret
+    };
+
+    Ok(json_response(StatusCode::CREATED, general::http_req_res::serialize_into_json(&client_created)?))
+}
+
+/// ##  Endpoint authorize
+/// GET {UTAUrl}:{UTAPort}/oauth/authorize (public)
+///
+/// #### Required Query Parameters
+/// - response_type: must be "code"
+/// - client_id: ans-24 string
+/// - redirect_uri: one of the client's registered redirect URIs
+/// - scope (optional): space separated scopes, all the client's scopes if not present
+/// - state (optional): opaque value returned to the client in the redirect
+/// - code_challenge: PKCE challenge
+/// - code_challenge_method (optional): "S256" (default) or "plain"
+/// - nonce (optional): opaque value included in the ID token, when the openid scope is requested
+///
+/// ### Description
+/// Starts the authorization code flow. If the user has a browser session and already consented
+/// to the scopes requested, redirects to the client with the authorization code. Otherwise
+/// responds with what the user must be prompted for, login and/or consent, which are sent back
+/// to the POST version of this endpoint
+#[utoipa::path(
+    tag = "OAuth",
+    summary = "Starts an authorization request",
+    params(AuthorizationRequest),
+    responses(
+        (status = 200, description = "Login or consent is required"),
+        (status = 302, description = "Redirect to the client with a code or an error")
+    )
+)]
+#[get("/authorize")]
+async fn authorize(request: HttpRequest, query: web::Query<AuthorizationRequest>) -> Result<HttpResponse, ApiError> {
+
+    let authorization = query.into_inner();
+
+    let valid = match validate_authorization_request(&authorization).await {
+        Ok(valid) => valid,
+        Err(response) => return Ok(response)
+    };
+
+    let user = functions::get_user_from_browser_session(&request).await?;
+
+    let consent_required = match &user {
+        Some(user) => !Consent::select(user.get_id(), valid.client.get_id()).await?
+            .is_some_and(|consent| consent.covers(valid.scopes.as_slice())),
+        None => true
+    };
+
+    match user {
+        Some(user) if !consent_required => {
+            issue_authorization_code(&authorization, &valid, &user, vec![]).await
+        },
+        _ => {
+            let prompt = AuthorizationPrompt {
+                client_name: valid.client.get_name().to_string(),
+                scope: scope::scopes_to_string(valid.scopes.as_slice()),
+                login_required: user.is_none(),
+                consent_required
+            };
+
+            Ok(json_response(StatusCode::OK, general::http_req_res::serialize_into_json(&prompt)?))
+        }
+    }
+}
+
+/// ##  Endpoint authorize decision
+/// POST {UTAUrl}:{UTAPort}/oauth/authorize (public)
+///
+/// #### Required Body
+/// - Same parameters as the GET version of this endpoint
+/// - username (optional): ans-20 max string, required if there's no browser session
+/// - password (optional): ans-30 max string, required if there's no browser session
+/// - consent: bool, whether the user grants the scopes requested to the client
+///
+/// ### Description
+/// Completes the authorization code flow. Logs the user in with the same credentials check as
+/// the login endpoint, opening a browser session kept in cookies, records the consent and
+/// redirects to the client with the authorization code. If the user denied consent, redirects to
+/// the client with an access_denied error
+#[utoipa::path(
+    tag = "OAuth",
+    summary = "Completes an authorization request",
+    responses(
+        (status = 302, description = "Redirect to the client with a code or an error")
+    )
+)]
+#[post("/authorize")]
+async fn authorize_decision(request: HttpRequest, body: web::Json<AuthorizationDecision>) -> Result<HttpResponse, ApiError> {
+
+    let decision = body.into_inner();
+
+    let valid = match validate_authorization_request(&decision.authorization).await {
+        Ok(valid) => valid,
+        Err(response) => return Ok(response)
+    };
+
+    let mut cookies = vec![];
+    let user = if let (Some(username), Some(password)) = (decision.username, decision.password) {
+        let user = User::select_by_username(username.as_str()).await?
+            .ok_or_else(|| ApiError::new(ErrorCode::InvalidCredentials, "Invalid username or password"))?;
+
+        if !user.validate_hashed_password(password.as_str()) {
+            return Err(ApiError::new(ErrorCode::InvalidCredentials, "Invalid username or password"))
+        }
+
+        let client = users::functions::get_client_from_request(&request).await;
+        let session_token = users::functions::open_user_session(&user, &client).await?;
+        cookies = functions::browser_session_cookies(
+            user.get_username(),
+            session_token.as_str(),
+            general::http_req_res::scope_path(&request).as_str()
+        );
+
+        user
+    } else {
+        functions::get_user_from_browser_session(&request).await?
+            .ok_or_else(|| ApiError::new(ErrorCode::LoginRequired, "Login required"))?
+    };
+
+    if decision.consent {
+        Consent::grant(user.get_id(), valid.client.get_id(), valid.scopes.as_slice()).await?;
+    } else {
+        //  Without a new consent, a previous one must cover the scopes requested
+        match Consent::select(user.get_id(), valid.client.get_id()).await? {
+            Some(consent) if consent.covers(valid.scopes.as_slice()) => {},
+            _ => return Ok(redirect_error(&decision.authorization, "access_denied", cookies))
+        }
+    }
+
+    issue_authorization_code(&decision.authorization, &valid, &user, cookies).await
+}
+
+/// ##  Endpoint token
+/// POST {UTAUrl}:{UTAPort}/oauth/token (public)
+///
+/// #### Required Form (application/x-www-form-urlencoded)
+/// - grant_type: "authorization_code", "refresh_token" or "client_credentials"
+/// - code, redirect_uri and code_verifier: for the authorization_code grant
+/// - refresh_token: for the refresh_token grant
+/// - scope (optional): space separated scopes, to narrow down the scopes of the new token
+/// - client_id and client_secret (optional): if the client doesn't authenticate with HTTP Basic.
+/// Public clients only send their client_id
+///
+/// ### Description
+/// Issues access tokens, to be sent as `Authorization: Bearer <token>`. Tokens issued for users
+/// come with a refresh token, which is rotated every time it's used, and with a signed ID token
+/// if the openid scope was granted. Tokens never act with a level above the highest of their scopes
+#[utoipa::path(
+    tag = "OAuth",
+    summary = "Issues tokens",
+    request_body(content = TokenRequest, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "Tokens issued"),
+        (status = 400, description = "OAuth error, as RFC 6749 defines it")
+    )
+)]
+#[post("/token")]
+async fn token(request: HttpRequest, form: web::Form<TokenRequest>) -> Result<HttpResponse, ApiError> {
+
+    let token_request = form.into_inner();
+
+    let client = match functions::authenticate_client(
+        &request,
+        token_request.client_id.as_deref(),
+        token_request.client_secret.as_deref()
+    ).await? {
+        Some(client) => client,
+        None => return Ok(oauth_error(StatusCode::UNAUTHORIZED, "invalid_client", "Client authentication failed"))
+    };
+
+    match token_request.grant_type.as_str() {
+        "authorization_code" => authorization_code_grant(&client, token_request).await,
+        "refresh_token" => refresh_token_grant(&client, token_request).await,
+        "client_credentials" => client_credentials_grant(&client, token_request).await,
+        _ => Ok(oauth_error(StatusCode::BAD_REQUEST, "unsupported_grant_type", "Unsupported grant type"))
+    }
+}
+
+/// ##  Endpoint introspect
+/// POST {UTAUrl}:{UTAPort}/oauth/introspect (private)
+///
+/// #### Required Form (application/x-www-form-urlencoded)
+/// - token: session token, OAuth access token or OAuth refresh token
+/// - token_type_hint (optional): accepted but not needed, the token itself tells its type
+/// - client_id and client_secret (optional): if the client doesn't authenticate with HTTP Basic
+///
+/// ### Description
+/// Lets other services validate tokens issued here without access to the database, as described
+/// in RFC 7662. Only confidential clients can introspect tokens. Active tokens are described with
+/// their user's id (sub), username and level, along with their expiry (exp) and issue (iat) times.
+/// The level is the effective level the token acts with, capped by its scopes for OAuth tokens
+#[utoipa::path(
+    tag = "OAuth",
+    summary = "Introspects a token",
+    request_body(content = TokenHintRequest, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "Token information, or only inactive"),
+        (status = 400, description = "OAuth error, as RFC 6749 defines it")
+    )
+)]
+#[post("/introspect")]
+async fn introspect(request: HttpRequest, form: web::Form<TokenHintRequest>) -> Result<HttpResponse, ApiError> {
+
+    let token_request = form.into_inner();
+
+    if authenticate_confidential_client(&request, &token_request).await?.is_none() {
+        return Ok(oauth_error(StatusCode::UNAUTHORIZED, "invalid_client", "Client authentication failed"))
+    }
+
+    let introspection = introspect_token(token_request.token.as_str()).await?.unwrap_or_default();
+
+    Ok(json_response(StatusCode::OK, general::http_req_res::serialize_into_json(&introspection)?))
+}
+
+/// ##  Endpoint revoke
+/// POST {UTAUrl}:{UTAPort}/oauth/revoke (private)
+///
+/// #### Required Form (application/x-www-form-urlencoded)
+/// - token: session token, OAuth access token or OAuth refresh token
+/// - token_type_hint (optional): accepted but not needed, the token itself tells its type
+/// - client_id and client_secret (optional): if the client doesn't authenticate with HTTP Basic
+///
+/// ### Description
+/// Revokes a token, as described in RFC 7009. Only confidential clients can revoke tokens, and
+/// OAuth tokens can only be revoked by the client they were issued to. Session tokens can only be
+/// revoked by clients registered to revoke sessions, any other client gets the
+/// unsupported_token_type error. Revoking a session token logs the user out, the same way the
+/// logout endpoint does. Revoking an access token also revokes
+/// its refresh token, and the other way around. Unknown or already invalid tokens are not an
+/// error, the response is the same as if they were revoked
+#[utoipa::path(
+    tag = "OAuth",
+    summary = "Revokes a token",
+    request_body(content = TokenHintRequest, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "Token revoked, or it was not valid"),
+        (status = 400, description = "OAuth error, as RFC 6749 defines it")
+    )
+)]
+#[post("/revoke")]
+async fn revoke(request: HttpRequest, form: web::Form<TokenHintRequest>) -> Result<HttpResponse, ApiError> {
+
+    let token_request = form.into_inner();
+    let revoked_token = token_request.token.as_str();
+
+    let Some(client) = authenticate_confidential_client(&request, &token_request).await? else {
+        return Ok(oauth_error(StatusCode::UNAUTHORIZED, "invalid_client", "Client authentication failed"))
+    };
+
+    let oauth_token = match OAuthToken::select_by_access_token(revoked_token).await? {
+        Some(oauth_token) => Some(oauth_token),
+        None => OAuthToken::select_by_refresh_token(revoked_token).await?
+    };
+
+    match oauth_token {
+        Some(oauth_token) => {
+            if !oauth_token.validate_access_token(revoked_token) && !oauth_token.validate_refresh_token(revoked_token) {
+                return Ok(HttpResponse::Ok().finish())
+            }
+            if oauth_token.get_oauth_clients_id() != client.get_id() {
+                return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_request", "Token wasn't issued to this client"))
+            }
+            oauth_token.revoke().await?;
+        },
+        None if client.revokes_sessions() => {
+            users::functions::revoke_session_token(revoked_token).await?;
+        },
+        None => {
+            return Ok(oauth_error(StatusCode::BAD_REQUEST, "unsupported_token_type", "Client can't revoke session tokens"))
+        }
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// ##  Endpoint userinfo
+/// GET {UTAUrl}:{UTAPort}/oauth/userinfo (private)
+///
+/// #### Required Headers
+/// - Authorization: Bearer access token granted with the openid scope
+///
+/// ### Description
+/// Responds with the claims about the user the access token was issued for. The profile claims
+/// (preferred_username, level, the profile attributes and the metadata registered as claims)
+/// require the profile scope, and the email the email scope
+#[utoipa::path(
+    tag = "OAuth",
+    summary = "Claims of the user an access token belongs to",
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "User claims"),
+        (status = 401, description = "Invalid token, as RFC 6750 defines it")
+    )
+)]
+#[get("/userinfo")]
+async fn userinfo(request: HttpRequest) -> Result<HttpResponse, ApiError> {
+
+    let access_token = request.headers().get("authorization")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "));
+    let Some(access_token) = access_token else {
+        return Ok(bearer_error("invalid_request"))
+    };
+
+    let oauth_token = match OAuthToken::select_by_access_token(access_token).await? {
+        Some(oauth_token) if oauth_token.validate_access_token(access_token) => oauth_token,
+        _ => return Ok(bearer_error("invalid_token"))
+    };
+
+    if !oauth_token.get_scopes().contains(&OAuthScope::OpenId) {
+        return Ok(bearer_error("insufficient_scope"))
+    }
+
+    let user = match User::select_by_id(oauth_token.get_user_id()).await? {
+        Some(user) => user,
+        None => return Ok(bearer_error("invalid_token"))
+    };
+
+    let user_info = UserInfo::new(&user, oauth_token.get_scopes()).await?;
+
+    Ok(json_response(StatusCode::OK, general::http_req_res::serialize_into_json(&user_info)?))
+}
+
+/// ##  Endpoint JWKS
+/// GET {UTAUrl}:{UTAPort}/oauth/jwks (public)
+///
+/// ### Description
+/// Responds with the public keys the ID tokens are signed with, for relying parties to verify them
+#[utoipa::path(
+    tag = "OAuth",
+    summary = "Public keys the ID tokens are signed with",
+    responses(
+        (status = 200, description = "JSON web key set")
+    )
+)]
+#[get("/jwks")]
+async fn jwks() -> Result<HttpResponse, ApiError> {
+    let jwks = SigningKey::instance().and_then(SigningKey::jwks)?;
+
+    Ok(json_response(StatusCode::OK, general::http_req_res::serialize_into_json(&jwks)?))
+}
+
+/// ##  Endpoint OpenID configuration
+/// GET {UTAUrl}:{UTAPort}/.well-known/openid-configuration (public)
+///
+/// ### Description
+/// OpenID Connect discovery document, with the endpoints and capabilities of this provider
+#[utoipa::path(
+    tag = "OAuth",
+    summary = "OpenID Connect discovery document",
+    responses(
+        (status = 200, description = "Discovery document")
+    )
+)]
+#[get("/openid-configuration")]
+async fn openid_configuration() -> Result<HttpResponse, ApiError> {
+    Ok(json_response(StatusCode::OK, general::http_req_res::serialize_into_json(&DiscoveryDocument::new().await)?))
+}
+
+async fn authorization_code_grant(client: &OAuthClient, token_request: TokenRequest) -> Result<HttpResponse, ApiError> {
+
+    let (Some(code), Some(redirect_uri), Some(code_verifier)) = (
+        token_request.code, token_request.redirect_uri, token_request.code_verifier
+    ) else {
+        return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_request", "code, redirect_uri and code_verifier are required"))
+    };
+
+    let authorization_code = match AuthorizationCode::select_by_code(code.as_str()).await? {
+        Some(authorization_code) => authorization_code,
+        None => return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_grant", "Invalid authorization code"))
+    };
+
+    if !authorization_code.is_valid_for(client.get_id(), redirect_uri.as_str())
+        || !authorization_code.verify_code_challenge(code_verifier.as_str()) {
+        return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_grant", "Invalid authorization code"))
+    }
+
+    if !authorization_code.consume().await? {
+        return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_grant", "Invalid authorization code"))
+    }
+
+    issue_token(
+        client,
+        authorization_code.get_user_id(),
+        authorization_code.get_scopes(),
+        true,
+        authorization_code.get_nonce()
+    ).await
+}
+
+async fn refresh_token_grant(client: &OAuthClient, token_request: TokenRequest) -> Result<HttpResponse, ApiError> {
+
+    let Some(refresh_token) = token_request.refresh_token else {
+        return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_request", "refresh_token is required"))
+    };
+
+    let oauth_token = match OAuthToken::select_by_refresh_token(refresh_token.as_str()).await? {
+        Some(oauth_token) => oauth_token,
+        None => return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_grant", "Invalid refresh token"))
+    };
+
+    if oauth_token.get_oauth_clients_id() != client.get_id() || !oauth_token.validate_refresh_token(refresh_token.as_str()) {
+        return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_grant", "Invalid refresh token"))
+    }
+
+    //  The new token can narrow down the scopes, never widen them
+    let scopes = match token_request.scope.as_deref().map(scope::scopes_from_string) {
+        Some(Some(scopes)) if !scopes.is_empty() && scope::scopes_cover(oauth_token.get_scopes(), scopes.as_slice()) => scopes,
+        Some(_) => return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_scope", "Invalid scope")),
+        None => oauth_token.get_scopes().to_vec()
+    };
+
+    //  Refresh tokens are rotated, the previous one can't be used again
+    if !oauth_token.revoke().await? {
+        return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_grant", "Invalid refresh token"))
+    }
+
+    issue_token(client, oauth_token.get_user_id(), scopes.as_slice(), true, None).await
+}
+
+async fn client_credentials_grant(client: &OAuthClient, token_request: TokenRequest) -> Result<HttpResponse, ApiError> {
+
+    //  Only confidential clients linked to a service account can act on their own behalf
+    let (true, Some(service_account_id)) = (client.is_confidential(), client.get_service_account_id()) else {
+        return Ok(oauth_error(StatusCode::BAD_REQUEST, "unauthorized_client", "Client can't use the client_credentials grant"))
+    };
+
+    let scopes = match token_request.scope.as_deref().map(scope::scopes_from_string) {
+        Some(Some(scopes)) if !scopes.is_empty() && scope::scopes_cover(client.get_scopes(), scopes.as_slice()) => scopes,
+        Some(_) => return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_scope", "Invalid scope")),
+        None => client.get_scopes().to_vec()
+    };
+
+    issue_token(client, service_account_id, scopes.as_slice(), false, None).await
+}
+
+async fn issue_token(
+    client: &OAuthClient,
+    user_id: &UsersIdType,
+    scopes: &[OAuthScope],
+    with_refresh_token: bool,
+    nonce: Option<&str>
+) -> Result<HttpResponse, ApiError> {
+
+    //  Deleted users can't get new tokens
+    let user = match User::select_by_id(user_id).await? {
+        Some(user) => user,
+        None => return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_grant", "User not found"))
+    };
+
+    //  Service accounts have no identity to assert, so they never get ID tokens
+    let id_token = if scopes.contains(&OAuthScope::OpenId) && !user.is_service_account() {
+        Some(oidc::create_id_token(&user, client.get_client_id(), scopes, nonce.map(|nonce| nonce.to_string())).await?)
+    } else {
+        None
+    };
+
+    let (_, access_token, refresh_token) = OAuthToken::create_oauth_token(
+        client.get_id(),
+        user_id,
+        scopes,
+        with_refresh_token
+    ).await?;
+
+    let token_response = TokenResponse {
+        access_token,
+        token_type: "Bearer",
+        expires_in: ACCESS_TOKEN_LIFETIME_SECONDS,
+        refresh_token,
+        id_token,
+        scope: scope::scopes_to_string(scopes)
+    };
+
+    let body = general::http_req_res::serialize_into_json(&token_response)?;
+
+    Ok(
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .insert_header((CACHE_CONTROL, "no-store"))
+            .body(body)
+    )
+}
+
+/// ## Description
+/// Checks the authorization request against the client registration. Errors found before the
+/// redirect URI is verified are responded directly, never redirected, so an unregistered URI
+/// can't receive anything. Later errors are redirected to the client
+async fn validate_authorization_request(authorization: &AuthorizationRequest) -> Result<ValidAuthorization, HttpResponse> {
+
+    let client = match OAuthClient::select_by_client_id(authorization.client_id.as_str()).await {
+        Ok(Some(client)) => client,
+        Ok(None) => return Err(ApiError::new(ErrorCode::InvalidClient, "Invalid client_id").error_response()),
+        Err(e) => return Err(ApiError::from(e).error_response())
+    };
+
+    if !client.allows_redirect_uri(authorization.redirect_uri.as_str()) {
+        return Err(ApiError::new(ErrorCode::InvalidRedirectUri, "Invalid redirect_uri").error_response())
+    }
+
+    if authorization.response_type != "code" {
+        return Err(redirect_error(authorization, "unsupported_response_type", vec![]))
+    }
+
+    //  PKCE is required for every client, confidential or not
+    let Some(code_challenge) = authorization.code_challenge.clone() else {
+        return Err(redirect_error(authorization, "invalid_request", vec![]))
+    };
+
+    let scopes = match authorization.scope.as_deref().map(scope::scopes_from_string) {
+        Some(Some(scopes)) if !scopes.is_empty() && scope::scopes_cover(client.get_scopes(), scopes.as_slice()) => scopes,
+        Some(_) => return Err(redirect_error(authorization, "invalid_scope", vec![])),
+        None => client.get_scopes().to_vec()
+    };
+
+    Ok(ValidAuthorization {
+        client,
+        scopes,
+        code_challenge,
+        code_challenge_method: authorization.code_challenge_method.unwrap_or_default()
+    })
+}
+
+async fn issue_authorization_code(
+    authorization: &AuthorizationRequest,
+    valid: &ValidAuthorization,
+    user: &User,
+    cookies: Vec<Cookie<'static>>
+) -> Result<HttpResponse, ApiError> {
+
+    let (_, code) = AuthorizationCode::create_authorization_code(
+        valid.client.get_id(),
+        user.get_id(),
+        authorization.redirect_uri.as_str(),
+        valid.scopes.as_slice(),
+        valid.code_challenge.as_str(),
+        valid.code_challenge_method,
+        authorization.nonce.as_deref()
+    ).await?;
+
+    Ok(redirect(authorization, &[("code", code.as_str())], cookies))
+}
+
+fn redirect_error(authorization: &AuthorizationRequest, error: &str, cookies: Vec<Cookie<'static>>) -> HttpResponse {
+    redirect(authorization, &[("error", error)], cookies)
+}
+
+/// Redirects to the client with the parameters, returning the state it sent if any
+fn redirect(authorization: &AuthorizationRequest, params: &[(&str, &str)], cookies: Vec<Cookie<'static>>) -> HttpResponse {
+
+    let mut params = params.to_vec();
+    if let Some(state) = authorization.state.as_deref() {
+        params.push(("state", state));
+    }
+
+    let mut response = HttpResponse::Found();
+    response.insert_header((LOCATION, functions::redirect_uri_with(authorization.redirect_uri.as_str(), params.as_slice())));
+    for cookie in cookies {
+        response.cookie(cookie);
+    }
+    response.finish()
+}
+
+/// Authenticates the client calling the introspection and revocation endpoints, which are only
+/// available to confidential clients. Returns None if the client failed to authenticate
+async fn authenticate_confidential_client(
+    request: &HttpRequest,
+    token_request: &TokenHintRequest
+) -> TheResult<Option<OAuthClient>> {
+    let client = functions::authenticate_client(
+        request,
+        token_request.client_id.as_deref(),
+        token_request.client_secret.as_deref()
+    ).await?;
+
+    Ok(client.filter(|client| client.is_confidential()))
+}
+
+/// ## Description
+/// Describes the token if it's active. Session tokens are looked up in the users sessions, and
+/// OAuth tokens by their prefix. Returns None if the token is unknown, expired or revoked, or its
+/// user no longer exists
+async fn introspect_token(introspected_token: &str) -> TheResult<Option<IntrospectionResponse>> {
+
+    let access_token = OAuthToken::select_by_access_token(introspected_token).await?;
+    let refresh_token = match access_token {
+        Some(_) => None,
+        None => OAuthToken::select_by_refresh_token(introspected_token).await?
+    };
+
+    let (user_id, issued_at, expires_at, scopes, token_type): (UsersIdType, NaiveDateTime, NaiveDateTime, Option<Vec<OAuthScope>>, &'static str) =
+        match (access_token, refresh_token) {
+            (Some(oauth_token), _) => {
+                if !oauth_token.validate_access_token(introspected_token) {
+                    return Ok(None)
+                }
+                (
+                    *oauth_token.get_user_id(),
+                    *oauth_token.get_created_at(),
+                    *oauth_token.get_expires_at(),
+                    Some(oauth_token.get_scopes().to_vec()),
+                    "access_token"
+                )
+            },
+            (None, Some(oauth_token)) => {
+                let Some(refresh_expires_at) = oauth_token.get_refresh_expires_at() else {
+                    return Ok(None)
+                };
+                if !oauth_token.validate_refresh_token(introspected_token) {
+                    return Ok(None)
+                }
+                (
+                    *oauth_token.get_user_id(),
+                    *oauth_token.get_created_at(),
+                    *refresh_expires_at,
+                    Some(oauth_token.get_scopes().to_vec()),
+                    "refresh_token"
+                )
+            },
+            (None, None) => {
+                let Some(session) = users_sessions::select_session_by_token(introspected_token).await? else {
+                    return Ok(None)
+                };
+                if !session.is_active() {
+                    return Ok(None)
+                }
+                (*session.get_user_id(), *session.get_creation(), *session.get_expiry(), None, "session_token")
+            }
+        };
+
+    let Some(mut user) = User::select_by_id(&user_id).await? else {
+        return Ok(None)
+    };
+    if let Some(scopes) = &scopes {
+        user.set_scope_level(scope::scopes_level(scopes.as_slice()));
+    }
+
+    Ok(Some(IntrospectionResponse {
+        active: true,
+        sub: Some(user.get_id().to_string()),
+        username: Some(user.get_username().to_string()),
+        level: Some(UsersSessions::instance().get_effective_level(&user).await),
+        exp: Some(expires_at.and_utc().timestamp()),
+        iat: Some(issued_at.and_utc().timestamp()),
+        scope: scopes.as_deref().map(scope::scopes_to_string),
+        token_type: Some(token_type)
+    }))
+}
+
+/// Bearer token errors are reported in the WWW-Authenticate header, as RFC 6750 requires
+fn bearer_error(error: &str) -> HttpResponse {
+    let status_code = match error {
+        "invalid_request" => StatusCode::BAD_REQUEST,
+        "insufficient_scope" => StatusCode::FORBIDDEN,
+        _ => StatusCode::UNAUTHORIZED
+    };
+    HttpResponse::build(status_code)
+        .insert_header((WWW_AUTHENTICATE, format!("Bearer error=\"{}\"", error)))
+        .finish()
+}
+
+fn oauth_error(status_code: StatusCode, error: &'static str, description: &str) -> HttpResponse {
+    let oauth_error = OAuthError {
+        error,
+        error_description: description.to_string()
+    };
+    json_response(status_code, general::http_req_res::serialize_into_json(&oauth_error).unwrap_or_default())
+}
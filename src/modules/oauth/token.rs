@@ -0,0 +1,240 @@
+use std::ops::Add;
+use chrono::NaiveDateTime;
+use error_mapper::{map_to_new_error, TheResult};
+use mysql_async::prelude::{FromRow, Queryable};
+use mysql_async::{FromRowError, Row};
+use crate::{auth, database, row_to_data, row_to_naive_datetime, row_to_optional_naive_datetime};
+use crate::database::db_conn::get_conn;
+use crate::general::types::{OAuthClientsIdType, OAuthTokensIdType, UsersIdType};
+use crate::modules::oauth::scope;
+use crate::modules::oauth::scope::OAuthScope;
+
+/// Every access token starts with this, so the middleware can tell them apart from personal tokens
+pub const ACCESS_TOKEN_HEADER: &str = "uto";
+const REFRESH_TOKEN_HEADER: &str = "utr";
+
+pub const ACCESS_TOKEN_LIFETIME_SECONDS: i64 = 3600;
+const REFRESH_TOKEN_LIFETIME_DAYS: i64 = 30;
+
+/// ## Description
+/// Access token issued to a client, along with its refresh token when the grant allows one. The
+/// token acts on behalf of the user that authorized the client or, for the client_credentials
+/// grant, of the service account linked to the client
+#[derive(Debug, Default, Clone)]
+pub struct OAuthToken {
+    id: OAuthTokensIdType,
+    oauth_clients_id: OAuthClientsIdType,
+    users_id: UsersIdType,
+    access_prefix: String,
+    hashed_access_token: String,
+    refresh_prefix: Option<String>,
+    hashed_refresh_token: Option<String>,
+    scopes: Vec<OAuthScope>,
+    created_at: NaiveDateTime,
+    expires_at: NaiveDateTime,
+    refresh_expires_at: Option<NaiveDateTime>,
+    revoked_at: Option<NaiveDateTime>
+}
+
+impl OAuthToken {
+
+    /// ## Description
+    /// Issues an access token, and a refresh token if requested. Returns the token along with the
+    /// full access and refresh tokens, only their hashes are stored
+    pub async fn create_oauth_token(
+        oauth_clients_id: &OAuthClientsIdType,
+        user_id: &UsersIdType,
+        scopes: &[OAuthScope],
+        with_refresh_token: bool
+    ) -> TheResult<(Self, String, Option<String>)> {
+
+        let now = chrono::Utc::now().naive_utc();
+
+        let (access_prefix, access_token) = auth::crypt::generate_prefixed_key(ACCESS_TOKEN_HEADER)?;
+        let (refresh_prefix, refresh_token) = match with_refresh_token {
+            true => {
+                let (prefix, token) = auth::crypt::generate_prefixed_key(REFRESH_TOKEN_HEADER)?;
+                (Some(prefix), Some(token))
+            },
+            false => (None, None)
+        };
+
+        let oauth_token = Self {
+            id: Self::select_last_id().await? + 1,
+            oauth_clients_id: *oauth_clients_id,
+            users_id: *user_id,
+            access_prefix,
+            hashed_access_token: auth::crypt::credential_digest(access_token.as_str()),
+            refresh_prefix,
+            hashed_refresh_token: refresh_token.as_deref().map(auth::crypt::credential_digest),
+            scopes: scopes.to_vec(),
+            created_at: now,
+            expires_at: now.add(chrono::Duration::seconds(ACCESS_TOKEN_LIFETIME_SECONDS)),
+            refresh_expires_at: with_refresh_token.then(|| now.add(chrono::Duration::days(REFRESH_TOKEN_LIFETIME_DAYS))),
+            revoked_at: None
+        };
+
+        oauth_token.insert().await?;
+
+        Ok((oauth_token, access_token, refresh_token))
+    }
+
+    pub async fn select_by_access_token(access_token: &str) -> TheResult<Option<Self>> {
+
+        let Some(prefix) = auth::crypt::get_prefix_from_key(access_token, ACCESS_TOKEN_HEADER) else {
+            return Ok(None)
+        };
+
+        let conn = &mut get_conn().await?;
+
+        let oauth_token = conn.exec_first::<Self, _, _>(
+            "SELECT * FROM oauth_tokens WHERE access_prefix = ?",
+            (prefix,)
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(oauth_token)
+    }
+
+    pub async fn select_by_refresh_token(refresh_token: &str) -> TheResult<Option<Self>> {
+
+        let Some(prefix) = auth::crypt::get_prefix_from_key(refresh_token, REFRESH_TOKEN_HEADER) else {
+            return Ok(None)
+        };
+
+        let conn = &mut get_conn().await?;
+
+        let oauth_token = conn.exec_first::<Self, _, _>(
+            "SELECT * FROM oauth_tokens WHERE refresh_prefix = ?",
+            (prefix,)
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(oauth_token)
+    }
+
+    async fn select_last_id() -> TheResult<OAuthTokensIdType> {
+
+        let conn = &mut get_conn().await?;
+
+        let id = conn.query_first::<Option<OAuthTokensIdType>, _>(
+            "SELECT MAX(ID) FROM oauth_tokens LIMIT 1"
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        if let Some(Some(token_id)) = id {
+            return Ok(token_id)
+        }
+
+        Ok(OAuthTokensIdType::default())
+    }
+
+    async fn insert(&self) -> TheResult<()> {
+
+        let conn = &mut get_conn().await?;
+
+        conn.exec_drop(
+            "INSERT INTO oauth_tokens (ID, oauth_clients_ID, users_ID, access_prefix, hashed_access_token, \
+            refresh_prefix, hashed_refresh_token, scopes, created_at, expires_at, refresh_expires_at) \
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            (
+                self.id,
+                self.oauth_clients_id,
+                self.users_id,
+                self.access_prefix.as_str(),
+                self.hashed_access_token.as_str(),
+                self.refresh_prefix.as_deref(),
+                self.hashed_refresh_token.as_deref(),
+                scope::scopes_to_string(self.scopes.as_slice()),
+                self.created_at.format(database::DATETIME_FORMAT).to_string(),
+                self.expires_at.format(database::DATETIME_FORMAT).to_string(),
+                self.refresh_expires_at
+                    .map(|refresh_expires_at| refresh_expires_at.format(database::DATETIME_FORMAT).to_string())
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+
+    /// ## Description
+    /// Revokes the access token along with its refresh token. Returns false if it was already
+    /// revoked, so a refresh token can't be rotated twice
+    pub async fn revoke(&self) -> TheResult<bool> {
+
+        let conn = &mut get_conn().await?;
+
+        conn.exec_drop(
+            "UPDATE oauth_tokens SET revoked_at = ? WHERE ID = ? AND revoked_at IS NULL",
+            (chrono::Utc::now().format(database::DATETIME_FORMAT).to_string(), self.id)
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(conn.affected_rows() > 0)
+    }
+
+    /// Checks the full access token received against the stored hash, and that it can still be used
+    pub fn validate_access_token(&self, access_token: &str) -> bool {
+        if self.revoked_at.is_some() || self.expires_at < chrono::Utc::now().naive_utc() {
+            return false
+        }
+        auth::crypt::credential_matches(&self.hashed_access_token, access_token)
+    }
+
+    /// Checks the full refresh token received against the stored hash, and that it can still be used
+    pub fn validate_refresh_token(&self, refresh_token: &str) -> bool {
+        if self.revoked_at.is_some() {
+            return false
+        }
+        match (&self.hashed_refresh_token, self.refresh_expires_at) {
+            (Some(hashed_refresh_token), Some(refresh_expires_at)) => {
+                refresh_expires_at > chrono::Utc::now().naive_utc()
+                    && auth::crypt::credential_matches(hashed_refresh_token, refresh_token)
+            },
+            _ => false
+        }
+    }
+
+    pub fn get_oauth_clients_id(&self) -> &OAuthClientsIdType {
+        &self.oauth_clients_id
+    }
+
+    pub fn get_user_id(&self) -> &UsersIdType {
+        &self.users_id
+    }
+
+    pub fn get_scopes(&self) -> &[OAuthScope] {
+        self.scopes.as_slice()
+    }
+
+    pub fn get_created_at(&self) -> &NaiveDateTime {
+        &self.created_at
+    }
+
+    pub fn get_expires_at(&self) -> &NaiveDateTime {
+        &self.expires_at
+    }
+
+    pub fn get_refresh_expires_at(&self) -> Option<&NaiveDateTime> {
+        self.refresh_expires_at.as_ref()
+    }
+}
+
+impl FromRow for OAuthToken {
+    fn from_row(row: Row) -> Self where Self: Sized {
+        Self {
+            id: row_to_data!(row, "ID", "oauth_tokens", OAuthTokensIdType),
+            oauth_clients_id: row_to_data!(row, "oauth_clients_ID", "oauth_tokens", OAuthClientsIdType),
+            users_id: row_to_data!(row, "users_ID", "oauth_tokens", UsersIdType),
+            access_prefix: row_to_data!(row, "access_prefix", "oauth_tokens", String),
+            hashed_access_token: row_to_data!(row, "hashed_access_token", "oauth_tokens", String),
+            refresh_prefix: row_to_data!(row, "refresh_prefix", "oauth_tokens", Option<String>),
+            hashed_refresh_token: row_to_data!(row, "hashed_refresh_token", "oauth_tokens", Option<String>),
+            scopes: scope::scopes_from_string(row_to_data!(row, "scopes", "oauth_tokens", String).as_str())
+                .unwrap_or_default(),
+            created_at: row_to_naive_datetime!(row, "created_at", "oauth_tokens"),
+            expires_at: row_to_naive_datetime!(row, "expires_at", "oauth_tokens"),
+            refresh_expires_at: row_to_optional_naive_datetime!(row, "refresh_expires_at", "oauth_tokens"),
+            revoked_at: row_to_optional_naive_datetime!(row, "revoked_at", "oauth_tokens")
+        }
+    }
+
+    fn from_row_opt(_: Row) -> Result<Self, FromRowError> where Self: Sized {
+        unimplemented!()
+    }
+}
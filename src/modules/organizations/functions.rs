@@ -0,0 +1,41 @@
+use actix_web::{HttpMessage, HttpRequest};
+use crate::api::authenticator::CredentialHeaders;
+use crate::config::environment::EnvironmentConfig;
+use crate::modules::organizations::organization;
+use crate::modules::organizations::Tenant;
+
+/// ## Description
+/// Resolves the slug of the organization a request is addressed to. The `organization` header
+/// takes precedence, otherwise the subdomain of the `host` header is used when it's a subdomain
+/// of the configured tenant domain. Only valid slugs are returned
+pub async fn get_organization_slug_from_headers(headers: &(impl CredentialHeaders + ?Sized)) -> Option<String> {
+
+    //  Attempt to get organization from headers
+    if let Some(organization) = headers.get_header("organization") {
+        return organization
+            .filter(|slug| organization::is_valid_slug(slug))
+            .map(str::to_string)
+    }
+
+    //  Attempt to get organization from the subdomain
+    let tenant_domain = EnvironmentConfig::instance().get_tenant_domain().await?;
+    let host = headers.get_header("host")??;
+
+    //  Port is not part of the domain
+    let host = host.split(':').next().unwrap_or_default();
+
+    match host.strip_suffix(tenant_domain.as_str()) {
+        Some(subdomain) => {
+            match subdomain.strip_suffix('.') {
+                //  Only a single label is accepted as organization
+                Some(slug) if organization::is_valid_slug(slug) => Some(slug.to_string()),
+                _ => None
+            }
+        },
+        None => None
+    }
+}
+
+pub fn get_tenant_from_request(request: &HttpRequest) -> Option<Tenant> {
+    request.extensions().get::<Tenant>().cloned()
+}
@@ -0,0 +1,154 @@
+use error_mapper::{map_to_new_error, TheResult};
+use mysql_async::prelude::{FromRow, Queryable};
+use mysql_async::{FromRowError, Row};
+use crate::{database, row_to_data, row_to_enum};
+use crate::database::db_conn::get_conn;
+use crate::general::types::{OrganizationsIdType, UsersIdType};
+use crate::modules::users::user::Level;
+
+/// ## Description
+/// A user's membership in an organization. The level stored here is the one that applies to
+/// anything done inside that organization, regardless of the user's global level
+#[derive(Debug, Default, Clone)]
+pub struct Membership {
+    organizations_id: OrganizationsIdType,
+    users_id: UsersIdType,
+    level: Level
+}
+
+impl Membership {
+
+    pub async fn create_membership(
+        organization_id: &OrganizationsIdType,
+        user_id: &UsersIdType,
+        level: &Level
+    ) -> TheResult<()> {
+
+        let conn = &mut get_conn().await?;
+
+        let now = chrono::Utc::now().format(database::DATETIME_FORMAT).to_string();
+
+        conn.query_drop(
+            format!(
+                "INSERT INTO organizations_users (organizations_ID, users_ID, level, created_at, updated_at) \
+                VALUES ({}, {}, '{}', '{}', '{}')",
+                organization_id,
+                user_id,
+                level,
+                now,
+                now
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+
+    /// Fetches the membership if the user is an active member of the organization
+    pub async fn select(
+        organization_id: &OrganizationsIdType,
+        user_id: &UsersIdType
+    ) -> TheResult<Option<Self>> {
+
+        let conn = &mut get_conn().await?;
+
+        let membership = conn.query_first::<Self, _>(
+            format!(
+                "SELECT * FROM organizations_users \
+                WHERE organizations_ID = {} AND users_ID = {} AND deleted_at IS NULL",
+                organization_id,
+                user_id
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(membership)
+    }
+
+    /// Fetches the membership if the user was removed from the organization
+    pub async fn select_deleted(
+        organization_id: &OrganizationsIdType,
+        user_id: &UsersIdType
+    ) -> TheResult<Option<Self>> {
+
+        let conn = &mut get_conn().await?;
+
+        let membership = conn.query_first::<Self, _>(
+            format!(
+                "SELECT * FROM organizations_users \
+                WHERE organizations_ID = {} AND users_ID = {} AND deleted_at IS NOT NULL",
+                organization_id,
+                user_id
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(membership)
+    }
+
+    pub async fn delete_membership(&self) -> TheResult<()> {
+
+        let conn = &mut get_conn().await?;
+
+        conn.query_drop(
+            format!(
+                "UPDATE organizations_users SET deleted_at = '{}' \
+                WHERE organizations_ID = {} AND users_ID = {}",
+                chrono::Utc::now().format(database::DATETIME_FORMAT),
+                self.organizations_id,
+                self.users_id
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+
+    pub async fn restore_membership(&self) -> TheResult<bool> {
+
+        let conn = &mut get_conn().await?;
+
+        conn.query_drop(
+            format!(
+                "UPDATE organizations_users SET deleted_at = NULL \
+                WHERE organizations_ID = {} AND users_ID = {}",
+                self.organizations_id,
+                self.users_id
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(conn.affected_rows() > 0)
+    }
+
+    pub async fn change_level(&self, target_level: &Level) -> TheResult<()> {
+
+        let conn = &mut get_conn().await?;
+
+        conn.query_drop(
+            format!(
+                "UPDATE organizations_users SET level = '{}', updated_at = '{}' \
+                WHERE organizations_ID = {} AND users_ID = {}",
+                target_level,
+                chrono::Utc::now().format(database::DATETIME_FORMAT),
+                self.organizations_id,
+                self.users_id
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+
+    pub fn get_level(&self) -> &Level {
+        &self.level
+    }
+}
+
+impl FromRow for Membership {
+    fn from_row(row: Row) -> Self where Self: Sized {
+        Self {
+            organizations_id: row_to_data!(row, "organizations_ID", "organizations_users", OrganizationsIdType),
+            users_id: row_to_data!(row, "users_ID", "organizations_users", UsersIdType),
+            level: row_to_enum!(row, "level", "organizations_users", Level)
+        }
+    }
+
+    fn from_row_opt(_: Row) -> Result<Self, FromRowError> where Self: Sized {
+        unimplemented!()
+    }
+}
@@ -0,0 +1,33 @@
+use crate::modules::organizations::organization::Organization;
+use crate::modules::users::user::Level;
+
+pub mod services;
+pub mod functions;
+pub mod organization;
+pub mod membership;
+
+/// ## Description
+/// Organization resolved for an organization scoped request, along with the level the requesting
+/// user holds in it. Inserted into the request extensions by the authentication middleware
+#[derive(Debug, Clone)]
+pub struct Tenant {
+    organization: Organization,
+    level: Level
+}
+
+impl Tenant {
+    pub fn new(organization: Organization, level: Level) -> Self {
+        Self {
+            organization,
+            level
+        }
+    }
+
+    pub fn get_organization(&self) -> &Organization {
+        &self.organization
+    }
+
+    pub fn get_level(&self) -> &Level {
+        &self.level
+    }
+}
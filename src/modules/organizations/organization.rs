@@ -0,0 +1,112 @@
+use chrono::NaiveDateTime;
+use error_mapper::{map_to_new_error, TheResult};
+use mysql_async::prelude::{FromRow, Queryable};
+use mysql_async::{FromRowError, Row};
+use serde::Serialize;
+use crate::{database, row_to_data, row_to_naive_datetime};
+use crate::database::db_conn::get_conn;
+use crate::general::types::OrganizationsIdType;
+
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct Organization {
+    id: OrganizationsIdType,
+    name: String,
+    slug: String,
+    #[serde(skip_serializing)]
+    created_at: NaiveDateTime
+}
+
+impl Organization {
+
+    pub async fn create_organization(name: &str, slug: &str) -> TheResult<Self> {
+
+        let organization = Self {
+            id: Self::select_last_id().await? + 1,
+            name: name.to_string(),
+            slug: slug.to_string(),
+            created_at: chrono::Utc::now().naive_utc()
+        };
+
+        organization.insert().await?;
+
+        Ok(organization)
+    }
+
+    pub async fn select_by_slug(slug: &str) -> TheResult<Option<Self>> {
+
+        let conn = &mut get_conn().await?;
+
+        //  Slugs come from request headers, they're sent as parameters and never formatted in
+        let organization = conn.exec_first::<Self, _, _>(
+            "SELECT * FROM organizations WHERE slug = ?",
+            (slug,)
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(organization)
+    }
+
+    async fn select_last_id() -> TheResult<OrganizationsIdType> {
+
+        let conn = &mut get_conn().await?;
+
+        let id = conn.query_first::<Option<OrganizationsIdType>, _>(
+            "SELECT MAX(ID) FROM organizations LIMIT 1"
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        if let Some(Some(organization_id)) = id {
+            return Ok(organization_id)
+        }
+
+        Ok(OrganizationsIdType::default())
+    }
+
+    async fn insert(&self) -> TheResult<()> {
+
+        let conn = &mut get_conn().await?;
+
+        conn.exec_drop(
+            "INSERT INTO organizations (ID, name, slug, created_at) VALUES (?, ?, ?, ?)",
+            (
+                self.id,
+                self.name.as_str(),
+                self.slug.as_str(),
+                self.created_at.format(database::DATETIME_FORMAT).to_string()
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+
+    pub fn get_id(&self) -> &OrganizationsIdType {
+        &self.id
+    }
+}
+
+/// Longest slug accepted, as stored in the organizations table
+const SLUG_MAX_LENGTH: usize = 30;
+
+/// Slugs are lowercase letters, numbers and hyphens, so they're valid as subdomains too
+pub fn is_valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug.len() <= SLUG_MAX_LENGTH
+        && slug.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+pub(super) async fn slug_available(slug: &str) -> TheResult<bool> {
+    Ok(Organization::select_by_slug(slug).await?.is_none())
+}
+
+impl FromRow for Organization {
+    fn from_row(row: Row) -> Self where Self: Sized {
+        Self {
+            id: row_to_data!(row, "ID", "organizations", OrganizationsIdType),
+            name: row_to_data!(row, "name", "organizations", String),
+            slug: row_to_data!(row, "slug", "organizations", String),
+            created_at: row_to_naive_datetime!(row, "created_at", "organizations")
+        }
+    }
+
+    fn from_row_opt(_: Row) -> Result<Self, FromRowError> where Self: Sized {
+        unimplemented!()
+    }
+}
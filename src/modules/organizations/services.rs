@@ -0,0 +1,422 @@
+
+use actix_web::{HttpRequest, HttpResponse, post, put, web};
+use actix_web::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::api::authentication::RequireScope;
+use crate::general;
+use crate::auth::policy::{Action, Policy, PolicyRequest};
+use crate::general::api_error::ApiError;
+use crate::general::http_req_res::{data_response, json_response};
+use crate::general::problem::{ErrorCode, Problem};
+use crate::general::types::{OrganizationsIdType, UsersIdType};
+use crate::modules::organizations::{functions, organization};
+use crate::modules::organizations::membership::Membership;
+use crate::modules::organizations::organization::Organization;
+use crate::modules::users;
+use crate::modules::users::user;
+use crate::modules::users::user::{Level, User};
+use crate::modules::personal_tokens::personal_token::TokenScope;
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct PostOrganization {
+    name: String,
+    slug: String,
+    #[schema(value_type = Option<u32>)]
+    admin_user_id: Option<UsersIdType>,
+    admin_username: Option<String>
+}
+
+#[derive(Serialize)]
+struct OrganizationCreated {
+    organization_id: OrganizationsIdType
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct PostMember {
+    username: String,
+    password: String,
+    email: String,
+    level: Option<u8>
+}
+
+#[derive(Serialize)]
+struct MemberCreated {
+    user_id: UsersIdType,
+    session_token: String
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct AddMember {
+    #[schema(value_type = Option<u32>)]
+    user_id: Option<UsersIdType>,
+    username: Option<String>,
+    level: Option<u8>
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct TargetMember {
+    #[schema(value_type = Option<u32>)]
+    user_id: Option<UsersIdType>,
+    username: Option<String>
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct ChangeMemberLevel {
+    #[schema(value_type = Option<u32>)]
+    user_id: Option<UsersIdType>,
+    username: Option<String>,
+    level: u8
+}
+
+/// ##  Endpoint create organization
+/// POST {UTAUrl}:{UTAPort}/internal/create_organization (private)
+///
+/// #### Required Body
+/// - name: ans-50 max string
+/// - slug: ans-30 max string, used in the organization header or as subdomain
+/// - admin_user_id (optional): optional u32
+/// - admin_username (optional): optional ans-20 max string
+///
+/// ### Description
+/// Creates an organization and adds the user sent in the body as its admin, with level High in
+/// the organization. One of the admin parameters must be present. Only available to Super
+#[utoipa::path(
+    tag = "Organizations",
+    summary = "Creates an organization",
+    responses(
+        (status = 201, description = "Organization created")
+    )
+)]
+#[post("/create_organization", wrap = "RequireScope::new(TokenScope::AdminUsers)")]
+async fn create_organization(request: HttpRequest, body: web::Json<PostOrganization>) -> Result<HttpResponse, ApiError> {
+
+    let organization_data = body.into_inner();
+
+    let user = users::functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
+
+    if !Policy::instance().evaluate(&PolicyRequest::new(Action::CreateOrganization, &user).await).await.is_allowed() {
+        return Err(ApiError::new(ErrorCode::Forbidden, "User lacks the privileges to perform this operation"))
+    }
+
+    if !organization::is_valid_slug(organization_data.slug.as_str()) {
+        return Err(ApiError::new(ErrorCode::InvalidRequest, "Slug must be up to 30 lowercase letters, numbers and hyphens"))
+    }
+
+    if !organization::slug_available(organization_data.slug.as_str()).await? {
+        return Err(ApiError::new(ErrorCode::SlugTaken, "Organization slug not available"))
+    }
+
+    let admin = users::functions::get_user_by_id_or_username(
+        organization_data.admin_user_id,
+        organization_data.admin_username
+    ).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::UserNotFound, "Invalid admin user id or username"))?;
+
+    let organization = Organization::create_organization(
+        organization_data.name.as_str(),
+        organization_data.slug.as_str()
+    ).await?;
+
+    Membership::create_membership(organization.get_id(), admin.get_id(), &Level::High).await?;
+
+    let organization_created = OrganizationCreated {
+        organization_id: *organization.get_id()
+    };
+
+    Ok(json_response(StatusCode::CREATED, general::http_req_res::serialize_into_json(&organization_created)?))
+}
+
+/// ##  Endpoint create organization user
+/// POST {UTAUrl}:{UTAPort}/organizations/internal/create_user (private)
+///
+/// #### Required Headers
+/// - organization: organization slug, unless the request is sent to the organization's subdomain
+///
+/// #### Required Body
+/// - username: ans-20 max string
+/// - password: ans-30 max string
+/// - email: ans-50 max string
+/// - level (optional): from 0 to 3 u8, level in the organization
+///
+/// ### Description
+/// Creates a new user as a member of the organization. The level in the organization can be at
+/// most one level below the requesting user's level in it. If a level was not sent, the member is
+/// created one level below the requesting user's
+#[utoipa::path(
+    tag = "Organizations",
+    summary = "Creates a user in the organization",
+    responses(
+        (status = 201, description = "User created and logged in, wrapped in a data envelope")
+    )
+)]
+#[post("/create_user", wrap = "RequireScope::new(TokenScope::AdminUsers)")]
+async fn create_user(request: HttpRequest, body: web::Json<PostMember>) -> Result<HttpResponse, ApiError> {
+
+    let Some(tenant) = functions::get_tenant_from_request(&request) else {
+        return Err(ApiError::new(ErrorCode::OrganizationNotSpecified, "No organization provided or found"))
+    };
+
+    if !user::username_available(body.username.as_str(), None).await? {
+        return Err(ApiError::new(ErrorCode::UsernameTaken, "Username not available"))
+    }
+
+    //  Validate password
+    let errors = User::validate_password(&body.password);
+    if !errors.is_empty() {
+        return Err(
+            Problem::new(ErrorCode::ValidationFailed)
+                .with_detail("Invalid password")
+                .with_extension("errors", errors)
+                .into()
+        )
+    }
+
+    //  The session opened for the new member is bound to the client creating it
+    let client = users::functions::get_client_from_request(&request).await;
+
+    let member_level = match body.level {
+        Some(level_u8) => level_u8.into(),
+        None => tenant.get_level().one_level_below()
+    };
+
+    let request = PolicyRequest::with_subject_level(Action::CreateUser, *tenant.get_level())
+        .with_resource_target_level(member_level);
+    if !Policy::instance().evaluate(&request).await.is_allowed() {
+        return Err(
+            ApiError::new(
+                ErrorCode::LevelNotAllowed,
+                "User level must be at least one level below the requesting account's"
+            )
+        )
+    }
+
+    //  The account itself is a regular one, privileges come from the organization membership
+    let (user_id, token) = User::create_user(
+        &body.username,
+        &body.password,
+        &body.email,
+        &Level::Low,
+        &client
+    ).await?;
+
+    Membership::create_membership(tenant.get_organization().get_id(), &user_id, &member_level).await?;
+
+    let member_created = MemberCreated {
+        user_id,
+        session_token: token
+    };
+
+    Ok(data_response(StatusCode::CREATED, &member_created))
+}
+
+/// ##  Endpoint add organization user
+/// POST {UTAUrl}:{UTAPort}/organizations/internal/add_user (private)
+///
+/// #### Required Headers
+/// - organization: organization slug, unless the request is sent to the organization's subdomain
+///
+/// #### Required Body
+/// One of the optional user parameters must be present in request body
+/// - user_id (optional): optional u32
+/// - username (optional): optional ans-20 max string
+/// - level (optional): from 0 to 3 u8, level in the organization
+///
+/// ### Description
+/// Adds an existing user to the organization, with the same level restrictions as create_user
+#[utoipa::path(
+    tag = "Organizations",
+    summary = "Adds an existing user to the organization",
+    responses(
+        (status = 201, description = "User added")
+    )
+)]
+#[post("/add_user", wrap = "RequireScope::new(TokenScope::AdminUsers)")]
+async fn add_user(request: HttpRequest, body: web::Json<AddMember>) -> Result<HttpResponse, ApiError> {
+
+    let member_data = body.into_inner();
+
+    let Some(tenant) = functions::get_tenant_from_request(&request) else {
+        return Err(ApiError::new(ErrorCode::OrganizationNotSpecified, "No organization provided or found"))
+    };
+
+    let target = users::functions::get_user_by_id_or_username(member_data.user_id, member_data.username).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::UserNotFound, "Invalid user id or username"))?;
+
+    let organization_id = tenant.get_organization().get_id();
+
+    //  Members, active or removed, can't be added again
+    if Membership::select(organization_id, target.get_id()).await?.is_some()
+        || Membership::select_deleted(organization_id, target.get_id()).await?.is_some() {
+        return Err(ApiError::new(ErrorCode::AlreadyMember, "User is already a member of this organization"))
+    }
+
+    let member_level = match member_data.level {
+        Some(level_u8) => level_u8.into(),
+        None => tenant.get_level().one_level_below()
+    };
+
+    let request = PolicyRequest::with_subject_level(Action::CreateUser, *tenant.get_level())
+        .with_resource_target_level(member_level);
+    if !Policy::instance().evaluate(&request).await.is_allowed() {
+        return Err(
+            ApiError::new(
+                ErrorCode::LevelNotAllowed,
+                "User level must be at least one level below the requesting account's"
+            )
+        )
+    }
+
+    Membership::create_membership(organization_id, target.get_id(), &member_level).await?;
+
+    Ok(HttpResponse::Created().finish())
+}
+
+/// ##  Endpoint delete organization user
+/// PUT {UTAUrl}:{UTAPort}/organizations/internal/delete_user (private)
+///
+/// #### Required Headers
+/// - organization: organization slug, unless the request is sent to the organization's subdomain
+///
+/// #### Required Body
+/// One of the optional parameters must be present in request body
+/// - user_id (optional): optional u32
+/// - username (optional): optional ans-20 max string
+///
+/// ### Description
+/// Removes the user from the organization. The user's account and memberships in other
+/// organizations are not affected. Same level restrictions as internal/delete_user apply, using
+/// the levels in the organization
+#[utoipa::path(
+    tag = "Organizations",
+    summary = "Removes a user from the organization",
+    responses(
+        (status = 204, description = "User removed")
+    )
+)]
+#[put("/delete_user", wrap = "RequireScope::new(TokenScope::AdminUsers)")]
+async fn delete_user(request: HttpRequest, body: web::Json<TargetMember>) -> Result<HttpResponse, ApiError> {
+
+    let member_data = body.into_inner();
+
+    let Some(tenant) = functions::get_tenant_from_request(&request) else {
+        return Err(ApiError::new(ErrorCode::OrganizationNotSpecified, "No organization provided or found"))
+    };
+
+    let target = users::functions::get_user_by_id_or_username(member_data.user_id, member_data.username).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::UserNotFound, "Invalid user id or username"))?;
+
+    //  Only members of this organization can be touched
+    let membership = Membership::select(tenant.get_organization().get_id(), target.get_id()).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::NotMember, "User is not a member of this organization"))?;
+
+    let request = PolicyRequest::with_subject_level(Action::DeleteUser, *tenant.get_level())
+        .with_resource_level(*membership.get_level());
+    if !Policy::instance().evaluate(&request).await.is_allowed() {
+        return Err(ApiError::new(ErrorCode::Forbidden, "User does not have permission to delete this account"))
+    }
+
+    membership.delete_membership().await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// ##  Endpoint undo delete organization user
+/// PUT {UTAUrl}:{UTAPort}/organizations/internal/undo_delete_user (private)
+///
+/// #### Required Headers
+/// - organization: organization slug, unless the request is sent to the organization's subdomain
+///
+/// #### Required Body
+/// One of the optional parameters must be present in request body
+/// - user_id (optional): optional u32
+/// - username (optional): optional ans-20 max string
+///
+/// ### Description
+/// Restores a user removed from the organization, with the level they had in it
+#[utoipa::path(
+    tag = "Organizations",
+    summary = "Restores a user removed from the organization",
+    responses(
+        (status = 200, description = "User restored")
+    )
+)]
+#[put("/undo_delete_user", wrap = "RequireScope::new(TokenScope::AdminUsers)")]
+async fn undo_delete_user(request: HttpRequest, body: web::Json<TargetMember>) -> Result<HttpResponse, ApiError> {
+
+    let member_data = body.into_inner();
+
+    let Some(tenant) = functions::get_tenant_from_request(&request) else {
+        return Err(ApiError::new(ErrorCode::OrganizationNotSpecified, "No organization provided or found"))
+    };
+
+    if !Policy::instance().evaluate(
+        &PolicyRequest::with_subject_level(Action::RestoreUser, *tenant.get_level())
+    ).await.is_allowed() {
+        return Err(ApiError::new(ErrorCode::Forbidden, "User does not have permission to restore this account"))
+    }
+
+    let target = users::functions::get_user_by_id_or_username(member_data.user_id, member_data.username).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::UserNotFound, "Invalid user id or username"))?;
+
+    let membership = Membership::select_deleted(tenant.get_organization().get_id(), target.get_id()).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::NotMember, "User was not removed from this organization"))?;
+
+    if !membership.restore_membership().await? {
+        return Err(ApiError::new(ErrorCode::Internal, "User account not restored"))
+    }
+
+    Ok(HttpResponse::Ok().json("User restored"))
+}
+
+/// ##  Endpoint change organization user level
+/// PUT {UTAUrl}:{UTAPort}/organizations/internal/change_user_level (private)
+///
+/// #### Required Headers
+/// - organization: organization slug, unless the request is sent to the organization's subdomain
+///
+/// #### Required Body
+/// One of the optional parameters must be present in the request body
+/// - user_id (optional): optional u32
+/// - username (optional): optional ans-20 max string
+/// - level (required): from 0 to 3 u8
+///
+/// ### Description
+/// Changes the level the user holds in the organization. The user's global level and levels in
+/// other organizations are not affected
+#[utoipa::path(
+    tag = "Organizations",
+    summary = "Changes the level of a user in the organization",
+    responses(
+        (status = 200, description = "Level changed")
+    )
+)]
+#[put("/change_user_level", wrap = "RequireScope::new(TokenScope::AdminUsers)")]
+async fn change_user_level(request: HttpRequest, body: web::Json<ChangeMemberLevel>) -> Result<HttpResponse, ApiError> {
+
+    let member_data = body.into_inner();
+
+    let Some(tenant) = functions::get_tenant_from_request(&request) else {
+        return Err(ApiError::new(ErrorCode::OrganizationNotSpecified, "No organization provided or found"))
+    };
+
+    let target = users::functions::get_user_by_id_or_username(member_data.user_id, member_data.username).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::UserNotFound, "Invalid user id or username"))?;
+
+    //  Only members of this organization can be touched
+    let membership = Membership::select(tenant.get_organization().get_id(), target.get_id()).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::NotMember, "User is not a member of this organization"))?;
+
+    let target_level: Level = member_data.level.into();
+    let request = PolicyRequest::with_subject_level(Action::ChangeUserLevel, *tenant.get_level())
+        .with_resource_level(*membership.get_level())
+        .with_resource_target_level(target_level);
+    if !Policy::instance().evaluate(&request).await.is_allowed() {
+        return Err(ApiError::new(ErrorCode::Forbidden, "User lacks the privileges to perform the required operation"))
+    }
+
+    membership.change_level(&target_level).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
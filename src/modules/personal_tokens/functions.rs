@@ -0,0 +1,41 @@
+use actix_web::{HttpMessage, HttpRequest};
+use error_mapper::TheResult;
+use crate::api::authenticator::Credential;
+use crate::modules::personal_tokens::personal_token;
+use crate::modules::personal_tokens::personal_token::PersonalToken;
+use crate::modules::users::user::User;
+
+/// ## Description
+/// Authenticates a request with a personal access token. Returns the user that owns the token
+/// along with the token, or None if the token is invalid, revoked or expired
+pub async fn authenticate_personal_token(token: &str) -> TheResult<Option<(User, PersonalToken)>> {
+
+    let Some(prefix) = personal_token::get_prefix_from_token(token) else {
+        return Ok(None)
+    };
+
+    let Some(personal_token) = PersonalToken::select_by_prefix(prefix).await? else {
+        return Ok(None)
+    };
+
+    if !personal_token.validate_token(token) {
+        return Ok(None)
+    }
+
+    //  Only human users that were not deleted can use their tokens
+    let user = match User::select_by_id(personal_token.get_user_id()).await? {
+        Some(user) if !user.is_service_account() => user,
+        _ => return Ok(None)
+    };
+
+    personal_token.update_last_used().await?;
+
+    Ok(Some((user, personal_token)))
+}
+
+/// ## Description
+/// Whether the request was authenticated with a bearer token. Requests without a recorded
+/// credential are treated as if they were, to refuse creating credentials with them
+pub fn is_token_authenticated(request: &HttpRequest) -> bool {
+    request.extensions().get::<Credential>().is_none_or(Credential::is_token)
+}
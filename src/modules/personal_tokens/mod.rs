@@ -0,0 +1,3 @@
+pub mod services;
+pub mod functions;
+pub mod personal_token;
@@ -0,0 +1,299 @@
+use std::ops::Add;
+use chrono::NaiveDateTime;
+use error_mapper::{map_to_new_error, TheResult};
+use mysql_async::prelude::{FromRow, Queryable};
+use mysql_async::{FromRowError, Row};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::{auth, database, row_to_data, row_to_naive_datetime, row_to_optional_naive_datetime};
+use crate::database::db_conn::get_conn;
+use crate::general::types::{PersonalTokensIdType, UsersIdType};
+
+/// Every personal access token starts with this, so they're easy to tell apart from API keys
+const PERSONAL_TOKEN_HEADER: &str = "utp";
+
+/// ## Description
+/// Token bound to a human user, used to script against the API without sharing the password.
+/// Unlike API keys, tokens don't cap the user's level, they restrict the endpoints that can be
+/// used with them through their scopes
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct PersonalToken {
+    id: PersonalTokensIdType,
+    #[serde(skip_serializing)]
+    users_id: UsersIdType,
+    name: String,
+    prefix: String,
+    #[serde(skip_serializing)]
+    hashed_token: String,
+    scopes: Vec<TokenScope>,
+    created_at: NaiveDateTime,
+    expires_at: NaiveDateTime,
+    last_used_at: Option<NaiveDateTime>,
+    revoked_at: Option<NaiveDateTime>
+}
+
+/// ## Description
+/// Scopes granted to a personal access token. Every endpoint reachable with a token requires one
+/// of them
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+pub enum TokenScope {
+    #[serde(rename = "read:self")]
+    ReadSelf,
+    #[serde(rename = "manage:password")]
+    ManagePassword,
+    #[serde(rename = "manage:account")]
+    ManageAccount,
+    #[serde(rename = "manage:api_keys")]
+    ManageApiKeys,
+    #[serde(rename = "admin:users")]
+    AdminUsers,
+    #[serde(rename = "admin:service")]
+    AdminService
+}
+
+impl PersonalToken {
+
+    /// ## Description
+    /// Creates a token for the user and returns it along with the full token. The full token is
+    /// only available here, only its hash is stored
+    pub async fn create_personal_token(
+        user_id: &UsersIdType,
+        name: &str,
+        scopes: &[TokenScope],
+        expires_in_days: u32
+    ) -> TheResult<(Self, String)> {
+
+        let now = chrono::Utc::now().naive_utc();
+
+        let (prefix, token) = auth::crypt::generate_prefixed_key(PERSONAL_TOKEN_HEADER)?;
+
+        let personal_token = Self {
+            id: Self::select_last_id().await? + 1,
+            users_id: *user_id,
+            name: name.to_string(),
+            prefix,
+            hashed_token: auth::crypt::credential_digest(token.as_str()),
+            scopes: scopes.to_vec(),
+            created_at: now,
+            expires_at: now.add(chrono::Duration::days(expires_in_days as i64)),
+            last_used_at: None,
+            revoked_at: None
+        };
+
+        personal_token.insert().await?;
+
+        Ok((personal_token, token))
+    }
+
+    pub async fn select_by_id(personal_token_id: &PersonalTokensIdType) -> TheResult<Option<Self>> {
+
+        let conn = &mut get_conn().await?;
+
+        let personal_token = conn.query_first::<Self, _>(
+            format!(
+                "SELECT * FROM personal_access_tokens WHERE ID = {}",
+                personal_token_id
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(personal_token)
+    }
+
+    pub async fn select_by_prefix(prefix: &str) -> TheResult<Option<Self>> {
+
+        let conn = &mut get_conn().await?;
+
+        //  Prefixes come from request bodies, they're sent as parameters and never formatted in
+        let personal_token = conn.exec_first::<Self, _, _>(
+            "SELECT * FROM personal_access_tokens WHERE prefix = ?",
+            (prefix,)
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(personal_token)
+    }
+
+    pub async fn select_by_user(user_id: &UsersIdType) -> TheResult<Vec<Self>> {
+
+        let conn = &mut get_conn().await?;
+
+        let personal_tokens = conn.query::<Self, _>(
+            format!(
+                "SELECT * FROM personal_access_tokens WHERE users_ID = {}",
+                user_id
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(personal_tokens)
+    }
+
+    async fn select_last_id() -> TheResult<PersonalTokensIdType> {
+
+        let conn = &mut get_conn().await?;
+
+        let id = conn.query_first::<Option<PersonalTokensIdType>, _>(
+            "SELECT MAX(ID) FROM personal_access_tokens LIMIT 1"
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        if let Some(Some(personal_token_id)) = id {
+            return Ok(personal_token_id)
+        }
+
+        Ok(PersonalTokensIdType::default())
+    }
+
+    async fn insert(&self) -> TheResult<()> {
+
+        let conn = &mut get_conn().await?;
+
+        conn.query_drop(
+            format!(
+                "INSERT INTO personal_access_tokens (ID, users_ID, name, prefix, hashed_token, scopes, created_at, expires_at) \
+                VALUES ({}, {}, '{}', '{}', '{}', '{}', '{}', '{}')",
+                self.id,
+                self.users_id,
+                self.name.as_str(),
+                self.prefix.as_str(),
+                self.hashed_token.as_str(),
+                scopes_to_string(self.scopes.as_slice()),
+                self.created_at.format(database::DATETIME_FORMAT),
+                self.expires_at.format(database::DATETIME_FORMAT)
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+
+    pub async fn revoke(&self) -> TheResult<()> {
+
+        let conn = &mut get_conn().await?;
+
+        conn.query_drop(
+            format!(
+                "UPDATE personal_access_tokens SET revoked_at = '{}' WHERE ID = {}",
+                chrono::Utc::now().format(database::DATETIME_FORMAT),
+                self.id
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+
+    /// Revokes every token of the user that was not revoked yet
+    pub async fn revoke_all_by_user(user_id: &UsersIdType) -> TheResult<()> {
+
+        let conn = &mut get_conn().await?;
+
+        conn.query_drop(
+            format!(
+                "UPDATE personal_access_tokens SET revoked_at = '{}' WHERE users_ID = {} AND revoked_at IS NULL",
+                chrono::Utc::now().format(database::DATETIME_FORMAT),
+                user_id
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+
+    pub async fn update_last_used(&self) -> TheResult<()> {
+
+        let conn = &mut get_conn().await?;
+
+        conn.query_drop(
+            format!(
+                "UPDATE personal_access_tokens SET last_used_at = '{}' WHERE ID = {}",
+                chrono::Utc::now().format(database::DATETIME_FORMAT),
+                self.id
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+
+    /// Checks the full token received against the stored hash, and that the token can still be used
+    pub fn validate_token(&self, token: &str) -> bool {
+        if self.revoked_at.is_some() || self.expires_at < chrono::Utc::now().naive_utc() {
+            return false
+        }
+        auth::crypt::credential_matches(&self.hashed_token, token)
+    }
+
+    pub fn has_scope(&self, scope: TokenScope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    pub fn get_id(&self) -> &PersonalTokensIdType {
+        &self.id
+    }
+
+    pub fn get_user_id(&self) -> &UsersIdType {
+        &self.users_id
+    }
+
+    pub fn get_prefix(&self) -> &str {
+        self.prefix.as_str()
+    }
+}
+
+impl TokenScope {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TokenScope::ReadSelf => "read:self",
+            TokenScope::ManagePassword => "manage:password",
+            TokenScope::ManageAccount => "manage:account",
+            TokenScope::ManageApiKeys => "manage:api_keys",
+            TokenScope::AdminUsers => "admin:users",
+            TokenScope::AdminService => "admin:service"
+        }
+    }
+
+    fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "read:self" => Some(TokenScope::ReadSelf),
+            "manage:password" => Some(TokenScope::ManagePassword),
+            "manage:account" => Some(TokenScope::ManageAccount),
+            "manage:api_keys" => Some(TokenScope::ManageApiKeys),
+            "admin:users" => Some(TokenScope::AdminUsers),
+            "admin:service" => Some(TokenScope::AdminService),
+            _ => None
+        }
+    }
+}
+
+/// Extracts the prefix from a full token, None if the token is not correctly formatted
+pub fn get_prefix_from_token(token: &str) -> Option<&str> {
+    auth::crypt::get_prefix_from_key(token, PERSONAL_TOKEN_HEADER)
+}
+
+fn scopes_to_string(scopes: &[TokenScope]) -> String {
+    scopes.iter()
+        .map(|scope| scope.as_str())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn scopes_from_string(scopes: &str) -> Vec<TokenScope> {
+    scopes.split(',')
+        .filter_map(TokenScope::parse)
+        .collect()
+}
+
+impl FromRow for PersonalToken {
+    fn from_row(row: Row) -> Self where Self: Sized {
+        Self {
+            id: row_to_data!(row, "ID", "personal_access_tokens", PersonalTokensIdType),
+            users_id: row_to_data!(row, "users_ID", "personal_access_tokens", UsersIdType),
+            name: row_to_data!(row, "name", "personal_access_tokens", String),
+            prefix: row_to_data!(row, "prefix", "personal_access_tokens", String),
+            hashed_token: row_to_data!(row, "hashed_token", "personal_access_tokens", String),
+            scopes: scopes_from_string(row_to_data!(row, "scopes", "personal_access_tokens", String).as_str()),
+            created_at: row_to_naive_datetime!(row, "created_at", "personal_access_tokens"),
+            expires_at: row_to_naive_datetime!(row, "expires_at", "personal_access_tokens"),
+            last_used_at: row_to_optional_naive_datetime!(row, "last_used_at", "personal_access_tokens"),
+            revoked_at: row_to_optional_naive_datetime!(row, "revoked_at", "personal_access_tokens")
+        }
+    }
+
+    fn from_row_opt(_: Row) -> Result<Self, FromRowError> where Self: Sized {
+        unimplemented!()
+    }
+}
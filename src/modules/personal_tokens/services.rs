@@ -0,0 +1,173 @@
+
+use actix_web::{get, HttpRequest, HttpResponse, post, put, web};
+use actix_web::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::api::authentication::RequireScope;
+use crate::{auth, general};
+use crate::auth::policy::{Action, Policy, PolicyRequest};
+use crate::general::api_error::ApiError;
+use crate::general::http_req_res::json_response;
+use crate::general::problem::ErrorCode;
+use crate::general::types::PersonalTokensIdType;
+use crate::modules::personal_tokens::functions;
+use crate::modules::personal_tokens::personal_token::{PersonalToken, TokenScope};
+use crate::modules::users;
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct PostPersonalToken {
+    name: String,
+    scopes: Vec<TokenScope>,
+    expires_in_days: u32
+}
+
+#[derive(Serialize)]
+struct PersonalTokenCreated {
+    token_id: PersonalTokensIdType,
+    prefix: String,
+    token: String
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct RevokePersonalToken {
+    #[schema(value_type = Option<u32>)]
+    token_id: Option<PersonalTokensIdType>,
+    prefix: Option<String>
+}
+
+/// ##  Endpoint create personal access token
+/// POST {UTAUrl}:{UTAPort}/users/manage/create_token (private)
+///
+/// #### Required Body
+/// - name: ans-50 max string, to tell tokens apart
+/// - scopes: list of "read:self", "manage:password", "manage:account", "manage:api_keys",
+/// "admin:users" or "admin:service"
+/// - expires_in_days: u32, the maximum allowed is set by the policy
+///
+/// ### Description
+/// Creates a personal access token for the requesting user, to be sent as `Authorization: Bearer
+/// <token>`. The token is included in the response and can't be retrieved again, only its hash is
+/// stored. Tokens can only be created with a session, never with another token or an API key
+#[utoipa::path(
+    tag = "Personal access tokens",
+    summary = "Creates a personal access token",
+    responses(
+        (status = 201, description = "Token created, the token is only included here")
+    )
+)]
+#[post("/create_token")]
+async fn create_personal_token(request: HttpRequest, body: web::Json<PostPersonalToken>) -> Result<HttpResponse, ApiError> {
+
+    let token_data = body.into_inner();
+
+    let user = users::functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
+
+    if user.is_service_account() || functions::is_token_authenticated(&request) {
+        return Err(ApiError::new(ErrorCode::SessionRequired, "Personal access tokens can only be created with a session"))
+    }
+
+    if token_data.scopes.is_empty() {
+        return Err(ApiError::new(ErrorCode::InvalidRequest, "At least one scope is required"))
+    }
+
+    //  Expirations too long to be represented are beyond any limit the policy can set
+    let expiry_days = i16::try_from(token_data.expires_in_days).unwrap_or(i16::MAX);
+    let request = PolicyRequest::new(Action::CreatePersonalToken, &user).await
+        .with_resource_expiry_days(expiry_days);
+    if !Policy::instance().evaluate(&request).await.is_allowed() {
+        return Err(ApiError::new(ErrorCode::ExpirationTooLong, "Token expiration exceeds the maximum allowed"))
+    }
+
+    let (personal_token, token) = PersonalToken::create_personal_token(
+        user.get_id(),
+        token_data.name.as_str(),
+        token_data.scopes.as_slice(),
+        token_data.expires_in_days
+    ).await?;
+
+    let personal_token_created = PersonalTokenCreated {
+        token_id: *personal_token.get_id(),
+        prefix: personal_token.get_prefix().to_string(),
+        token
+    };
+
+    Ok(json_response(StatusCode::CREATED, general::http_req_res::serialize_into_json(&personal_token_created)?))
+}
+
+/// ##  Endpoint list personal access tokens
+/// GET {UTAUrl}:{UTAPort}/users/manage/list_tokens (private)
+///
+/// ### Description
+/// Lists the personal access tokens of the requesting user, including revoked and expired ones,
+/// with their prefix, scopes, expiry and last time they were used. The tokens themselves are
+/// never included. Requires the "read:self" scope when used with a token
+#[utoipa::path(
+    tag = "Personal access tokens",
+    summary = "Lists the personal access tokens of the requesting user",
+    responses(
+        (status = 200, description = "Tokens, without the tokens themselves")
+    )
+)]
+#[get("/list_tokens", wrap = "RequireScope::new(TokenScope::ReadSelf)")]
+async fn list_personal_tokens(request: HttpRequest) -> Result<HttpResponse, ApiError> {
+
+    let user = users::functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
+
+    let personal_tokens = PersonalToken::select_by_user(user.get_id()).await?;
+
+    Ok(json_response(StatusCode::OK, general::http_req_res::serialize_into_json(&personal_tokens)?))
+}
+
+/// ##  Endpoint revoke personal access token
+/// PUT {UTAUrl}:{UTAPort}/users/manage/revoke_token (private)
+///
+/// #### Required Body
+/// One of the optional parameters must be present in the request body
+/// - token_id (optional): optional u32
+/// - prefix (optional): optional ans-8 string, as returned when the token was created
+///
+/// ### Description
+/// Revokes one of the requesting user's personal access tokens. Revoked tokens are rejected from
+/// then on. Tokens can only be revoked with a session
+#[utoipa::path(
+    tag = "Personal access tokens",
+    summary = "Revokes a personal access token",
+    responses(
+        (status = 204, description = "Token revoked")
+    )
+)]
+#[put("/revoke_token")]
+async fn revoke_personal_token(request: HttpRequest, body: web::Json<RevokePersonalToken>) -> Result<HttpResponse, ApiError> {
+
+    let revoke_data = body.into_inner();
+
+    let user = users::functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
+
+    if functions::is_token_authenticated(&request) {
+        return Err(ApiError::new(ErrorCode::SessionRequired, "Personal access tokens can only be revoked with a session"))
+    }
+
+    let personal_token = if let Some(token_id) = revoke_data.token_id {
+        PersonalToken::select_by_id(&token_id).await?
+    } else if let Some(prefix) = revoke_data.prefix {
+        if !auth::crypt::is_valid_key_prefix(prefix.as_str()) {
+            return Err(ApiError::new(ErrorCode::InvalidRequest, "Invalid token prefix"))
+        }
+        PersonalToken::select_by_prefix(prefix.as_str()).await?
+    } else {
+        return Err(ApiError::new(ErrorCode::InvalidRequest, "Invalid token id and prefix"))
+    };
+
+    //  Tokens of other users are reported as not found, to not disclose they exist
+    let personal_token = match personal_token {
+        Some(personal_token) if personal_token.get_user_id() == user.get_id() => personal_token,
+        _ => return Err(ApiError::new(ErrorCode::PersonalTokenNotFound, "Personal access token not found"))
+    };
+
+    personal_token.revoke().await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -1,11 +1,13 @@
-use actix_web::HttpRequest;
-use error_mapper::{map_to_new_error, TheResult};
+use actix_web::{HttpMessage, HttpRequest};
+use error_mapper::{map_to_new_error, SystemErrorCodes, TheError, TheResult};
 use mysql_async::prelude::Queryable;
 use crate::auth;
 use crate::database::db_conn::get_conn;
 use crate::general::types::UsersIdType;
+use crate::modules::users::session_binding::ClientFingerprint;
 use crate::modules::users::user::User;
 use crate::modules::users::{users_sessions, UsersSessions};
+use crate::modules::users::users_sessions::{SessionCheck, SessionStatus};
 
 pub async fn create_default_super_user() -> TheResult<()> {
 
@@ -40,9 +42,9 @@ pub async fn create_default_super_user() -> TheResult<()> {
 pub fn get_username_from_request(request: HttpRequest) -> Option<String> {
 
     //  Attempt to get username from headers
-    return match request.headers().get("username") {
+    match request.headers().get("username") {
         Some(username) => {
-            return match username.to_str() {
+            match username.to_str() {
                 Ok(username) => Some(username.to_string()),
                 Err(_) =>  None
             }
@@ -54,9 +56,9 @@ pub fn get_username_from_request(request: HttpRequest) -> Option<String> {
 pub fn get_session_token_from_request(request: HttpRequest) -> Option<String> {
 
     //  Attempt to get session token from request
-    return match request.headers().get("token") {
+    match request.headers().get("token") {
         Some(token) => {
-            return match token.to_str() {
+            match token.to_str() {
                 Ok(token) => Some(token.to_string()),
                 Err(_) => None
             }
@@ -65,40 +67,130 @@ pub fn get_session_token_from_request(request: HttpRequest) -> Option<String> {
     }
 }
 
-pub async fn get_user_from_headers(username: Option<String>, token: Option<String>) -> TheResult<Option<User>> {
+/// ## Description
+/// Fetches the user making the request. Requests that went through the authentication middleware
+/// carry the authenticated user, otherwise the user is restored from the username and session
+/// token headers
+pub async fn get_user_from_request(request: &HttpRequest) -> TheResult<Option<User>> {
 
-    let user;
-    if username.is_none() || token.is_none() {
-        Ok(None)
-    } else {
-        //  At this point, both username and session_token are validated to be a Some.
-        let username = username.unwrap();
-        let token = token.unwrap();
-        // We fetch the user from database
-        user = match User::select_by_username(username.as_str()).await {
-            Ok(Some(user)) => user,
-            Ok(None) => {
-                return Ok(None)
-            },
-            Err(e) => return Err(e)
-        };
-
-        //  Validating user is online
-        if !UsersSessions::instance().is_user_logged_in(user.get_id()).await {
+    let authenticated_user = request.extensions().get::<User>().cloned();
+    if let Some(user) = authenticated_user {
+        return Ok(Some(user))
+    }
+
+    let username = get_username_from_request(request.clone());
+    let session_token = get_session_token_from_request(request.clone());
+    let client = get_client_from_request(request).await;
+
+    get_user_from_headers(username, session_token, &client).await
+}
+
+/// Fingerprint of the client making the request, to bind sessions to it
+pub async fn get_client_from_request(request: &HttpRequest) -> ClientFingerprint {
+    ClientFingerprint::from_request(request.peer_addr().map(|address| address.ip()), request.headers()).await
+}
+
+/// ## Description
+/// Logs out the user the session token belongs to, the same way the logout endpoint does. Returns
+/// false if the token doesn't belong to any session
+pub async fn revoke_session_token(token: &str) -> TheResult<bool> {
+
+    let Some(session) = users_sessions::select_session_by_token(token).await? else {
+        return Ok(false)
+    };
+
+    let Some(user) = User::select_by_id(session.get_user_id()).await? else {
+        return Ok(false)
+    };
+
+    users_sessions::terminate_user_session(&user).await?;
+
+    Ok(true)
+}
+
+pub async fn get_user_from_headers(
+    username: Option<String>,
+    token: Option<String>,
+    client: &ClientFingerprint
+) -> TheResult<Option<User>> {
+
+    //  Both username and session token are needed to restore the user
+    let (Some(username), Some(token)) = (username, token) else {
+        return Ok(None)
+    };
+
+    // We fetch the user from database
+    let user = match User::select_by_username(username.as_str()).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
             return Ok(None)
+        },
+        Err(e) => return Err(e)
+    };
+
+    //  Validating user is online
+    if !UsersSessions::instance().is_user_logged_in(user.get_id()).await {
+        return Ok(None)
+    }
+
+    //  Validating the session token, and the client it's used from
+    match users_sessions::validate_session(&user, token.as_str(), client).await {
+        Ok(SessionCheck::Valid) => {
+            Ok(Some(user))
+        },
+        Ok(_) => {
+            Ok(None)
+        },
+        Err(e) => {
+            Err(e)
         }
+    }
+}
+
+/// ## Description
+/// Fetches a user by id or, if no id was received, by username. Returns None if neither was
+/// received or the user was not found
+pub async fn get_user_by_id_or_username(
+    user_id: Option<UsersIdType>,
+    username: Option<String>
+) -> TheResult<Option<User>> {
+
+    if let Some(user_id) = user_id {
+        User::select_by_id(&user_id).await
+    } else if let Some(username) = username {
+        User::select_by_username(username.as_str()).await
+    } else {
+        Ok(None)
+    }
+}
 
-        //  Validating the session token
-        match users_sessions::validate_session_token(&user, token.as_str()).await {
-            Ok(true) => {
-                Ok(Some(user))
-            },
-            Ok(false) => {
-                Ok(None)
-            },
-            Err(e) => {
-                Err(e)
+/// ## Description
+/// Logs the user in once their credentials were checked. If the user already has an active
+/// session, it's extended and its token returned, otherwise a new session is opened with a new
+/// token. Either way, the session gets bound to the client logging in
+pub async fn open_user_session(user: &User, client: &ClientFingerprint) -> TheResult<String> {
+
+    //  Check if user has an active session
+    match users_sessions::check_user_active_session(user.get_id()).await? {
+        SessionStatus::Active => {
+            return match users_sessions::extend_user_session(user, client).await? {
+                //  Fetch existing token from db
+                SessionStatus::Active => users_sessions::fetch_session_token(user.get_id()).await,
+                _ => Err(TheError::new(SystemErrorCodes::GenericError, "Error extending user session".to_string()))
             }
+        },
+        //  If session is expired, the user gets logged in next
+        SessionStatus::Expired => {},
+        SessionStatus::SessionError => {
+            return Err(TheError::new(SystemErrorCodes::GenericError, "Error checking user session".to_string()))
         }
     }
+
+    //  Generate new token to login user
+    let token = auth::crypt::generate_session_token()?;
+
+    //  If user has no active session, execute log in
+    users_sessions::activate_user_session(user, &token, client).await?;
+
+    Ok(token)
 }
@@ -0,0 +1,350 @@
+use std::fmt::{Display, Formatter};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::NaiveDateTime;
+use error_mapper::{map_to_new_error, TheResult};
+use mysql_async::prelude::{FromRow, Queryable};
+use mysql_async::{FromRowError, Row};
+use serde::Serialize;
+use crate::{database, row_to_data, row_to_enum, row_to_naive_datetime, row_to_optional_naive_datetime};
+use crate::database::db_conn::get_conn;
+use crate::general::types::UsersIdType;
+use crate::modules::users::user::Level;
+use crate::modules::users::UsersSessions;
+
+/// Users in a page unless a size is requested, and the most a page can have
+pub const DEFAULT_PAGE_SIZE: u32 = 50;
+pub const MAX_PAGE_SIZE: u32 = 200;
+
+/// The session status isn't in database, so filtering by it scans batches of users until the page
+/// is full. This many at most, after that the page is responded as is, with a cursor to go on
+const MAX_SCANNED_BATCHES: usize = 10;
+
+/// ## Description
+/// What the listing tells about a user. The password hash is never selected
+#[derive(Serialize, Debug, Clone)]
+pub struct UserSummary {
+    id: UsersIdType,
+    username: String,
+    email: String,
+    level: Level,
+    service_account: bool,
+    created_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
+    deleted_at: Option<NaiveDateTime>,
+    logged_in: bool
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct UserPage {
+    users: Vec<UserSummary>,
+    //  Only present if there might be more users, to be sent back as is for the next page
+    next_cursor: Option<String>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SortField {
+    Id,
+    Username,
+    CreatedAt,
+    Level
+}
+
+/// ## Description
+/// Order of the listing, by one field with the user id breaking ties. Parsed from the field name,
+/// prefixed with `-` for descending order
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UserSort {
+    field: SortField,
+    descending: bool
+}
+
+/// ## Description
+/// Position in a listing, the last user scanned for the previous page along with the sort it was
+/// listed with. Only the user id is kept, the value it's sorted by is read from database, so
+/// cursors never carry anything into the queries but a number
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UserCursor {
+    sort: UserSort,
+    after: UsersIdType
+}
+
+/// ## Description
+/// Users to list. Only users with one of the visible levels are ever listed, the rest of the
+/// filters narrow them down
+#[derive(Debug, Clone)]
+pub struct UserFilter {
+    visible_levels: Vec<Level>,
+    level: Option<Level>,
+    deleted: bool,
+    created_after: Option<NaiveDateTime>,
+    created_before: Option<NaiveDateTime>,
+    email_domain: Option<String>,
+    logged_in: Option<bool>
+}
+
+impl UserSort {
+    pub fn parse(source: &str) -> Option<Self> {
+        let (field, descending) = match source.strip_prefix('-') {
+            Some(field) => (field, true),
+            None => (source, false)
+        };
+
+        let field = match field {
+            "id" => SortField::Id,
+            "username" => SortField::Username,
+            "created_at" => SortField::CreatedAt,
+            "level" => SortField::Level,
+            _ => return None
+        };
+
+        Some(Self { field, descending })
+    }
+
+    fn column(&self) -> &'static str {
+        match self.field {
+            SortField::Id => "ID",
+            SortField::Username => "username",
+            SortField::CreatedAt => "created_at",
+            //  Enums are compared as strings, their index keeps the order of the levels
+            SortField::Level => "level + 0"
+        }
+    }
+
+    /// Condition of the users after the one received, in this order
+    fn after_condition(&self, after: UsersIdType) -> String {
+        let comparison = if self.descending { "<" } else { ">" };
+
+        if self.field == SortField::Id {
+            return format!("ID {} {}", comparison, after)
+        }
+
+        let value = format!("(SELECT {} FROM users WHERE ID = {})", self.column(), after);
+        format!(
+            "({column} {comparison} {value} OR ({column} = {value} AND ID {comparison} {after}))",
+            column = self.column(),
+            comparison = comparison,
+            value = value,
+            after = after
+        )
+    }
+
+    fn order_by(&self) -> String {
+        let direction = if self.descending { "DESC" } else { "ASC" };
+        format!("{} {}, ID {}", self.column(), direction, direction)
+    }
+}
+
+impl Default for UserSort {
+    fn default() -> Self {
+        Self { field: SortField::Id, descending: false }
+    }
+}
+
+impl Display for UserSort {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let field = match self.field {
+            SortField::Id => "id",
+            SortField::Username => "username",
+            SortField::CreatedAt => "created_at",
+            SortField::Level => "level"
+        };
+        write!(f, "{}{}", if self.descending { "-" } else { "" }, field)
+    }
+}
+
+impl UserCursor {
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let decoded = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        let (sort, after) = std::str::from_utf8(decoded.as_slice()).ok()?.split_once(':')?;
+
+        Some(Self {
+            sort: UserSort::parse(sort)?,
+            after: after.parse().ok()?
+        })
+    }
+
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(format!("{}:{}", self.sort, self.after))
+    }
+
+    pub fn get_sort(&self) -> UserSort {
+        self.sort
+    }
+
+    pub fn get_after(&self) -> UsersIdType {
+        self.after
+    }
+}
+
+impl UserFilter {
+    pub fn new(visible_levels: Vec<Level>) -> Self {
+        Self {
+            visible_levels,
+            level: None,
+            deleted: false,
+            created_after: None,
+            created_before: None,
+            email_domain: None,
+            logged_in: None
+        }
+    }
+
+    pub fn with_level(mut self, level: Level) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    /// Lists the deleted users instead of the active ones
+    pub fn with_deleted(mut self, deleted: bool) -> Self {
+        self.deleted = deleted;
+        self
+    }
+
+    pub fn with_created_after(mut self, created_after: NaiveDateTime) -> Self {
+        self.created_after = Some(created_after);
+        self
+    }
+
+    pub fn with_created_before(mut self, created_before: NaiveDateTime) -> Self {
+        self.created_before = Some(created_before);
+        self
+    }
+
+    /// Only domains made of letters, digits, dots and hyphens are accepted, returns None otherwise
+    pub fn with_email_domain(mut self, email_domain: &str) -> Option<Self> {
+        let valid = !email_domain.is_empty()
+            && email_domain.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-');
+        if !valid {
+            return None
+        }
+        self.email_domain = Some(email_domain.to_lowercase());
+        Some(self)
+    }
+
+    pub fn with_logged_in(mut self, logged_in: bool) -> Self {
+        self.logged_in = Some(logged_in);
+        self
+    }
+
+    fn conditions(&self) -> Vec<String> {
+        let levels = self.visible_levels.iter()
+            .map(|level| format!("'{}'", level))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let mut conditions = vec![
+            format!("level IN ({})", levels),
+            format!("deleted_at IS {}", if self.deleted { "NOT NULL" } else { "NULL" })
+        ];
+        if let Some(level) = self.level {
+            conditions.push(format!("level = '{}'", level));
+        }
+        if let Some(created_after) = self.created_after {
+            conditions.push(format!("created_at >= '{}'", created_after.format(database::DATETIME_FORMAT)));
+        }
+        if let Some(created_before) = self.created_before {
+            conditions.push(format!("created_at < '{}'", created_before.format(database::DATETIME_FORMAT)));
+        }
+        if let Some(email_domain) = &self.email_domain {
+            conditions.push(format!("LOWER(email) LIKE '%@{}'", email_domain));
+        }
+        conditions
+    }
+}
+
+/// ## Description
+/// Lists a page of the users matching the filter, in the order requested, after the user of the
+/// cursor if there's one. The next cursor is only included if there might be more users
+pub async fn list_users(
+    filter: &UserFilter,
+    sort: UserSort,
+    after: Option<UsersIdType>,
+    page_size: u32
+) -> TheResult<UserPage> {
+
+    let mut users = vec![];
+    let mut after = after;
+
+    for _ in 0..MAX_SCANNED_BATCHES {
+        //  One more than needed, to know if there are more users after the batch
+        let batch = select_batch(filter, sort, after, page_size + 1).await?;
+        let has_more = batch.len() > page_size as usize;
+        let scanned = batch.len().min(page_size as usize);
+
+        for (index, mut user) in batch.into_iter().take(scanned).enumerate() {
+            after = Some(user.id);
+            user.logged_in = UsersSessions::instance().is_user_logged_in(&user.id).await;
+            if filter.logged_in.is_some_and(|logged_in| logged_in != user.logged_in) {
+                continue
+            }
+
+            users.push(user);
+            if users.len() == page_size as usize {
+                let more = index + 1 < scanned || has_more;
+                return Ok(UserPage::new(users, sort, after.filter(|_| more)))
+            }
+        }
+
+        if !has_more {
+            return Ok(UserPage::new(users, sort, None))
+        }
+    }
+
+    Ok(UserPage::new(users, sort, after))
+}
+
+async fn select_batch(
+    filter: &UserFilter,
+    sort: UserSort,
+    after: Option<UsersIdType>,
+    size: u32
+) -> TheResult<Vec<UserSummary>> {
+
+    let conn = &mut get_conn().await?;
+
+    let mut conditions = filter.conditions();
+    if let Some(after) = after {
+        conditions.push(sort.after_condition(after));
+    }
+
+    conn.query::<UserSummary, _>(
+        format!(
+            "SELECT ID, username, email, level, service_account, created_at, updated_at, deleted_at \
+                FROM users WHERE {} ORDER BY {} LIMIT {}",
+            conditions.join(" AND "),
+            sort.order_by(),
+            size
+        )
+    ).await.map_err(|e| map_to_new_error!(e))
+}
+
+impl UserPage {
+    fn new(users: Vec<UserSummary>, sort: UserSort, after: Option<UsersIdType>) -> Self {
+        Self {
+            users,
+            next_cursor: after.map(|after| UserCursor { sort, after }.encode())
+        }
+    }
+}
+
+impl FromRow for UserSummary {
+    fn from_row(row: Row) -> Self where Self: Sized {
+        Self {
+            id: row_to_data!(row, "ID", "users", UsersIdType),
+            username: row_to_data!(row, "username", "users", String),
+            email: row_to_data!(row, "email", "users", String),
+            level: row_to_enum!(row, "level", "users", Level),
+            service_account: row_to_data!(row, "service_account", "users", bool),
+            created_at: row_to_naive_datetime!(row, "created_at", "users"),
+            updated_at: row_to_naive_datetime!(row, "updated_at", "users"),
+            deleted_at: row_to_optional_naive_datetime!(row, "deleted_at", "users"),
+            //  Set from the session store once selected
+            logged_in: false
+        }
+    }
+
+    fn from_row_opt(_: Row) -> Result<Self, FromRowError> where Self: Sized {
+        unimplemented!()
+    }
+}
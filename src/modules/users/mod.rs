@@ -1,14 +1,27 @@
 use std::collections::HashMap;
+use std::sync::Arc;
+use chrono::NaiveDateTime;
 use error_mapper::TheResult;
 use lazy_static::lazy_static;
 use tokio::sync::RwLock;
+use crate::auth;
 use crate::general::types::UsersIdType;
-use crate::modules::users::user::User;
-use crate::modules::users::users_sessions::SessionStatus;
+use crate::modules::users::session_binding::ClientFingerprint;
+use crate::modules::users::session_store::{SessionStore, StoredSession};
+use crate::modules::users::session_store::memory_store::MemorySessionStore;
+use crate::modules::users::user::{Level, User};
+use crate::modules::users::users_sessions::{SessionData, SessionStatus};
 
 pub mod services;
 pub mod functions;
+pub mod listing;
+pub mod profile;
+pub mod profile_schema;
 pub mod queries;
+pub mod reauthentication;
+pub mod session_binding;
+pub mod session_events;
+pub mod session_store;
 pub mod user;
 pub mod users_sessions;
 
@@ -23,14 +36,15 @@ lazy_static!{
 }
 
 
-#[derive(Debug)]
 pub struct UsersSessions {
-    inner: RwLock<UsersSessionsInner>
+    inner: RwLock<UsersSessionsInner>,
+    //  Where the sessions are answered from, set when the service is built
+    store: RwLock<Arc<dyn SessionStore>>
 }
 
 #[derive(Debug)]
 struct UsersSessionsInner {
-    //  User ID, is logged in
+    //  User ID, runtime data of the user
     sessions: HashMap<UsersIdType, UserSessionData>
 }
 
@@ -38,7 +52,8 @@ struct UsersSessionsInner {
 struct UserSessionData {
     username: String,
     email: String,
-    session_status: SessionStatus
+    //  Highest between the user's own level and the levels granted by their groups
+    effective_level: Level
 }
 
 impl UsersSessions {
@@ -46,7 +61,8 @@ impl UsersSessions {
         Self {
             inner: RwLock::new(UsersSessionsInner {
                 sessions: HashMap::new()
-            })
+            }),
+            store: RwLock::new(Arc::new(MemorySessionStore::new()))
         }
     }
 
@@ -54,79 +70,149 @@ impl UsersSessions {
         &USERS_SESSIONS
     }
 
+    pub async fn set_session_store(&self, store: Arc<dyn SessionStore>) {
+        *self.store.write().await = store;
+    }
+
+    async fn session_store(&self) -> Arc<dyn SessionStore> {
+        self.store.read().await.clone()
+    }
+
     pub async fn is_user_logged_in(&self, user_id: &UsersIdType) -> bool {
-        if let Some(session_data) = self.inner.read().await.sessions.get(user_id) {
-            if session_data.session_status == SessionStatus::Active {
-                return true
+        self.get_session_status(user_id).await == SessionStatus::Active
+    }
+
+    /// Status of the user's session. Sessions past their expiry are expired even if the sessions
+    /// cron didn't close them yet
+    pub async fn get_session_status(&self, user_id: &UsersIdType) -> SessionStatus {
+        match self.session_store().await.get_session(user_id).await {
+            Ok(Some(session)) if session.is_active() => SessionStatus::Active,
+            Ok(_) => SessionStatus::Expired,
+            Err(e) => {
+                //  TODO remove when logger is implemented
+                eprintln!("Error reading user {} session: {}", user_id, e);
+                SessionStatus::SessionError
             }
         }
-        false
     }
 
-    pub async fn login_user(&self, user: &User) {
-        //  Looks for a key. If not found, it inserts the login value, otherwise, it sets the logged in status as true
+    /// Checks the session token received against the digest of the user's active session. Returns
+    /// the session if the token is valid
+    pub async fn get_valid_session(&self, user_id: &UsersIdType, token: &str) -> TheResult<Option<StoredSession>> {
+        match self.session_store().await.get_session(user_id).await? {
+            Some(session) if session.is_active() && auth::crypt::credential_matches(session.get_token_digest(), token) => {
+                Ok(Some(session))
+            },
+            _ => Ok(None)
+        }
+    }
+
+    /// Registers the session opened for the user. The session must be already stored in database
+    pub async fn open_session(
+        &self,
+        user: &User,
+        token: &str,
+        expiry: NaiveDateTime,
+        client: &ClientFingerprint
+    ) -> TheResult<()> {
+        //  A new user is registered here when logged in for the first time. Users whose level was
+        // cached before, by a group change, only get their data filled in
         self.inner.write().await.sessions
             .entry(*user.get_id())
-            .and_modify(|session_data| {session_data.session_status = SessionStatus::Active})
-            .or_insert_with(|| {
-                //  This point will be reached when a new user is created and is logged in for the first time
-                UserSessionData{
-                    username: user.get_username().to_string(),
-                    email: user.get_email().to_string(),
-                    session_status: SessionStatus::Active
-                }
-            });
-    }
-
-    pub async fn logout_user(&self, user: &User) {
-        self.inner.write().await.sessions.entry(*user.get_id())
-            .and_modify(|session_data| {session_data.session_status = SessionStatus::Expired})
-            .or_insert_with(|| {
-                //  This point will be reached when a new user is created and is logged in for the first time
-                UserSessionData{
-                    username: user.get_username().to_string(),
-                    email: user.get_email().to_string(),
-                    session_status: SessionStatus::Expired
-                }
-            });
+            .and_modify(|session_data| {
+                session_data.username = user.get_username().to_string();
+                session_data.email = user.get_email().to_string();
+            })
+            .or_insert_with(|| UserSessionData::new(user));
+
+        self.session_store().await
+            .open_session(
+                user.get_id(),
+                StoredSession::new(auth::crypt::session_token_digest(token).as_str(), expiry, client.clone())
+            )
+            .await
+    }
+
+    pub async fn extend_session(&self, user_id: &UsersIdType, expiry: NaiveDateTime, client: &ClientFingerprint) -> TheResult<()> {
+        self.session_store().await.extend_session(user_id, expiry, client).await
+    }
+
+    pub async fn logout_user(&self, user: &User) -> TheResult<()> {
+        self.close_session(user.get_id()).await
+    }
+
+    pub async fn close_session(&self, user_id: &UsersIdType) -> TheResult<()> {
+        self.session_store().await.close_session(user_id).await
+    }
+
+    /// ## Description
+    /// Sets the session store to the active sessions stored in database, which are the source of
+    /// truth. Any other session is closed. Returns how many sessions were different from the database
+    pub async fn reconcile_sessions(&self, active_sessions: &[SessionData]) -> TheResult<usize> {
+        self.session_store().await.reconcile(active_sessions).await
     }
     
-    pub async fn delete_user_entry(&self, user_id: &UsersIdType) {
+    /// Returns the cached effective level of the user, or the user's own level if it's not cached
+    /// or higher. If the user authenticated with a scoped credential, the level is capped by its
+    /// scopes
+    pub async fn get_effective_level(&self, user: &User) -> Level {
+        let effective_level = match self.inner.read().await.sessions.get(user.get_id()) {
+            Some(session_data) => session_data.effective_level.max(*user.get_level()),
+            None => *user.get_level()
+        };
+
+        match user.get_scope_level() {
+            Some(scope_level) => effective_level.min(*scope_level),
+            None => effective_level
+        }
+    }
+
+    /// Caches the effective level of the user, registering the user in runtime if it wasn't, such
+    /// as users created after startup that never logged in
+    pub async fn set_effective_level(&self, user_id: &UsersIdType, level: Level) {
+        self.inner.write().await.sessions.entry(*user_id)
+            .or_default()
+            .effective_level = level;
+    }
+
+    /// Raises the cached effective level of the user to the level received, if it's higher
+    pub async fn grant_effective_level(&self, user_id: &UsersIdType, level: Level) {
+        let mut inner = self.inner.write().await;
+        let session_data = inner.sessions.entry(*user_id).or_default();
+        session_data.effective_level = session_data.effective_level.max(level);
+    }
+
+    pub async fn rename_user(&self, user_id: &UsersIdType, username: &str) {
+        self.inner.write().await.sessions.entry(*user_id)
+            .and_modify(|session_data| {session_data.username = username.to_string()});
+    }
+
+    pub async fn delete_user_entry(&self, user_id: &UsersIdType) -> TheResult<()> {
         //  If the user exists, it'll get deleted. If not, there was no user to start with. No need to check
         self.inner.write().await.sessions.remove(user_id);
+        self.close_session(user_id).await
     }
 
     pub async fn register_users_in_runtime(&self, users: &[User]) -> TheResult<()> {
 
         //  Registering users in runtime session data
         for user in users {
-            self.inner.write().await.sessions.insert(
-                *user.get_id(),
-                UserSessionData {
-                    username: user.get_username().to_string(),
-                    email: user.get_email().to_string(),
-                    session_status: SessionStatus::Expired
-                }
-            );
+            self.inner.write().await.sessions.insert(*user.get_id(), UserSessionData::new(user));
         }
 
-        //  Updating the session status reading user sessions from database
-        let sessions = users_sessions::SessionData::get_all_user_sessions().await?;
-
-        for session in sessions {
-            self.inner.write().await.sessions.entry(*session.get_user_id())
-                .and_modify(|session_data| {
-                    session_data.session_status = *session.get_session_status()
-                })
-                .or_insert_with(||{
-                    //  If we get here, it means that there's a user session registered in database
-                    // but the origin user doesn't exist. Big problem
-                    //  TODO remove when logger is implemented
-                    println!("User {} not found in runtime sessions", session.get_user_id());
-                    UserSessionData::default()
-                });
-        }
+        //  Loading the active sessions from database, closing the expired ones
+        users_sessions::reconcile_runtime_sessions().await?;
 
         Ok(())
     }
 }
+
+impl UserSessionData {
+    fn new(user: &User) -> Self {
+        Self {
+            username: user.get_username().to_string(),
+            email: user.get_email().to_string(),
+            effective_level: *user.get_level()
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use error_mapper::{map_to_new_error, TheResult};
+use mysql_async::prelude::{FromRow, Queryable};
+use mysql_async::{FromRowError, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use utoipa::ToSchema;
+use crate::{database, row_to_data};
+use crate::database::db_conn::get_conn;
+use crate::general::types::UsersIdType;
+use crate::modules::users::profile_schema::ProfileSchema;
+
+/// ## Description
+/// Profile attributes of a user, kept apart from the account. The typed attributes are the same
+/// for every app, and the metadata holds the ones registered in the profile schema
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct UserProfile {
+    first_name: Option<String>,
+    last_name: Option<String>,
+    locale: Option<String>,
+    timezone: Option<String>,
+    avatar_url: Option<String>,
+    metadata: Map<String, Value>
+}
+
+/// ## Description
+/// Changes to a profile. Only the attributes present are changed, an empty string removes a typed
+/// attribute and a null removes a metadata attribute
+#[derive(Deserialize, Debug, Clone, Default, ToSchema)]
+pub struct ProfileChanges {
+    first_name: Option<String>,
+    last_name: Option<String>,
+    locale: Option<String>,
+    timezone: Option<String>,
+    avatar_url: Option<String>,
+    #[schema(value_type = Option<Object>)]
+    metadata: Option<Map<String, Value>>
+}
+
+impl UserProfile {
+
+    /// Profile of the user, empty if the user never set one
+    pub async fn select_by_user_id(user_id: &UsersIdType) -> TheResult<Self> {
+
+        let conn = &mut get_conn().await?;
+
+        let profile = conn.exec_first::<Self, _, _>(
+            "SELECT first_name, last_name, locale, timezone, avatar_url, metadata FROM users_profiles WHERE users_ID = ?",
+            (user_id,)
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(profile.unwrap_or_default())
+    }
+
+    pub async fn save(&self, user_id: &UsersIdType) -> TheResult<()> {
+
+        let conn = &mut get_conn().await?;
+
+        let metadata = serde_json::to_string(&self.metadata).map_err(|e| map_to_new_error!(e))?;
+
+        //  Metadata is free text, the values are sent as parameters and never formatted in
+        conn.exec_drop(
+            "INSERT INTO users_profiles (users_ID, first_name, last_name, locale, timezone, avatar_url, metadata, updated_at) \
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?) \
+                ON DUPLICATE KEY UPDATE first_name = VALUES(first_name), last_name = VALUES(last_name), \
+                locale = VALUES(locale), timezone = VALUES(timezone), avatar_url = VALUES(avatar_url), \
+                metadata = VALUES(metadata), updated_at = VALUES(updated_at)",
+            (
+                user_id,
+                self.first_name.as_deref(),
+                self.last_name.as_deref(),
+                self.locale.as_deref(),
+                self.timezone.as_deref(),
+                self.avatar_url.as_deref(),
+                metadata,
+                chrono::Utc::now().naive_utc().format(database::DATETIME_FORMAT).to_string()
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+
+    /// Applies the changes received, without validating them
+    pub fn apply(&mut self, changes: ProfileChanges) {
+        let clear_empty = |value: String| Some(value).filter(|value| !value.is_empty());
+
+        if let Some(first_name) = changes.first_name {
+            self.first_name = clear_empty(first_name);
+        }
+        if let Some(last_name) = changes.last_name {
+            self.last_name = clear_empty(last_name);
+        }
+        if let Some(locale) = changes.locale {
+            self.locale = clear_empty(locale);
+        }
+        if let Some(timezone) = changes.timezone {
+            self.timezone = clear_empty(timezone);
+        }
+        if let Some(avatar_url) = changes.avatar_url {
+            self.avatar_url = clear_empty(avatar_url);
+        }
+        for (name, value) in changes.metadata.unwrap_or_default() {
+            match value {
+                Value::Null => self.metadata.remove(&name),
+                value => self.metadata.insert(name, value)
+            };
+        }
+    }
+
+    /// ## Description
+    /// Validates the profile, the typed attributes by their format and the metadata against the
+    /// profile schema. Returns an error for each attribute not valid
+    pub fn validate(&self) -> Vec<String> {
+
+        let mut errors = vec![];
+
+        for (name, value) in [("First name", &self.first_name), ("Last name", &self.last_name)] {
+            if value.as_ref().is_some_and(|value| value.chars().count() > 50 || value.chars().any(char::is_control)) {
+                errors.push(format!("{} must be at most 50 characters long, without control characters", name));
+            }
+        }
+        //  Language tags such as "en" or "es-AR"
+        if self.locale.as_ref().is_some_and(|locale| {
+            locale.len() > 35 || !locale.split('-').all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric()))
+        }) {
+            errors.push("Locale must be a language tag, such as en-US".to_string());
+        }
+        //  Time zone names such as "America/Argentina/Buenos_Aires" or "UTC"
+        if self.timezone.as_ref().is_some_and(|timezone| {
+            timezone.len() > 64 || !timezone.split('/').all(|part| {
+                !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || "_-+".contains(c))
+            })
+        }) {
+            errors.push("Timezone must be a time zone name, such as Europe/Madrid".to_string());
+        }
+        if self.avatar_url.as_ref().is_some_and(|avatar_url| {
+            avatar_url.len() > 255 || !avatar_url.starts_with("https://") || avatar_url.chars().any(|c| c.is_whitespace() || c.is_control())
+        }) {
+            errors.push("Avatar URL must be an https URL of at most 255 characters".to_string());
+        }
+
+        errors.extend(ProfileSchema::instance().validate(&self.metadata));
+
+        errors
+    }
+
+    pub fn get_first_name(&self) -> Option<&str> {
+        self.first_name.as_deref()
+    }
+
+    pub fn get_last_name(&self) -> Option<&str> {
+        self.last_name.as_deref()
+    }
+
+    pub fn get_locale(&self) -> Option<&str> {
+        self.locale.as_deref()
+    }
+
+    pub fn get_timezone(&self) -> Option<&str> {
+        self.timezone.as_deref()
+    }
+
+    pub fn get_avatar_url(&self) -> Option<&str> {
+        self.avatar_url.as_deref()
+    }
+
+    pub fn get_metadata(&self) -> &Map<String, Value> {
+        &self.metadata
+    }
+}
+
+impl ProfileChanges {
+    pub fn is_empty(&self) -> bool {
+        self.first_name.is_none()
+            && self.last_name.is_none()
+            && self.locale.is_none()
+            && self.timezone.is_none()
+            && self.avatar_url.is_none()
+            && self.metadata.is_none()
+    }
+}
+
+impl FromRow for UserProfile {
+    fn from_row(row: Row) -> Self where Self: Sized {
+        Self {
+            first_name: row_to_data!(row, "first_name", "users_profiles", Option<String>),
+            last_name: row_to_data!(row, "last_name", "users_profiles", Option<String>),
+            locale: row_to_data!(row, "locale", "users_profiles", Option<String>),
+            timezone: row_to_data!(row, "timezone", "users_profiles", Option<String>),
+            avatar_url: row_to_data!(row, "avatar_url", "users_profiles", Option<String>),
+            metadata: row_to_data!(row, "metadata", "users_profiles", Option<String>)
+                .and_then(|metadata| serde_json::from_str(metadata.as_str()).ok())
+                .unwrap_or_default()
+        }
+    }
+
+    fn from_row_opt(_: Row) -> Result<Self, FromRowError> where Self: Sized {
+        unimplemented!()
+    }
+}
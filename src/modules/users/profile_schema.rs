@@ -0,0 +1,149 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Error;
+use std::io::ErrorKind::InvalidData;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use crate::modules::oauth::oidc::RESERVED_CLAIMS;
+
+lazy_static!{
+    /// Metadata attributes users can have, loaded from the profile schema file at startup. Every
+    /// metadata value is validated against it before being stored
+    static ref PROFILE_SCHEMA: ProfileSchema = ProfileSchema::new();
+}
+
+const PROFILE_SCHEMA_FILE_PATH: &str = "config/profile_schema.json";
+
+/// Longest attribute name accepted, and longest string value of attributes without max_length
+const ATTRIBUTE_NAME_MAX_LENGTH: usize = 30;
+const DEFAULT_STRING_MAX_LENGTH: usize = 255;
+
+pub struct ProfileSchema {
+    attributes: Vec<MetadataAttribute>
+}
+
+#[derive(Deserialize)]
+struct ProfileSchemaFile {
+    attributes: Vec<MetadataAttribute>
+}
+
+/// ## Description
+/// Registered metadata attribute. Only registered attributes can be stored, with values of their
+/// type, and only the ones marked as claim are included in the tokens issued for the user
+#[derive(Deserialize, Debug, Clone)]
+pub struct MetadataAttribute {
+    name: String,
+    #[serde(rename = "type")]
+    attribute_type: AttributeType,
+    //  Strings only
+    max_length: Option<usize>,
+    //  Values accepted, any value of the type if empty. Strings only
+    #[serde(default)]
+    values: Vec<String>,
+    #[serde(default)]
+    claim: bool
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum AttributeType {
+    String,
+    Number,
+    Boolean
+}
+
+impl ProfileSchema {
+    fn new() -> Self {
+        Self::load().unwrap()
+    }
+
+    fn load() -> std::io::Result<Self> {
+        let file = File::open(PROFILE_SCHEMA_FILE_PATH)
+            .map_err(|e| Error::new(InvalidData, format!("{}", e)))?;
+
+        let schema_file = serde_json::from_reader::<_, ProfileSchemaFile>(file)
+            .map_err(|e| Error::new(InvalidData, format!("{}", e)))?;
+
+        let mut names = HashSet::new();
+        for attribute in &schema_file.attributes {
+            let valid_name = !attribute.name.is_empty()
+                && attribute.name.len() <= ATTRIBUTE_NAME_MAX_LENGTH
+                && attribute.name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+            if !valid_name {
+                return Err(Error::new(InvalidData, format!("Attribute {}: invalid name", attribute.name)))
+            }
+            if !names.insert(attribute.name.as_str()) {
+                return Err(Error::new(InvalidData, format!("Attribute {}: registered twice", attribute.name)))
+            }
+            //  Claims are flattened into the tokens, they can't take the place of the standard ones
+            if attribute.claim && RESERVED_CLAIMS.contains(&attribute.name.as_str()) {
+                return Err(Error::new(InvalidData, format!("Attribute {}: reserved claim name", attribute.name)))
+            }
+            if attribute.attribute_type != AttributeType::String && (attribute.max_length.is_some() || !attribute.values.is_empty()) {
+                return Err(Error::new(InvalidData, format!("Attribute {}: only strings take max_length and values", attribute.name)))
+            }
+        }
+
+        Ok(Self {
+            attributes: schema_file.attributes
+        })
+    }
+
+    pub fn instance() -> &'static Self {
+        &PROFILE_SCHEMA
+    }
+
+    /// Forces the schema file to be read and parsed, so a broken schema stops the app at startup
+    pub fn load_at_startup() {
+        lazy_static::initialize(&PROFILE_SCHEMA);
+    }
+
+    fn get_attribute(&self, name: &str) -> Option<&MetadataAttribute> {
+        self.attributes.iter().find(|attribute| attribute.name == name)
+    }
+
+    /// ## Description
+    /// Validates the metadata against the registered attributes. Returns an error for each
+    /// attribute not registered or with a value not accepted, none if the metadata is valid
+    pub fn validate(&self, metadata: &Map<String, Value>) -> Vec<String> {
+
+        let mut errors = vec![];
+
+        for (name, value) in metadata {
+            let Some(attribute) = self.get_attribute(name) else {
+                errors.push(format!("Attribute {} is not registered", name));
+                continue
+            };
+
+            let valid = match (attribute.attribute_type, value) {
+                (AttributeType::String, Value::String(value)) => {
+                    value.chars().count() <= attribute.max_length.unwrap_or(DEFAULT_STRING_MAX_LENGTH)
+                        && (attribute.values.is_empty() || attribute.values.contains(value))
+                },
+                (AttributeType::Number, Value::Number(_)) => true,
+                (AttributeType::Boolean, Value::Bool(_)) => true,
+                _ => false
+            };
+            if !valid {
+                errors.push(format!("Attribute {} must be a valid {:?}", name, attribute.attribute_type));
+            }
+        }
+
+        errors
+    }
+
+    /// Attributes of the metadata included as claims in the tokens
+    pub fn claims(&self, metadata: &Map<String, Value>) -> Map<String, Value> {
+        metadata.iter()
+            .filter(|(name, _)| self.get_attribute(name).is_some_and(|attribute| attribute.claim))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    }
+
+    pub fn claim_names(&self) -> Vec<&str> {
+        self.attributes.iter()
+            .filter(|attribute| attribute.claim)
+            .map(|attribute| attribute.name.as_str())
+            .collect()
+    }
+}
@@ -0,0 +1,48 @@
+use actix_web::{HttpMessage, HttpRequest};
+use crate::api::authenticator::Credential;
+use crate::api::versioning::ApiVersion;
+use crate::config::environment::EnvironmentConfig;
+use crate::general::api_error::ApiError;
+use crate::general::problem::{ErrorCode, Problem};
+use crate::modules::users::user::User;
+use crate::modules::users::users_sessions;
+
+/// Endpoint confirming the password, sent along with the re-authentication problems
+const REAUTHENTICATE_WITH: &str = "/users/manage/check_password";
+
+/// ## Description
+/// Whether the request was authenticated with a session, as recorded by the authentication.
+/// Requests without a recorded credential are treated as sessions, so they must re-authenticate
+pub fn authenticated_with_session(request: &HttpRequest) -> bool {
+    request.extensions().get::<Credential>()
+        .is_none_or(|credential| matches!(credential, Credential::Session))
+}
+
+/// ## Description
+/// Guard for sensitive operations. Requests authenticated with a session must come after the
+/// user confirmed their password within the configured window, and get the
+/// `auth.reauthentication_required` problem otherwise. API keys and tokens are limited by their
+/// scopes instead, they have no session to re-authenticate
+pub async fn require_recent_reauthentication(request: &HttpRequest, user: &User) -> Result<(), ApiError> {
+
+    if !authenticated_with_session(request) {
+        return Ok(())
+    }
+
+    let window_seconds = EnvironmentConfig::instance().get_reauthentication_window_seconds().await;
+
+    let reauthenticated_at = users_sessions::select_reauthenticated_at(user.get_id()).await?;
+
+    let oldest_allowed = chrono::Utc::now().naive_utc() - chrono::Duration::seconds(window_seconds);
+    if reauthenticated_at.is_some_and(|reauthenticated_at| reauthenticated_at >= oldest_allowed) {
+        return Ok(())
+    }
+
+    Err(
+        Problem::new(ErrorCode::ReauthenticationRequired)
+            .with_detail("Confirm your password to perform this operation")
+            .with_extension("reauthenticate_with", format!("{}{}", ApiVersion::CURRENT.prefix(), REAUTHENTICATE_WITH))
+            .with_extension("window_seconds", window_seconds)
+            .into()
+    )
+}
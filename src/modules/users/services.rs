@@ -1,15 +1,27 @@
 
-use actix_web::{get, HttpRequest, HttpResponse, post, put, web};
+use actix_web::{get, HttpMessage, HttpRequest, HttpResponse, patch, post, put, web};
 use actix_web::http::StatusCode;
+use chrono::NaiveDateTime;
+use error_mapper::TheResult;
 use serde::{Deserialize, Serialize};
-use crate::{auth, general};
-use crate::general::http_req_res::{json_response, plain_text_response};
+use utoipa::{IntoParams, ToSchema};
+use crate::api::authentication::RequireScope;
+use crate::api::authenticator::Credential;
+use crate::general;
+use crate::auth::policy::{Action, Policy, PolicyRequest};
+use crate::general::api_error::ApiError;
+use crate::general::http_req_res::{data_response, json_response};
+use crate::general::problem::{ErrorCode, Problem};
 use crate::general::types::UsersIdType;
-use crate::modules::users::{functions, user, users_sessions};
+use crate::modules::groups;
+use crate::modules::users::{functions, listing, reauthentication, user, users_sessions, UsersSessions};
+use crate::modules::users::listing::{UserCursor, UserFilter, UserSort};
+use crate::modules::users::profile::{ProfileChanges, UserProfile};
 use crate::modules::users::user::{Level, User};
-use crate::modules::users::users_sessions::{SessionStatus};
+use crate::modules::users::users_sessions::{SessionCheck, SessionData, SessionStatus};
+use crate::modules::personal_tokens::personal_token::TokenScope;
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, ToSchema)]
 struct PostUser {
     username: String,
     password: String,
@@ -23,7 +35,13 @@ struct UserCreated {
     session_token: String
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize)]
+struct UserLoggedIn {
+    user_id: UsersIdType,
+    session_token: String
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
 struct UserLoginData {
     #[serde(default)]
     username: String,
@@ -31,36 +49,100 @@ struct UserLoginData {
     password: String
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, ToSchema)]
 struct UserDelete {
+    #[schema(value_type = Option<u32>)]
     user_id: Option<UsersIdType>,
     username: Option<String>
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, ToSchema)]
 struct ValidatePassword {
     password: String
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, ToSchema)]
 struct ChangePassword {
     old_password: String,
     new_password: String
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct ChangeUsername {
+    new_username: String
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
 struct UndoDeleteUser {
+    #[schema(value_type = Option<u32>)]
     user_id: Option<UsersIdType>,
     username: Option<String>
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, ToSchema)]
 struct ChangeUserLevel {
+    #[schema(value_type = Option<u32>)]
     user_id: Option<UsersIdType>,
     username: Option<String>,
     level: u8
 }
 
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct ExplainPolicy {
+    action: Action,
+    #[schema(value_type = Option<u32>)]
+    user_id: Option<UsersIdType>,
+    username: Option<String>,
+    level: Option<u8>
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+struct UpdateProfile {
+    email: Option<String>,
+    display_name: Option<String>,
+    #[serde(flatten)]
+    attributes: ProfileChanges
+}
+
+#[derive(Serialize)]
+struct Profile {
+    id: UsersIdType,
+    username: String,
+    email: String,
+    display_name: Option<String>,
+    level: Level,
+    created_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
+    #[serde(flatten)]
+    attributes: UserProfile,
+    sessions: Vec<ActiveSession>
+}
+
+#[derive(Serialize)]
+struct ActiveSession {
+    created_at: NaiveDateTime,
+    expires_at: NaiveDateTime,
+    client_ip: Option<String>,
+    user_agent_family: Option<String>
+}
+
+#[derive(Deserialize, Debug, Clone, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListUsers {
+    cursor: Option<String>,
+    limit: Option<u32>,
+    level: Option<u8>,
+    #[serde(default)]
+    deleted: bool,
+    #[param(value_type = Option<String>)]
+    created_after: Option<NaiveDateTime>,
+    #[param(value_type = Option<String>)]
+    created_before: Option<NaiveDateTime>,
+    email_domain: Option<String>,
+    logged_in: Option<bool>,
+    sort: Option<String>
+}
+
 
 /// ##  Endpoint login
 /// POST {UTAUrl}:{UTAPort}/users/login
@@ -68,8 +150,15 @@ struct ChangeUserLevel {
 /// #### Required Body fields
 /// - username: ans-20 max
 /// - password: ans-30 max
+#[utoipa::path(
+    tag = "Users",
+    summary = "Logs a user in",
+    responses(
+        (status = 200, description = "Session created, wrapped in a data envelope")
+    )
+)]
 #[post("/login")]
-async fn user_login(body: web::Json<UserLoginData>) -> HttpResponse {
+async fn user_login(request: HttpRequest, body: web::Json<UserLoginData>) -> Result<HttpResponse, ApiError> {
 
     let user_login_data = body.into_inner();
     let (username, password) = (
@@ -77,60 +166,26 @@ async fn user_login(body: web::Json<UserLoginData>) -> HttpResponse {
     );
 
     //  Get user data from db
-    let user = match User::select_by_username(username).await {
-        Ok(Some(user)) => user,
-        Ok(None) => return json_response(StatusCode::BAD_REQUEST, "Invalid username or password".to_string()),
-        Err(_) => return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error logging in".to_string())
-    };
+    let user = User::select_by_username(username).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidCredentials, "Invalid username or password"))?;
 
     //  Check password and execute login
     //  Password is the value received from the request. self.hashed_pass is the value fetched from db
     if !user.validate_hashed_password(password) {
-        return json_response(StatusCode::UNAUTHORIZED, "Invalid username or password".to_string())
+        return Err(ApiError::new(ErrorCode::InvalidCredentials, "Invalid username or password"))
     };
 
-    //  Check if user has an active session
-    match users_sessions::check_user_active_session(user.get_id()).await {
-        Ok(SessionStatus::Active) => {
-            return match users_sessions::extend_user_session(&user).await {
-                Ok(_) => {
-                    //  Fetch existing token from db
-                    let token = match users_sessions::fetch_session_token(user.get_id()).await {
-                        Ok(token) => token,
-                        Err(_) => {
-                            return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error logging in".to_string())
-                        }
-                    };
-
-                    plain_text_response(StatusCode::OK, token)
-                },
-                Err(_) => json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error logging in".to_string())
-            }
-        },
-        //  If session is expired, the user gets logged in next
-        Ok(SessionStatus::Expired) => {},
-        Ok(SessionStatus::SessionError) | Err(_) => {
-            return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error checking user session. Session error".to_string())
-        }
-    }
+    //  Opens a new session, or extends the active one and responds with its token. Either way, the
+    // session is bound to the client logging in
+    let client = functions::get_client_from_request(&request).await;
+    let token = functions::open_user_session(&user, &client).await?;
 
-    //  Generate new token to login user
-    let token = match auth::crypt::generate_session_token() {
-        Ok(token) => token,
-        Err(_) => {
-            return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error processing login".to_string())
-        }
+    let user_logged_in = UserLoggedIn {
+        user_id: *user.get_id(),
+        session_token: token
     };
 
-    //  If user has no active session, execute log in
-    match users_sessions::activate_user_session(&user, &token).await {
-        Ok(_) => {
-            plain_text_response(StatusCode::OK, token)
-        },
-        Err(_) => {
-            json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error logging in".to_string())
-        }
-    }
+    Ok(data_response(StatusCode::OK, &user_logged_in))
 }
 
 /// ##  Endpoint logout
@@ -139,39 +194,40 @@ async fn user_login(body: web::Json<UserLoginData>) -> HttpResponse {
 /// #### Required Headers
 /// - username: ans-20 max
 /// - token: ans-50 max
+#[utoipa::path(
+    tag = "Users",
+    summary = "Logs a user out",
+    responses(
+        (status = 200, description = "Session closed")
+    )
+)]
 #[post("/logout")]
-async fn user_logout(request: HttpRequest) -> HttpResponse {
+async fn user_logout(request: HttpRequest) -> Result<HttpResponse, ApiError> {
 
     let username = functions::get_username_from_request(request.clone());
     let session_token = functions::get_session_token_from_request(request.clone());
 
-    let user = match functions::get_user_from_headers(username, session_token.clone()).await {
-        Ok(Some(user)) => user,
-        Ok(None) => return json_response(StatusCode::UNAUTHORIZED, "Invalid username or session token".to_string()),
-        Err(_) => return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error changing password".to_string())
-    };
+    let client = functions::get_client_from_request(&request).await;
+
+    let user = functions::get_user_from_headers(username, session_token.clone(), &client).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
 
     //  Check if user has an active session
-    match users_sessions::check_user_active_session(user.get_id()).await {
-        Ok(SessionStatus::Active) => {
+    match users_sessions::check_user_active_session(user.get_id()).await? {
+        SessionStatus::Active => {
             //  If the session is active, proceed to logout
         },
-        Ok(SessionStatus::Expired) => {
-            return json_response(StatusCode::UNAUTHORIZED, "Session expired".to_string())
+        SessionStatus::Expired => {
+            return Err(ApiError::new(ErrorCode::SessionExpired, "Session expired"))
         },
-        Ok(SessionStatus::SessionError) | Err(_) => {
-            return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error checking user session".to_string())
+        SessionStatus::SessionError => {
+            return Err(ApiError::new(ErrorCode::Internal, "Error checking user session"))
         }
     }
 
-    match users_sessions::terminate_user_session(&user).await {
-        Ok(_) => {
-            json_response(StatusCode::OK, "Successfully logged out".to_string())
-        },
-        Err(_) => {
-            json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error logging out".to_string())
-        }
-    }
+    users_sessions::terminate_user_session(&user).await?;
+
+    Ok(HttpResponse::Ok().json("Successfully logged out"))
 }
 
 /// ##  Endpoint create user
@@ -182,18 +238,27 @@ async fn user_logout(request: HttpRequest) -> HttpResponse {
 /// - username: ans-20 max string
 /// - password: ans-30 max string
 /// - email: ans-50 max string
+#[utoipa::path(
+    tag = "Users",
+    summary = "Creates a user",
+    responses(
+        (status = 201, description = "User created and logged in, wrapped in a data envelope")
+    )
+)]
 #[post("/create_user")]
-async fn create_user(request: HttpRequest, body: web::Json<PostUser>) -> HttpResponse {
+async fn create_user(request: HttpRequest, body: web::Json<PostUser>) -> Result<HttpResponse, ApiError> {
+
+    //  Also mounted in the public scope for sign ups, so it can't have a scope guard. Requests
+    // authenticated in the internal scope must still have the scope
+    let credential = request.extensions().get::<Credential>().cloned();
+    if let Some(credential) = credential {
+        credential.require_scope(TokenScope::AdminUsers)
+            .map_err(|_| ApiError::new(ErrorCode::InsufficientScope, "Token lacks the required scope"))?;
+    }
 
     //  First of all check if username is available, to avoid unnecessary computations
-    match user::username_available(body.username.as_str()).await {
-        Ok(true) => {},
-        Ok(false) => {
-            return json_response(StatusCode::BAD_REQUEST, "Username not available".to_string())
-        },
-        Err(_) => {
-            return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error creating user".to_string())
-        }
+    if !user::username_available(body.username.as_str(), None).await? {
+        return Err(ApiError::new(ErrorCode::UsernameTaken, "Username not available"))
     }
 
     //  Attempt to get username from headers
@@ -202,80 +267,75 @@ async fn create_user(request: HttpRequest, body: web::Json<PostUser>) -> HttpRes
     //  Attempt to get session token from request
     let session_token = functions::get_session_token_from_request(request.clone());
 
+    //  The session opened for the new user is bound to the client creating it
+    let client = functions::get_client_from_request(&request).await;
+
     //  Check availability of user to create
-    match User::select_by_username(body.username.as_str()).await {
-        Ok(Some(_)) => return json_response(StatusCode::BAD_REQUEST, "Username not available".to_string()),
-        Ok(None) => {},
-        Err(_) => return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error creating user".to_string())
+    if User::select_by_username(body.username.as_str()).await?.is_some() {
+        return Err(ApiError::new(ErrorCode::UsernameTaken, "Username not available"))
     }
 
     //  Validate password
     let errors = User::validate_password(&body.password);
     if !errors.is_empty() {
-        return json_response(StatusCode::BAD_REQUEST, errors.join("\n"));
+        return Err(
+            Problem::new(ErrorCode::ValidationFailed)
+                .with_detail("Invalid password")
+                .with_extension("errors", errors)
+                .into()
+        )
     }
 
     //  If username and session token could be retrieved from headers, validate level to create an
     // account one level below that one
     let mut account_level = Level::Low;
-    if username.is_some() && session_token.is_some() {
-        //  At this point username and session_token are validated to be a Some
-        let user = match User::select_by_username(username.unwrap().as_str()).await {
-            Ok(Some(user)) => user,
-            Ok(None) => return json_response(StatusCode::BAD_REQUEST, "Invalid username or session token".to_string()),
-            Err(_) => return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error fetching user data".to_string())
-        };
+    if let (Some(username), Some(session_token)) = (username, session_token) {
+        let user = User::select_by_username(username.as_str()).await?
+            .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
 
-        match users_sessions::validate_session_token(&user, session_token.unwrap().as_str()).await {
-            Ok(true) => {
+        match users_sessions::validate_session(&user, session_token.as_str(), &client).await? {
+            SessionCheck::Valid => {
                 //  Attempts to fetch the Level sent in the request body
                 if let Some(level_u8) = body.level {
                     let level = level_u8.into();
-                    if level > user.get_level().one_level_below() {
-                        return json_response(
-                            StatusCode::BAD_REQUEST,
-                            "User level must be at least one level below the requesting account's".to_string()
+                    let request = PolicyRequest::new(Action::CreateUser, &user).await
+                        .with_resource_target_level(level);
+                    if !Policy::instance().evaluate(&request).await.is_allowed() {
+                        return Err(
+                            ApiError::new(
+                                ErrorCode::LevelNotAllowed,
+                                "User level must be at least one level below the requesting account's"
+                            )
                         )
                     } else {
                         account_level = level;
                     }
                 } else {
                     //  If not possible to fetch, it'll create a user with one level below the requesting user
-                    account_level = user.get_level().one_level_below();
+                    account_level = UsersSessions::instance().get_effective_level(&user).await.one_level_below();
                 }
             },
             _ => {
-                return json_response(StatusCode::UNAUTHORIZED, "Invalid username or session token".to_string())
+                return Err(ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))
             }
         };
     }
 
     //  Create a user account with the data from the body, and the level fetched above
-    let (user_id, token) = match User::create_user(
+    let (user_id, token) = User::create_user(
         &body.username,
         &body.password,
         &body.email,
-        &account_level
-    ).await {
-        Ok((user, token)) => (user, token),
-        Err(_) => {
-            return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error creating user".to_string())
-        }
-    };
+        &account_level,
+        &client
+    ).await?;
 
     let user_created = UserCreated {
         user_id,
         session_token: token
     };
 
-    let body = match general::http_req_res::serialize_into_json(&user_created) {
-        Ok(body) => body,
-        Err(_) => {
-            return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error creating user".to_string())
-        }
-    };
-
-    json_response(StatusCode::CREATED, body)
+    Ok(data_response(StatusCode::CREATED, &user_created))
 }
 
 /// ##  Endpoint change password
@@ -284,31 +344,89 @@ async fn create_user(request: HttpRequest, body: web::Json<PostUser>) -> HttpRes
 /// #### Required Body
 /// - old_password: ans-50 max String
 /// - new_password: ans-50 max String
-#[put("/change_password")]
-async fn change_password(request: HttpRequest, body: web::Json<ChangePassword>) -> HttpResponse {
+#[utoipa::path(
+    tag = "Users",
+    summary = "Changes the password of the requesting user",
+    responses(
+        (status = 200, description = "Password changed")
+    )
+)]
+#[put("/change_password", wrap = "RequireScope::new(TokenScope::ManagePassword)")]
+async fn change_password(request: HttpRequest, body: web::Json<ChangePassword>) -> Result<HttpResponse, ApiError> {
+
+    let user = functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
+
+    //  Sensitive operation, the user must have confirmed their password recently
+    reauthentication::require_recent_reauthentication(&request, &user).await?;
 
-    let username = functions::get_username_from_request(request.clone());
-    let session_token = functions::get_session_token_from_request(request.clone());
+    //  Validating old password
+    if !user.validate_hashed_password(body.old_password.as_str()) {
+        return Err(ApiError::new(ErrorCode::IncorrectPassword, "Old password is incorrect"))
+    }
 
-    let user = match functions::get_user_from_headers(username, session_token).await {
-        Ok(Some(user)) => user,
-        Ok(None) => return json_response(StatusCode::UNAUTHORIZED, "Invalid username or session token".to_string()),
-        Err(_) => return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error changing password".to_string())
-    };
+    //  Validate password
+    User::validate_password(&body.new_password);
 
-    //  Validating old password
-    if user.validate_hashed_password(body.old_password.as_str()) {
-        //  Validate password
-        User::validate_password(&body.new_password);
-
-        //  Changing password
-        match user.change_password(body.new_password.as_str()).await {
-            Ok(_) => HttpResponse::Ok().finish(),
-            Err(_) => json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error changing password".to_string())
-        }
-    } else {
-        json_response(StatusCode::BAD_REQUEST, "Old password is incorrect".to_string())
+    //  Changing password
+    user.change_password(body.new_password.as_str()).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// ##  Endpoint change username
+/// PUT {UTAUrl}:{UTAPort}/users/manage/change_username
+///
+/// #### Required Body
+/// - new_username: ans-20 max string, letters, numbers, underscores, hyphens and dots
+///
+/// ### Description
+/// Renames the user making the request. The session stays open, requests must be sent with the new
+/// username from then on. The previous username can't be claimed by other users during the
+/// quarantine set in the config file
+#[utoipa::path(
+    tag = "Users",
+    summary = "Changes the username of the requesting user",
+    responses(
+        (status = 200, description = "Username changed")
+    )
+)]
+#[put("/change_username", wrap = "RequireScope::new(TokenScope::ManageAccount)")]
+async fn change_username(request: HttpRequest, body: web::Json<ChangeUsername>) -> Result<HttpResponse, ApiError> {
+
+    let mut user = functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
+
+    //  Sensitive operation, the user must have confirmed their password recently
+    reauthentication::require_recent_reauthentication(&request, &user).await?;
+
+    let new_username = body.new_username.as_str();
+
+    let errors = User::validate_username(new_username);
+    if !errors.is_empty() {
+        return Err(
+            Problem::new(ErrorCode::ValidationFailed)
+                .with_detail("Invalid username")
+                .with_extension("errors", errors)
+                .into()
+        )
+    }
+
+    if new_username == user.get_username() {
+        return Err(ApiError::new(ErrorCode::InvalidRequest, "New username is the current one"))
+    }
+
+    //  Usernames in quarantine are only available to the user who gave them up
+    if !user::username_available(new_username, Some(user.get_id())).await? {
+        return Err(ApiError::new(ErrorCode::UsernameTaken, "Username not available"))
     }
+
+    //  Checked again along with the rename, someone could have taken it since
+    if !user.change_username(new_username).await? {
+        return Err(ApiError::new(ErrorCode::UsernameTaken, "Username not available"))
+    }
+
+    Ok(HttpResponse::Ok().finish())
 }
 
 /// ## Endpoint check password
@@ -325,28 +443,36 @@ async fn change_password(request: HttpRequest, body: web::Json<ChangePassword>)
 /// ### Warning
 /// User needs to be logged in for this endpoint to work, otherwise it will return an unauthorized
 ///
+/// When the user is authenticated with a session, a valid password also allows the sensitive
+/// operations, like deleting the account, for the configured re-authentication window
+///
 /// #### Response:
 /// - 201 if Ok. No need for extra content
-/// - 400 if invalid password. An empty bad request http response message is enough for this case
-#[get("/check_password")]
-async fn check_password(request: HttpRequest, body: web::Json<ValidatePassword>) -> HttpResponse {
-
-    //  Fetch username and token from headers
-    let username = functions::get_username_from_request(request.clone());
-    let session_token = functions::get_session_token_from_request(request.clone());
-
-    let user = match functions::get_user_from_headers(username, session_token).await {
-        Ok(Some(user)) => user,
-        Ok(None) => return json_response(StatusCode::UNAUTHORIZED, "Invalid username or session token".to_string()),
-        Err(_) => return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error validating password".to_string())
-    };
+/// - 400 if invalid password, with the `users.incorrect_password` problem
+#[utoipa::path(
+    tag = "Users",
+    summary = "Checks the password of the requesting user",
+    responses(
+        (status = 200, description = "Password is correct")
+    )
+)]
+#[get("/check_password", wrap = "RequireScope::new(TokenScope::ManagePassword)")]
+async fn check_password(request: HttpRequest, body: web::Json<ValidatePassword>) -> Result<HttpResponse, ApiError> {
+
+    let user = functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
 
     //  Validating password
-    if user.validate_hashed_password(body.password.as_str()) {
-        HttpResponse::Ok().finish()
-    } else {
-        HttpResponse::BadRequest().finish()
+    if !user.validate_hashed_password(body.password.as_str()) {
+        return Err(ApiError::new(ErrorCode::IncorrectPassword, "Invalid password"))
+    }
+
+    //  Confirming the password allows the sensitive operations for a while, in this session only
+    if reauthentication::authenticated_with_session(&request) {
+        users_sessions::mark_session_reauthenticated(user.get_id()).await?;
     }
+
+    Ok(HttpResponse::Ok().finish())
 }
 
 /// ##  Endpoint delete user
@@ -355,24 +481,26 @@ async fn check_password(request: HttpRequest, body: web::Json<ValidatePassword>)
 /// #### Required Headers
 /// - username (required): ans-20 max string
 /// - token (required): session token provided by the app in login
-#[put("/delete_user")]
-async fn delete_user(request: HttpRequest) -> HttpResponse {
-
-    //  Fetch username and token from headers
-    let username = functions::get_username_from_request(request.clone());
-    let session_token = functions::get_session_token_from_request(request.clone());
-
-    let user = match functions::get_user_from_headers(username, session_token).await {
-        Ok(Some(user)) => user,
-        Ok(None) => return json_response(StatusCode::UNAUTHORIZED, "Invalid username or session token".to_string()),
-        Err(_) => return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error deleting user".to_string())
-    };
+#[utoipa::path(
+    tag = "Users",
+    summary = "Deletes the requesting user",
+    responses(
+        (status = 204, description = "User deleted")
+    )
+)]
+#[put("/delete_user", wrap = "RequireScope::new(TokenScope::ManageAccount)")]
+async fn delete_user(request: HttpRequest) -> Result<HttpResponse, ApiError> {
+
+    let user = functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
+
+    //  Sensitive operation, the user must have confirmed their password recently
+    reauthentication::require_recent_reauthentication(&request, &user).await?;
 
     //  Deleting account (own account in this endpoint, user does not have permission to delete another user's account)
-    match user.delete_account().await {
-        Ok(_) => HttpResponse::NoContent().finish(),
-        Err(_) => json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error deleting user".to_string())
-    }
+    user.delete_account().await?;
+
+    Ok(HttpResponse::NoContent().finish())
 }
 
 /// ##  Endpoint delete user internal
@@ -385,51 +513,43 @@ async fn delete_user(request: HttpRequest) -> HttpResponse {
 /// ### Description
 /// Deletes an account sent in the body of the request. This endpoint is only accessible to super
 /// and admins (high). The account to delete should be the one included in the request body
-#[put("/delete_user")]
-async fn delete_user_internal(request: HttpRequest, body: web::Json<UserDelete>) -> HttpResponse {
-
-    //  Fetch username and token from headers
-    let username = functions::get_username_from_request(request.clone());
-    let session_token = functions::get_session_token_from_request(request.clone());
-
-    let user = match functions::get_user_from_headers(username, session_token).await {
-        Ok(Some(user)) => user,
-        Ok(None) => return json_response(StatusCode::UNAUTHORIZED, "Invalid username or session token".to_string()),
-        Err(_) => return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error deleting user".to_string())
-    };
+#[utoipa::path(
+    tag = "Users",
+    summary = "Deletes a user",
+    responses(
+        (status = 204, description = "User deleted")
+    )
+)]
+#[put("/delete_user", wrap = "RequireScope::new(TokenScope::AdminUsers)")]
+async fn delete_user_internal(request: HttpRequest, body: web::Json<UserDelete>) -> Result<HttpResponse, ApiError> {
+
+    let user = functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
+
+    //  Sensitive operation, the user must have confirmed their password recently
+    reauthentication::require_recent_reauthentication(&request, &user).await?;
 
     //  Fetching user to be deleted
-    let user_to_delete;
-    if let Some(user_id) = body.user_id {
-        user_to_delete = match User::select_by_id(&user_id).await {
-            Ok(Some(user)) => user,
-            Ok(None) => {
-                return json_response(StatusCode::BAD_REQUEST, "Invalid user id".to_string())
-            },
-            Err(_) => {
-                return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error deleting user".to_string())
-            }
-        }
+    let user_to_delete = if let Some(user_id) = body.user_id {
+        User::select_by_id(&user_id).await?
+            .ok_or_else(|| ApiError::new(ErrorCode::UserNotFound, "Invalid user id"))?
     } else if let Some(username) = body.username.clone() {
-        user_to_delete = match User::select_by_username(username.as_str()).await {
-            Ok(Some(user)) => user,
-            Ok(None) => return json_response(StatusCode::BAD_REQUEST, "Invalid username or password".to_string()),
-            Err(_) => return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error logging in".to_string())
-        };
+        User::select_by_username(username.as_str()).await?
+            .ok_or_else(|| ApiError::new(ErrorCode::UserNotFound, "Invalid username"))?
     } else {
-        return json_response(StatusCode::BAD_REQUEST, "Invalid user id and username".to_string())
-    }
+        return Err(ApiError::new(ErrorCode::InvalidRequest, "Invalid user id and username"))
+    };
 
-    //  Checking that level of user to delete is one lower than user that is deleting
-    if user.get_level().one_level_below() <= *user_to_delete.get_level() {
-        return json_response(StatusCode::UNAUTHORIZED, "User does not have permission to delete this account".to_string())
+    //  Checking that the policy allows this user to delete the account
+    let request = PolicyRequest::new(Action::DeleteUser, &user).await
+        .with_resource_level(UsersSessions::instance().get_effective_level(&user_to_delete).await);
+    if !Policy::instance().evaluate(&request).await.is_allowed() {
+        return Err(ApiError::new(ErrorCode::Forbidden, "User does not have permission to delete this account"))
     }
 
+    user_to_delete.delete_account().await?;
 
-    match user_to_delete.delete_account().await {
-        Ok(_) => HttpResponse::NoContent().finish(),
-        Err(_) => json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error deleting user".to_string())
-    }
+    Ok(HttpResponse::NoContent().finish())
 }
 
 /// ##  Endpoint undo delete account
@@ -439,47 +559,28 @@ async fn delete_user_internal(request: HttpRequest, body: web::Json<UserDelete>)
 /// One of the optional parameters must be present in request body
 /// - user_id (optional): optional u32
 /// - username (optional): optional ans-20 max string
-#[put("/undo_delete_user")]
-async fn undo_delete_user(request: HttpRequest, body: web::Json<UndoDeleteUser>) -> HttpResponse {
-
-    //  Fetch username and token from headers
-    let username = functions::get_username_from_request(request.clone());
-    let session_token = functions::get_session_token_from_request(request.clone());
-
-    let user = match functions::get_user_from_headers(username, session_token).await {
-        Ok(Some(user)) => user,
-        Ok(None) => return json_response(StatusCode::UNAUTHORIZED, "Invalid username or session token".to_string()),
-        Err(_) => return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error restoring user".to_string())
-    };
+#[utoipa::path(
+    tag = "Users",
+    summary = "Restores a deleted user",
+    responses(
+        (status = 200, description = "User restored")
+    )
+)]
+#[put("/undo_delete_user", wrap = "RequireScope::new(TokenScope::AdminUsers)")]
+async fn undo_delete_user(request: HttpRequest, body: web::Json<UndoDeleteUser>) -> Result<HttpResponse, ApiError> {
+
+    let user = functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
 
     //  Validate the user has privileges to restore an account
-    if user.get_level() < &Level::High {
-        return json_response(
-            StatusCode::UNAUTHORIZED,
-            "User does not have permission to restore this account".to_string()
-        )
+    if !Policy::instance().evaluate(&PolicyRequest::new(Action::RestoreUser, &user).await).await.is_allowed() {
+        return Err(ApiError::new(ErrorCode::Forbidden, "User does not have permission to restore this account"))
     }
 
-    match User::restore_user(body.user_id, body.username.clone()).await {
-        Ok(Some(true)) => json_response(StatusCode::OK, "User restored".to_string()),
-        Ok(Some(false)) => {
-            json_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "User account not restored".to_string()
-            )
-        },
-        Ok(None) => {
-            json_response(
-                StatusCode::BAD_REQUEST,
-                "User id and username not received in request".to_string()
-            )
-        },
-        Err(_) => {
-            json_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Error restoring user account".to_string()
-            )
-        }
+    match User::restore_user(body.user_id, body.username.clone()).await? {
+        Some(true) => Ok(HttpResponse::Ok().json("User restored")),
+        Some(false) => Err(ApiError::new(ErrorCode::Internal, "User account not restored")),
+        None => Err(ApiError::new(ErrorCode::InvalidRequest, "User id and username not received in request"))
     }
 }
 
@@ -491,88 +592,303 @@ async fn undo_delete_user(request: HttpRequest, body: web::Json<UndoDeleteUser>)
 /// - user_id (optional): optional u32
 /// - username (optional): optional ans-20 max string
 /// - level (required): from 0 to 3 u8
-#[put("/change_user_level")]
-async fn change_user_level(request: HttpRequest, body: web::Json<ChangeUserLevel>) -> HttpResponse {
+#[utoipa::path(
+    tag = "Users",
+    summary = "Changes the level of a user",
+    responses(
+        (status = 200, description = "Level changed")
+    )
+)]
+#[put("/change_user_level", wrap = "RequireScope::new(TokenScope::AdminUsers)")]
+async fn change_user_level(request: HttpRequest, body: web::Json<ChangeUserLevel>) -> Result<HttpResponse, ApiError> {
 
     let target_user = body.into_inner();
 
-    //  Fetch username and token from headers
-    let username = functions::get_username_from_request(request.clone());
-    let session_token = functions::get_session_token_from_request(request.clone());
+    let user = functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
+
+    //  Sensitive operation, the user must have confirmed their password recently
+    reauthentication::require_recent_reauthentication(&request, &user).await?;
+
+    let target_level: Level = target_user.level.into();
 
-    let user = match functions::get_user_from_headers(username, session_token).await {
-        Ok(Some(user)) => user,
-        Ok(None) => return json_response(StatusCode::UNAUTHORIZED, "Invalid username or session token".to_string()),
-        Err(_) => return json_response(StatusCode::INTERNAL_SERVER_ERROR, "Error restoring user".to_string())
+    let target = if let Some(user) = target_user.user_id {
+        User::select_by_id(&user).await?
+            .ok_or_else(|| ApiError::new(ErrorCode::UserNotFound, "Invalid user id"))?
+    } else if let Some(username) = target_user.username {
+        User::select_by_username(username.as_str()).await?
+            .ok_or_else(|| ApiError::new(ErrorCode::UserNotFound, "Invalid username"))?
+    } else {
+        return Err(ApiError::new(ErrorCode::InvalidRequest, "Invalid user id and username"))
     };
 
-    //  Validate user has privileges to change an account's level
-    if user.get_level() < &Level::High {
-        return json_response(
-            StatusCode::FORBIDDEN,
-            "User lacks the privileges to perform the required operation".to_string()
-        )
+    //  Validate user has privileges to change the account's level to the requested one
+    let request = PolicyRequest::new(Action::ChangeUserLevel, &user).await
+        .with_resource_level(UsersSessions::instance().get_effective_level(&target).await)
+        .with_resource_target_level(target_level);
+    if !Policy::instance().evaluate(&request).await.is_allowed() {
+        return Err(ApiError::new(ErrorCode::Forbidden, "User lacks the privileges to perform the required operation"))
     }
 
-    let target_level = target_user.level.into();
-    if user.get_level().one_level_below() < target_level {
-        return json_response(
-            StatusCode::FORBIDDEN,
-            "User lacks privileges to perform required operation".to_string()
-        )
+    //  Change the level
+    User::change_user_level(target.get_id(), &target_level).await?;
+
+    //  Groups might still grant a higher level than the new one
+    groups::functions::refresh_effective_level(target.get_id(), &target_level).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// ##  Endpoint explain policy
+/// POST {UTAUrl}:{UTAPort}/internal/explain_policy (private)
+///
+/// #### Required Body
+/// - action (required): one of the policy actions, such as "users.delete" or "service.stop_now"
+/// - user_id (optional): optional u32, the user the action would be performed on
+/// - username (optional): optional ans-20 max string, used when user_id is not present
+/// - level (optional): from 0 to 4 u8, the level requested for the user (create and change level)
+///
+/// ### Description
+/// Evaluates the action against the policy as if the requesting user performed it, without
+/// executing it. Responds with the decision, the rule that took it and the result of every rule
+/// and condition checked along the way
+#[utoipa::path(
+    tag = "Users",
+    summary = "Explains the policy decision for an action",
+    responses(
+        (status = 200, description = "Decision with every rule and condition checked")
+    )
+)]
+#[post("/explain_policy", wrap = "RequireScope::new(TokenScope::AdminUsers)")]
+async fn explain_policy(request: HttpRequest, body: web::Json<ExplainPolicy>) -> Result<HttpResponse, ApiError> {
+
+    let explain_data = body.into_inner();
+
+    let user = functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
+
+    let mut policy_request = PolicyRequest::new(explain_data.action, &user).await;
+
+    //  Resource level is only added when a target user was sent
+    if let Some(target) = functions::get_user_by_id_or_username(explain_data.user_id, explain_data.username).await? {
+        policy_request = policy_request.with_resource_level(
+            UsersSessions::instance().get_effective_level(&target).await
+        );
     }
 
-    let user_id = if let Some(user) = target_user.user_id {
-        match User::select_by_id(&user).await {
-            Ok(Some(user)) => {
-                *user.get_id()
-            }
-            Ok(None) => {
-                return json_response(
-                    StatusCode::BAD_REQUEST,
-                    "Invalid user id".to_string()
-                )
-            },
-            Err(_) => {
-                return json_response(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Error changing user level".to_string()
-                )
-            }
+    if let Some(level) = explain_data.level {
+        policy_request = policy_request.with_resource_target_level(level.into());
+    }
+
+    let decision = Policy::instance().explain(&policy_request).await;
+
+    Ok(json_response(StatusCode::OK, general::http_req_res::serialize_into_json(&decision)?))
+}
+
+/// ##  Endpoint list users
+/// GET {UTAUrl}:{UTAPort}/internal/users (private)
+///
+/// #### Query parameters
+/// - cursor (optional): next_cursor of the previous page, must be sent with the same sort
+/// - limit (optional): users per page, 50 by default and 200 at most
+/// - level (optional): from 0 to 4 u8, only users with this level
+/// - deleted (optional): bool, lists deleted users instead of active ones, false by default
+/// - created_after / created_before (optional): datetimes as "2024-01-31T00:00:00"
+/// - email_domain (optional): only users with an email in this domain
+/// - logged_in (optional): bool, only users with or without an open session
+/// - sort (optional): id, username, created_at or level, prefixed with "-" for descending order
+///
+/// ### Description
+/// Lists the users the requesting user can manage, one page at a time. Pages filtered by session
+/// status might come with fewer users than requested, the listing goes on while a cursor is returned
+#[utoipa::path(
+    tag = "Users",
+    summary = "Lists and searches users",
+    params(ListUsers),
+    responses(
+        (status = 200, description = "Page of users and the cursor of the next one")
+    )
+)]
+#[get("/users", wrap = "RequireScope::new(TokenScope::AdminUsers)")]
+async fn list_users(request: HttpRequest, query: web::Query<ListUsers>) -> Result<HttpResponse, ApiError> {
+
+    let query = query.into_inner();
+
+    let user = functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
+
+    //  Only the levels the user is allowed to list are ever selected
+    let mut visible_levels = vec![];
+    for level in Level::ALL {
+        let request = PolicyRequest::new(Action::ListUsers, &user).await.with_resource_level(level);
+        if Policy::instance().evaluate(&request).await.is_allowed() {
+            visible_levels.push(level);
         }
-    } else if let Some(username) = target_user.username {
-        match User::select_by_username(username.as_str()).await {
-            Ok(Some(user)) => {
-                *user.get_id()
-            },
-            Ok(None) => {
-                return json_response(
-                    StatusCode::BAD_REQUEST,
-                    "Invalid username".to_string()
-                )
-            },
-            Err(_) => {
-                return json_response(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Error changing user level".to_string()
-                )
-            }
+    }
+    if visible_levels.is_empty() {
+        return Err(ApiError::new(ErrorCode::Forbidden, "User lacks the privileges to list users"))
+    }
+
+    let mut filter = UserFilter::new(visible_levels.clone()).with_deleted(query.deleted);
+    if let Some(level) = query.level {
+        if level > 4 {
+            return Err(ApiError::new(ErrorCode::InvalidRequest, "Invalid level"))
         }
-    } else {
-        return json_response(
-            StatusCode::BAD_REQUEST,
-            "Invalid user id and username".to_string()
-        )
+        if !visible_levels.contains(&level.into()) {
+            return Err(ApiError::new(ErrorCode::LevelNotAllowed, "User lacks the privileges to list users of this level"))
+        }
+        filter = filter.with_level(level.into());
+    }
+    if let Some(created_after) = query.created_after {
+        filter = filter.with_created_after(created_after);
+    }
+    if let Some(created_before) = query.created_before {
+        filter = filter.with_created_before(created_before);
+    }
+    if let Some(email_domain) = query.email_domain {
+        filter = filter.with_email_domain(email_domain.as_str())
+            .ok_or_else(|| ApiError::new(ErrorCode::InvalidRequest, "Invalid email domain"))?;
+    }
+    if let Some(logged_in) = query.logged_in {
+        filter = filter.with_logged_in(logged_in);
+    }
+
+    let sort = match query.sort {
+        Some(sort) => UserSort::parse(sort.as_str())
+            .ok_or_else(|| ApiError::new(ErrorCode::InvalidRequest, "Invalid sort"))?,
+        None => UserSort::default()
     };
 
-    //  Change the level
-    match User::change_user_level(&user_id, &target_level).await {
-        Ok(_) => HttpResponse::Ok().finish(),
-        Err(_) => {
-            json_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Error changing user level".to_string()
-            )
-        }
+    let after = match query.cursor {
+        Some(cursor) => {
+            let cursor = UserCursor::decode(cursor.as_str())
+                .filter(|cursor| cursor.get_sort() == sort)
+                .ok_or_else(|| ApiError::new(ErrorCode::InvalidRequest, "Invalid cursor for this sort"))?;
+            Some(cursor.get_after())
+        },
+        None => None
+    };
+
+    let page_size = query.limit.unwrap_or(listing::DEFAULT_PAGE_SIZE).clamp(1, listing::MAX_PAGE_SIZE);
+
+    let page = listing::list_users(&filter, sort, after, page_size).await?;
+
+    Ok(data_response(StatusCode::OK, &page))
+}
+
+/// ##  Endpoint me
+/// GET {UTAUrl}:{UTAPort}/users/manage/me
+///
+/// ### Description
+/// Responds with the account of the requesting user, its profile attributes and its active sessions
+#[utoipa::path(
+    tag = "Users",
+    summary = "Gets the account of the requesting user",
+    responses(
+        (status = 200, description = "Account and active sessions, wrapped in a data envelope")
+    )
+)]
+#[get("/me", wrap = "RequireScope::new(TokenScope::ReadSelf)")]
+async fn get_me(request: HttpRequest) -> Result<HttpResponse, ApiError> {
+
+    let user = functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
+
+    Ok(data_response(StatusCode::OK, &Profile::new(&user).await?))
+}
+
+/// ##  Endpoint update me
+/// PATCH {UTAUrl}:{UTAPort}/users/manage/me
+///
+/// #### Required Body
+/// Only the fields present are updated
+/// - email (optional): ans-50 max string
+/// - display_name (optional): 50 chars max string, an empty one removes it
+/// - first_name, last_name (optional): 50 chars max strings, empty ones remove them
+/// - locale (optional): language tag, such as "en-US"
+/// - timezone (optional): time zone name, such as "America/Argentina/Buenos_Aires"
+/// - avatar_url (optional): https URL, 255 chars max
+/// - metadata (optional): object with attributes registered in the profile schema, null values
+///   remove them
+///
+/// ### Description
+/// Updates the account of the requesting user and responds with it. Changing the email is a
+/// sensitive operation, which requires a recent re-authentication
+#[utoipa::path(
+    tag = "Users",
+    summary = "Updates the account of the requesting user",
+    responses(
+        (status = 200, description = "Updated account, wrapped in a data envelope")
+    )
+)]
+#[patch("/me", wrap = "RequireScope::new(TokenScope::ManageAccount)")]
+async fn update_me(request: HttpRequest, body: web::Json<UpdateProfile>) -> Result<HttpResponse, ApiError> {
+
+    let update = body.into_inner();
+
+    let mut user = functions::get_user_from_request(&request).await?
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidSession, "Invalid username or session token"))?;
+
+    if update.email.is_none() && update.display_name.is_none() && update.attributes.is_empty() {
+        return Err(ApiError::new(ErrorCode::InvalidRequest, "No fields to update received in request body"))
+    }
+
+    let email = update.email.unwrap_or_else(|| user.get_email().to_string());
+    let display_name = update.display_name.or_else(|| user.get_display_name().map(str::to_string));
+
+    let mut attributes = UserProfile::select_by_user_id(user.get_id()).await?;
+    let attributes_changed = !update.attributes.is_empty();
+    attributes.apply(update.attributes);
+
+    let mut errors = User::validate_profile(email.as_str(), display_name.as_deref());
+    errors.extend(attributes.validate());
+    if !errors.is_empty() {
+        return Err(
+            Problem::new(ErrorCode::ValidationFailed)
+                .with_detail("Invalid profile")
+                .with_extension("errors", errors)
+                .into()
+        )
+    }
+
+    //  The email is where the account is recovered from, only the owner should be able to change it
+    if email != user.get_email() {
+        reauthentication::require_recent_reauthentication(&request, &user).await?;
+    }
+
+    user.update_profile(email, display_name).await?;
+    if attributes_changed {
+        attributes.save(user.get_id()).await?;
+    }
+
+    Ok(data_response(StatusCode::OK, &Profile::new(&user).await?))
+}
+
+impl Profile {
+    async fn new(user: &User) -> TheResult<Self> {
+
+        //  Users have a single session, listed only while it's active
+        let sessions = SessionData::select_by_user_id(user.get_id()).await?
+            .filter(|session| *session.get_session_status() == SessionStatus::Active)
+            .map(|session| ActiveSession {
+                created_at: *session.get_creation(),
+                expires_at: *session.get_expiry(),
+                client_ip: session.get_client().get_ip().map(|ip| ip.to_string()),
+                user_agent_family: session.get_client().get_user_agent_family().map(str::to_string)
+            })
+            .into_iter()
+            .collect();
+
+        Ok(Self {
+            id: *user.get_id(),
+            username: user.get_username().to_string(),
+            email: user.get_email().to_string(),
+            display_name: user.get_display_name().map(str::to_string),
+            level: *user.get_level(),
+            created_at: *user.get_created_at(),
+            updated_at: *user.get_updated_at(),
+            attributes: UserProfile::select_by_user_id(user.get_id()).await?,
+            sessions
+        })
     }
 }
@@ -0,0 +1,218 @@
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use serde::Deserialize;
+use crate::api::authenticator::CredentialHeaders;
+use crate::config::environment::{EnvironmentConfig, SessionBindingConfig};
+use crate::modules::users::user::User;
+use crate::modules::users::UsersSessions;
+
+/// ## Description
+/// What to do with a session used from a client that doesn't match the one it's bound to,
+/// configured per level. Mismatches are always logged
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum BindingAction {
+    /// The request goes through
+    #[default]
+    Allow,
+    /// The request is rejected until the user logs in again from the new client, which binds the
+    /// session to it
+    StepUp,
+    /// The session is closed
+    Reject
+}
+
+/// ## Description
+/// Client a session is bound to: the IP it was opened from and the family of its user agent, such
+/// as Firefox or curl. Either might be unknown. Parts the session isn't bound to are never
+/// checked, but a client that doesn't tell a part the session is bound to doesn't match
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientFingerprint {
+    ip: Option<IpAddr>,
+    user_agent_family: Option<String>
+}
+
+/// Longest user agent family kept, as stored in the users_sessions table
+const USER_AGENT_FAMILY_MAX_LENGTH: usize = 30;
+
+impl ClientFingerprint {
+    pub fn new(ip: Option<IpAddr>, user_agent_family: Option<String>) -> Self {
+        Self { ip, user_agent_family }
+    }
+
+    /// ## Description
+    /// Fingerprint of the client making a request. The IP is the one of the peer, unless the
+    /// service is configured to trust the X-Forwarded-For header set by the proxies in front of it
+    pub async fn from_request(peer_ip: Option<IpAddr>, headers: &(impl CredentialHeaders + ?Sized)) -> Self {
+
+        let forwarded_ip = EnvironmentConfig::instance().get_session_binding().await
+            .filter(|binding| binding.trust_forwarded_headers())
+            .and_then(|binding| {
+                let forwarded = headers.get_header("x-forwarded-for").flatten()?;
+                forwarded_client_ip(forwarded, binding.get_trusted_proxies())
+            });
+
+        Self {
+            ip: forwarded_ip.or(peer_ip),
+            user_agent_family: headers.get_header("user-agent").flatten().map(user_agent_family)
+        }
+    }
+
+    pub fn get_ip(&self) -> Option<&IpAddr> {
+        self.ip.as_ref()
+    }
+
+    pub fn get_user_agent_family(&self) -> Option<&str> {
+        self.user_agent_family.as_deref()
+    }
+
+    /// Whether the client is within the tolerance of this fingerprint: an IP in the same network,
+    /// with the prefixes configured, and the same user agent family
+    fn matches(&self, client: &ClientFingerprint, binding: &SessionBindingConfig) -> bool {
+
+        let same_network = match (self.ip, client.ip) {
+            (Some(IpAddr::V4(bound)), Some(IpAddr::V4(ip))) => {
+                same_prefix(u32::from(bound) as u128, u32::from(ip) as u128, 32, binding.get_ipv4_prefix())
+            },
+            (Some(IpAddr::V6(bound)), Some(IpAddr::V6(ip))) => {
+                same_prefix(u128::from(bound), u128::from(ip), 128, binding.get_ipv6_prefix())
+            },
+            (None, _) => true,
+            //  Different versions of IP, or a client whose IP is unknown
+            (Some(_), _) => false
+        };
+
+        let same_user_agent = match &self.user_agent_family {
+            Some(bound) => client.user_agent_family.as_ref() == Some(bound),
+            None => true
+        };
+
+        same_network && same_user_agent
+    }
+}
+
+impl Display for ClientFingerprint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({})",
+            self.ip.map(|ip| ip.to_string()).unwrap_or("unknown IP".to_string()),
+            self.user_agent_family.as_deref().unwrap_or("unknown user agent")
+        )
+    }
+}
+
+/// ## Description
+/// IP of the client in an X-Forwarded-For header. Each proxy appends the address it got the
+/// request from, so only the addresses appended by the trusted proxies in front of the service,
+/// the rightmost ones, can be believed. Anything to their left was sent by the client
+fn forwarded_client_ip(forwarded: &str, trusted_proxies: u8) -> Option<IpAddr> {
+    forwarded
+        .rsplit(',')
+        .nth(usize::from(trusted_proxies).checked_sub(1)?)?
+        .trim()
+        .parse::<IpAddr>()
+        .ok()
+}
+
+fn same_prefix(bound: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+    let prefix = prefix.min(bits);
+    if prefix == 0 {
+        return true
+    }
+    let shift = (bits - prefix) as u32;
+    bound >> shift == ip >> shift
+}
+
+/// ## Description
+/// Family of the user agent, the browser or tool without its version. Browsers are told apart by
+/// the product tokens only each of them sends, anything else by its first product token, keeping
+/// only alphanumeric characters, dots, dashes and underscores
+pub fn user_agent_family(user_agent: &str) -> String {
+
+    //  Order matters, Edge and Opera also send Chrome and Safari, and Chrome sends Safari
+    const BROWSERS: [(&str, &str); 5] = [
+        ("Edg/", "Edge"),
+        ("OPR/", "Opera"),
+        ("Firefox/", "Firefox"),
+        ("Chrome/", "Chrome"),
+        ("Safari/", "Safari")
+    ];
+
+    if let Some((_, family)) = BROWSERS.iter().find(|(token, _)| user_agent.contains(token)) {
+        return family.to_string()
+    }
+
+    user_agent
+        .split(['/', ' '])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || ['.', '-', '_'].contains(c))
+        .take(USER_AGENT_FAMILY_MAX_LENGTH)
+        .collect()
+}
+
+/// ## Description
+/// Checks the client using the session against the one the session is bound to, and returns the
+/// action configured for the user's level. Always allowed if binding is not configured
+pub async fn check_session_binding(user: &User, bound: &ClientFingerprint, client: &ClientFingerprint) -> BindingAction {
+
+    let Some(binding) = EnvironmentConfig::instance().get_session_binding().await else {
+        return BindingAction::Allow
+    };
+
+    if bound.matches(client, &binding) {
+        return BindingAction::Allow
+    }
+
+    let level = UsersSessions::instance().get_effective_level(user).await;
+    let action = binding.get_action(&level);
+
+    //  TODO remove when logger is implemented
+    eprintln!(
+        "Session binding mismatch for user {} ({}): bound to {}, used from {}. Action: {:?}",
+        user.get_id(),
+        level,
+        bound,
+        client,
+        action
+    );
+
+    action
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprint(ip: Option<&str>, user_agent_family: Option<&str>) -> ClientFingerprint {
+        ClientFingerprint::new(ip.map(|ip| ip.parse().unwrap()), user_agent_family.map(str::to_string))
+    }
+
+    #[test]
+    fn clients_hiding_what_the_session_is_bound_to_dont_match() {
+
+        let binding = SessionBindingConfig::new(24, 64);
+        let bound = fingerprint(Some("203.0.113.10"), Some("Firefox"));
+
+        assert!(bound.matches(&fingerprint(Some("203.0.113.99"), Some("Firefox")), &binding));
+        assert!(!bound.matches(&fingerprint(Some("198.51.100.10"), Some("Firefox")), &binding));
+        assert!(!bound.matches(&fingerprint(None, Some("Firefox")), &binding));
+        assert!(!bound.matches(&fingerprint(Some("203.0.113.10"), None), &binding));
+        assert!(!bound.matches(&fingerprint(Some("2001:db8::1"), Some("Firefox")), &binding));
+
+        //  Sessions bound to nothing have nothing to compare against
+        assert!(fingerprint(None, None).matches(&fingerprint(None, None), &binding));
+    }
+
+    #[test]
+    fn forwarded_ip_is_the_one_appended_by_the_trusted_proxies() {
+
+        //  The client sent a forged first address, the proxy appended the real one
+        let forwarded = "10.0.0.1, 203.0.113.10";
+        assert_eq!(forwarded_client_ip(forwarded, 1), Some("203.0.113.10".parse().unwrap()));
+        assert_eq!(forwarded_client_ip(forwarded, 2), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(forwarded_client_ip(forwarded, 3), None);
+        assert_eq!(forwarded_client_ip(forwarded, 0), None);
+    }
+}
@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use error_mapper::{map_to_new_error, TheResult};
+use lazy_static::lazy_static;
+use mysql_async::prelude::{FromRow, Queryable};
+use mysql_async::FromRowError;
+use tokio::sync::{Mutex, RwLock};
+use crate::{database, modules, row_to_data, row_to_naive_datetime};
+use crate::database::db_conn::get_conn;
+use crate::general::types::UsersIdType;
+use crate::modules::users::user::User;
+use crate::modules::users::UsersSessions;
+
+lazy_static!{
+    static ref SESSION_EVENTS: SessionEvents = SessionEvents::new();
+}
+
+/// How long published events are kept in the session_events table before being pruned
+const SESSION_EVENTS_RETENTION_MINUTES: i64 = 10;
+
+/// Events are read again from this long before the cursor, so events committed after others with
+/// a later ID were received aren't missed
+const RECEIVE_MARGIN_SECONDS: i64 = 5;
+
+/// ## Description
+/// Changes to a user made in one replica that every other replica must apply to its runtime
+/// sessions, instead of waiting for the sessions cron
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionEventKind {
+    SessionTerminated,
+    AccountDeleted,
+    LevelChanged,
+    UsernameChanged
+}
+
+//  Event as stored in the session_events table, along with when it was created
+struct SessionEventRow {
+    event: SessionEvent,
+    created_at: NaiveDateTime
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionEvent {
+    //  ID given by the transport that carried it, if any, to acknowledge it
+    id: Option<u32>,
+    kind: SessionEventKind,
+    users_id: UsersIdType,
+    //  Replica that published the event, which already applied it
+    origin: String
+}
+
+/// ## Description
+/// Carries the session events between replicas. Events published by any replica, this one
+/// included, must be returned by `receive` in the order they were published, until they are
+/// acknowledged
+#[async_trait]
+pub trait SessionEventTransport: Send + Sync {
+    async fn publish(&self, event: &SessionEvent) -> TheResult<()>;
+
+    /// Events published that weren't acknowledged yet
+    async fn receive(&self) -> TheResult<Vec<SessionEvent>>;
+
+    /// Marks the events received as applied, so they aren't received again. Transports that
+    /// never return an event twice have nothing to do
+    async fn acknowledge(&self, _events: &[SessionEvent]) -> TheResult<()> {
+        Ok(())
+    }
+
+    /// Drops the events every replica already had the chance to receive
+    async fn prune(&self) -> TheResult<()> {
+        Ok(())
+    }
+}
+
+/// ## Description
+/// Default transport, polling the session_events table. The first poll only takes note of the
+/// time, older events were already applied by the sessions loaded on startup.
+///
+/// IDs are given when inserting but rows are seen when committed, so a poll may see an event
+/// before another with a lower ID. Each poll reads every event from a margin before the cursor,
+/// skipping the ones already applied, and the cursor never moves past an event not applied
+#[derive(Debug, Default)]
+pub struct MySqlEventTransport {
+    window: Mutex<Option<ReceiveWindow>>
+}
+
+#[derive(Debug)]
+struct ReceiveWindow {
+    //  Every event created before it was applied
+    cursor: NaiveDateTime,
+    //  Events applied since the margin before the cursor, with when they were created
+    applied: HashMap<u32, NaiveDateTime>,
+    //  Events received in the last poll and not applied yet
+    pending: HashMap<u32, NaiveDateTime>
+}
+
+pub struct SessionEvents {
+    transport: RwLock<Arc<dyn SessionEventTransport>>,
+    origin: String
+}
+
+impl SessionEvent {
+    pub fn get_kind(&self) -> &SessionEventKind {
+        &self.kind
+    }
+
+    pub fn get_user_id(&self) -> &UsersIdType {
+        &self.users_id
+    }
+
+    pub fn get_origin(&self) -> &str {
+        self.origin.as_str()
+    }
+}
+
+impl SessionEvents {
+    fn new() -> Self {
+        Self {
+            transport: RwLock::new(Arc::new(MySqlEventTransport::default())),
+            origin: format!("{:016x}", rand::random::<u64>())
+        }
+    }
+
+    pub fn instance() -> &'static Self {
+        &SESSION_EVENTS
+    }
+
+    pub async fn set_transport(&self, transport: Arc<dyn SessionEventTransport>) {
+        *self.transport.write().await = transport;
+    }
+
+    async fn transport(&self) -> Arc<dyn SessionEventTransport> {
+        self.transport.read().await.clone()
+    }
+
+    /// ## Description
+    /// Tells the other replicas about a change this replica already applied. A failed publish is
+    /// only logged, the other replicas catch up when their sessions cron runs
+    pub async fn publish(&self, kind: SessionEventKind, user_id: &UsersIdType) {
+        let event = SessionEvent {
+            id: None,
+            kind,
+            users_id: *user_id,
+            origin: self.origin.clone()
+        };
+
+        if let Err(e) = self.transport().await.publish(&event).await {
+            //  TODO remove when logger is implemented
+            eprintln!("Error publishing {} event for user {}: {}", kind, user_id, e);
+        }
+    }
+
+    /// Applies the events published by the other replicas since the last call, in order. An event
+    /// that fails to apply is received again in the next call, along with every event after it.
+    /// Returns how many were applied
+    pub async fn apply_received(&self) -> TheResult<usize> {
+
+        let transport = self.transport().await;
+        let events = transport.receive().await?;
+
+        let mut applied = 0;
+        for (index, event) in events.iter().enumerate() {
+            if event.origin == self.origin {
+                continue
+            }
+            if let Err(e) = apply_event(event).await {
+                transport.acknowledge(&events[..index]).await?;
+                return Err(e)
+            }
+            applied += 1;
+        }
+
+        transport.acknowledge(events.as_slice()).await?;
+
+        Ok(applied)
+    }
+
+    pub async fn prune(&self) -> TheResult<()> {
+        self.transport().await.prune().await
+    }
+}
+
+async fn apply_event(event: &SessionEvent) -> TheResult<()> {
+    match event.kind {
+        SessionEventKind::SessionTerminated => {
+            UsersSessions::instance().close_session(&event.users_id).await?;
+        },
+        SessionEventKind::AccountDeleted => {
+            UsersSessions::instance().delete_user_entry(&event.users_id).await?;
+        },
+        SessionEventKind::LevelChanged => {
+            if let Some(user) = User::select_by_id(&event.users_id).await? {
+                modules::groups::functions::refresh_effective_level(user.get_id(), user.get_level()).await?;
+            }
+        },
+        SessionEventKind::UsernameChanged => {
+            if let Some(user) = User::select_by_id(&event.users_id).await? {
+                UsersSessions::instance().rename_user(user.get_id(), user.get_username()).await;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl SessionEventTransport for MySqlEventTransport {
+    async fn publish(&self, event: &SessionEvent) -> TheResult<()> {
+
+        let conn = &mut get_conn().await?;
+
+        //  Replicas poll by creation time, which is taken from the database so they share a clock
+        conn.query_drop(
+            format!(
+                "INSERT INTO session_events (event, users_ID, origin, created_at) VALUES ('{}', {}, '{}', UTC_TIMESTAMP())",
+                event.kind,
+                event.users_id,
+                event.origin
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+
+    async fn receive(&self) -> TheResult<Vec<SessionEvent>> {
+
+        let mut window = self.window.lock().await;
+
+        let conn = &mut get_conn().await?;
+
+        let Some(window) = window.as_mut() else {
+            let now = conn.query_first::<String, _>("SELECT UTC_TIMESTAMP()")
+                .await.map_err(|e| map_to_new_error!(e))?
+                .and_then(|now| NaiveDateTime::parse_from_str(now.as_str(), database::DATETIME_FORMAT).ok())
+                .unwrap_or(chrono::Utc::now().naive_utc());
+            *window = Some(ReceiveWindow::new(now));
+            return Ok(Vec::new())
+        };
+
+        let rows = conn.query::<SessionEventRow, _>(
+            format!(
+                "SELECT ID, event, users_ID, origin, created_at FROM session_events WHERE created_at >= '{}' ORDER BY ID",
+                window.margin_start().format(database::DATETIME_FORMAT)
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        let events = rows.into_iter()
+            .filter(|row| row.event.id.is_some_and(|id| !window.applied.contains_key(&id)))
+            .collect::<Vec<_>>();
+
+        window.pending = events.iter()
+            .filter_map(|row| Some((row.event.id?, row.created_at)))
+            .collect();
+
+        Ok(events.into_iter().map(|row| row.event).collect())
+    }
+
+    async fn acknowledge(&self, events: &[SessionEvent]) -> TheResult<()> {
+        if let Some(window) = self.window.lock().await.as_mut() {
+            window.acknowledge(events);
+        }
+        Ok(())
+    }
+
+    async fn prune(&self) -> TheResult<()> {
+
+        let conn = &mut get_conn().await?;
+
+        let oldest = chrono::Utc::now().naive_utc() - chrono::Duration::minutes(SESSION_EVENTS_RETENTION_MINUTES);
+
+        conn.query_drop(
+            format!(
+                "DELETE FROM session_events WHERE created_at < '{}'",
+                oldest.format(database::DATETIME_FORMAT)
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(())
+    }
+}
+
+impl ReceiveWindow {
+    fn new(cursor: NaiveDateTime) -> Self {
+        Self {
+            cursor,
+            applied: HashMap::new(),
+            pending: HashMap::new()
+        }
+    }
+
+    /// Where the next poll starts reading
+    fn margin_start(&self) -> NaiveDateTime {
+        self.cursor - chrono::Duration::seconds(RECEIVE_MARGIN_SECONDS)
+    }
+
+    /// Moves the pending events acknowledged to the applied ones, and the cursor up to the oldest
+    /// event still pending, or the newest one applied if none is
+    fn acknowledge(&mut self, events: &[SessionEvent]) {
+
+        for id in events.iter().filter_map(|event| event.id) {
+            if let Some(created_at) = self.pending.remove(&id) {
+                self.applied.insert(id, created_at);
+            }
+        }
+
+        self.cursor = match self.pending.values().min() {
+            Some(oldest_pending) => *oldest_pending,
+            None => self.applied.values().copied().chain([self.cursor]).max().unwrap_or(self.cursor)
+        };
+
+        //  Events before the margin aren't read again, there's no need to tell them apart
+        let margin_start = self.margin_start();
+        self.applied.retain(|_, created_at| *created_at >= margin_start);
+    }
+}
+
+impl Display for SessionEventKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionEventKind::SessionTerminated => write!(f, "SessionTerminated"),
+            SessionEventKind::AccountDeleted => write!(f, "AccountDeleted"),
+            SessionEventKind::LevelChanged => write!(f, "LevelChanged"),
+            SessionEventKind::UsernameChanged => write!(f, "UsernameChanged")
+        }
+    }
+}
+
+impl TryFrom<String> for SessionEventKind {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "SessionTerminated" => Ok(SessionEventKind::SessionTerminated),
+            "AccountDeleted" => Ok(SessionEventKind::AccountDeleted),
+            "LevelChanged" => Ok(SessionEventKind::LevelChanged),
+            "UsernameChanged" => Ok(SessionEventKind::UsernameChanged),
+            _ => Err(format!("Unknown session event: {}", value))
+        }
+    }
+}
+
+impl FromRow for SessionEventRow {
+    fn from_row(row: mysql_async::Row) -> Self {
+        let kind = row_to_data!(row, "event", "session_events", String);
+        Self {
+            created_at: row_to_naive_datetime!(row, "created_at", "session_events"),
+            event: SessionEvent {
+                id: Some(row_to_data!(row, "ID", "session_events", u32)),
+                //  Unknown events come from newer replicas, terminating the session is the safest
+                kind: SessionEventKind::try_from(kind).unwrap_or(SessionEventKind::SessionTerminated),
+                users_id: row_to_data!(row, "users_ID", "session_events", UsersIdType),
+                origin: row_to_data!(row, "origin", "session_events", String)
+            }
+        }
+    }
+
+    fn from_row_opt(_: mysql_async::Row) -> Result<Self, FromRowError> where Self: Sized {
+        unimplemented!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: i64) -> NaiveDateTime {
+        chrono::DateTime::from_timestamp(1_700_000_000 + seconds, 0).unwrap().naive_utc()
+    }
+
+    fn event(id: u32) -> SessionEvent {
+        SessionEvent {
+            id: Some(id),
+            kind: SessionEventKind::SessionTerminated,
+            users_id: 1,
+            origin: "origin".to_string()
+        }
+    }
+
+    #[test]
+    fn the_cursor_stops_at_events_not_applied() {
+
+        let mut window = ReceiveWindow::new(at(0));
+        window.pending = HashMap::from([(1, at(18)), (2, at(20)), (3, at(30))]);
+
+        //  The second event failed to apply
+        window.acknowledge(&[event(1)]);
+        assert_eq!(window.cursor, at(20));
+        assert!(window.applied.contains_key(&1));
+
+        window.pending = HashMap::from([(2, at(20)), (3, at(30))]);
+        window.acknowledge(&[event(2), event(3)]);
+        assert_eq!(window.cursor, at(30));
+
+        //  Applied events are only remembered while they can be read again
+        assert_eq!(window.applied.keys().copied().collect::<Vec<_>>(), vec![3]);
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use error_mapper::TheResult;
+use tokio::sync::RwLock;
+use crate::general::types::UsersIdType;
+use crate::modules::users::session_binding::ClientFingerprint;
+use crate::modules::users::session_store::{SessionStore, StoredSession};
+use crate::modules::users::users_sessions::SessionData;
+
+/// ## Description
+/// Sessions kept in the memory of the process. The fastest store, and the default one, but each
+/// replica only knows about its own logins and logouts until the sessions cron runs
+#[derive(Debug, Default)]
+pub struct MemorySessionStore {
+    sessions: RwLock<HashMap<UsersIdType, StoredSession>>
+}
+
+impl MemorySessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemorySessionStore {
+    async fn open_session(&self, user_id: &UsersIdType, session: StoredSession) -> TheResult<()> {
+        self.sessions.write().await.insert(*user_id, session);
+        Ok(())
+    }
+
+    async fn extend_session(&self, user_id: &UsersIdType, expiry: NaiveDateTime, client: &ClientFingerprint) -> TheResult<()> {
+        self.sessions.write().await.entry(*user_id)
+            .and_modify(|session| {
+                session.expiry = expiry;
+                session.client = client.clone();
+            });
+        Ok(())
+    }
+
+    async fn close_session(&self, user_id: &UsersIdType) -> TheResult<()> {
+        self.sessions.write().await.remove(user_id);
+        Ok(())
+    }
+
+    async fn get_session(&self, user_id: &UsersIdType) -> TheResult<Option<StoredSession>> {
+        Ok(self.sessions.read().await.get(user_id).cloned())
+    }
+
+    async fn reconcile(&self, active_sessions: &[SessionData]) -> TheResult<usize> {
+
+        let mut sessions = self.sessions.write().await;
+        let mut corrected = 0;
+
+        for session in active_sessions {
+            let stored_session = StoredSession::from(session);
+            if sessions.get(session.get_user_id()) != Some(&stored_session) {
+                sessions.insert(*session.get_user_id(), stored_session);
+                corrected += 1;
+            }
+        }
+
+        let before = sessions.len();
+        sessions.retain(|user_id, _| {
+            active_sessions.iter().any(|session| session.get_user_id() == user_id)
+        });
+        corrected += before - sessions.len();
+
+        Ok(corrected)
+    }
+}
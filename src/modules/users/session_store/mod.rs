@@ -0,0 +1,100 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use error_mapper::TheResult;
+use crate::config::environment::SessionStoreConfig;
+use crate::general::types::UsersIdType;
+use crate::modules::users::session_binding::ClientFingerprint;
+use crate::modules::users::users_sessions::SessionData;
+
+pub mod memory_store;
+pub mod mysql_store;
+#[cfg(feature = "redis")]
+pub mod redis_store;
+
+/// ## Description
+/// Open session of a user, as kept by the session stores. Only the digest of the token is kept,
+/// the token itself lives in the database
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredSession {
+    token_digest: String,
+    expiry: NaiveDateTime,
+    //  Client the session is bound to
+    client: ClientFingerprint
+}
+
+/// ## Description
+/// Where the sessions are answered from when authenticating requests. The database is always the
+/// source of truth: sessions are written there first, and then to the store, which is reconciled
+/// with the database on startup and in the sessions cron.
+///
+/// Stores only keep open sessions, closing a session removes it
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn open_session(&self, user_id: &UsersIdType, session: StoredSession) -> TheResult<()>;
+
+    /// Extends the session, binding it to the client that extended it
+    async fn extend_session(&self, user_id: &UsersIdType, expiry: NaiveDateTime, client: &ClientFingerprint) -> TheResult<()>;
+
+    async fn close_session(&self, user_id: &UsersIdType) -> TheResult<()>;
+
+    async fn get_session(&self, user_id: &UsersIdType) -> TheResult<Option<StoredSession>>;
+
+    /// Sets the store to exactly the active sessions in database, closing any other session.
+    /// Returns how many sessions had to be corrected
+    async fn reconcile(&self, active_sessions: &[SessionData]) -> TheResult<usize>;
+}
+
+impl StoredSession {
+    pub fn new(token_digest: &str, expiry: NaiveDateTime, client: ClientFingerprint) -> Self {
+        Self {
+            token_digest: token_digest.to_string(),
+            expiry,
+            client
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.expiry > chrono::Utc::now().naive_utc()
+    }
+
+    pub fn get_token_digest(&self) -> &str {
+        self.token_digest.as_str()
+    }
+
+    pub fn get_expiry(&self) -> &NaiveDateTime {
+        &self.expiry
+    }
+
+    pub fn get_client(&self) -> &ClientFingerprint {
+        &self.client
+    }
+}
+
+impl From<&SessionData> for StoredSession {
+    fn from(session: &SessionData) -> Self {
+        Self::new(session.get_token_digest(), *session.get_expiry(), session.get_client().clone())
+    }
+}
+
+/// Creates the session store set in the config file
+pub async fn from_config(config: &SessionStoreConfig) -> TheResult<Arc<dyn SessionStore>> {
+    match config {
+        SessionStoreConfig::Memory => Ok(Arc::new(memory_store::MemorySessionStore::new())),
+        SessionStoreConfig::Mysql => Ok(Arc::new(mysql_store::MySqlSessionStore)),
+        #[cfg(feature = "redis")]
+        SessionStoreConfig::Redis { url, key_prefix } => {
+            let store = redis_store::RedisSessionStore::connect(url, key_prefix.as_str()).await?;
+            Ok(Arc::new(store))
+        },
+        #[cfg(not(feature = "redis"))]
+        SessionStoreConfig::Redis { .. } => Err(
+            error_mapper::map_to_new_error!(
+                error_mapper::TheError::new(
+                    error_mapper::SystemErrorCodes::GenericError,
+                    "The redis session store needs the redis feature".to_string()
+                )
+            )
+        )
+    }
+}
@@ -0,0 +1,44 @@
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use error_mapper::TheResult;
+use crate::general::types::UsersIdType;
+use crate::modules::users::session_binding::ClientFingerprint;
+use crate::modules::users::session_store::{SessionStore, StoredSession};
+use crate::modules::users::users_sessions::{SessionData, SessionStatus};
+
+/// ## Description
+/// Sessions read straight from the database on every check. Sessions are already written to the
+/// database before reaching the store, so writes are no-ops. Every replica always agrees, at the
+/// cost of a query per authenticated request
+#[derive(Debug, Default)]
+pub struct MySqlSessionStore;
+
+#[async_trait]
+impl SessionStore for MySqlSessionStore {
+    async fn open_session(&self, _: &UsersIdType, _: StoredSession) -> TheResult<()> {
+        Ok(())
+    }
+
+    async fn extend_session(&self, _: &UsersIdType, _: NaiveDateTime, _: &ClientFingerprint) -> TheResult<()> {
+        Ok(())
+    }
+
+    async fn close_session(&self, _: &UsersIdType) -> TheResult<()> {
+        Ok(())
+    }
+
+    async fn get_session(&self, user_id: &UsersIdType) -> TheResult<Option<StoredSession>> {
+        let session = SessionData::select_by_user_id(user_id).await?;
+
+        Ok(
+            session
+                .filter(|session| *session.get_session_status() == SessionStatus::Active)
+                .map(|session| StoredSession::from(&session))
+        )
+    }
+
+    async fn reconcile(&self, _: &[SessionData]) -> TheResult<usize> {
+        //  The database is the store, there's nothing to reconcile
+        Ok(0)
+    }
+}
@@ -0,0 +1,530 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use error_mapper::{SystemErrorCodes, TheError, TheResult};
+use futures_util::StreamExt;
+use redis::{AsyncCommands, RedisError};
+use redis::aio::ConnectionManager;
+use tokio::sync::RwLock;
+use crate::general::types::UsersIdType;
+use crate::modules::users::session_binding::ClientFingerprint;
+use crate::modules::users::session_store::{SessionStore, StoredSession};
+use crate::modules::users::users_sessions::SessionData;
+
+/// Payload published to invalidate every cached session, instead of a user ID
+const INVALIDATE_ALL: &str = "*";
+
+type SharedSessionsCache = Arc<RwLock<SessionsCache>>;
+
+/// ## Description
+/// Sessions cached by the replica. Every invalidation bumps the generation of the user, or the
+/// epoch when invalidating them all, so a session read from Redis while it was being invalidated
+/// isn't cached after the invalidation dropped it
+#[derive(Debug, Default)]
+struct SessionsCache {
+    sessions: HashMap<UsersIdType, StoredSession>,
+    generations: HashMap<UsersIdType, u64>,
+    epoch: u64
+}
+
+/// Generation of a user's cached session, taken before reading it from Redis
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Generation {
+    epoch: u64,
+    user: u64
+}
+
+impl SessionsCache {
+    fn get(&self, user_id: &UsersIdType) -> Option<&StoredSession> {
+        self.sessions.get(user_id)
+    }
+
+    fn generation(&self, user_id: &UsersIdType) -> Generation {
+        Generation {
+            epoch: self.epoch,
+            user: self.generations.get(user_id).copied().unwrap_or_default()
+        }
+    }
+
+    /// Caches the session unless it was invalidated since the generation was taken
+    fn insert(&mut self, user_id: &UsersIdType, generation: Generation, session: StoredSession) {
+        if self.generation(user_id) == generation {
+            self.sessions.insert(*user_id, session);
+        }
+    }
+
+    /// Applies a payload of the invalidation channel, a user ID or anything else for every user
+    fn invalidate(&mut self, payload: &str) {
+        match payload.parse::<UsersIdType>() {
+            Ok(user_id) => {
+                self.sessions.remove(&user_id);
+                *self.generations.entry(user_id).or_default() += 1;
+            },
+            Err(_) => self.clear()
+        }
+    }
+
+    fn clear(&mut self) {
+        self.sessions.clear();
+        //  The new epoch already tells apart every read in flight, the generations can start over
+        self.generations.clear();
+        self.epoch += 1;
+    }
+}
+
+/// ## Description
+/// Sessions shared by every replica through Redis, or any server speaking its protocol. Each
+/// session is a hash under `{key_prefix}:session:{user_id}` which Redis drops on its expiry.
+///
+/// Sessions read are cached in the replica. Every write is published on the
+/// `{key_prefix}:sessions:invalidate` channel, and every replica drops the cached session when
+/// receiving it, so a logout in one replica is seen by all of them
+#[derive(Clone)]
+pub struct RedisSessionStore {
+    connection: ConnectionManager,
+    key_prefix: String,
+    cache: SharedSessionsCache
+}
+
+impl RedisSessionStore {
+    /// Connects to the Redis server in the URL, like `redis://127.0.0.1:6379`, and starts
+    /// listening to the sessions invalidated by the other replicas
+    pub async fn connect(url: &str, key_prefix: &str) -> TheResult<Self> {
+
+        let client = redis::Client::open(url).map_err(redis_error)?;
+
+        let connection = client.get_connection_manager().await.map_err(redis_error)?;
+
+        let store = Self {
+            connection,
+            key_prefix: key_prefix.to_string(),
+            cache: Arc::new(RwLock::new(SessionsCache::default()))
+        };
+
+        tokio::spawn(listen_invalidations(client, store.invalidation_channel(), store.cache.clone()));
+
+        Ok(store)
+    }
+
+    fn session_key(&self, user_id: &UsersIdType) -> String {
+        format!("{}:session:{}", self.key_prefix, user_id)
+    }
+
+    fn invalidation_channel(&self) -> String {
+        format!("{}:sessions:invalidate", self.key_prefix)
+    }
+
+    async fn invalidate(&self, payload: String) -> TheResult<()> {
+        self.cache.write().await.invalidate(payload.as_str());
+
+        let mut connection = self.connection.clone();
+        connection.publish::<_, _, ()>(self.invalidation_channel(), payload).await
+            .map_err(redis_error)?;
+
+        Ok(())
+    }
+
+    async fn write_session(&self, user_id: &UsersIdType, session: &StoredSession) -> TheResult<()> {
+
+        let key = self.session_key(user_id);
+
+        let mut connection = self.connection.clone();
+        redis::pipe()
+            .atomic()
+            .hset_multiple(
+                key.as_str(),
+                &[
+                    ("token_digest", session.get_token_digest().to_string()),
+                    ("expiry", session.get_expiry().and_utc().timestamp().to_string()),
+                    //  Unknown parts of the client are kept empty
+                    ("client_ip", session.get_client().get_ip().map(|ip| ip.to_string()).unwrap_or_default()),
+                    ("user_agent_family", session.get_client().get_user_agent_family().unwrap_or_default().to_string())
+                ]
+            )
+            .expire_at(key.as_str(), session.get_expiry().and_utc().timestamp())
+            .query_async::<_, ()>(&mut connection).await
+            .map_err(redis_error)?;
+
+        Ok(())
+    }
+
+    async fn read_session(&self, user_id: &UsersIdType) -> TheResult<Option<StoredSession>> {
+
+        let mut connection = self.connection.clone();
+        let fields: HashMap<String, String> = connection.hgetall(self.session_key(user_id)).await
+            .map_err(redis_error)?;
+
+        let expiry = fields.get("expiry")
+            .and_then(|expiry| expiry.parse::<i64>().ok())
+            .and_then(|expiry| chrono::DateTime::from_timestamp(expiry, 0))
+            .map(|expiry| expiry.naive_utc());
+
+        let client = ClientFingerprint::new(
+            fields.get("client_ip").and_then(|ip| ip.parse().ok()),
+            fields.get("user_agent_family").filter(|family| !family.is_empty()).cloned()
+        );
+
+        //  A session missing its token or expiry is as good as closed
+        match (fields.get("token_digest"), expiry) {
+            (Some(token_digest), Some(expiry)) => Ok(Some(StoredSession::new(token_digest, expiry, client))),
+            _ => Ok(None)
+        }
+    }
+}
+
+#[async_trait]
+impl SessionStore for RedisSessionStore {
+    async fn open_session(&self, user_id: &UsersIdType, session: StoredSession) -> TheResult<()> {
+        self.write_session(user_id, &session).await?;
+        self.invalidate(user_id.to_string()).await
+    }
+
+    async fn extend_session(&self, user_id: &UsersIdType, expiry: NaiveDateTime, client: &ClientFingerprint) -> TheResult<()> {
+        if let Some(session) = self.read_session(user_id).await? {
+            self.write_session(user_id, &StoredSession::new(session.get_token_digest(), expiry, client.clone())).await?;
+        }
+        self.invalidate(user_id.to_string()).await
+    }
+
+    async fn close_session(&self, user_id: &UsersIdType) -> TheResult<()> {
+        let mut connection = self.connection.clone();
+        connection.del::<_, ()>(self.session_key(user_id)).await.map_err(redis_error)?;
+
+        self.invalidate(user_id.to_string()).await
+    }
+
+    async fn get_session(&self, user_id: &UsersIdType) -> TheResult<Option<StoredSession>> {
+
+        if let Some(session) = self.cache.read().await.get(user_id) {
+            return Ok(Some(session.clone()))
+        }
+
+        //  An invalidation received while reading keeps what was read out of the cache, it may be
+        // the session that was just closed
+        let generation = self.cache.read().await.generation(user_id);
+        let session = self.read_session(user_id).await?;
+        if let Some(session) = &session {
+            self.cache.write().await.insert(user_id, generation, session.clone());
+        }
+
+        Ok(session)
+    }
+
+    async fn reconcile(&self, active_sessions: &[SessionData]) -> TheResult<usize> {
+
+        let mut corrected = 0;
+
+        for session in active_sessions {
+            let stored_session = StoredSession::from(session);
+            if self.read_session(session.get_user_id()).await? != Some(stored_session.clone()) {
+                self.write_session(session.get_user_id(), &stored_session).await?;
+                corrected += 1;
+            }
+        }
+
+        //  Sessions in Redis but not in database are closed
+        let mut connection = self.connection.clone();
+        let keys: Vec<String> = {
+            let mut iter = connection.scan_match::<_, String>(format!("{}:session:*", self.key_prefix)).await
+                .map_err(redis_error)?;
+            let mut keys = Vec::new();
+            while let Some(key) = iter.next_item().await {
+                keys.push(key);
+            }
+            keys
+        };
+
+        for key in keys {
+            let in_database = active_sessions.iter()
+                .any(|session| self.session_key(session.get_user_id()) == key);
+            if !in_database {
+                connection.del::<_, ()>(key).await.map_err(redis_error)?;
+                corrected += 1;
+            }
+        }
+
+        if corrected > 0 {
+            self.invalidate(INVALIDATE_ALL.to_string()).await?;
+        }
+
+        Ok(corrected)
+    }
+}
+
+/// Keeps the subscription to the invalidation channel alive for as long as the service runs
+async fn listen_invalidations(client: redis::Client, channel: String, cache: SharedSessionsCache) {
+    loop {
+        if let Err(e) = receive_invalidations(&client, channel.as_str(), &cache).await {
+            //  TODO remove when logger is implemented
+            eprintln!("Lost the subscription to the Redis sessions invalidations: {}", e);
+        }
+
+        //  Anything published while disconnected was missed, so nothing cached can be trusted
+        cache.write().await.clear();
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn receive_invalidations(client: &redis::Client, channel: &str, cache: &SharedSessionsCache) -> TheResult<()> {
+
+    let mut pubsub = client.get_async_connection().await
+        .map_err(redis_error)?
+        .into_pubsub();
+    pubsub.subscribe(channel).await.map_err(redis_error)?;
+
+    cache.write().await.clear();
+
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        let payload = message.get_payload::<String>().map_err(redis_error)?;
+        cache.write().await.invalidate(payload.as_str());
+    }
+
+    Ok(())
+}
+
+fn redis_error(e: RedisError) -> TheError {
+    TheError::default()
+        .with_type(SystemErrorCodes::ReadWriteError)
+        .with_content(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use super::*;
+
+    /// Stand-in of a Redis server, answering just the commands the store sends
+    mod stand_in {
+        use std::collections::HashMap;
+        use std::sync::{Arc, Mutex};
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+        use tokio::net::{TcpListener, TcpStream};
+        use tokio::net::tcp::OwnedReadHalf;
+        use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+        #[derive(Default)]
+        pub struct State {
+            hashes: HashMap<String, HashMap<String, String>>,
+            subscribers: HashMap<String, Vec<UnboundedSender<Vec<u8>>>>
+        }
+
+        impl State {
+            pub fn subscribers(&self, channel: &str) -> usize {
+                self.subscribers.get(channel).map(Vec::len).unwrap_or_default()
+            }
+        }
+
+        /// Listens in a random local port, returning the URL to connect to
+        pub async fn start() -> (String, Arc<Mutex<State>>) {
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("redis://{}", listener.local_addr().unwrap());
+            let state = Arc::new(Mutex::new(State::default()));
+
+            let server_state = Arc::clone(&state);
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(serve(stream, Arc::clone(&server_state)));
+                }
+            });
+
+            (url, state)
+        }
+
+        async fn serve(stream: TcpStream, state: Arc<Mutex<State>>) {
+
+            //  Replies and the messages published to the subscriptions of the connection share
+            // the writing half
+            let (reader, mut writer) = stream.into_split();
+            let (sender, mut receiver) = unbounded_channel::<Vec<u8>>();
+            tokio::spawn(async move {
+                while let Some(bytes) = receiver.recv().await {
+                    if writer.write_all(bytes.as_slice()).await.is_err() {
+                        break
+                    }
+                }
+            });
+
+            let mut reader = BufReader::new(reader);
+            let mut transaction: Option<Vec<Vec<u8>>> = None;
+            while let Some(command) = read_command(&mut reader).await {
+                let name = command[0].to_uppercase();
+                let reply = match (name.as_str(), transaction.as_mut()) {
+                    ("MULTI", None) => {
+                        transaction = Some(Vec::new());
+                        simple("OK")
+                    },
+                    ("EXEC", Some(_)) => array(transaction.take().unwrap_or_default()),
+                    (_, Some(replies)) => {
+                        replies.push(execute(&command, &state, &sender));
+                        simple("QUEUED")
+                    },
+                    (_, None) => execute(&command, &state, &sender)
+                };
+                if sender.send(reply).is_err() {
+                    break
+                }
+            }
+        }
+
+        fn execute(command: &[String], state: &Mutex<State>, sender: &UnboundedSender<Vec<u8>>) -> Vec<u8> {
+
+            let mut state = state.lock().unwrap();
+
+            match command[0].to_uppercase().as_str() {
+                "PING" => simple("PONG"),
+                name @ ("HSET" | "HMSET") => {
+                    let hash = state.hashes.entry(command[1].clone()).or_default();
+                    for pair in command[2..].chunks(2) {
+                        hash.insert(pair[0].clone(), pair[1].clone());
+                    }
+                    match name {
+                        "HMSET" => simple("OK"),
+                        _ => integer(command[2..].len() / 2)
+                    }
+                },
+                "HGETALL" => array(
+                    state.hashes.get(&command[1])
+                        .into_iter()
+                        .flatten()
+                        .flat_map(|(field, value)| [bulk(field), bulk(value)])
+                        .collect()
+                ),
+                "EXPIREAT" => integer(state.hashes.contains_key(&command[1]) as usize),
+                "DEL" => integer(
+                    command[1..].iter()
+                        .filter(|key| state.hashes.remove(key.as_str()).is_some())
+                        .count()
+                ),
+                "SCAN" => {
+                    let prefix = command.iter()
+                        .position(|argument| argument.eq_ignore_ascii_case("MATCH"))
+                        .map(|position| command[position + 1].trim_end_matches('*'))
+                        .unwrap_or_default();
+                    let keys = state.hashes.keys()
+                        .filter(|key| key.starts_with(prefix))
+                        .map(|key| bulk(key))
+                        .collect();
+                    array(vec![bulk("0"), array(keys)])
+                },
+                "SUBSCRIBE" => {
+                    state.subscribers.entry(command[1].clone()).or_default().push(sender.clone());
+                    array(vec![bulk("subscribe"), bulk(&command[1]), integer(1)])
+                },
+                "PUBLISH" => {
+                    let message = array(vec![bulk("message"), bulk(&command[1]), bulk(&command[2])]);
+                    let subscribers = state.subscribers.entry(command[1].clone()).or_default();
+                    subscribers.retain(|subscriber| subscriber.send(message.clone()).is_ok());
+                    integer(subscribers.len())
+                },
+                _ => simple("OK")
+            }
+        }
+
+        async fn read_command(reader: &mut BufReader<OwnedReadHalf>) -> Option<Vec<String>> {
+
+            let mut line = String::new();
+            reader.read_line(&mut line).await.ok().filter(|read| *read > 0)?;
+            let count = line.trim_end().strip_prefix('*')?.parse::<usize>().ok()?;
+
+            let mut command = Vec::with_capacity(count);
+            for _ in 0..count {
+                line.clear();
+                reader.read_line(&mut line).await.ok()?;
+                let length = line.trim_end().strip_prefix('$')?.parse::<usize>().ok()?;
+                let mut bytes = vec![0; length + 2];
+                reader.read_exact(bytes.as_mut_slice()).await.ok()?;
+                bytes.truncate(length);
+                command.push(String::from_utf8(bytes).ok()?);
+            }
+
+            Some(command)
+        }
+
+        fn simple(value: &str) -> Vec<u8> {
+            format!("+{}\r\n", value).into_bytes()
+        }
+
+        fn integer(value: usize) -> Vec<u8> {
+            format!(":{}\r\n", value).into_bytes()
+        }
+
+        fn bulk(value: &str) -> Vec<u8> {
+            format!("${}\r\n{}\r\n", value.len(), value).into_bytes()
+        }
+
+        fn array(items: Vec<Vec<u8>>) -> Vec<u8> {
+            let mut bytes = format!("*{}\r\n", items.len()).into_bytes();
+            items.into_iter().for_each(|item| bytes.extend(item));
+            bytes
+        }
+    }
+
+    fn session() -> StoredSession {
+        StoredSession::new(
+            "digest",
+            chrono::Utc::now().naive_utc() + chrono::Duration::hours(1),
+            ClientFingerprint::new(Some([127, 0, 0, 1].into()), Some("Firefox".to_string()))
+        )
+    }
+
+    /// Polls until the condition holds, for up to a second
+    async fn eventually<F: std::future::Future<Output = bool>>(condition: impl Fn() -> F) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(1) {
+            if condition().await {
+                return true
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn a_logout_in_one_replica_is_seen_by_the_others() {
+
+        let (url, state) = stand_in::start().await;
+        let replica = RedisSessionStore::connect(url.as_str(), "uta").await.unwrap();
+        let other_replica = RedisSessionStore::connect(url.as_str(), "uta").await.unwrap();
+        assert!(eventually(|| async { state.lock().unwrap().subscribers("uta:sessions:invalidate") == 2 }).await);
+
+        replica.open_session(&1, session()).await.unwrap();
+
+        //  The other replica reads the session from the server and caches it
+        let stored_session = other_replica.get_session(&1).await.unwrap().unwrap();
+        assert_eq!(stored_session.get_token_digest(), "digest");
+        assert_eq!(stored_session.get_client(), session().get_client());
+        assert!(other_replica.cache.read().await.get(&1).is_some());
+
+        replica.close_session(&1).await.unwrap();
+
+        assert!(eventually(|| async { other_replica.get_session(&1).await.unwrap().is_none() }).await);
+        assert!(replica.get_session(&1).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn sessions_invalidated_while_read_are_not_cached() {
+
+        let mut cache = SessionsCache::default();
+
+        let generation = cache.generation(&1);
+        cache.invalidate("1");
+        cache.insert(&1, generation, session());
+        assert!(cache.get(&1).is_none());
+
+        let generation = cache.generation(&1);
+        cache.invalidate(INVALIDATE_ALL);
+        cache.insert(&1, generation, session());
+        assert!(cache.get(&1).is_none());
+
+        //  Other users' invalidations don't keep it out
+        let generation = cache.generation(&1);
+        cache.invalidate("2");
+        cache.insert(&1, generation, session());
+        assert!(cache.get(&1).is_some());
+    }
+}
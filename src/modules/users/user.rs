@@ -3,13 +3,21 @@ use std::fmt::{Display, Formatter};
 use chrono::NaiveDateTime;
 use error_mapper::{map_to_new_error, SystemErrorCodes, TheError, TheResult};
 use mysql_async::prelude::{FromRow, Queryable};
-use mysql_async::{FromRowError, Row};
+use mysql_async::{FromRowError, Row, TxOpts};
 use serde::{Deserialize, Serialize};
 use crate::{auth, database, row_to_enum, row_to_naive_datetime};
 use crate::database::db_conn::get_conn;
 use crate::general::types::UsersIdType;
 use crate::{row_to_data};
+use crate::config::environment::EnvironmentConfig;
 use crate::modules::users;
+use crate::modules::users::session_binding::ClientFingerprint;
+use crate::modules::users::session_events::{SessionEventKind, SessionEvents};
+use crate::modules::users::UsersSessions;
+use crate::modules::personal_tokens::personal_token::PersonalToken;
+
+/// Error code of the database for a duplicate value of a unique key
+const ER_DUP_ENTRY: u16 = 1062;
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct User {
@@ -21,14 +29,22 @@ pub struct User {
     #[serde(skip_serializing)]
     email: String,
     #[serde(skip_serializing, skip_deserializing)]
+    display_name: Option<String>,
+    #[serde(skip_serializing, skip_deserializing)]
     level: Level,
     #[serde(skip_serializing, skip_deserializing)]
     created_at: NaiveDateTime,
     #[serde(skip_serializing, skip_deserializing)]
-    updated_at: NaiveDateTime
+    updated_at: NaiveDateTime,
+    #[serde(skip_serializing, skip_deserializing)]
+    service_account: bool,
+    //  Highest level the user can act with in the current request, set when authenticated with a
+    // scoped credential such as an API key. Not stored in database
+    #[serde(skip_serializing, skip_deserializing)]
+    scope_level: Option<Level>
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default, Copy, PartialEq, PartialOrd)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Level {
     #[default]
     View = 0,
@@ -44,7 +60,8 @@ impl User {
         username: &str,
         pass: &str,
         email: &str,
-        level: &Level
+        level: &Level,
+        client: &ClientFingerprint
     ) -> TheResult<(UsersIdType, String)> {
 
         let mut user = User::default();
@@ -70,21 +87,48 @@ impl User {
 
         //  Start a session, a logged in user gets created with an open session
         let token = auth::crypt::generate_session_token()?;
-        users::users_sessions::activate_user_session(&user, &token).await?;
+        users::users_sessions::activate_user_session(&user, &token, client).await?;
 
         //  Return the user id
         Ok((user.id, token))
     }
 
+    /// ## Description
+    /// Creates a service account. Service accounts have no password, so they can't log in, and
+    /// authenticate with API keys instead
+    pub async fn create_service_account(
+        username: &str,
+        email: &str,
+        level: &Level
+    ) -> TheResult<UsersIdType> {
+
+        let mut user = User::default();
+
+        user.id = User::select_last_id().await? + 1;
+        user.username = username.to_owned();
+        user.email = email.to_owned();
+        user.level = *level;
+        user.created_at = chrono::Utc::now().naive_utc();
+        user.updated_at = user.created_at;
+        user.service_account = true;
+
+        user.insert().await?;
+
+        Ok(user.id)
+    }
+
     pub fn create_super_user() -> Self {
         Self {
             id: 1,
             username: "super".to_string(),
             hashed_pass: "".to_string(),
             email: "super_user@yomama.com".to_string(),
+            display_name: None,
             level: Level::Super,
             created_at: chrono::Utc::now().naive_utc(),
-            updated_at: chrono::Utc::now().naive_utc()
+            updated_at: chrono::Utc::now().naive_utc(),
+            service_account: false,
+            scope_level: None
         }
     }
 
@@ -92,20 +136,9 @@ impl User {
 
         let conn = &mut get_conn().await?;
 
-        let user = conn.query::<User, _>(
+        conn.query::<User, _>(
                 "SELECT * FROM users WHERE deleted_at IS NULL"
-        ).await.map_err(|e| map_to_new_error!(e))?;
-
-        if !user.is_empty() {
-            Ok(user)
-        } else {
-            Err(
-                TheError::new(
-                    SystemErrorCodes::NotFound,
-                    "No users found".to_string()
-                )
-            )
-        }
+        ).await.map_err(|e| map_to_new_error!(e))
     }
 
     pub async fn select_by_username(username: &str) -> TheResult<Option<User>> {
@@ -165,15 +198,16 @@ impl User {
 
         conn.query_drop(
             format! (
-                "INSERT INTO users (ID, username, hashed_pass, email, level, created_at, updated_at) \
-                    VALUES ({}, '{}', '{}', '{}', '{}', '{}', '{}')",
+                "INSERT INTO users (ID, username, hashed_pass, email, level, created_at, updated_at, service_account) \
+                    VALUES ({}, '{}', '{}', '{}', '{}', '{}', '{}', {})",
                 self.id,
                 self.username.as_str(),
                 self.hashed_pass.as_str(),
                 self.email.as_str(),
                 self.level,
                 self.created_at,
-                self.updated_at
+                self.updated_at,
+                self.service_account
             )
         ).await.map_err(|e| map_to_new_error!(e))?;
 
@@ -192,6 +226,13 @@ impl User {
             )
         ).await.map_err(|e| map_to_new_error!(e))?;
 
+        //  Tokens must not outlive the account they were issued for
+        PersonalToken::revoke_all_by_user(&self.id).await?;
+
+        //  Nor sessions, in this replica and in the others
+        UsersSessions::instance().delete_user_entry(&self.id).await?;
+        SessionEvents::instance().publish(SessionEventKind::AccountDeleted, &self.id).await;
+
         Ok(())
     }
 
@@ -229,6 +270,10 @@ impl User {
     }
 
     pub fn validate_hashed_password(&self, pass: &str) -> bool {
+        //  Service accounts have no password to validate against
+        if self.service_account {
+            return false
+        }
         let hashed_pass = self.build_string_to_hash(pass);
         self.hashed_pass == auth::crypt::generate_hash(hashed_pass.as_str())
     }
@@ -244,8 +289,9 @@ impl User {
 
         conn.query_drop(
             format!(
-                "UPDATE users SET hashed_pass = '{}' WHERE ID = {}",
+                "UPDATE users SET hashed_pass = '{}', updated_at = '{}' WHERE ID = {}",
                 hashed_new_pass,
+                chrono::Utc::now().naive_utc().format(database::DATETIME_FORMAT),
                 self.id
             )
         ).await.map_err(|e| map_to_new_error!(e))?;
@@ -253,7 +299,62 @@ impl User {
         Ok(())
     }
 
-    pub(super) fn validate_password(pass: &String) -> Vec<String> {
+    /// ## Description
+    /// Replaces the email and display name of the user, in database and in this instance. An
+    /// empty display name removes it
+    pub(super) async fn update_profile(&mut self, email: String, display_name: Option<String>) -> TheResult<()> {
+
+        let conn = &mut get_conn().await?;
+
+        let display_name = display_name.filter(|display_name| !display_name.is_empty());
+        let now = chrono::Utc::now().naive_utc();
+
+        //  Display names are free text, the values are sent as parameters and never formatted in
+        conn.exec_drop(
+            "UPDATE users SET email = ?, display_name = ?, updated_at = ? WHERE ID = ?",
+            (
+                email.as_str(),
+                display_name.as_deref(),
+                now.format(database::DATETIME_FORMAT).to_string(),
+                self.id
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        self.email = email;
+        self.display_name = display_name;
+        self.updated_at = now;
+
+        Ok(())
+    }
+
+    pub(crate) fn validate_profile(email: &str, display_name: Option<&str>) -> Vec<String> {
+
+        let mut errors = vec![];
+
+        match email.split_once('@') {
+            Some((local, domain)) if !local.is_empty() && domain.contains('.') && !domain.contains('@') => {},
+            _ => errors.push("Email must be a valid email address".to_string())
+        }
+        if email.len() > 50 {
+            errors.push("Email must be at most 50 characters long".to_string());
+        }
+        if email.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            errors.push("Email must not contain whitespaces".to_string());
+        }
+
+        if let Some(display_name) = display_name {
+            if display_name.chars().count() > 50 {
+                errors.push("Display name must be at most 50 characters long".to_string());
+            }
+            if display_name.chars().any(char::is_control) {
+                errors.push("Display name must not contain control characters".to_string());
+            }
+        }
+
+        errors
+    }
+
+    pub(crate) fn validate_password(pass: &str) -> Vec<String> {
 
         let mut errors = vec![];
 
@@ -292,19 +393,82 @@ impl User {
         errors
     }
 
+    /// ## Description
+    /// Renames the user, recording the previous username in the history, where it's reserved for
+    /// the user during the quarantine. The username is checked again along with the rename, in a
+    /// single transaction, and false is returned without renaming if it was taken in the meantime
+    pub(super) async fn change_username(&mut self, new_username: &str) -> TheResult<bool> {
+
+        let conn = &mut get_conn().await?;
+
+        let now = chrono::Utc::now().naive_utc().format(database::DATETIME_FORMAT).to_string();
+
+        //  Dropping the transaction without committing rolls it back
+        let mut transaction = conn.start_transaction(TxOpts::default()).await
+            .map_err(|e| map_to_new_error!(e))?;
+
+        if !username_free(&mut transaction, new_username, Some(&self.id), true).await? {
+            return Ok(false)
+        }
+
+        let renamed = transaction.exec_drop(
+            "UPDATE users SET username = ?, updated_at = ? WHERE ID = ?",
+            (new_username, now.as_str(), self.id)
+        ).await;
+        match renamed {
+            //  Someone else took it between the check and the update
+            Err(mysql_async::Error::Server(e)) if e.code == ER_DUP_ENTRY => return Ok(false),
+            renamed => renamed.map_err(|e| map_to_new_error!(e))?
+        }
+
+        transaction.exec_drop(
+            "INSERT INTO users_username_history (users_ID, old_username, new_username, changed_at) VALUES (?, ?, ?, ?)",
+            (self.id, self.username.as_str(), new_username, now.as_str())
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        transaction.commit().await.map_err(|e| map_to_new_error!(e))?;
+
+        self.username = new_username.to_string();
+
+        //  The runtime data of this replica is updated here, the others on the event
+        UsersSessions::instance().rename_user(&self.id, new_username).await;
+        SessionEvents::instance().publish(SessionEventKind::UsernameChanged, &self.id).await;
+
+        Ok(true)
+    }
+
+    pub(crate) fn validate_username(username: &str) -> Vec<String> {
+
+        let mut errors = vec![];
+
+        if username.is_empty() || username.len() > 20 {
+            errors.push("Username must be between 1 and 20 characters long".to_string());
+        }
+        if !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.') {
+            errors.push("Username must contain only letters, numbers, underscores, hyphens and dots".to_string());
+        }
+
+        errors
+    }
+
     pub(super) async fn change_user_level(user_id: &UsersIdType, target_level: &Level) -> TheResult<()> {
 
         let conn = &mut get_conn().await?;
 
         conn.query_drop(
             format!(
-                "UPDATE users SET level = '{}' WHERE ID = {}",
+                "UPDATE users SET level = '{}', updated_at = '{}' WHERE ID = {}",
                 target_level,
+                chrono::Utc::now().naive_utc().format(database::DATETIME_FORMAT),
                 user_id
             )
         ).await.map_err(|e| map_to_new_error!(e))?;
 
         if conn.affected_rows() > 0 {
+            //  Tokens were issued with the privileges of the previous level
+            PersonalToken::revoke_all_by_user(user_id).await?;
+            //  This replica refreshes the effective level after the change, the others on the event
+            SessionEvents::instance().publish(SessionEventKind::LevelChanged, user_id).await;
             return Ok(())
         }
 
@@ -328,31 +492,87 @@ impl User {
         self.email.as_str()
     }
 
+    pub fn get_display_name(&self) -> Option<&str> {
+        self.display_name.as_deref()
+    }
+
     pub fn get_level(&self) -> &Level {
         &self.level
     }
 
+    pub fn get_created_at(&self) -> &NaiveDateTime {
+        &self.created_at
+    }
+
+    pub fn get_updated_at(&self) -> &NaiveDateTime {
+        &self.updated_at
+    }
+
+    pub fn is_service_account(&self) -> bool {
+        self.service_account
+    }
+
+    pub fn get_scope_level(&self) -> Option<&Level> {
+        self.scope_level.as_ref()
+    }
+
     pub fn set_hashed_pass(&mut self, pass: String) {
         self.hashed_pass = pass
     }
+
+    pub fn set_scope_level(&mut self, level: Level) {
+        self.scope_level = Some(level)
+    }
 }
 
-pub(super) async fn username_available(username: &str) -> TheResult<bool> {
+/// ## Description
+/// Whether the username can be taken. Usernames given up in a rename are reserved for the user who
+/// had them during the quarantine, so the claimant is the only one who can take them back
+pub(crate) async fn username_available(username: &str, claimant: Option<&UsersIdType>) -> TheResult<bool> {
 
     let conn = &mut get_conn().await?;
 
-    let user = conn.query_first::<Option<UsersIdType>, _>(
-        format!(
-            "SELECT ID FROM users WHERE username = '{}'",
-            username
-        )
+    username_free(conn, username, claimant, false).await
+}
+
+/// Whether no user has the username and it's not in quarantine, unless for the claimant. Locking
+/// the rows read keeps other transactions from taking the username until the transaction ends
+async fn username_free(
+    conn: &mut impl Queryable,
+    username: &str,
+    claimant: Option<&UsersIdType>,
+    lock: bool
+) -> TheResult<bool> {
+
+    let lock = if lock { " FOR UPDATE" } else { "" };
+
+    let user = conn.exec_first::<UsersIdType, _, _>(
+        format!("SELECT ID FROM users WHERE username = ?{}", lock),
+        (username,)
     ).await.map_err(|e| map_to_new_error!(e))?;
 
-    if let Some(Some(_)) = user {
+    if user.is_some() {
         return Ok(false)
     }
 
-    Ok(true)
+    let quarantine_days = EnvironmentConfig::instance().get_username_quarantine_days().await;
+    let quarantined_since = chrono::Utc::now().naive_utc() - chrono::Duration::days(quarantine_days);
+
+    let previous_owner = conn.exec_first::<UsersIdType, _, _>(
+        format!(
+            "SELECT users_ID FROM users_username_history \
+                WHERE old_username = ? AND changed_at > ? AND (? IS NULL OR users_ID != ?) LIMIT 1{}",
+            lock
+        ),
+        (
+            username,
+            quarantined_since.format(database::DATETIME_FORMAT).to_string(),
+            claimant,
+            claimant
+        )
+    ).await.map_err(|e| map_to_new_error!(e))?;
+
+    Ok(previous_owner.is_none())
 }
 
 impl FromRow for User {
@@ -362,9 +582,12 @@ impl FromRow for User {
             username: row_to_data!(row, "username", "users", String),
             hashed_pass: row_to_data!(row, "hashed_pass", "users", String),
             email: row_to_data!(row, "email", "users", String),
+            display_name: row_to_data!(row, "display_name", "users", Option<String>),
             level: row_to_enum!(row, "level", "users", Level),
             created_at: row_to_naive_datetime!(row, "created_at", "users"),
             updated_at: row_to_naive_datetime!(row, "updated_at", "users"),
+            service_account: row_to_data!(row, "service_account", "users", bool),
+            scope_level: None
         }
     }
 
@@ -419,6 +642,8 @@ impl From<u8> for Level {
 }
 
 impl Level {
+    pub const ALL: [Level; 5] = [Level::View, Level::Low, Level::Medium, Level::High, Level::Super];
+
     pub fn one_level_below(&self) -> Self {
         match self {
             Level::Super => Level::High,
@@ -1,11 +1,13 @@
 use std::ops::Add;
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime, Timelike};
 use error_mapper::{map_to_new_error, SystemErrorCodes, TheError, TheResult};
 use mysql_async::prelude::{FromRow, Queryable};
 use mysql_async::{FromRowError};
-use crate::{database, row_to_data, row_to_naive_datetime};
+use crate::{auth, database, row_to_data, row_to_naive_datetime};
 use crate::database::db_conn::get_conn;
 use crate::general::types::UsersIdType;
+use crate::modules::users::session_binding::{self, BindingAction, ClientFingerprint};
+use crate::modules::users::session_events::{SessionEventKind, SessionEvents};
 use crate::modules::users::user::User;
 use crate::modules::users::UsersSessions;
 
@@ -18,39 +20,62 @@ pub enum SessionStatus {
     SessionError
 }
 
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug)]
 pub struct SessionData {
     users_id: UsersIdType,
+    token_digest: String,
+    creation: NaiveDateTime,
+    expiry: NaiveDateTime,
+    client: ClientFingerprint,
     session_status: SessionStatus
 }
 
+/// Result of checking the session a request was made with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionCheck {
+    Valid,
+    InvalidToken,
+    /// The session is used from another client, and the user must log in again from it
+    StepUpRequired,
+    /// The session was used from another client and got closed
+    Rejected
+}
+
+/// Lifetime of a session since the user's last login
+const SESSION_LIFETIME_MINUTES: i64 = 30;
+
+/// Session looked up by its token rather than by its user, for services that only hold the token
+#[derive(Clone, Debug, Copy)]
+pub struct TokenSession {
+    users_id: UsersIdType,
+    creation: NaiveDateTime,
+    expiry: NaiveDateTime
+}
+
+/// Sessions are answered from the session store, after being written to the database
+/// every time they are opened, extended or closed
 pub(super) async fn check_user_active_session(user_id: &UsersIdType) -> TheResult<SessionStatus> {
+    Ok(UsersSessions::instance().get_session_status(user_id).await)
+}
 
-    match select_expiry_from_user_session(user_id).await {
+pub(super) async fn extend_user_session(user: &User, client: &ClientFingerprint) -> TheResult<SessionStatus> {
+    match update_login_session(user.get_id(), client).await {
         Ok(expiry) => {
-            if expiry < chrono::Utc::now().naive_utc() {
-                //  At this point, if the session is expired, 
-                return Ok(SessionStatus::Expired)
-            }
-            Ok(SessionStatus::Active)
+            UsersSessions::instance().extend_session(user.get_id(), expiry, client).await?;
         },
         Err(_) => {
-            Ok(SessionStatus::SessionError)
+            //  If the session couldn't be updated, delete the session from database
+            if let Err(e) = UsersSessions::instance().logout_user(user).await {
+                //  TODO remove when logger is implemented
+                eprintln!("Error closing user {} session: {}", user.get_id(), e);
+            }
+            if let Err(e) = delete_logins_session(user.get_id()).await {
+                //  TODO remove when logger is implemented
+                eprintln!("Error deleting user {} session: {}", user.get_id(), e);
+                return Ok(SessionStatus::SessionError)
+            };
         }
     }
-}
-
-pub(super) async fn extend_user_session(user: &User) -> TheResult<SessionStatus> {
-    //  If a session was found, then the user is logged in. Make sure the static sessions data is updated
-    UsersSessions::instance().login_user(user).await;
-    if update_login_session(user.get_id()).await.is_err() {
-        //  If the session couldn't be updated, delete the session from database
-        if let Err(e) = delete_logins_session(user.get_id()).await {
-            //  TODO remove when logger is implemented
-            println!("Error deleting user {} session: {}", user.get_id(), e);
-            return Ok(SessionStatus::SessionError)
-        };
-    };
 
     Ok(SessionStatus::Active)
 }
@@ -61,97 +86,116 @@ pub(super) async fn terminate_user_session(user: &User) -> TheResult<()> {
     delete_logins_session(user.get_id()).await?;
 
     //  Close session from session data
-    UsersSessions::instance().logout_user(user).await;
+    UsersSessions::instance().logout_user(user).await?;
+
+    //  Other replicas close it too, without waiting for their sessions cron
+    SessionEvents::instance().publish(SessionEventKind::SessionTerminated, user.get_id()).await;
 
     Ok(())
 }
 
-pub(super) async fn activate_user_session(user: &User, token: &String) -> TheResult<()> {
+pub(super) async fn activate_user_session(user: &User, token: &String, client: &ClientFingerprint) -> TheResult<()> {
 
-    insert_login_session(user.get_id(), token).await?;
+    let expiry = insert_login_session(user.get_id(), token, client).await?;
 
-    UsersSessions::instance().login_user(user).await;
+    UsersSessions::instance().open_session(user, token.as_str(), expiry, client).await
+}
 
-    Ok(())
+/// Expiry of a session opened or extended now. Seconds are the most precise the database keeps,
+/// the runtime sessions keep the same value
+fn new_session_expiry(now: NaiveDateTime) -> NaiveDateTime {
+    let expiry = now.add(chrono::Duration::minutes(SESSION_LIFETIME_MINUTES));
+    expiry.with_nanosecond(0).unwrap_or(expiry)
 }
 
-pub(super) async fn select_expiry_from_user_session(
-    user_id: &UsersIdType
+pub(super) async fn insert_login_session(
+    user_id: &UsersIdType,
+    token: &String,
+    client: &ClientFingerprint
 ) -> TheResult<NaiveDateTime> {
 
     let conn = &mut get_conn().await?;
 
-    //  If no session is active, creation won't be found, and if it is found, expiry value will tell
-    // whether the session is active or not
-    let expiry = conn.query_first::<Option<String>,_>(
+    let now = chrono::Utc::now().naive_utc();
+
+    let expiry = new_session_expiry(now);
+
+    let creation = now.format(database::DATETIME_FORMAT).to_string();
+
+    conn.query_drop(
         format!(
-            "SELECT expiry FROM users_sessions \
-            WHERE users_ID = {}",
-            user_id
+            "INSERT INTO users_sessions (users_ID, token, creation, expiry, client_ip, user_agent_family) \
+            VALUES ({}, '{}', '{}', '{}', {}, {})",
+            user_id,
+            token,
+            creation,
+            expiry.format(database::DATETIME_FORMAT),
+            client_ip_value(client),
+            user_agent_family_value(client)
         )
     ).await.map_err(|e| map_to_new_error!(e))?;
 
-    //  Try expiry into NaiveDateTime
-    let expiry = if let Some(Some(string)) = expiry {
-        NaiveDateTime::parse_from_str(string.as_str(), database::DATETIME_FORMAT)
-            .map_err(|e| map_to_new_error!(e))?
-    } else {
-        //  If it's default, the session's expiry datetime will return as EPOCH time, so it's expired
-        return Ok(NaiveDateTime::default())
-    };
-
     Ok(expiry)
 }
 
-pub(super) async fn insert_login_session(user_id: &UsersIdType, token: &String) -> TheResult<()> {
+pub(super) async fn update_login_session(user_id: &UsersIdType, client: &ClientFingerprint) -> TheResult<NaiveDateTime> {
 
     let conn = &mut get_conn().await?;
 
-    let now = chrono::Utc::now();
+    let now = chrono::Utc::now().naive_utc();
 
-    let expiry = now.add(chrono::Duration::minutes(30))
-        .format(database::DATETIME_FORMAT)
-        .to_string();
+    let expiry = new_session_expiry(now);
 
     let creation = now.format(database::DATETIME_FORMAT).to_string();
 
     conn.query_drop(
         format!(
-            "INSERT INTO users_sessions (users_ID, token, creation, expiry) \
-            VALUES ({}, '{}', '{}', '{}')",
-            user_id,
-            token,
+            "UPDATE users_sessions SET creation = '{}', expiry = '{}', client_ip = {}, user_agent_family = {} \
+            WHERE users_ID = {}",
             creation,
-            expiry
+            expiry.format(database::DATETIME_FORMAT),
+            client_ip_value(client),
+            user_agent_family_value(client),
+            user_id
         )
     ).await.map_err(|e| map_to_new_error!(e))?;
 
-    Ok(())
+    Ok(expiry)
 }
 
-pub(super) async fn update_login_session(user_id: &UsersIdType) -> TheResult<()> {
+/// Records that the user just confirmed their password, for the sensitive operations that require
+/// a recent re-authentication
+pub(super) async fn mark_session_reauthenticated(user_id: &UsersIdType) -> TheResult<()> {
 
     let conn = &mut get_conn().await?;
 
-    let now = chrono::Utc::now();
+    conn.query_drop(
+        format!(
+            "UPDATE users_sessions SET reauthenticated_at = '{}' WHERE users_ID = {}",
+            chrono::Utc::now().naive_utc().format(database::DATETIME_FORMAT),
+            user_id
+        )
+    ).await.map_err(|e| map_to_new_error!(e))?;
 
-    let expiry = now.add(chrono::Duration::minutes(30))
-        .format(database::DATETIME_FORMAT)
-        .to_string();
+    Ok(())
+}
 
-    let creation = now.format(database::DATETIME_FORMAT).to_string();
+pub(super) async fn select_reauthenticated_at(user_id: &UsersIdType) -> TheResult<Option<NaiveDateTime>> {
 
-    conn.query_drop(
+    let conn = &mut get_conn().await?;
+
+    let reauthenticated_at = conn.query_first::<Option<String>, _>(
         format!(
-            "UPDATE users_sessions SET creation = '{}', expiry = '{}' \
-            WHERE users_ID = {}",
-            creation,
-            expiry,
+            "SELECT reauthenticated_at FROM users_sessions WHERE users_ID = {}",
             user_id
         )
     ).await.map_err(|e| map_to_new_error!(e))?;
 
-    Ok(())
+    Ok(
+        reauthenticated_at
+            .flatten()
+            .and_then(|date| NaiveDateTime::parse_from_str(date.as_str(), database::DATETIME_FORMAT).ok())
+    )
 }
 
 pub async fn delete_logins_session(user_id: &UsersIdType) -> TheResult<()> {
@@ -168,24 +212,100 @@ pub async fn delete_logins_session(user_id: &UsersIdType) -> TheResult<()> {
     Ok(())
 }
 
-pub async fn validate_session_token(user: &User, user_token: &str) -> TheResult<bool> {
+/// Client IP as a SQL value. IPs are parsed, so they are safe to write as they are
+fn client_ip_value(client: &ClientFingerprint) -> String {
+    match client.get_ip() {
+        Some(ip) => format!("'{}'", ip),
+        None => "NULL".to_string()
+    }
+}
+
+/// User agent family as a SQL value. Families only keep alphanumeric characters, dots, dashes
+/// and underscores, so they are safe to write as they are too
+fn user_agent_family_value(client: &ClientFingerprint) -> String {
+    match client.get_user_agent_family() {
+        Some(family) => format!("'{}'", family),
+        None => "NULL".to_string()
+    }
+}
+
+/// ## Description
+/// Checks the session token against the digest kept in the session store, and the client making
+/// the request against the client the session is bound to. Sessions rejected by the binding are
+/// closed here
+pub async fn validate_session(user: &User, user_token: &str, client: &ClientFingerprint) -> TheResult<SessionCheck> {
+
+    let Some(session) = UsersSessions::instance().get_valid_session(user.get_id(), user_token).await? else {
+        return Ok(SessionCheck::InvalidToken)
+    };
+
+    match session_binding::check_session_binding(user, session.get_client(), client).await {
+        BindingAction::Allow => Ok(SessionCheck::Valid),
+        BindingAction::StepUp => Ok(SessionCheck::StepUpRequired),
+        BindingAction::Reject => {
+            terminate_user_session(user).await?;
+            Ok(SessionCheck::Rejected)
+        }
+    }
+}
+
+/// ## Description
+/// Consistency check between the session store and the database. Sessions expired in the
+/// database are deleted, sessions of users that no longer exist too, and the session store is
+/// set to exactly what's left in the database. Runs on startup and in the sessions cron. Returns
+/// how many sessions in the store had to be corrected
+pub async fn reconcile_runtime_sessions() -> TheResult<usize> {
+
+    let sessions = SessionData::get_all_user_sessions().await?;
+
+    let mut active_sessions = Vec::new();
+    for session in sessions {
+        let Some(user) = User::select_by_id(session.get_user_id()).await? else {
+            //  If no user was found, need to make sure he's logged out and no active sessions are present in db
+            delete_logins_session(session.get_user_id()).await?;
+            UsersSessions::instance().delete_user_entry(session.get_user_id()).await?;
+            continue;
+        };
+        match session.get_session_status() {
+            SessionStatus::Expired | SessionStatus::SessionError => {
+                //  If session is expired or has any error, delete it from DB and logout from runtime
+                delete_logins_session(session.get_user_id()).await?;
+                UsersSessions::instance().logout_user(&user).await?;
+            },
+            SessionStatus::Active => {
+                active_sessions.push(session);
+            }
+        }
+    }
+
+    let corrected = UsersSessions::instance().reconcile_sessions(active_sessions.as_slice()).await?;
+    if corrected > 0 {
+        //  TODO remove when logger is implemented
+        eprintln!("{} stored sessions didn't match the database and were corrected", corrected);
+    }
+
+    Ok(corrected)
+}
+
+/// ## Description
+/// Finds the session the token belongs to. Tokens that couldn't have been generated by this
+/// service are never looked up
+pub async fn select_session_by_token(token: &str) -> TheResult<Option<TokenSession>> {
+
+    if !auth::crypt::is_session_token(token) {
+        return Ok(None)
+    }
 
     let conn = &mut get_conn().await?;
 
-    let token = conn.query_first::<Option<String>, _>(
+    let session = conn.query_first::<TokenSession, _>(
         format!(
-            "SELECT token FROM users_sessions WHERE users_ID = {}",
-            user.get_id()
+            "SELECT users_ID, creation, expiry FROM users_sessions WHERE token = '{}'",
+            token
         )
     ).await.map_err(|e| map_to_new_error!(e))?;
 
-    if let Some(Some(db_token)) = token {
-        if db_token.as_str() == user_token {
-            return Ok(true)
-        }
-    }
-
-    Ok(false)
+    Ok(session)
 }
 
 pub(super) async fn fetch_session_token(user_id: &UsersIdType) -> TheResult<String> {
@@ -222,21 +342,83 @@ impl SessionData {
         let conn = &mut get_conn().await?;
 
         let sessions = conn.query::<Self, _>(
-            "SELECT users_ID, creation, expiry FROM users_sessions"
+            "SELECT users_ID, token, creation, expiry, client_ip, user_agent_family FROM users_sessions"
         ).await.map_err(|e| map_to_new_error!(e))?;
 
         Ok(sessions)
     }
 
+    pub async fn select_by_user_id(user_id: &UsersIdType) -> TheResult<Option<Self>> {
+
+        let conn = &mut get_conn().await?;
+
+        let session = conn.query_first::<Self, _>(
+            format!(
+                "SELECT users_ID, token, creation, expiry, client_ip, user_agent_family FROM users_sessions WHERE users_ID = {}",
+                user_id
+            )
+        ).await.map_err(|e| map_to_new_error!(e))?;
+
+        Ok(session)
+    }
+
     pub fn get_user_id(&self) -> &UsersIdType {
         &self.users_id
     }
 
+    pub fn get_token_digest(&self) -> &str {
+        self.token_digest.as_str()
+    }
+
+    pub fn get_creation(&self) -> &NaiveDateTime {
+        &self.creation
+    }
+
+    pub fn get_expiry(&self) -> &NaiveDateTime {
+        &self.expiry
+    }
+
+    pub fn get_client(&self) -> &ClientFingerprint {
+        &self.client
+    }
+
     pub fn get_session_status(&self) -> &SessionStatus {
         &self.session_status
     }
 }
 
+impl TokenSession {
+    pub fn is_active(&self) -> bool {
+        self.expiry > chrono::Utc::now().naive_utc()
+    }
+
+    pub fn get_user_id(&self) -> &UsersIdType {
+        &self.users_id
+    }
+
+    pub fn get_creation(&self) -> &NaiveDateTime {
+        &self.creation
+    }
+
+    pub fn get_expiry(&self) -> &NaiveDateTime {
+        &self.expiry
+    }
+}
+
+impl FromRow for TokenSession {
+    fn from_row(row: mysql_async::Row) -> Self {
+        Self {
+            users_id: row_to_data!(row, "users_ID", "users_sessions", UsersIdType),
+            creation: row_to_naive_datetime!(row, "creation", "users_sessions"),
+            expiry: row_to_naive_datetime!(row, "expiry", "users_sessions")
+        }
+    }
+
+    fn from_row_opt(_: mysql_async::Row) -> Result<Self, FromRowError> where Self: Sized {
+        unimplemented!()
+    }
+}
+
 impl FromRow for SessionData {
     fn from_row(row: mysql_async::Row) -> Self {
         let creation = row_to_naive_datetime!(row, "creation", "users_sessions");
@@ -244,6 +426,15 @@ impl FromRow for SessionData {
 
         Self {
             users_id: row_to_data!(row, "users_ID", "users_sessions", UsersIdType),
+            token_digest: auth::crypt::session_token_digest(
+                row_to_data!(row, "token", "users_sessions", String).as_str()
+            ),
+            creation,
+            expiry,
+            client: ClientFingerprint::new(
+                row_to_data!(row, "client_ip", "users_sessions", Option<String>).and_then(|ip| ip.parse().ok()),
+                row_to_data!(row, "user_agent_family", "users_sessions", Option<String>)
+            ),
             session_status: {
                 if creation > chrono::Utc::now().naive_utc() {
                     SessionStatus::SessionError
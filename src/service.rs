@@ -0,0 +1,156 @@
+use std::sync::Arc;
+use actix_web::web;
+use error_mapper::TheResult;
+use tokio::sync::broadcast::{Receiver, Sender};
+use crate::{api, config, crons, database, modules};
+use crate::api::StopMethod;
+use crate::auth::jwt::SigningKey;
+use crate::auth::policy::Policy;
+use crate::config::environment::{CONFIG_FILE_PATH, EnvironmentConfig, EnvironmentSettings};
+use crate::config::shutdown::Shutdown;
+use crate::database::db_conn::Store;
+use crate::modules::users::profile_schema::ProfileSchema;
+use crate::modules::users::session_events::{SessionEvents, SessionEventTransport};
+use crate::modules::users::session_store::{self, SessionStore};
+use crate::modules::users::user::User;
+use crate::modules::users::UsersSessions;
+
+/// ## Description
+/// The authentication service, ready to be mounted in any Actix Web app with `configure`, or run
+/// on its own server with `run`. Built with `AuthService::builder()`, which loads everything the
+/// endpoints rely on
+#[derive(Clone)]
+pub struct AuthService {
+    sender: Sender<StopMethod>,
+    local_relying_party: bool
+}
+
+#[derive(Default)]
+pub struct AuthServiceBuilder {
+    store: Option<Store>,
+    settings: Option<EnvironmentSettings>,
+    session_store: Option<Arc<dyn SessionStore>>,
+    session_events: Option<Arc<dyn SessionEventTransport>>
+}
+
+impl AuthService {
+    pub fn builder() -> AuthServiceBuilder {
+        AuthServiceBuilder::default()
+    }
+
+    /// ## Description
+    /// Mounts the users, internal, api, oauth and organizations scopes, among the rest of the
+    /// endpoints, into the app:
+    ///
+    /// `App::new().configure(|cfg| auth_service.configure(cfg))`
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        api::configure(cfg, self.sender.clone(), self.local_relying_party);
+    }
+
+    /// Receives the stop requests of the `api/internal/stop` endpoints. The standalone server
+    /// stops on them, apps embedding the service decide what to do
+    pub fn stop_receiver(&self) -> Receiver<StopMethod> {
+        self.sender.subscribe()
+    }
+
+    /// Runs the service on its own Http server, with the address and certificates configured
+    pub async fn run(self) -> TheResult<()> {
+        let builder = config::openssl::create_openssl_builder()?;
+        api::start_api(self, builder).await
+    }
+}
+
+impl AuthServiceBuilder {
+    /// Database the service uses. Defaults to a pool created from the configured database URL
+    pub fn with_store(mut self, store: Store) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Settings of the service. Defaults to the ones in the config file
+    pub fn with_config(mut self, settings: EnvironmentSettings) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+
+    /// Store the sessions are answered from. Defaults to the one set in the settings
+    pub fn with_session_store(mut self, session_store: Arc<dyn SessionStore>) -> Self {
+        self.session_store = Some(session_store);
+        self
+    }
+
+    /// Transport of the session events between replicas. Defaults to polling the database
+    pub fn with_session_events(mut self, transport: Arc<dyn SessionEventTransport>) -> Self {
+        self.session_events = Some(transport);
+        self
+    }
+
+    /// ## Description
+    /// Loads the settings, authorization policy and signing key, prepares the database and the
+    /// users sessions, and starts the crons. Must be called once, before any app is started
+    pub async fn build(self) -> TheResult<AuthService> {
+
+        let settings = match self.settings {
+            Some(settings) => settings,
+            None => EnvironmentSettings::from_file(CONFIG_FILE_PATH)?
+        };
+        EnvironmentConfig::instance().set_settings(settings).await;
+
+        if let Some(store) = self.store {
+            database::db_conn::set_store(store).await;
+        }
+
+        let session_store = match self.session_store {
+            Some(session_store) => session_store,
+            None => session_store::from_config(&EnvironmentConfig::instance().get_session_store().await).await?
+        };
+        UsersSessions::instance().set_session_store(session_store).await;
+
+        if let Some(transport) = self.session_events {
+            SessionEvents::instance().set_transport(transport).await;
+        }
+
+        //  Authorization rules are loaded once, a broken policy file stops the app here
+        Policy::load_at_startup();
+
+        //  Same for the profile schema the metadata of the users is validated against
+        ProfileSchema::load_at_startup();
+
+        //  The key signing the ID tokens is generated here the first time, and a key file that
+        // can't be read is returned as an error instead of replaced
+        SigningKey::load_at_startup()?;
+
+        let (sender, receiver) = tokio::sync::broadcast::channel::<StopMethod>(4);
+
+        Shutdown::instance().set_shutdown_state_to_false().await;
+
+        setup_initial_env(receiver).await?;
+
+        Ok(AuthService {
+            sender,
+            local_relying_party: EnvironmentConfig::instance().get_local_relying_party().await.is_some()
+        })
+    }
+}
+
+async fn setup_initial_env(stopper: Receiver<StopMethod>) -> TheResult<()> {
+
+    if EnvironmentConfig::instance().reset_db().await {
+        if let Err(e) = database::reset_db().await {
+            println!("There was an error resetting database: {}", e);
+        };
+    }
+
+    modules::users::functions::create_default_super_user().await?;
+
+    let users = User::select_all().await?;
+
+    UsersSessions::instance().register_users_in_runtime(users.as_slice()).await?;
+
+    modules::groups::functions::register_group_levels_in_runtime().await?;
+
+    //  Start session cron(s)
+    tokio::spawn(crons::run_crons(stopper));
+
+    Ok(())
+}
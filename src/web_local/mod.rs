@@ -1 +1,2 @@
-mod requests;
\ No newline at end of file
+mod requests;
+pub mod services;
@@ -0,0 +1,82 @@
+use error_mapper::{map_to_new_error, TheResult};
+use serde::Deserialize;
+use crate::auth::jwt::JwkSet;
+use crate::config::environment::LocalRelyingPartyConfig;
+use crate::modules::oauth::oidc::UserInfo;
+
+/// Endpoints of the provider the relying party needs, as published in its discovery document
+#[derive(Deserialize, Debug, Clone)]
+pub(super) struct ProviderMetadata {
+    pub(super) issuer: String,
+    pub(super) authorization_endpoint: String,
+    pub(super) token_endpoint: String,
+    pub(super) userinfo_endpoint: String,
+    pub(super) jwks_uri: String
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub(super) struct TokenResponse {
+    pub(super) access_token: String,
+    pub(super) id_token: Option<String>
+}
+
+/// The provider usually runs locally with a self signed certificate, so it's accepted as is
+fn client() -> TheResult<reqwest::Client> {
+    reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .map_err(|e| map_to_new_error!(e))
+}
+
+pub(super) async fn fetch_provider_metadata(issuer: &str) -> TheResult<ProviderMetadata> {
+    client()?
+        .get(format!("{}/.well-known/openid-configuration", issuer))
+        .send().await.map_err(|e| map_to_new_error!(e))?
+        .error_for_status().map_err(|e| map_to_new_error!(e))?
+        .json::<ProviderMetadata>().await.map_err(|e| map_to_new_error!(e))
+}
+
+pub(super) async fn fetch_jwks(provider: &ProviderMetadata) -> TheResult<JwkSet> {
+    client()?
+        .get(provider.jwks_uri.as_str())
+        .send().await.map_err(|e| map_to_new_error!(e))?
+        .error_for_status().map_err(|e| map_to_new_error!(e))?
+        .json::<JwkSet>().await.map_err(|e| map_to_new_error!(e))
+}
+
+/// Exchanges the authorization code for tokens, authenticating with client_secret_post if the
+/// client is confidential
+pub(super) async fn exchange_code(
+    provider: &ProviderMetadata,
+    config: &LocalRelyingPartyConfig,
+    code: &str,
+    code_verifier: &str
+) -> TheResult<TokenResponse> {
+
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", config.get_redirect_uri()),
+        ("code_verifier", code_verifier),
+        ("client_id", config.get_client_id())
+    ];
+    if let Some(client_secret) = config.get_client_secret() {
+        form.push(("client_secret", client_secret));
+    }
+
+    client()?
+        .post(provider.token_endpoint.as_str())
+        .form(&form)
+        .send().await.map_err(|e| map_to_new_error!(e))?
+        .error_for_status().map_err(|e| map_to_new_error!(e))?
+        .json::<TokenResponse>().await.map_err(|e| map_to_new_error!(e))
+}
+
+pub(super) async fn fetch_userinfo(provider: &ProviderMetadata, access_token: &str) -> TheResult<UserInfo> {
+    client()?
+        .get(provider.userinfo_endpoint.as_str())
+        .bearer_auth(access_token)
+        .send().await.map_err(|e| map_to_new_error!(e))?
+        .error_for_status().map_err(|e| map_to_new_error!(e))?
+        .json::<UserInfo>().await.map_err(|e| map_to_new_error!(e))
+}
@@ -0,0 +1,387 @@
+
+use actix_web::{get, HttpRequest, HttpResponse, web};
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::header::LOCATION;
+use actix_web::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use utoipa::IntoParams;
+use crate::{auth, general};
+use crate::config::environment::EnvironmentConfig;
+use crate::general::http_req_res::{json_response, problem_response};
+use crate::general::problem::ErrorCode;
+use crate::modules::oauth::functions::redirect_uri_with;
+use crate::modules::oauth::oidc::{IdTokenClaims, UserInfo};
+use crate::web_local::requests;
+
+/// Cookie keeping the state, nonce and PKCE verifier between the login and the callback
+const FLOW_COOKIE: &str = "web_local_flow";
+
+#[derive(Deserialize, Debug, Clone, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct Callback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>
+}
+
+#[derive(Serialize)]
+struct SignedIn {
+    id_token_claims: IdTokenClaims,
+    userinfo: UserInfo
+}
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(login)
+        .service(callback);
+}
+
+/// ##  Endpoint local relying party login
+/// GET {UTAUrl}:{UTAPort}/web_local/login (public)
+///
+/// ### Description
+/// Stand-in for a downstream app signing its users in with OpenID Connect. Discovers the
+/// provider, this same service, and redirects to its authorization endpoint with PKCE, state and
+/// nonce. Only mounted when `local_relying_party` is set in the config file
+#[utoipa::path(
+    tag = "Local relying party",
+    summary = "Signs in through the provider",
+    responses(
+        (status = 302, description = "Redirect to the authorization endpoint")
+    )
+)]
+#[get("/login")]
+async fn login(request: HttpRequest) -> HttpResponse {
+
+    let Some(config) = EnvironmentConfig::instance().get_local_relying_party().await else {
+        return problem_response(ErrorCode::RelyingPartyNotConfigured, "Local relying party not configured")
+    };
+
+    let provider = match requests::fetch_provider_metadata(EnvironmentConfig::instance().get_issuer().await.as_str()).await {
+        Ok(provider) => provider,
+        Err(_) => return problem_response(ErrorCode::ProviderError, "Error discovering the provider")
+    };
+
+    let (Ok(state), Ok(nonce), Ok(code_verifier)) = (
+        auth::crypt::generate_session_token(),
+        auth::crypt::generate_session_token(),
+        auth::crypt::generate_session_token()
+    ) else {
+        return problem_response(ErrorCode::Internal, "Error starting sign in")
+    };
+
+    let authorization_url = redirect_uri_with(
+        provider.authorization_endpoint.as_str(),
+        &[
+            ("response_type", "code"),
+            ("client_id", config.get_client_id()),
+            ("redirect_uri", config.get_redirect_uri()),
+            ("scope", "openid profile email"),
+            ("state", state.as_str()),
+            ("nonce", nonce.as_str()),
+            ("code_challenge", auth::crypt::pkce_challenge(code_verifier.as_str()).as_str()),
+            ("code_challenge_method", "S256")
+        ]
+    );
+
+    let flow_cookie = Cookie::build(FLOW_COOKIE, format!("{}.{}.{}", state, nonce, code_verifier))
+        .path(general::http_req_res::scope_path(&request))
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .finish();
+
+    HttpResponse::Found()
+        .insert_header((LOCATION, authorization_url))
+        .cookie(flow_cookie)
+        .finish()
+}
+
+/// ##  Endpoint local relying party callback
+/// GET {UTAUrl}:{UTAPort}/web_local/callback (public)
+///
+/// ### Description
+/// Redirect URI of the local relying party. Exchanges the code for tokens, verifies the ID token
+/// signature against the provider's JWKS along with its issuer, audience, expiry and nonce, and
+/// fetches the userinfo. Responds with both, so the whole flow can be checked from a browser
+#[utoipa::path(
+    tag = "Local relying party",
+    summary = "Completes the sign in",
+    params(Callback),
+    responses(
+        (status = 200, description = "ID token claims and userinfo")
+    )
+)]
+#[get("/callback")]
+async fn callback(request: HttpRequest, query: web::Query<Callback>) -> HttpResponse {
+
+    let Some(config) = EnvironmentConfig::instance().get_local_relying_party().await else {
+        return problem_response(ErrorCode::RelyingPartyNotConfigured, "Local relying party not configured")
+    };
+
+    if let Some(error) = &query.error {
+        return problem_response(ErrorCode::InvalidCallback, format!("Authorization failed: {}", error).as_str())
+    }
+
+    //  The state must match the one sent in the login, or the callback wasn't started here
+    let flow = request.cookie(FLOW_COOKIE).map(|cookie| cookie.value().to_string()).unwrap_or_default();
+    let flow = flow.split('.').collect::<Vec<_>>();
+    let (Some(code), Some(state), [expected_state, nonce, code_verifier]) = (&query.code, &query.state, flow.as_slice()) else {
+        return problem_response(ErrorCode::InvalidCallback, "Invalid callback")
+    };
+    if state != expected_state {
+        return problem_response(ErrorCode::InvalidCallback, "Invalid state")
+    }
+
+    let provider = match requests::fetch_provider_metadata(EnvironmentConfig::instance().get_issuer().await.as_str()).await {
+        Ok(provider) => provider,
+        Err(_) => return problem_response(ErrorCode::ProviderError, "Error discovering the provider")
+    };
+
+    let tokens = match requests::exchange_code(&provider, &config, code.as_str(), code_verifier).await {
+        Ok(tokens) => tokens,
+        Err(_) => return problem_response(ErrorCode::ProviderError, "Error exchanging the authorization code")
+    };
+
+    let jwks = match requests::fetch_jwks(&provider).await {
+        Ok(jwks) => jwks,
+        Err(_) => return problem_response(ErrorCode::ProviderError, "Error fetching the provider keys")
+    };
+
+    let Some(id_token_claims) = tokens.id_token.as_deref()
+        .and_then(|id_token| auth::jwt::verify::<IdTokenClaims>(id_token, &jwks)) else {
+        return problem_response(ErrorCode::InvalidIdToken, "Invalid ID token signature")
+    };
+
+    if id_token_claims.iss != provider.issuer
+        || id_token_claims.aud != config.get_client_id()
+        || id_token_claims.exp < chrono::Utc::now().timestamp()
+        || id_token_claims.nonce.as_deref() != Some(*nonce) {
+        return problem_response(ErrorCode::InvalidIdToken, "Invalid ID token claims")
+    }
+
+    let userinfo = match requests::fetch_userinfo(&provider, tokens.access_token.as_str()).await {
+        Ok(userinfo) if userinfo.sub == id_token_claims.user_info.sub => userinfo,
+        Ok(_) => return problem_response(ErrorCode::InvalidIdToken, "Userinfo subject doesn't match the ID token"),
+        Err(_) => return problem_response(ErrorCode::ProviderError, "Error fetching the userinfo")
+    };
+
+    match general::http_req_res::serialize_into_json(&SignedIn { id_token_claims, userinfo }) {
+        Ok(body) => json_response(StatusCode::OK, body),
+        Err(_) => problem_response(ErrorCode::Internal, "Error signing in")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use actix_web::{App, HttpServer, web};
+    use actix_web::cookie::Cookie;
+    use actix_web::http::header::LOCATION;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use serde_json::{json, Map, Value};
+    use crate::auth;
+    use crate::auth::jwt::SigningKey;
+    use crate::config::environment::{EnvironmentConfig, EnvironmentSettings, LocalRelyingPartyConfig};
+    use crate::modules::oauth::oidc::{IdTokenClaims, UserInfo};
+    use crate::modules::users::user::Level;
+    use super::FLOW_COOKIE;
+
+    const CLIENT_ID: &str = "local-relying-party";
+    const CODE: &str = "authorization-code";
+    const ACCESS_TOKEN: &str = "uto_access_token";
+
+    /// The relying party reads the provider from the global config, so the tests take turns
+    static PROVIDER_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    /// What the provider stand-in got from the authorization request, to check the token request
+    /// against it
+    #[derive(Default)]
+    struct Authorization {
+        code_challenge: String,
+        nonce: String
+    }
+
+    struct Provider {
+        issuer: String,
+        authorization: Mutex<Authorization>
+    }
+
+    fn user_info() -> UserInfo {
+        UserInfo {
+            sub: "42".to_string(),
+            preferred_username: Some("ada".to_string()),
+            name: Some("Ada Lovelace".to_string()),
+            given_name: None,
+            family_name: None,
+            locale: None,
+            zoneinfo: None,
+            picture: None,
+            email: Some("ada@example.com".to_string()),
+            level: Some(Level::Low),
+            attributes: Map::new()
+        }
+    }
+
+    async fn discovery(provider: web::Data<Provider>) -> web::Json<Value> {
+        web::Json(json!({
+            "issuer": provider.issuer,
+            "authorization_endpoint": format!("{}/oauth/authorize", provider.issuer),
+            "token_endpoint": format!("{}/oauth/token", provider.issuer),
+            "userinfo_endpoint": format!("{}/oauth/userinfo", provider.issuer),
+            "jwks_uri": format!("{}/oauth/jwks", provider.issuer)
+        }))
+    }
+
+    /// Issues the tokens for the code, checking the PKCE verifier and the client the same way the
+    /// token endpoint does, and signs the ID token with the service's signing key
+    async fn token(provider: web::Data<Provider>, form: web::Form<Vec<(String, String)>>) -> actix_web::HttpResponse {
+        let field = |name: &str| form.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+        let authorization = provider.authorization.lock().unwrap();
+
+        if field("grant_type") != Some("authorization_code")
+            || field("code") != Some(CODE)
+            || field("client_id") != Some(CLIENT_ID)
+            || field("code_verifier").map(auth::crypt::pkce_challenge) != Some(authorization.code_challenge.clone()) {
+            return actix_web::HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" }))
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        let id_token = SigningKey::instance().unwrap().sign(&IdTokenClaims {
+            iss: provider.issuer.clone(),
+            aud: CLIENT_ID.to_string(),
+            exp: now + 300,
+            iat: now,
+            nonce: Some(authorization.nonce.clone()),
+            user_info: user_info()
+        }).unwrap();
+
+        actix_web::HttpResponse::Ok().json(json!({
+            "access_token": ACCESS_TOKEN,
+            "token_type": "Bearer",
+            "id_token": id_token
+        }))
+    }
+
+    async fn jwks() -> actix_web::HttpResponse {
+        actix_web::HttpResponse::Ok().json(SigningKey::instance().unwrap().jwks().unwrap())
+    }
+
+    async fn userinfo(request: actix_web::HttpRequest) -> actix_web::HttpResponse {
+        let bearer = format!("Bearer {}", ACCESS_TOKEN);
+        match request.headers().get("authorization").and_then(|value| value.to_str().ok()) {
+            Some(authorization) if authorization == bearer => actix_web::HttpResponse::Ok().json(user_info()),
+            _ => actix_web::HttpResponse::Unauthorized().finish()
+        }
+    }
+
+    /// Starts the provider stand-in on a free port and points the relying party at it
+    async fn start_provider() -> web::Data<Provider> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+
+        let provider = web::Data::new(Provider { issuer: issuer.clone(), authorization: Mutex::default() });
+        let app_provider = provider.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(app_provider.clone())
+                .route("/.well-known/openid-configuration", web::get().to(discovery))
+                .route("/oauth/token", web::post().to(token))
+                .route("/oauth/jwks", web::get().to(jwks))
+                .route("/oauth/userinfo", web::get().to(userinfo))
+        })
+            .workers(1)
+            .listen(listener).unwrap()
+            .run();
+        actix_web::rt::spawn(server);
+
+        EnvironmentConfig::instance().set_settings(
+            EnvironmentSettings::new("127.0.0.1", "0", "")
+                .with_issuer(issuer.as_str())
+                .with_local_relying_party(
+                    LocalRelyingPartyConfig::new(CLIENT_ID, None, "https://127.0.0.1/web_local/callback")
+                )
+        ).await;
+
+        provider
+    }
+
+    fn query_value(url: &str, name: &str) -> String {
+        let query = url.split_once('?').unwrap().1;
+        serde_urlencoded::from_str::<Vec<(String, String)>>(query).unwrap()
+            .into_iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn relying_party_signs_in_through_the_provider() {
+        let _lock = PROVIDER_LOCK.lock().await;
+        let provider = start_provider().await;
+        let app = init_service(App::new().service(web::scope("/web_local").configure(super::services))).await;
+
+        //  Discovery, and the redirect to the authorization endpoint with PKCE, state and nonce
+        let response = call_service(&app, TestRequest::get().uri("/web_local/login").to_request()).await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        let location = response.headers().get(LOCATION).unwrap().to_str().unwrap().to_string();
+        assert!(location.starts_with(format!("{}/oauth/authorize?", provider.issuer).as_str()));
+        assert_eq!(query_value(&location, "client_id"), CLIENT_ID);
+        assert_eq!(query_value(&location, "code_challenge_method"), "S256");
+        let flow = response.response().cookies().find(|cookie| cookie.name() == FLOW_COOKIE).unwrap().value().to_string();
+
+        //  The provider authenticates the user and redirects back with the code
+        *provider.authorization.lock().unwrap() = Authorization {
+            code_challenge: query_value(&location, "code_challenge"),
+            nonce: query_value(&location, "nonce")
+        };
+        let state = query_value(&location, "state");
+
+        //  Code exchange, ID token verification and userinfo
+        let request = TestRequest::get()
+            .uri(format!("/web_local/callback?code={}&state={}", CODE, state).as_str())
+            .cookie(Cookie::new(FLOW_COOKIE, flow))
+            .to_request();
+        let response = call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let signed_in: Value = read_body_json(response).await;
+        assert_eq!(signed_in["id_token_claims"]["sub"], "42");
+        assert_eq!(signed_in["id_token_claims"]["aud"], CLIENT_ID);
+        assert_eq!(signed_in["id_token_claims"]["iss"], provider.issuer.as_str());
+        assert_eq!(signed_in["userinfo"]["preferred_username"], "ada");
+        assert_eq!(signed_in["userinfo"]["email"], "ada@example.com");
+    }
+
+    #[actix_web::test]
+    async fn relying_party_rejects_a_forged_callback() {
+        let _lock = PROVIDER_LOCK.lock().await;
+        let provider = start_provider().await;
+        let app = init_service(App::new().service(web::scope("/web_local").configure(super::services))).await;
+
+        let response = call_service(&app, TestRequest::get().uri("/web_local/login").to_request()).await;
+        let location = response.headers().get(LOCATION).unwrap().to_str().unwrap().to_string();
+        let flow = response.response().cookies().find(|cookie| cookie.name() == FLOW_COOKIE).unwrap().value().to_string();
+        *provider.authorization.lock().unwrap() = Authorization {
+            code_challenge: query_value(&location, "code_challenge"),
+            nonce: "another nonce".to_string()
+        };
+
+        //  A state that's not the one sent in the login
+        let request = TestRequest::get()
+            .uri(format!("/web_local/callback?code={}&state=forged", CODE).as_str())
+            .cookie(Cookie::new(FLOW_COOKIE, flow.clone()))
+            .to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::BAD_REQUEST);
+
+        //  An ID token issued for another sign in
+        let request = TestRequest::get()
+            .uri(format!("/web_local/callback?code={}&state={}", CODE, query_value(&location, "state")).as_str())
+            .cookie(Cookie::new(FLOW_COOKIE, flow))
+            .to_request();
+        let response = call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let problem: Value = read_body_json(response).await;
+        assert_eq!(problem["detail"], "Invalid ID token claims");
+    }
+}
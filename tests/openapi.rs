@@ -0,0 +1,87 @@
+//! Checks the OpenAPI document and the routes `api::configure` mounts don't diverge
+
+use std::collections::BTreeSet;
+use actix_web::{test, web, App, HttpResponse};
+use actix_web::http::StatusCode;
+use actix_web::introspection::IntrospectionTree;
+use tokio::sync::broadcast;
+use token_authentication_public::api;
+use token_authentication_public::api::openapi;
+
+/// Routes serving the document and its docs UI, which aren't part of it
+const DOCUMENT_ROUTES: [&str; 3] = [
+    "/v1/api/openapi.json",
+    "/v1/api/docs",
+    "/v1/api/docs/{_:.*}"
+];
+
+/// Responds with the routes of the app, as actix reports them
+async fn routes(tree: web::Data<IntrospectionTree>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(tree.report_as_json())
+}
+
+/// Path and method of every operation in the document
+fn documented_operations() -> BTreeSet<(String, String)> {
+    openapi::document(true).paths.paths.into_iter()
+        .flat_map(|(path, item)| {
+            [("GET", item.get), ("PUT", item.put), ("POST", item.post), ("PATCH", item.patch), ("DELETE", item.delete)]
+                .into_iter()
+                .filter(|(_, operation)| operation.is_some())
+                .map(move |(method, _)| (path.clone(), method.to_string()))
+        })
+        .collect()
+}
+
+/// Path and method of every route mounted under the versioned scopes and the well-known URIs, the
+/// unversioned ones are the same handlers
+fn mounted_operations(routes: &[serde_json::Value]) -> BTreeSet<(String, String)> {
+    routes.iter()
+        .filter(|route| route["resource_type"] == "resource")
+        .filter_map(|route| Some((route["full_path"].as_str()?, route["methods"].as_array()?)))
+        .filter(|(path, _)| (path.starts_with("/v1/") || path.starts_with("/.well-known/")) && !DOCUMENT_ROUTES.contains(path))
+        .flat_map(|(path, methods)| {
+            methods.iter()
+                .filter_map(|method| method.as_str())
+                .map(move |method| (path.to_string(), method.to_string()))
+        })
+        .collect()
+}
+
+#[actix_web::test]
+async fn openapi_document_matches_routes() {
+    let (sender, _) = broadcast::channel(1);
+    let app = test::init_service(
+        App::new()
+            .configure(|cfg| api::configure(cfg, sender, true))
+            .route("/__routes", web::get().to(routes))
+    ).await;
+
+    let routes: Vec<serde_json::Value> = test::call_and_read_body_json(
+        &app,
+        test::TestRequest::get().uri("/__routes").to_request()
+    ).await;
+    let mounted = mounted_operations(&routes);
+    let documented = documented_operations();
+
+    let undocumented = mounted.difference(&documented).collect::<Vec<_>>();
+    assert!(
+        undocumented.is_empty(),
+        "Routes mounted that aren't in the OpenAPI document, add them to the API of their scope in api::openapi: {:?}",
+        undocumented
+    );
+    let unmounted = documented.difference(&mounted).collect::<Vec<_>>();
+    assert!(unmounted.is_empty(), "Operations in the OpenAPI document that aren't mounted: {:?}", unmounted);
+
+    let response = test::call_service(&app, test::TestRequest::get().uri("/api/openapi.json").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let served: serde_json::Value = test::read_body_json(response).await;
+    assert!(served["openapi"].as_str().is_some_and(|version| version.starts_with("3.1")));
+
+    //  The docs UI and its assets are served from the binary
+    let response = test::call_service(&app, test::TestRequest::get().uri("/v1/api/docs/").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = test::call_service(&app, test::TestRequest::get().uri("/v1/api/docs/swagger-ui-bundle.js").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
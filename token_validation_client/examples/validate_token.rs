@@ -0,0 +1,31 @@
+//! Validates a token against a locally running server, twice, to show the second answer comes
+//! from the cache.
+//!
+//! UTA_CLIENT_ID=... UTA_CLIENT_SECRET=... cargo run -p token_validation_client --example validate_token -- <token>
+
+use std::time::Instant;
+use error_mapper::TheResult;
+use token_validation_client::{TokenValidator, ValidatorConfig};
+
+#[tokio::main]
+async fn main() -> TheResult<()> {
+
+    let introspection_url = std::env::var("UTA_INTROSPECTION_URL")
+        .unwrap_or("https://127.0.0.1:8010/v1/oauth/introspect".to_string());
+    let client_id = std::env::var("UTA_CLIENT_ID").unwrap_or_default();
+    let client_secret = std::env::var("UTA_CLIENT_SECRET").unwrap_or_default();
+    let token = std::env::args().nth(1).unwrap_or_default();
+
+    let validator = TokenValidator::new(
+        ValidatorConfig::new(introspection_url.as_str(), client_id.as_str(), client_secret.as_str())
+            .accept_invalid_certs(true)
+    )?;
+
+    for _ in 0..2 {
+        let start = Instant::now();
+        let token_info = validator.validate(token.as_str()).await?;
+        println!("{:?} in {:?}", token_info, start.elapsed());
+    }
+
+    Ok(())
+}
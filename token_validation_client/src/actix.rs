@@ -0,0 +1,90 @@
+//! Actix Web middleware validating the bearer token of every request. The [`TokenInfo`] of valid
+//! tokens is inserted in the request extensions for the handlers
+
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use actix_web::{Error, HttpMessage};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::AUTHORIZATION;
+use futures_util::future::LocalBoxFuture;
+use crate::token_info::{Level, TokenInfo};
+use crate::validator::{Rejection, TokenValidator};
+
+pub struct TokenValidation {
+    validator: Arc<TokenValidator>,
+    level: Option<Level>
+}
+
+impl TokenValidation {
+    pub fn new(validator: Arc<TokenValidator>) -> Self {
+        Self { validator, level: None }
+    }
+
+    /// Requests with tokens acting with a level below this one are forbidden
+    pub fn with_level(mut self, level: Level) -> Self {
+        self.level = Some(level);
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for TokenValidation
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+        S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = TokenValidationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TokenValidationMiddleware {
+            service: Rc::new(service),
+            validator: Arc::clone(&self.validator),
+            level: self.level
+        }))
+    }
+}
+
+pub struct TokenValidationMiddleware<S> {
+    service: Rc<S>,
+    validator: Arc<TokenValidator>,
+    level: Option<Level>
+}
+
+impl<S, B> Service<ServiceRequest> for TokenValidationMiddleware<S>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+        S::Future: 'static
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+
+        let service = Rc::clone(&self.service);
+        let validator = Arc::clone(&self.validator);
+        let level = self.level;
+
+        Box::pin(async move {
+            let authorization = req.headers().get(AUTHORIZATION).and_then(|header| header.to_str().ok());
+            match validator.authorize(authorization, level).await {
+                Ok(token_info) => {
+                    req.extensions_mut().insert::<TokenInfo>(token_info);
+                    service.call(req).await
+                },
+                Err(Rejection::Unauthorized) => Err(actix_web::error::ErrorUnauthorized("Invalid token")),
+                Err(Rejection::Forbidden) => Err(actix_web::error::ErrorForbidden("Token level below required privileges")),
+                Err(Rejection::Unavailable) => Err(actix_web::error::ErrorServiceUnavailable("Token validation unavailable"))
+            }
+        })
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+use crate::token_info::TokenInfo;
+
+/// ## Description
+/// Introspection results by token. Active tokens are kept for the configured TTL, or until they
+/// expire if that's sooner, and inactive ones for the negative TTL. Tokens are only kept hashed
+pub(crate) struct IntrospectionCache {
+    entries: RwLock<HashMap<String, CacheEntry>>,
+    ttl: Duration,
+    negative_ttl: Duration,
+    max_entries: usize
+}
+
+struct CacheEntry {
+    token_info: Option<TokenInfo>,
+    expires_at: Instant
+}
+
+impl IntrospectionCache {
+    pub(crate) fn new(ttl: Duration, negative_ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            entries: RwLock::new(HashMap::new()),
+            ttl,
+            negative_ttl,
+            max_entries
+        }
+    }
+
+    /// Returns None on a miss, and Some with the cached result on a hit, which is None itself
+    /// for tokens known to be inactive
+    pub(crate) async fn get(&self, token: &str) -> Option<Option<TokenInfo>> {
+        let entries = self.entries.read().await;
+        let entry = entries.get(&cache_key(token))?;
+        if entry.expires_at <= Instant::now() {
+            return None
+        }
+        Some(entry.token_info.clone())
+    }
+
+    pub(crate) async fn insert(&self, token: &str, token_info: Option<TokenInfo>) {
+
+        let ttl = match &token_info {
+            Some(token_info) => self.ttl.min(time_until(token_info.exp)),
+            None => self.negative_ttl
+        };
+        if ttl.is_zero() {
+            return
+        }
+
+        let mut entries = self.entries.write().await;
+
+        //  When full, expired entries make room first. If there's still no room, the result just
+        // isn't cached
+        if entries.len() >= self.max_entries {
+            let now = Instant::now();
+            entries.retain(|_, entry| entry.expires_at > now);
+            if entries.len() >= self.max_entries {
+                return
+            }
+        }
+
+        entries.insert(
+            cache_key(token),
+            CacheEntry {
+                token_info,
+                expires_at: Instant::now() + ttl
+            }
+        );
+    }
+}
+
+fn cache_key(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn time_until(timestamp: i64) -> Duration {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+    Duration::from_secs(timestamp.saturating_sub(now).max(0) as u64)
+}
@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
+const DEFAULT_NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_CACHE_ENTRIES: usize = 10_000;
+
+/// ## Description
+/// Where and how to reach the introspection endpoint, and how long to trust its answers. The
+/// client must be a confidential OAuth client of the server, since only those can introspect
+#[derive(Debug, Clone)]
+pub struct ValidatorConfig {
+    pub(crate) introspection_url: String,
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) timeout: Duration,
+    pub(crate) connect_timeout: Duration,
+    pub(crate) cache_ttl: Duration,
+    pub(crate) negative_cache_ttl: Duration,
+    pub(crate) max_cache_entries: usize,
+    pub(crate) accept_invalid_certs: bool
+}
+
+impl ValidatorConfig {
+    pub fn new(introspection_url: &str, client_id: &str, client_secret: &str) -> Self {
+        Self {
+            introspection_url: introspection_url.to_string(),
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            timeout: DEFAULT_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            cache_ttl: DEFAULT_CACHE_TTL,
+            negative_cache_ttl: DEFAULT_NEGATIVE_CACHE_TTL,
+            max_cache_entries: DEFAULT_MAX_CACHE_ENTRIES,
+            accept_invalid_certs: false
+        }
+    }
+
+    /// Maximum time for the whole introspection request
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// How long active tokens are trusted without asking the server again. Never longer than the
+    /// token's own expiry, and a revoked token keeps being accepted until its entry expires
+    pub fn with_cache_ttl(mut self, cache_ttl: Duration) -> Self {
+        self.cache_ttl = cache_ttl;
+        self
+    }
+
+    /// How long inactive or unknown tokens are rejected without asking the server again
+    pub fn with_negative_cache_ttl(mut self, negative_cache_ttl: Duration) -> Self {
+        self.negative_cache_ttl = negative_cache_ttl;
+        self
+    }
+
+    pub fn with_max_cache_entries(mut self, max_cache_entries: usize) -> Self {
+        self.max_cache_entries = max_cache_entries;
+        self
+    }
+
+    /// Only meant for a server running locally with a self signed certificate
+    pub fn accept_invalid_certs(mut self, accept_invalid_certs: bool) -> Self {
+        self.accept_invalid_certs = accept_invalid_certs;
+        self
+    }
+}
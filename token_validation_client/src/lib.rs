@@ -0,0 +1,19 @@
+//! Client for services that accept tokens issued by the user token authentication server. Tokens
+//! are validated against the server's introspection endpoint, and the results are cached for a
+//! while so every request doesn't cost a round trip.
+//!
+//! Besides the [`TokenValidator`] itself, the `actix` and `tower` features (both enabled by
+//! default) provide a ready-made middleware for each of them.
+
+mod cache;
+mod config;
+mod token_info;
+mod validator;
+#[cfg(feature = "actix")]
+pub mod actix;
+#[cfg(feature = "tower")]
+pub mod tower;
+
+pub use config::ValidatorConfig;
+pub use token_info::{Level, TokenInfo};
+pub use validator::TokenValidator;
@@ -0,0 +1,48 @@
+use serde::{Deserialize, Serialize};
+
+/// Levels of the users of the server, from lowest to highest
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    #[default]
+    View = 0,
+    Low = 1,
+    Medium = 2,
+    High = 3,
+    Super = 4
+}
+
+/// ## Description
+/// What the server says about an active token. The level is the one the token acts with, already
+/// capped by its scopes for OAuth tokens
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TokenInfo {
+    /// Id of the user the token belongs to
+    pub sub: String,
+    pub username: String,
+    pub level: Level,
+    /// Unix timestamp the token expires at
+    pub exp: i64,
+    /// Unix timestamp the token was issued at
+    pub iat: i64,
+    /// Space separated scopes, only for OAuth tokens
+    pub scope: Option<String>,
+    /// "session_token", "access_token" or "refresh_token"
+    pub token_type: Option<String>
+}
+
+/// Introspection response as sent by the server. Inactive tokens only come with `active`
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct IntrospectionResponse {
+    active: bool,
+    #[serde(flatten)]
+    token_info: Option<TokenInfo>
+}
+
+impl IntrospectionResponse {
+    pub(crate) fn into_token_info(self) -> Option<TokenInfo> {
+        match self.active {
+            true => self.token_info,
+            false => None
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Tower layer validating the bearer token of every request, for axum, tonic or any other
+//! service built on tower. The [`TokenInfo`] of valid tokens is inserted in the request extensions
+
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use futures_util::future::BoxFuture;
+use http::{HeaderValue, Request, Response, StatusCode};
+use http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use tower::{Layer, Service};
+use crate::token_info::{Level, TokenInfo};
+use crate::validator::{Rejection, TokenValidator};
+
+#[derive(Clone)]
+pub struct TokenValidationLayer {
+    validator: Arc<TokenValidator>,
+    level: Option<Level>
+}
+
+impl TokenValidationLayer {
+    pub fn new(validator: Arc<TokenValidator>) -> Self {
+        Self { validator, level: None }
+    }
+
+    /// Requests with tokens acting with a level below this one are forbidden
+    pub fn with_level(mut self, level: Level) -> Self {
+        self.level = Some(level);
+        self
+    }
+}
+
+impl<S> Layer<S> for TokenValidationLayer {
+    type Service = TokenValidationService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TokenValidationService {
+            inner,
+            validator: Arc::clone(&self.validator),
+            level: self.level
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TokenValidationService<S> {
+    inner: S,
+    validator: Arc<TokenValidator>,
+    level: Option<Level>
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for TokenValidationService<S>
+    where
+        S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+        S::Future: Send + 'static,
+        ReqBody: Send + 'static,
+        ResBody: Default
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(ctx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+
+        //  The service that was polled ready is the one that must be called, a fresh clone is left
+        // in its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let validator = Arc::clone(&self.validator);
+        let level = self.level;
+
+        Box::pin(async move {
+            let authorization = req.headers().get(AUTHORIZATION).and_then(|header| header.to_str().ok());
+            let status_code = match validator.authorize(authorization, level).await {
+                Ok(token_info) => {
+                    req.extensions_mut().insert::<TokenInfo>(token_info);
+                    return inner.call(req).await
+                },
+                Err(Rejection::Unauthorized) => StatusCode::UNAUTHORIZED,
+                Err(Rejection::Forbidden) => StatusCode::FORBIDDEN,
+                Err(Rejection::Unavailable) => StatusCode::SERVICE_UNAVAILABLE
+            };
+
+            let mut response = Response::new(ResBody::default());
+            *response.status_mut() = status_code;
+            if status_code == StatusCode::UNAUTHORIZED {
+                response.headers_mut().insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            Ok(response)
+        })
+    }
+}
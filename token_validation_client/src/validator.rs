@@ -0,0 +1,106 @@
+use error_mapper::{map_to_new_error, SystemErrorCodes, TheError, TheResult};
+use crate::cache::IntrospectionCache;
+use crate::config::ValidatorConfig;
+use crate::token_info::{IntrospectionResponse, TokenInfo};
+#[cfg(any(feature = "actix", feature = "tower"))]
+use crate::token_info::Level;
+
+/// ## Description
+/// Validates tokens against the introspection endpoint of the server. Meant to be built once and
+/// shared, behind an Arc, by every request of the service
+pub struct TokenValidator {
+    http_client: reqwest::Client,
+    config: ValidatorConfig,
+    cache: IntrospectionCache
+}
+
+/// Why a request was turned down by the middlewares
+#[cfg(any(feature = "actix", feature = "tower"))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Rejection {
+    /// No bearer token, or the server says it's not active
+    Unauthorized,
+    /// The token is active but its level is below the required one
+    Forbidden,
+    /// The server couldn't be reached or didn't answer as expected
+    Unavailable
+}
+
+impl TokenValidator {
+    pub fn new(config: ValidatorConfig) -> TheResult<Self> {
+
+        let http_client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .connect_timeout(config.connect_timeout)
+            .danger_accept_invalid_certs(config.accept_invalid_certs)
+            .build()
+            .map_err(|e| map_to_new_error!(e))?;
+
+        Ok(Self {
+            http_client,
+            cache: IntrospectionCache::new(config.cache_ttl, config.negative_cache_ttl, config.max_cache_entries),
+            config
+        })
+    }
+
+    /// ## Description
+    /// Returns what the server says about the token if it's active, or None if it's not. Errors
+    /// reaching the server are returned as such, and never cached
+    pub async fn validate(&self, token: &str) -> TheResult<Option<TokenInfo>> {
+
+        if let Some(token_info) = self.cache.get(token).await {
+            return Ok(token_info)
+        }
+
+        let response = self.http_client
+            .post(self.config.introspection_url.as_str())
+            .basic_auth(self.config.client_id.as_str(), Some(self.config.client_secret.as_str()))
+            .form(&[("token", token)])
+            .send()
+            .await
+            .map_err(|e| map_to_new_error!(e))?;
+
+        if !response.status().is_success() {
+            return Err(
+                map_to_new_error!(
+                    TheError::new(
+                        SystemErrorCodes::GenericError,
+                        format!("Introspection failed with status {}", response.status())
+                    )
+                )
+            )
+        }
+
+        let token_info = response.json::<IntrospectionResponse>()
+            .await
+            .map_err(|e| map_to_new_error!(e))?
+            .into_token_info();
+
+        self.cache.insert(token, token_info.clone()).await;
+
+        Ok(token_info)
+    }
+
+    /// Validates the bearer token of the authorization header for the middlewares, requiring the
+    /// level received if any
+    #[cfg(any(feature = "actix", feature = "tower"))]
+    pub(crate) async fn authorize(
+        &self,
+        authorization: Option<&str>,
+        level: Option<Level>
+    ) -> Result<TokenInfo, Rejection> {
+
+        let Some(token) = authorization.and_then(|header| header.strip_prefix("Bearer ")) else {
+            return Err(Rejection::Unauthorized)
+        };
+
+        match self.validate(token).await {
+            Ok(Some(token_info)) => match level {
+                Some(level) if token_info.level < level => Err(Rejection::Forbidden),
+                _ => Ok(token_info)
+            },
+            Ok(None) => Err(Rejection::Unauthorized),
+            Err(_) => Err(Rejection::Unavailable)
+        }
+    }
+}
@@ -0,0 +1,384 @@
+//! Runs the validator and both middlewares against an instance of the server, spawned in a local
+//! port with `AuthService::configure`. The instance uses the database in config/env.json, which
+//! must be up, so the tests are ignored unless asked for:
+//!
+//! cargo test -p token_validation_client --test introspection -- --ignored
+//!
+//! Every test registers its own OAuth client, and the introspection requests are counted by the
+//! client that made them
+
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use actix_web::{App, HttpMessage, HttpRequest, HttpResponse, HttpServer, web};
+use actix_web::dev::Service;
+use actix_web::http::StatusCode;
+use actix_web::http::header::AUTHORIZATION;
+use actix_rt::{ArbiterHandle, System};
+use actix_web::test::{call_service, init_service, read_body, try_call_service, TestRequest};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use mysql_async::prelude::Queryable;
+use tower::ServiceExt;
+use token_authentication_public::AuthService;
+use token_authentication_public::auth::crypt;
+use token_authentication_public::config::environment::{CONFIG_FILE_PATH, EnvironmentSettings};
+use token_authentication_public::database;
+use token_authentication_public::database::db_conn;
+use token_authentication_public::general::types::{OAuthClientsIdType, UsersIdType};
+use token_authentication_public::modules::oauth::client::OAuthClient;
+use token_authentication_public::modules::oauth::scope::OAuthScope;
+use token_authentication_public::modules::oauth::token::{ACCESS_TOKEN_HEADER, OAuthToken};
+use token_authentication_public::modules::users::session_binding::ClientFingerprint;
+use token_authentication_public::modules::users::user;
+use token_authentication_public::modules::users::user::User;
+use token_validation_client::{Level, TokenInfo, TokenValidator, ValidatorConfig};
+use token_validation_client::actix::TokenValidation;
+use token_validation_client::tower::TokenValidationLayer;
+
+const INACTIVE: &str = "inactive-token";
+
+/// How long the introspection requests of the slow clients take
+const SLOW_INTROSPECTION: Duration = Duration::from_secs(2);
+
+static SERVER: OnceLock<Server> = OnceLock::new();
+
+/// Ids come from the last one in each table, the fixtures are created one at a time
+static SEEDING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Introspection requests made by each client, and the clients whose requests are delayed, by
+/// their Authorization header
+#[derive(Default)]
+struct Traffic {
+    hits: Mutex<HashMap<String, usize>>,
+    slow: Mutex<HashSet<String>>
+}
+
+struct Server {
+    url: String,
+    arbiter: ArbiterHandle,
+    traffic: Arc<Traffic>,
+    user_id: UsersIdType,
+    username: String
+}
+
+struct Client {
+    client_id: String,
+    secret: String,
+    authorization: String,
+    id: OAuthClientsIdType
+}
+
+impl Server {
+    /// Starts the server the first time, in a thread of its own that outlives the tests
+    fn get() -> &'static Self {
+        SERVER.get_or_init(|| {
+
+            //  The config, policy and signing key are read relative to the root of the repository
+            std::env::set_current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/..")).unwrap();
+
+            let (sender, receiver) = std::sync::mpsc::channel();
+            std::thread::spawn(move || System::new().block_on(async move {
+
+                let settings = EnvironmentSettings::from_file(CONFIG_FILE_PATH).unwrap().with_reset_db(false);
+                let auth_service = AuthService::builder().with_config(settings).build().await.unwrap();
+
+                //  Usernames are unique, each run creates its own user
+                let username = format!("client_{}", chrono::Utc::now().timestamp());
+                let (user_id, _) = User::create_user(
+                    username.as_str(),
+                    "Password123!",
+                    format!("{}@example.com", username).as_str(),
+                    &user::Level::High,
+                    &ClientFingerprint::default()
+                ).await.unwrap();
+
+                let traffic = Arc::new(Traffic::default());
+                let server_traffic = Arc::clone(&traffic);
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let url = format!("http://{}/v1/oauth/introspect", listener.local_addr().unwrap());
+
+                let server = HttpServer::new(move || {
+                    let traffic = Arc::clone(&server_traffic);
+                    let auth_service = auth_service.clone();
+                    App::new()
+                        .configure(|cfg| auth_service.configure(cfg))
+                        .wrap_fn(move |request, service| {
+                            let authorization = request.headers().get(AUTHORIZATION)
+                                .and_then(|header| header.to_str().ok())
+                                .unwrap_or_default()
+                                .to_string();
+                            let slow = request.path().ends_with("/introspect") && traffic.count(authorization);
+                            let response = service.call(request);
+                            async move {
+                                if slow {
+                                    tokio::time::sleep(SLOW_INTROSPECTION).await;
+                                }
+                                response.await
+                            }
+                        })
+                })
+                    .workers(2)
+                    .listen(listener)
+                    .unwrap()
+                    .run();
+
+                //  The fixtures are created in this thread too, where the connections of the pool live
+                sender.send(Server {
+                    url,
+                    arbiter: System::current().arbiter().clone(),
+                    traffic,
+                    user_id,
+                    username
+                }).unwrap();
+
+                server.await
+            }).unwrap());
+
+            receiver.recv().expect("The server failed to start, is the database in config/env.json up?")
+        })
+    }
+
+    /// Runs the future in the server's thread
+    async fn run<T: Send + 'static>(&self, future: impl Future<Output = T> + Send + 'static) -> T {
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        self.arbiter.spawn(async move {
+            let _guard = SEEDING.lock().await;
+            let _ = sender.send(future.await);
+        });
+        receiver.await.unwrap()
+    }
+
+    /// Registers a confidential client, whose introspection requests take `SLOW_INTROSPECTION` if
+    /// it's slow
+    async fn client(&self, slow: bool) -> Client {
+        let (client, secret) = self.run(async {
+            OAuthClient::create_oauth_client(
+                "Token validation client",
+                &["https://localhost/callback".to_string()],
+                &[OAuthScope::Read, OAuthScope::Admin],
+                true,
+                None,
+                false
+            ).await
+        }).await.unwrap();
+
+        let client_id = client.get_client_id().to_string();
+        let secret = secret.unwrap();
+        let authorization = format!("Basic {}", STANDARD.encode(format!("{}:{}", client_id, secret)));
+        if slow {
+            self.traffic.slow.lock().unwrap().insert(authorization.clone());
+        }
+
+        Client { client_id, secret, authorization, id: *client.get_id() }
+    }
+
+    /// Access token issued to the client for the user of the run
+    async fn token(&self, client: &Client, scopes: &[OAuthScope]) -> String {
+        let (oauth_clients_id, user_id, scopes) = (client.id, self.user_id, scopes.to_vec());
+        let (_, access_token, _) = self.run(async move {
+            OAuthToken::create_oauth_token(&oauth_clients_id, &user_id, scopes.as_slice(), false).await
+        }).await.unwrap();
+        access_token
+    }
+
+    /// Moves the expiry of the access token to the given seconds from now
+    async fn expire_in(&self, access_token: &str, seconds: i64) {
+        let prefix = crypt::get_prefix_from_key(access_token, ACCESS_TOKEN_HEADER).unwrap().to_string();
+        let expires_at = chrono::Utc::now().naive_utc() + chrono::Duration::seconds(seconds);
+        self.run(async move {
+            let conn = &mut db_conn::get_conn().await.unwrap();
+            conn.exec_drop(
+                "UPDATE oauth_tokens SET expires_at = ? WHERE access_prefix = ?",
+                (expires_at.format(database::DATETIME_FORMAT).to_string(), prefix)
+            ).await
+        }).await.unwrap();
+    }
+
+    fn validator(&self, client: &Client, config: impl FnOnce(ValidatorConfig) -> ValidatorConfig) -> Arc<TokenValidator> {
+        let defaults = ValidatorConfig::new(self.url.as_str(), client.client_id.as_str(), client.secret.as_str());
+        Arc::new(TokenValidator::new(config(defaults)).unwrap())
+    }
+
+    fn hits(&self, client: &Client) -> usize {
+        self.traffic.hits.lock().unwrap().get(&client.authorization).copied().unwrap_or_default()
+    }
+}
+
+impl Traffic {
+    /// Counts an introspection request, returning whether it's from a slow client
+    fn count(&self, authorization: String) -> bool {
+        let slow = self.slow.lock().unwrap().contains(&authorization);
+        *self.hits.lock().unwrap().entry(authorization).or_default() += 1;
+        slow
+    }
+}
+
+#[actix_web::test]
+#[ignore = "needs the database in config/env.json"]
+async fn active_tokens_are_cached() {
+
+    let server = Server::get();
+    let client = server.client(false).await;
+    let token = server.token(&client, &[OAuthScope::Admin]).await;
+    let validator = server.validator(&client, |config| config);
+
+    for _ in 0..3 {
+        let token_info = validator.validate(token.as_str()).await.unwrap().unwrap();
+        assert_eq!(token_info.username, server.username);
+        assert_eq!(token_info.level, Level::High);
+    }
+    assert_eq!(server.hits(&client), 1);
+}
+
+#[actix_web::test]
+#[ignore = "needs the database in config/env.json"]
+async fn inactive_tokens_are_cached_for_the_negative_ttl() {
+
+    let server = Server::get();
+    let client = server.client(false).await;
+    let validator = server.validator(&client, |config| config.with_negative_cache_ttl(Duration::from_millis(300)));
+
+    assert!(validator.validate(INACTIVE).await.unwrap().is_none());
+    assert!(validator.validate(INACTIVE).await.unwrap().is_none());
+    assert_eq!(server.hits(&client), 1);
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+
+    assert!(validator.validate(INACTIVE).await.unwrap().is_none());
+    assert_eq!(server.hits(&client), 2);
+}
+
+#[actix_web::test]
+#[ignore = "needs the database in config/env.json"]
+async fn cache_ttl_is_capped_at_the_token_expiry() {
+
+    let server = Server::get();
+    let client = server.client(false).await;
+    let token = server.token(&client, &[OAuthScope::Admin]).await;
+    server.expire_in(token.as_str(), 2).await;
+    let validator = server.validator(&client, |config| config.with_cache_ttl(Duration::from_secs(3600)));
+
+    validator.validate(token.as_str()).await.unwrap().unwrap();
+    validator.validate(token.as_str()).await.unwrap().unwrap();
+    assert_eq!(server.hits(&client), 1);
+
+    //  The token expires within 2 seconds, the hour of TTL doesn't keep it any longer
+    tokio::time::sleep(Duration::from_millis(2100)).await;
+
+    assert!(validator.validate(token.as_str()).await.unwrap().is_none());
+    assert_eq!(server.hits(&client), 2);
+}
+
+#[actix_web::test]
+#[ignore = "needs the database in config/env.json"]
+async fn timeouts_are_errors_and_never_cached() {
+
+    let server = Server::get();
+    let client = server.client(true).await;
+    let token = server.token(&client, &[OAuthScope::Admin]).await;
+    let validator = server.validator(&client, |config| config.with_timeout(Duration::from_millis(200)));
+
+    assert!(validator.validate(token.as_str()).await.is_err());
+    assert!(validator.validate(token.as_str()).await.is_err());
+    assert_eq!(server.hits(&client), 2);
+}
+
+#[actix_web::test]
+#[ignore = "needs the database in config/env.json"]
+async fn actix_middleware() {
+
+    let server = Server::get();
+    let client = server.client(false).await;
+    let active = server.token(&client, &[OAuthScope::Admin]).await;
+    let low = server.token(&client, &[OAuthScope::Read]).await;
+    let slow_client = server.client(true).await;
+    let slow = server.token(&slow_client, &[OAuthScope::Admin]).await;
+
+    let app_with = |validator: Arc<TokenValidator>| init_service(
+        App::new()
+            .route(
+                "/",
+                web::get().to(|request: HttpRequest| async move {
+                    let username = request.extensions().get::<TokenInfo>().map(|token_info| token_info.username.clone());
+                    HttpResponse::Ok().body(username.unwrap_or_default())
+                })
+            )
+            .wrap(TokenValidation::new(validator).with_level(Level::Medium))
+    );
+    let app = app_with(server.validator(&client, |config| config)).await;
+    let slow_app = app_with(server.validator(&slow_client, |config| config.with_timeout(Duration::from_millis(200)))).await;
+
+    let request_with = |authorization: Option<String>| {
+        let mut request = TestRequest::get().uri("/");
+        if let Some(authorization) = authorization {
+            request = request.insert_header(("authorization", authorization));
+        }
+        request.to_request()
+    };
+
+    let response = call_service(&app, request_with(Some(format!("Bearer {active}")))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(read_body(response).await, server.username);
+
+    //  The middleware answers its rejections as errors, which the server turns into responses
+    let cases = [
+        (None, StatusCode::UNAUTHORIZED),
+        (Some(active.clone()), StatusCode::UNAUTHORIZED),
+        (Some(format!("Bearer {INACTIVE}")), StatusCode::UNAUTHORIZED),
+        (Some(format!("Bearer {low}")), StatusCode::FORBIDDEN)
+    ];
+    for (authorization, status_code) in cases {
+        let error = try_call_service(&app, request_with(authorization)).await.unwrap_err();
+        assert_eq!(error.as_response_error().status_code(), status_code);
+    }
+
+    let error = try_call_service(&slow_app, request_with(Some(format!("Bearer {slow}")))).await.unwrap_err();
+    assert_eq!(error.as_response_error().status_code(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[actix_web::test]
+#[ignore = "needs the database in config/env.json"]
+async fn tower_layer() {
+
+    let server = Server::get();
+    let client = server.client(false).await;
+    let active = server.token(&client, &[OAuthScope::Admin]).await;
+    let low = server.token(&client, &[OAuthScope::Read]).await;
+    let slow_client = server.client(true).await;
+    let slow = server.token(&slow_client, &[OAuthScope::Admin]).await;
+
+    let service_with = |validator: Arc<TokenValidator>| tower::ServiceBuilder::new()
+        .layer(TokenValidationLayer::new(validator).with_level(Level::Medium))
+        .service_fn(|request: http::Request<()>| async move {
+            let username = request.extensions().get::<TokenInfo>().map(|token_info| token_info.username.clone());
+            Ok::<_, std::convert::Infallible>(http::Response::new(username.unwrap_or_default()))
+        });
+    let service = service_with(server.validator(&client, |config| config));
+    let slow_service = service_with(server.validator(&slow_client, |config| config.with_timeout(Duration::from_millis(200))));
+
+    let request_with = |authorization: Option<String>| {
+        let mut request = http::Request::builder();
+        if let Some(authorization) = authorization {
+            request = request.header("authorization", authorization);
+        }
+        request.body(()).unwrap()
+    };
+
+    let response = service.clone().oneshot(request_with(Some(format!("Bearer {active}")))).await.unwrap();
+    assert_eq!(response.status(), http::StatusCode::OK);
+    assert_eq!(response.body(), &server.username);
+
+    let response = service.clone().oneshot(request_with(None)).await.unwrap();
+    assert_eq!(response.status(), http::StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers().get("www-authenticate").unwrap(), "Bearer");
+
+    let response = service.clone().oneshot(request_with(Some(format!("Bearer {INACTIVE}")))).await.unwrap();
+    assert_eq!(response.status(), http::StatusCode::UNAUTHORIZED);
+    let response = service.oneshot(request_with(Some(format!("Bearer {low}")))).await.unwrap();
+    assert_eq!(response.status(), http::StatusCode::FORBIDDEN);
+    let response = slow_service.oneshot(request_with(Some(format!("Bearer {slow}")))).await.unwrap();
+    assert_eq!(response.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+}